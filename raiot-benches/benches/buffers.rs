@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use raiot_buffers::CircularBuffer;
+
+const BUFFER_SIZE: usize = 4 * 1024;
+
+fn append_and_read(c: &mut Criterion) {
+    let stream = raiot_benches::session_stream();
+    let mut group = c.benchmark_group("circular_buffer");
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    group.bench_function("append_read", |b| {
+        let mut sut = CircularBuffer::new(BUFFER_SIZE);
+        b.iter(|| {
+            sut.append_all_bytes(&stream).unwrap();
+            let _ = sut.read_bytes(stream.len());
+        })
+    });
+    // appends which wrap around the end of the buffer
+    group.bench_function("wrapping_append_read", |b| {
+        let mut sut = CircularBuffer::new(stream.len() + stream.len() / 2);
+        b.iter(|| {
+            sut.append_all_bytes(&stream).unwrap();
+            let _ = sut.read_bytes(stream.len());
+        })
+    });
+    group.bench_function("write_into", |b| {
+        let mut sut = CircularBuffer::new(BUFFER_SIZE);
+        let mut sink = Vec::with_capacity(stream.len());
+        b.iter(|| {
+            sut.append_all_bytes(&stream).unwrap();
+            sink.clear();
+            sut.write_into(&mut sink).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, append_and_read);
+criterion_main!(benches);
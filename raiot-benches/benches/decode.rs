@@ -0,0 +1,22 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use mqtt::packet::VariablePacket;
+use mqtt::Decodable;
+use raiot_protocol::IotCodec;
+
+fn decode_packet(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_packet");
+    for (name, bytes) in raiot_benches::ALL.iter().copied() {
+        let packet = VariablePacket::decode(&mut &bytes[..]).unwrap();
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || packet.clone(),
+                |packet| IotCodec::decode_packet(packet).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode_packet);
+criterion_main!(benches);
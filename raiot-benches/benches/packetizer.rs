@@ -0,0 +1,33 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use raiot_mqtt::packets::MqttPacketizer;
+
+fn packetize_session(c: &mut Criterion) {
+    let stream = raiot_benches::session_stream();
+    let mut group = c.benchmark_group("packetizer");
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    group.bench_function("session_stream", |b| {
+        let mut sut = MqttPacketizer::new();
+        b.iter(|| {
+            sut.append_all_bytes(&stream).unwrap();
+            let mut packets = 0;
+            while sut.get_next_packet().unwrap().is_some() {
+                packets += 1;
+            }
+            assert_eq!(packets, raiot_benches::ALL.len());
+        })
+    });
+    // a packet arriving a few bytes at a time, as over a slow link
+    group.bench_function("fragmented_c2d", |b| {
+        let mut sut = MqttPacketizer::new();
+        b.iter(|| {
+            for fragment in raiot_benches::C2D.chunks(16) {
+                sut.append_all_bytes(fragment).unwrap();
+                let _ = sut.get_next_packet().unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, packetize_session);
+criterion_main!(benches);
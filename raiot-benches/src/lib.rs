@@ -0,0 +1,56 @@
+//! Packets captured from an IoT Hub session, replayed by the benchmarks.
+//! Run with `cargo bench -p raiot-benches`.
+
+/// A C2D message with a typical set of system properties, QoS 1
+pub const C2D: &[u8] = include_bytes!("../fixtures/c2d.bin");
+
+/// A direct method invocation, QoS 0
+pub const DIRECT_METHOD: &[u8] = include_bytes!("../fixtures/dmi.bin");
+
+/// The response to a full twin read
+pub const TWIN_RESPONSE: &[u8] = include_bytes!("../fixtures/twin_response.bin");
+
+/// A desired properties update notification
+pub const DESIRED_UPDATE: &[u8] = include_bytes!("../fixtures/desired_update.bin");
+
+/// A telemetry acknowledgement
+pub const PUBACK: &[u8] = include_bytes!("../fixtures/puback.bin");
+
+/// A subscription acknowledgement
+pub const SUBACK: &[u8] = include_bytes!("../fixtures/suback.bin");
+
+/// Every fixture, by name
+pub const ALL: &[(&str, &[u8])] = &[
+    ("c2d", C2D),
+    ("direct_method", DIRECT_METHOD),
+    ("twin_response", TWIN_RESPONSE),
+    ("desired_update", DESIRED_UPDATE),
+    ("puback", PUBACK),
+    ("suback", SUBACK),
+];
+
+/// The fixtures back to back, as read from the stream of a busy session
+pub fn session_stream() -> Vec<u8> {
+    ALL.iter().flat_map(|(_, packet)| packet.iter().copied()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt::packet::VariablePacket;
+    use mqtt::Decodable;
+    use raiot_protocol::{IotCodec, MsgFromHub};
+
+    #[test]
+    fn test_fixtures_decode_to_known_messages() {
+        for (name, mut packet) in ALL.iter().copied() {
+            let decoded = VariablePacket::decode(&mut packet).unwrap();
+            assert!(packet.is_empty(), "{} has trailing bytes", name);
+            match IotCodec::decode_packet(decoded) {
+                Ok(MsgFromHub::UnknownMessage()) => panic!("{} is not a hub message", name),
+                Err(e) => panic!("{} failed to decode: {:?}", name, e),
+                Ok(_) => {}
+            }
+        }
+    }
+}
@@ -13,7 +13,7 @@ pub enum BufferSlice<'a> {
 impl Read for BufferSlice<'_> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self {
-            BufferSlice::Consecutive(ref mut bytes) => return bytes.read(buf),
+            BufferSlice::Consecutive(ref mut bytes) => bytes.read(buf),
             BufferSlice::Splitted(ref mut part1, ref mut part2) => {
                 if buf.len() <= part1.len() {
                     part1.read(buf)
@@ -61,7 +61,7 @@ impl CircularBuffer {
 
     /// TRUE if the buffer is completely empty
     pub fn is_empty(&self) -> bool {
-        return (self.read == self.write) && !self.full;
+        (self.read == self.write) && !self.full
     }
 
     /// The buffer capacity, in bytes
@@ -130,16 +130,15 @@ impl CircularBuffer {
         let from = self.read;
         self.read = (self.read + length) % self.size();
         self.full = false;
-        let slice = self.get_buffer_slice(from, length);
-        return slice;
+        self.get_buffer_slice(from, length)
     }
 
     /// The amount of data bytes currently in the buffer
     pub fn valid_length(&self) -> usize {
         if self.is_full() {
-            return self.size();
+            self.size()
         } else if self.is_empty() {
-            return 0;
+            0
         } else if self.write >= self.read {
             self.write - self.read
         } else {
@@ -156,16 +155,12 @@ impl CircularBuffer {
             return 0;
         }
 
-        let used: usize;
-        if self.write >= self.read {
-            used = self.write - self.read;
-            let available = self.size() - used;
-            available
+        let used = if self.write >= self.read {
+            self.write - self.read
         } else {
-            used = self.write + self.size() - self.read;
-            let available = self.size() - used;
-            available
-        }
+            self.write + self.size() - self.read
+        };
+        self.size() - used
     }
 
     /// Writes all the specified bytes into the buffer.
@@ -253,7 +248,7 @@ impl CircularBuffer {
 
     fn get_next_consecutive_buffer(&mut self) -> &mut [u8] {
         let (from, to) = self.get_next_consecutive_free_space();
-        return &mut self.buffer[from..to];
+        &mut self.buffer[from..to]
     }
 
     fn get_next_consecutive_free_space(&mut self) -> (usize, usize) {
@@ -362,20 +357,20 @@ mod tests {
     fn test_buffer_write_available_space() {
         let mut sut = CircularBuffer::new(10);
         assert_eq!(sut.available_space(), 10);
-        assert_eq!(sut.is_empty(), true);
+        assert!(sut.is_empty());
         let test_data = b"01234";
         sut.write_all(test_data).unwrap();
         assert_eq!(sut.available_space(), 5);
         sut.write_all(test_data).unwrap();
         assert_eq!(sut.available_space(), 0);
-        assert_eq!(sut.is_full(), true);
+        assert!(sut.is_full());
         let read_slice = sut.read_bytes(5);
         assert_eq!(read_slice.len(), 5);
         assert_eq!(sut.available_space(), 5);
         let read_slice = sut.read_bytes(5);
         assert_eq!(read_slice.len(), 5);
         assert_eq!(sut.available_space(), 10);
-        assert_eq!(sut.is_empty(), true);
+        assert!(sut.is_empty());
     }
 
     #[test]
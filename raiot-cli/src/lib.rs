@@ -1,13 +1,19 @@
+use std::sync::Arc;
 use std::time::Duration;
 
-use raiot_client_base::ConnectionSettings;
+use raiot_client_base::{ConnectionSettings, ProxySettings, RetrySchedules};
 use raiot_protocol::{
-    auth::{certificate::DeviceCertificate, DeviceCredentials},
-    qos::SessionMode,
-    ClientIdentity,
+    auth::{certificate::DeviceCertificate, sas::derive_device_key, DeviceCredentials},
+    connect::MqttClientId,
+    validate_id, ClientIdentity, IdentityError,
 };
 use structopt::StructOpt;
 
+/// Rejects invalid device and module IDs with a usage error, rather than failing to connect
+fn parse_id(id: &str) -> Result<String, IdentityError> {
+    validate_id(id).map(|()| id.to_owned())
+}
+
 #[derive(StructOpt)]
 pub struct Options {
     #[structopt(short = "p", long = "port", default_value = "8883")]
@@ -16,12 +22,31 @@ pub struct Options {
     #[structopt(short = "h", long = "hostname")]
     pub hostname: String,
 
-    #[structopt(short = "d", long = "device")]
+    #[structopt(long = "gateway-hostname")]
+    pub gateway_hostname: Option<String>,
+
+    /// The MQTT client ID to connect with, for gateways requiring prefixed or tenant-scoped IDs
+    #[structopt(long = "mqtt-client-id")]
+    pub mqtt_client_id: Option<String>,
+
+    /// A PEM file of CAs to trust in addition to the system's, e.g. the root CA of the gateway
+    #[structopt(long = "trusted-ca-file")]
+    pub trusted_ca_file: Option<String>,
+
+    #[structopt(short = "d", long = "device", parse(try_from_str = "parse_id"))]
     pub device_id: String,
 
+    /// Connect as this module of the device, e.g. an IoT Edge module
+    #[structopt(long = "module", parse(try_from_str = "parse_id"))]
+    pub module_id: Option<String>,
+
     #[structopt(short = "k", long = "key")]
     pub key: Option<String>,
 
+    /// The SAS key of the device's enrollment group (e.g. an IoT Central application), from which the device key is derived
+    #[structopt(long = "group-key")]
+    pub group_key: Option<String>,
+
     #[structopt(long = "cert-file")]
     pub cert_file: Option<String>,
 
@@ -31,38 +56,88 @@ pub struct Options {
     #[structopt(long = "connect-timeout", default_value = "30")]
     pub connect_timeout_secs: u16,
 
+    /// An HTTP proxy to tunnel the connection through, as host:port
+    #[structopt(long = "proxy")]
+    pub proxy: Option<String>,
+
+    #[structopt(long = "proxy-user")]
+    pub proxy_user: Option<String>,
+
+    #[structopt(long = "proxy-pass")]
+    pub proxy_pass: Option<String>,
+
     #[structopt(long = "token-ttl", default_value = "60")]
     pub token_ttl_mins: u64,
+
+    /// Log payloads in full. Credentials are masked either way.
+    #[structopt(long = "verbose-logs")]
+    pub verbose_logs: bool,
 }
 
 impl Options {
     pub fn from_cmd_line() -> Options {
-        Options::from_args()
+        let options = Options::from_args();
+        raiot_protocol::redact::set_verbose_logging(options.verbose_logs);
+        options
     }
 
     pub fn get_connection_settings(&self) -> ConnectionSettings {
         ConnectionSettings {
             hostname: self.hostname.clone(),
-            client_id: ClientIdentity::from_device_id(&self.device_id),
+            client_id: self.get_identity(),
             port: self.port,
             timeout: Duration::from_secs(self.connect_timeout_secs as u64),
-            session_mode: SessionMode::Clean,
             token_ttl: Duration::from_secs(60 * self.token_ttl_mins),
             credentials: self.get_credentials(),
+            client_id_override: self.mqtt_client_id.as_ref().map(|client_id| {
+                MqttClientId::new(client_id).unwrap_or_else(|e| panic!("Invalid MQTT client ID: {}", e))
+            }),
+            gateway_hostname: self.gateway_hostname.clone(),
+            retry_policy: Some(Arc::new(RetrySchedules::default())),
+            proxy: self.get_proxy(),
+            trusted_ca_certs: self.trusted_ca_file.as_ref().map(|path| {
+                std::fs::read(path).unwrap_or_else(|e| panic!("Failed to read the trusted CA file: {}", e))
+            }),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_identity(&self) -> ClientIdentity {
+        let identity = match &self.module_id {
+            Some(module_id) => ClientIdentity::try_from_module_id(&self.device_id, module_id),
+            None => ClientIdentity::try_from_device_id(&self.device_id),
+        };
+        identity.expect("IDs are validated when parsing the command line")
+    }
+
+    pub fn get_proxy(&self) -> Option<ProxySettings> {
+        let proxy = self.proxy.as_ref()?;
+        let (host, port) = proxy
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .unwrap_or_else(|| panic!("Invalid proxy, expected host:port: {}", proxy));
+        let proxy = ProxySettings::new(host, port);
+        match (&self.proxy_user, &self.proxy_pass) {
+            (Some(user), Some(pass)) => Some(proxy.with_credentials(user, pass)),
+            (None, None) => Some(proxy),
+            _ => panic!("Must provide both proxy user and password, or neither"),
         }
     }
 
     pub fn get_credentials(&self) -> DeviceCredentials {
         if let Some(ref key) = self.key {
             DeviceCredentials::Sas(key.clone())
-        } else if self.cert_file.is_some() && self.cert_pass.is_some() {
+        } else if let Some(ref group_key) = self.group_key {
+            let key = derive_device_key(group_key, &self.device_id)
+                .unwrap_or_else(|e| panic!("Invalid group key: {}", e));
+            DeviceCredentials::Sas(key)
+        } else if let (Some(cert_file), Some(cert_pass)) = (&self.cert_file, &self.cert_pass) {
             DeviceCredentials::Certificate(DeviceCertificate {
-                bytes: std::fs::read(std::path::PathBuf::from(&self.cert_file.as_ref().unwrap()))
-                    .unwrap(),
-                password: self.cert_pass.as_ref().unwrap().clone(),
+                bytes: std::fs::read(std::path::PathBuf::from(cert_file)).unwrap(),
+                password: cert_pass.clone(),
             })
         } else {
-            panic!("Must provide certificate + password, or SAS key");
+            panic!("Must provide certificate + password, SAS key, or group SAS key");
         }
     }
 }
@@ -0,0 +1,180 @@
+use std::time::SystemTime;
+
+use raiot_protocol::{qos::PacketId, MsgFromHub, MsgToHub};
+
+/// The property carrying the application-defined ID of telemetry and C2D messages
+pub const MESSAGE_ID_PROPERTY: &str = "$.mid";
+
+/// Receives a record of every application message sent or received by a client.
+/// Invoked synchronously on the client's I/O path, so implementations should be quick (e.g. append to a file or queue).
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AuditDirection {
+    Outbound,
+    Inbound,
+}
+
+/// The kinds of application messages; acknowledgements and subscriptions are not audited
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AuditKind {
+    Telemetry,
+    TwinRead,
+    ReportedPropertiesUpdate,
+    DirectMethodResponse,
+    TwinResponse,
+    DesiredPropertiesUpdate,
+    CloudToDevice,
+    ModuleInput,
+    DirectMethodInvocation,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    /// The message was handed to the transport
+    Sent,
+
+    /// The message could not be sent
+    Failed(String),
+
+    /// The message was received from the hub
+    Received,
+
+    /// The message was discarded by the client, e.g. an expired C2D message or one dropped by an interceptor
+    Dropped,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub timestamp: SystemTime,
+    pub direction: AuditDirection,
+    pub kind: AuditKind,
+    pub packet_id: Option<PacketId>,
+    /// The message ID of telemetry and C2D messages, or the request ID of twin and direct method messages
+    pub message_id: Option<String>,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditRecord {
+    /// Records an outbound message. Returns None if the message is not an application message.
+    pub fn outbound(msg: &MsgToHub, outcome: AuditOutcome) -> Option<AuditRecord> {
+        let (kind, message_id) = match msg {
+            MsgToHub::Telemetry(msg) => (
+                AuditKind::Telemetry,
+                msg.headers.as_ref().and_then(|h| h.get(MESSAGE_ID_PROPERTY)).cloned(),
+            ),
+            MsgToHub::ReadTwin(msg) => (AuditKind::TwinRead, Some(msg.request_id.clone())),
+            MsgToHub::UpdateReportedProperties(msg) => {
+                (AuditKind::ReportedPropertiesUpdate, Some(msg.request_id.clone()))
+            }
+            MsgToHub::DirectMethodResponse(msg) => {
+                (AuditKind::DirectMethodResponse, Some(msg.request_id.clone()))
+            }
+            _ => return None,
+        };
+        Some(AuditRecord {
+            timestamp: SystemTime::now(),
+            direction: AuditDirection::Outbound,
+            kind,
+            packet_id: msg.packet_id(),
+            message_id,
+            outcome,
+        })
+    }
+
+    /// Records an inbound message. Returns None if the message is not an application message.
+    pub fn inbound(msg: &MsgFromHub, outcome: AuditOutcome) -> Option<AuditRecord> {
+        let (kind, packet_id, message_id) = match msg {
+            MsgFromHub::TwinResponseMessage(msg) => {
+                (AuditKind::TwinResponse, msg.packet_id, Some(msg.request_id.clone()))
+            }
+            MsgFromHub::ReportedPropertiesUpdated(msg) => {
+                (AuditKind::TwinResponse, msg.packet_id, Some(msg.request_id.clone()))
+            }
+            MsgFromHub::DesiredPropertiesUpdated(msg) => (
+                AuditKind::DesiredPropertiesUpdate,
+                msg.packet_id,
+                Some(msg.desired_properties_version.to_string()),
+            ),
+            MsgFromHub::CloudToDeviceMessage(msg) => (
+                AuditKind::CloudToDevice,
+                msg.packet_id,
+                msg.props.as_ref().and_then(|p| p.get(MESSAGE_ID_PROPERTY)).cloned(),
+            ),
+            MsgFromHub::ModuleInputMessage(msg) => (
+                AuditKind::ModuleInput,
+                msg.packet_id,
+                msg.props.as_ref().and_then(|p| p.get(MESSAGE_ID_PROPERTY)).cloned(),
+            ),
+            MsgFromHub::DirectMethodInvocation(msg) => (
+                AuditKind::DirectMethodInvocation,
+                msg.packet_id,
+                Some(msg.request_id.clone()),
+            ),
+            _ => return None,
+        };
+        Some(AuditRecord {
+            timestamp: SystemTime::now(),
+            direction: AuditDirection::Inbound,
+            kind,
+            packet_id,
+            message_id,
+            outcome,
+        })
+    }
+}
+
+/// Records an outbound message into the sink, if any
+pub fn audit_outbound(sink: Option<&dyn AuditSink>, msg: &MsgToHub, outcome: AuditOutcome) {
+    if let (Some(sink), Some(record)) = (sink, AuditRecord::outbound(msg, outcome)) {
+        sink.record(&record);
+    }
+}
+
+/// Records an inbound message into the sink, if any
+pub fn audit_inbound(sink: Option<&dyn AuditSink>, msg: &MsgFromHub, outcome: AuditOutcome) {
+    if let (Some(sink), Some(record)) = (sink, AuditRecord::inbound(msg, outcome)) {
+        sink.record(&record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raiot_protocol::messages::c2d::C2DMsg;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemorySink(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for MemorySink {
+        fn record(&self, record: &AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[test]
+    fn test_only_application_messages_are_audited() {
+        let sink = MemorySink::default();
+        let mut props = HashMap::new();
+        let _ = props.insert(MESSAGE_ID_PROPERTY.to_owned(), "m1".to_owned());
+        let c2d = MsgFromHub::CloudToDeviceMessage(C2DMsg {
+            packet_id: None,
+            body: None,
+            device_id: "d1".to_owned(),
+            props: Some(props),
+        });
+
+        audit_inbound(Some(&sink), &c2d, AuditOutcome::Received);
+        audit_inbound(Some(&sink), &MsgFromHub::PublicationSucceeded(1.into()), AuditOutcome::Received);
+
+        let records = sink.0.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, AuditKind::CloudToDevice);
+        assert_eq!(records[0].direction, AuditDirection::Inbound);
+        assert_eq!(records[0].message_id.as_deref(), Some("m1"));
+    }
+}
@@ -0,0 +1,178 @@
+//! The optional IoT Hub features of the clients. Each client implements them with its cargo features,
+//! so that a telemetry-only build carries none of the others.
+//!
+//! The handlers, and what requests return, are the client's: nothing for a client which handles the hub's
+//! responses as it processes its connection, a future of the hub's answer for an asynchronous client.
+
+use std::fmt;
+use std::time::Duration;
+
+use raiot_errors::TransportError;
+use raiot_protocol::qos::DeliveryGuarantees;
+
+/// Reading the twin and receiving desired properties updates
+pub trait TwinCapable {
+    /// Receives the desired properties updates
+    type TwinUpdatesHandler: ?Sized;
+
+    /// What reading the twin returns
+    type TwinRead<'a>
+    where
+        Self: 'a;
+
+    /// What unsubscribing returns
+    type Unsubscribed<'a>
+    where
+        Self: 'a;
+
+    /// Requests the twin, subscribing to the twin responses first if needed
+    fn read_twin(&mut self) -> Self::TwinRead<'_>;
+
+    /// Subscribes to desired properties updates. A mode of None uses the default twin delivery guarantees.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::Transport` if the subscription could not be written to the connection
+    fn sub_twin_updates(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<Self::TwinUpdatesHandler>,
+    ) -> Result<(), CapabilityError>;
+
+    /// Stops receiving desired properties updates and drops the twin updates handler
+    fn unsub_twin_updates(&mut self) -> Self::Unsubscribed<'_>;
+}
+
+/// Receiving and answering direct method invocations
+pub trait MethodsCapable {
+    /// Receives the direct method invocations
+    type DMIHandler: ?Sized;
+
+    /// What unsubscribing returns
+    type Unsubscribed<'a>
+    where
+        Self: 'a;
+
+    /// Subscribes to direct method invocations. A mode of None uses the default methods delivery guarantees,
+    /// or those of the current subscription.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::ModeConflict` if the client can't change the delivery guarantees
+    /// of its current subscription, with `CapabilityError::Transport` if the subscription could not be written
+    fn sub_dmi(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<Self::DMIHandler>,
+    ) -> Result<(), CapabilityError>;
+
+    /// Stops receiving direct method invocations and drops the DMI handler
+    fn unsub_dmi(&mut self) -> Self::Unsubscribed<'_>;
+
+    /// Sets the time the hub waits for direct method responses (the invocation's responseTimeoutInSeconds).
+    /// Invocations not answered in time are answered with a timeout status.
+    fn set_dmi_response_window(&mut self, window: Duration);
+}
+
+/// Receiving C2D messages on a device, or the messages routed to the inputs of a module
+pub trait C2DCapable {
+    /// Receives the C2D messages
+    type C2DHandler: ?Sized;
+
+    /// Receives the C2D messages which already expired upon receipt
+    type C2DExpiredHandler: ?Sized;
+
+    /// Receives the messages routed to the inputs of the module
+    type InputHandler: ?Sized;
+
+    /// What unsubscribing returns
+    type Unsubscribed<'a>
+    where
+        Self: 'a;
+
+    /// Subscribes to C2D messages. A mode of None uses the default C2D delivery guarantees.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotADevice` on a module,
+    /// with `CapabilityError::Transport` if the subscription could not be written
+    fn sub_c2d(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<Self::C2DHandler>,
+    ) -> Result<(), CapabilityError>;
+
+    /// Drops C2D messages that already expired upon receipt, passing them to the specified handler
+    /// instead of the C2D handler
+    fn set_c2d_expired_handler(&mut self, handler: Box<Self::C2DExpiredHandler>);
+
+    /// Stops receiving C2D messages and drops the C2D handler
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotADevice` on a module
+    fn unsub_c2d(&mut self) -> Result<Self::Unsubscribed<'_>, CapabilityError>;
+
+    /// Subscribes to the messages routed to the inputs of the module, which modules receive instead of C2D messages.
+    /// A mode of None uses the default C2D delivery guarantees.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotAModule` on a device,
+    /// with `CapabilityError::Transport` if the subscription could not be written
+    fn sub_inputs(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<Self::InputHandler>,
+    ) -> Result<(), CapabilityError>;
+
+    /// Stops receiving the messages routed to the inputs of the module and drops the input handler
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotAModule` on a device
+    fn unsub_inputs(&mut self) -> Result<Self::Unsubscribed<'_>, CapabilityError>;
+}
+
+/// A feature the client can't provide
+#[derive(Debug)]
+pub enum CapabilityError {
+    /// C2D messages are received by devices: modules receive the messages routed to their inputs instead
+    NotADevice,
+
+    /// Only modules have inputs
+    NotAModule,
+
+    /// The client is already subscribed with other delivery guarantees, which its other handlers rely on
+    ModeConflict {
+        subscribed: DeliveryGuarantees,
+        requested: DeliveryGuarantees,
+    },
+
+    /// The subscription could not be written to the connection
+    Transport(TransportError),
+}
+
+impl From<TransportError> for CapabilityError {
+    fn from(e: TransportError) -> Self {
+        CapabilityError::Transport(e)
+    }
+}
+
+impl fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapabilityError::NotADevice => write!(f, "C2D messages are only received by devices, modules receive inputs"),
+            CapabilityError::NotAModule => write!(f, "Only modules receive the messages routed to inputs"),
+            CapabilityError::ModeConflict { subscribed, requested } => write!(
+                f,
+                "Already subscribed with {:?}, cannot subscribe with {:?}",
+                subscribed, requested
+            ),
+            CapabilityError::Transport(e) => write!(f, "Failed sending the subscription: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CapabilityError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CapabilityError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
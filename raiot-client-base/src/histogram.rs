@@ -0,0 +1,169 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counts values into buckets with fixed upper bounds, and one more bucket for the values above the last bound.
+/// Updated lock-free, so it can be recorded into on the I/O path and read from any thread.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<u64>,
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    /// A histogram with the specified (inclusive) bucket upper bounds, in increasing order
+    pub fn new(bounds: Vec<u64>) -> Histogram {
+        assert!(
+            bounds.windows(2).all(|pair| pair[0] < pair[1]),
+            "Histogram bounds must be increasing"
+        );
+        Histogram {
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    /// A histogram of durations, recorded in microseconds
+    pub fn of_durations(bounds: &[Duration]) -> Histogram {
+        Histogram::new(bounds.iter().map(|bound| bound.as_micros() as u64).collect())
+    }
+
+    pub fn record(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    pub fn record_duration(&self, duration: Duration) {
+        self.record(duration.as_micros() as u64);
+    }
+
+    /// The counts so far. Values recorded concurrently may be missing from some of the totals.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let counts: Vec<u64> = self.counts.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        HistogramSnapshot {
+            buckets: self
+                .bounds
+                .iter()
+                .map(|bound| Some(*bound))
+                .chain(Some(None))
+                .zip(counts.iter().copied())
+                .collect(),
+            count: counts.iter().sum(),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counts of a `Histogram` at some point
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// The upper bound of every bucket (None for the values above the last bound), with its count
+    pub buckets: Vec<(Option<u64>, u64)>,
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            count => Some(self.sum as f64 / count as f64),
+        }
+    }
+
+    /// An upper estimate of the specified percentile (0 to 100): the upper bound of the bucket it falls into,
+    /// or the maximum for the values above the last bound
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Some(bound.map_or(self.max, |bound| bound.min(self.max)));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// One line per non-empty bucket, e.g. `<= 1024: 17`, after a summary line
+impl fmt::Display for HistogramSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "count={} sum={} max={}", self.count, self.sum, self.max)?;
+        if let (Some(p50), Some(p99)) = (self.percentile(50.0), self.percentile(99.0)) {
+            write!(f, " p50<={} p99<={}", p50, p99)?;
+        }
+        for (bound, count) in self.buckets.iter().filter(|(_, count)| *count > 0) {
+            match bound {
+                Some(bound) => write!(f, "\n  <= {}: {}", bound, count)?,
+                None => {
+                    let last_bound = self.buckets.iter().filter_map(|(bound, _)| *bound).next_back();
+                    write!(f, "\n   > {}: {}", last_bound.unwrap_or_default(), count)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bucket bounds of the histograms kept by the raiot-client socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistogramBuckets {
+    /// Upper bounds of the payload sizes, in bytes
+    pub payload_sizes: Vec<u64>,
+
+    /// Upper bounds of the publish-to-acknowledgement latencies and direct method handling durations
+    pub latencies: Vec<Duration>,
+}
+
+impl Default for HistogramBuckets {
+    fn default() -> Self {
+        HistogramBuckets {
+            // 64 bytes to the hub's 256KB message size limit
+            payload_sizes: (0..7).map(|exponent| 64 << (2 * exponent)).collect(),
+            latencies: [1, 5, 10, 50, 100, 500, 1000, 5000, 10000, 30000]
+                .iter()
+                .map(|millis| Duration::from_millis(*millis))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_counts_values_into_buckets() {
+        let sut = Histogram::new(vec![10, 100]);
+        for value in [1, 10, 11, 100, 5000] {
+            sut.record(value);
+        }
+
+        let snapshot = sut.snapshot();
+        assert_eq!(snapshot.buckets, vec![(Some(10), 2), (Some(100), 2), (None, 1)]);
+        assert_eq!((snapshot.count, snapshot.sum, snapshot.max), (5, 5122, 5000));
+        assert_eq!(snapshot.percentile(40.0), Some(10));
+        assert_eq!(snapshot.percentile(50.0), Some(100));
+        assert_eq!(snapshot.percentile(100.0), Some(5000));
+        assert_eq!(
+            snapshot.to_string(),
+            "count=5 sum=5122 max=5000 p50<=100 p99<=5000\n  <= 10: 2\n  <= 100: 2\n   > 100: 1"
+        );
+
+        let empty = Histogram::of_durations(&HistogramBuckets::default().latencies).snapshot();
+        assert_eq!((empty.mean(), empty.percentile(50.0)), (None, None));
+        assert_eq!(HistogramBuckets::default().payload_sizes.last(), Some(&(256 * 1024)));
+    }
+}
@@ -1,8 +1,37 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt,
+    io::ErrorKind,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use raiot_protocol::{
-    auth::sas::SasToken, auth::DeviceCredentials, qos::PacketId, qos::SessionMode, ClientIdentity,
+    auth::sas::SasToken, auth::DeviceCredentials, qos::PacketId, qos::QosDefaults, qos::SessionMode,
+    telemetry::DIAGNOSTIC_CONTEXT_PROPERTY, telemetry::DIAGNOSTIC_ID_PROPERTY,
+    telemetry::SEQUENCE_NUMBER_PROPERTY, twin::StatusCode, ClientIdentity, DeviceIdentity, MsgFromHub,
+    connect::MqttClientId, messages::AckMsg, qos::DeliveryGuarantees, ActiveSubscription, MsgToHub, MultiSub,
+    connect::ConnectError, CodecOptions,
 };
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+pub use raiot_streams::{ClientCertificate, PollStrategy, ProxySettings, TlsSettings};
+
+pub mod audit;
+pub mod capabilities;
+pub mod histogram;
+pub mod memory;
+
+use crate::histogram::HistogramBuckets;
+use crate::memory::MemoryBudget;
+pub mod outbox;
+pub mod scheduler;
+
+/// The port of MQTT over TLS, which IoT Hub listens on
+pub const DEFAULT_PORT: u16 = 8883;
 
 #[derive(Clone, Debug)]
 pub struct ConnectionSettings {
@@ -13,23 +42,596 @@ pub struct ConnectionSettings {
     pub timeout: Duration,
     pub token_ttl: Duration,
     pub credentials: DeviceCredentials,
+    /// The MQTT client ID to connect with instead of the one derived from `client_id`, if any
+    pub client_id_override: Option<MqttClientId>,
+    /// The IoT Edge gateway to connect through, if any.
+    /// Only the TCP/TLS target changes: SAS tokens and MQTT usernames keep referring to `hostname`.
+    pub gateway_hostname: Option<String>,
+    /// The delivery guarantees used by the client unless overridden per call
+    pub qos: QosDefaults,
+    /// What to do when another client connects with the same identity, see `ReconnectPlanner`
+    pub takeover_policy: TakeoverPolicy,
+    /// Verify that C2D and module input messages are addressed to `client_id`, e.g. behind a shared gateway.
+    /// Messages addressed to another client are reported as `MsgFromHub::MisroutedMessage` and not processed.
+    pub validate_topics: bool,
+    /// How the TLS handshake waits for a slow link
+    pub handshake_poll: PollStrategy,
+    /// A budget keeping telemetry below the hub's quota for the device, if any
+    pub telemetry_quota: Option<TelemetryQuota>,
+    /// Reconnect with a fresh SAS token before the current one expires, see `TokenRenewal`.
+    /// None lets the hub close the connection once the token expires, reported as `DisconnectReason::TokenExpired`.
+    /// Clients which never reconnect by themselves, like the blocking client, leave renewal to the application.
+    pub token_renewal: Option<TokenRenewal>,
+    /// Reconnect lost connections as the policy allows, e.g. `ExponentialBackoff`.
+    /// None reports a lost connection without reconnecting, which is all the blocking client does:
+    /// its application connects again and resumes the session.
+    pub retry_policy: Option<Arc<dyn RetryPolicy>>,
+    /// Codec options applied to every message sent, e.g. strict mode failing messages which violate
+    /// IoT Hub constraints instead of sending them
+    pub codec: CodecOptions,
+    /// Generates SAS tokens by the hub's clock rather than the local one, see `ClockSync`.
+    /// The clients feed it the enqueued time of the C2D messages they receive.
+    pub clock: Option<Arc<ClockSync>>,
+    /// The HTTP proxy the TLS connection is tunneled through, if any. The proxy resolves the transport hostname.
+    pub proxy: Option<ProxySettings>,
+    /// PEM certificates of CAs trusted in addition to the system's, e.g. the CA of the IoT Edge gateway
+    /// in `gateway_hostname`
+    pub trusted_ca_certs: Option<Vec<u8>>,
+    /// Registers the device again once the retry policy gave up on reconnections refused for its credentials,
+    /// see `ConnectionEvent::ReprovisionRequired`. None only reports the event.
+    /// Without a retry policy, no reconnection is attempted and the device is never registered again.
+    pub reprovisioner: Option<Arc<dyn Reprovisioner>>,
+    /// The buckets of the payload size and latency histograms of the session statistics.
+    /// Unused by clients which keep no statistics.
+    pub histograms: HistogramBuckets,
+    /// The memory budget the buffers, the outbound queues and the messages awaiting an acknowledgement draw from.
+    /// None leaves them unbounded. The buffers of the raiot-client socket take 512KB of it, failing the connection
+    /// if the budget can't hold them. The blocking client, which writes straight to the stream, queues nothing.
+    pub memory_budget: Option<MemoryBudget>,
+}
+
+/// Settings to be completed with the hub, the identity and the credentials of the client, e.g. with
+/// `ConnectionSettings { hostname, client_id, credentials, ..Default::default() }`
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            hostname: String::new(),
+            port: DEFAULT_PORT,
+            client_id: ClientIdentity::Device(DeviceIdentity {
+                device_id: String::new(),
+            }),
+            session_mode: SessionMode::Clean,
+            timeout: Duration::from_secs(30),
+            token_ttl: Duration::from_secs(60 * 60),
+            credentials: DeviceCredentials::Sas(String::new()),
+            client_id_override: None,
+            gateway_hostname: None,
+            qos: QosDefaults::default(),
+            takeover_policy: TakeoverPolicy::default(),
+            validate_topics: false,
+            handshake_poll: PollStrategy::default(),
+            telemetry_quota: None,
+            token_renewal: None,
+            retry_policy: None,
+            codec: CodecOptions::default(),
+            clock: None,
+            proxy: None,
+            trusted_ca_certs: None,
+            reprovisioner: None,
+            histograms: HistogramBuckets::default(),
+            memory_budget: None,
+        }
+    }
+}
+
+impl ConnectionSettings {
+    /// The settings of a connection to the specified hub, with the defaults of every other setting
+    pub fn new(hostname: &str, client_id: ClientIdentity, credentials: DeviceCredentials) -> ConnectionSettings {
+        ConnectionSettings {
+            hostname: hostname.to_owned(),
+            client_id,
+            credentials,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_session_mode(mut self, session_mode: SessionMode) -> Self {
+        self.session_mode = session_mode;
+        self
+    }
+
+    pub fn with_token_ttl(mut self, token_ttl: Duration) -> Self {
+        self.token_ttl = token_ttl;
+        self
+    }
+
+    /// Connects through the specified IoT Edge gateway, see `gateway_hostname`
+    pub fn with_gateway(mut self, gateway_hostname: &str) -> Self {
+        self.gateway_hostname = Some(gateway_hostname.to_owned());
+        self
+    }
+
+    pub fn with_qos(mut self, qos: QosDefaults) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn with_token_renewal(mut self, renewal: TokenRenewal) -> Self {
+        self.token_renewal = Some(renewal);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, policy: Arc<dyn RetryPolicy>) -> Self {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    pub fn with_codec(mut self, codec: CodecOptions) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: ProxySettings) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Trusts the CAs of the specified PEM certificates, see `trusted_ca_certs`
+    pub fn with_trusted_ca_certs(mut self, pem: Vec<u8>) -> Self {
+        self.trusted_ca_certs = Some(pem);
+        self
+    }
+
+    pub fn with_reprovisioner(mut self, reprovisioner: Arc<dyn Reprovisioner>) -> Self {
+        self.reprovisioner = Some(reprovisioner);
+        self
+    }
+
+    pub fn with_memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// The host the TCP/TLS connection is opened to: the gateway, if any, or the hub itself
+    pub fn transport_hostname(&self) -> &str {
+        self.gateway_hostname.as_deref().unwrap_or(&self.hostname)
+    }
+
+    /// The TLS settings of the connection: the device certificate, if any, and the trusted CAs
+    pub fn tls_settings(&self) -> TlsSettings {
+        TlsSettings {
+            client_certificate: match &self.credentials {
+                DeviceCredentials::Certificate(cert) => Some(ClientCertificate {
+                    bytes: cert.bytes.clone(),
+                    password: cert.password.clone(),
+                }),
+                DeviceCredentials::Sas(_) => None,
+            },
+            trusted_ca_certs: self.trusted_ca_certs.clone(),
+        }
+    }
+
+    /// The expiry of the SAS token generated for a connection made at the specified time.
+    /// Returns None for certificate authentication, whose connections don't expire.
+    pub fn token_expiry(&self, connected_at: SystemTime) -> Option<SystemTime> {
+        match self.credentials {
+            DeviceCredentials::Sas(_) => Some(connected_at + self.token_ttl),
+            DeviceCredentials::Certificate(_) => None,
+        }
+    }
+
+    /// The time a connection made at the specified time should be renewed with a fresh SAS token.
+    /// Returns None if renewal is disabled, or for certificate authentication.
+    pub fn token_renewal_time(&self, connected_at: SystemTime) -> Option<SystemTime> {
+        let renewal = self.token_renewal?;
+        let expiry = self.token_expiry(connected_at)?;
+        Some(renewal.renew_at(expiry, self.token_ttl))
+    }
+}
+
+/// Renews SAS-authenticated connections ahead of the token expiry, which would otherwise make the hub close them.
+/// The client reconnects with a fresh token, replays its subscriptions
+/// and resends the messages awaiting an acknowledgement.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TokenRenewal {
+    /// How long before the token expiry the connection is renewed, at most half the token lifetime
+    pub margin: Duration,
+
+    /// How long to wait before retrying a renewal which failed to connect
+    pub retry_interval: Duration,
+}
+
+impl Default for TokenRenewal {
+    fn default() -> Self {
+        TokenRenewal {
+            margin: Duration::from_secs(5 * 60),
+            retry_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl TokenRenewal {
+    /// The time to renew a connection authenticated by a token with the specified expiry and lifetime
+    pub fn renew_at(&self, expiry: SystemTime, token_ttl: Duration) -> SystemTime {
+        expiry - self.margin.min(token_ttl / 2)
+    }
+}
+
+/// The topic filters a connection is subscribed to, so that they can be subscribed to again on a new connection
+#[derive(Clone, Debug, Default)]
+pub struct SubscriptionReplay {
+    filters: Vec<(String, DeliveryGuarantees)>,
+}
+
+impl SubscriptionReplay {
+    pub fn new() -> SubscriptionReplay {
+        SubscriptionReplay::default()
+    }
+
+    /// Records the topic filters added by a subscription request, or removed by an unsubscription request.
+    /// Other messages are ignored.
+    pub fn track(&mut self, msg: &MsgToHub) {
+        let added = match msg {
+            MsgToHub::SubscribeToMany(msg) => msg.filters.clone(),
+            MsgToHub::Unsubscribe(msg) => {
+                self.filters.retain(|(filter, _)| !msg.topic_filters.contains(filter));
+                return;
+            }
+            other => match ActiveSubscription::from_msg(other) {
+                Some(subscription) => vec![(subscription.topic_filter, subscription.mode)],
+                None => return,
+            },
+        };
+        for (topic_filter, mode) in added {
+            self.filters.retain(|(filter, _)| *filter != topic_filter);
+            self.filters.push((topic_filter, mode));
+        }
+    }
+
+    /// A single request subscribing to every tracked topic filter, or None if there are none
+    pub fn to_msg(&self, packet_id: PacketId) -> Option<MultiSub> {
+        if self.filters.is_empty() {
+            return None;
+        }
+        Some(MultiSub {
+            packet_id,
+            filters: self.filters.clone(),
+        })
+    }
+}
+
+/// A change of the state of the connection to the hub
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The connection was established again, after a reconnection or a token renewal
+    Connected,
+
+    /// The connection was lost, and will not be reconnected
+    Disconnected { reason: DisconnectReason },
+
+    /// The connection was lost, and the specified reconnection attempt (counting from 1) is about to be made
+    Reconnecting { reason: DisconnectReason, attempt: u32 },
+
+    /// The SAS token is about to expire, and the connection is being renewed with a fresh one
+    TokenExpired,
+
+    /// The retry policy gave up on reconnections refused for a reason which calls for provisioning the device again,
+    /// e.g. it was moved to another hub. The device is registered again if the settings have a `Reprovisioner`.
+    ReprovisionRequired { reason: DisconnectReason },
 }
 
+/// The state of the connection to the hub, following its `ConnectionEvent`s
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Connected, messages are sent as they are submitted
+    Connected,
+
+    /// Lost or renewing the connection, messages are sent once reconnected
+    Reconnecting,
+
+    /// The connection was lost for good, or ended by the client
+    Disconnected(DisconnectReason),
+}
+
+impl ConnectionStatus {
+    /// Moves to the state an event leads to
+    pub fn apply(&mut self, event: ConnectionEvent) {
+        *self = match event {
+            ConnectionEvent::Connected => ConnectionStatus::Connected,
+            ConnectionEvent::Disconnected { reason } => ConnectionStatus::Disconnected(reason),
+            ConnectionEvent::Reconnecting { .. } | ConnectionEvent::TokenExpired => ConnectionStatus::Reconnecting,
+            // followed by a reconnection or a disconnection
+            ConnectionEvent::ReprovisionRequired { .. } => return,
+        };
+    }
+}
+
+/// Disconnections this close to the token expiry are attributed to it, to account for clock skew
+pub const TOKEN_EXPIRY_GRACE: Duration = Duration::from_secs(30);
+
+/// Why the connection to the hub was lost. MQTT 3.1.1 gives no reason, so it is inferred by `DisconnectReason::infer`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The SAS token authenticating the connection expired
+    TokenExpired,
+
+    /// The hub closed the connection while the token was valid,
+    /// most likely because another client connected with the same identity
+    ClientIdTakeover,
+
+    /// The connection failed without the hub closing it
+    NetworkLoss(ErrorKind),
+
+    /// The client ended the connection gracefully, with an MQTT DISCONNECT
+    Requested,
+
+    /// The hub refused a reconnection as unavailable, typically during maintenance
+    ServiceUnavailable,
+
+    /// The hub refused the credentials of a reconnection
+    AuthenticationFailed,
+}
+
+impl DisconnectReason {
+    /// Infers the reason of a connection failure from the stream error, and the expiry of the token authenticating the connection.
+    /// Streams report a connection closed by the hub as UnexpectedEof.
+    pub fn infer(error: ErrorKind, token_expiry: Option<SystemTime>, now: SystemTime) -> DisconnectReason {
+        if token_expiry.is_some_and(|expiry| now + TOKEN_EXPIRY_GRACE >= expiry) {
+            return DisconnectReason::TokenExpired;
+        }
+        match error {
+            ErrorKind::UnexpectedEof => DisconnectReason::ClientIdTakeover,
+            other => DisconnectReason::NetworkLoss(other),
+        }
+    }
+
+    /// The reason a reconnection attempt failed with the connection error, if it calls for another retry schedule
+    /// than the disconnection did. Other failures don't tell anything new about the disconnection.
+    pub fn of_refusal(error: &ConnectError) -> Option<DisconnectReason> {
+        match error {
+            ConnectError::ServiceUnavailable => Some(DisconnectReason::ServiceUnavailable),
+            ConnectError::AuthenticationFailed | ConnectError::Unauthorized => {
+                Some(DisconnectReason::AuthenticationFailed)
+            }
+            _ => None,
+        }
+    }
+
+    /// TRUE if reconnections still refused for this reason once the retry policy gave up call for provisioning
+    /// the device again: the hub refusing the credentials for good is what a device deleted from it, or moved to
+    /// another hub by its provisioning service, runs into
+    pub fn requires_reprovisioning(&self) -> bool {
+        *self == DisconnectReason::AuthenticationFailed
+    }
+}
+
+/// Registers the device with its provisioning service again, e.g. `raiot_provisioning::ProvisioningSettings`
+pub trait Reprovisioner: fmt::Debug + Send + Sync {
+    /// The settings of a connection to the hub the device is now assigned to, derived from the current ones
+    fn reprovision(&self, current: &ConnectionSettings) -> Result<ConnectionSettings, Box<dyn Error + Send + Sync>>;
+}
+
+/// What to do when the hub drops the connection because another client connected with the same identity.
+/// Two clients sharing an identity keep taking the connection from each other, so reconnecting immediately causes a reconnect storm.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TakeoverPolicy {
+    /// Reconnect immediately, taking the identity back
+    Reconnect,
+
+    /// Reconnect after a delay, doubling with every consecutive takeover up to a maximum
+    BackOff { initial: Duration, max: Duration },
+
+    /// Stop reconnecting, failing with `IdentityTakenOver`
+    GiveUp,
+}
+
+impl Default for TakeoverPolicy {
+    fn default() -> Self {
+        TakeoverPolicy::BackOff {
+            initial: Duration::from_secs(5),
+            max: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Returned by `ReconnectPlanner` when the takeover policy forbids reconnecting
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IdentityTakenOver;
+
+impl fmt::Display for IdentityTakenOver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Another client is connected with the same identity")
+    }
+}
+
+impl std::error::Error for IdentityTakenOver {}
+
+/// Decides when a supervisor should reconnect a lost connection, applying the takeover policy
+pub struct ReconnectPlanner {
+    policy: TakeoverPolicy,
+    consecutive_takeovers: u32,
+}
+
+impl ReconnectPlanner {
+    pub fn new(policy: TakeoverPolicy) -> ReconnectPlanner {
+        ReconnectPlanner {
+            policy,
+            consecutive_takeovers: 0,
+        }
+    }
+
+    /// Returns the time to wait before reconnecting after a disconnection.
+    /// Disconnections other than takeovers are reconnected immediately, and reset the takeover back-off.
+    ///
+    /// # Errors
+    /// Returns IdentityTakenOver if the connection was taken over and the policy is to give up
+    pub fn next_delay(&mut self, reason: DisconnectReason) -> Result<Duration, IdentityTakenOver> {
+        if reason != DisconnectReason::ClientIdTakeover {
+            self.consecutive_takeovers = 0;
+            return Ok(Duration::from_secs(0));
+        }
+        let consecutive = self.consecutive_takeovers;
+        self.consecutive_takeovers += 1;
+        match self.policy {
+            TakeoverPolicy::Reconnect => Ok(Duration::from_secs(0)),
+            TakeoverPolicy::BackOff { initial, max } => Ok(initial
+                .checked_mul(1 << consecutive.min(16))
+                .unwrap_or(max)
+                .min(max)),
+            TakeoverPolicy::GiveUp => Err(IdentityTakenOver),
+        }
+    }
+}
+
+/// Decides whether, and when, a lost connection is reconnected.
+/// Takeovers are delayed by the takeover policy as well, see `ReconnectPlanner`.
+pub trait RetryPolicy: fmt::Debug + Send + Sync {
+    /// The delay before the specified reconnection attempt, counting from 1, or None to give up
+    fn next_delay(&self, attempt: u32, reason: DisconnectReason) -> Option<Duration>;
+}
+
+/// Reconnects after a delay doubling with every failed attempt, up to a maximum.
+/// Delays are randomized, so that devices cut off together don't all reconnect at the same time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ExponentialBackoff {
+    /// The delay before the first attempt
+    pub initial: Duration,
+
+    /// The longest delay between attempts
+    pub max: Duration,
+
+    /// The fraction of each delay which is randomized, between 0 and 1
+    pub jitter: f64,
+
+    /// Give up after this many failed attempts, if any
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        ExponentialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(2 * 60),
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ExponentialBackoff {
+    /// The delay before an attempt, given a random sample between 0 and 1
+    pub fn delay(&self, attempt: u32, sample: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let delay = self.initial.checked_mul(1 << exponent).unwrap_or(self.max).min(self.max);
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * sample.clamp(0.0, 1.0))
+    }
+}
+
+impl RetryPolicy for ExponentialBackoff {
+    fn next_delay(&self, attempt: u32, _reason: DisconnectReason) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max_attempts| attempt > max_attempts) {
+            return None;
+        }
+        // the first bytes of a random UUID are all random
+        let bytes = Uuid::new_v4();
+        let random = bytes.as_bytes()[..6].iter().fold(0u64, |acc, byte| acc << 8 | u64::from(*byte));
+        Some(self.delay(attempt, random as f64 / (1u64 << 48) as f64))
+    }
+}
+
+/// A retry schedule per kind of disconnection: hub maintenance is ridden out patiently with long delays,
+/// while refused credentials are retried a few times only, as they seldom fix themselves
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetrySchedules {
+    /// The schedule of network failures, token expiries and takeovers
+    pub default: ExponentialBackoff,
+
+    /// The schedule once the hub refused a reconnection as unavailable
+    pub maintenance: ExponentialBackoff,
+
+    /// The schedule once the hub refused the credentials of a reconnection
+    pub authentication: ExponentialBackoff,
+}
+
+impl Default for RetrySchedules {
+    fn default() -> Self {
+        RetrySchedules {
+            default: ExponentialBackoff::default(),
+            maintenance: ExponentialBackoff {
+                initial: Duration::from_secs(15),
+                max: Duration::from_secs(10 * 60),
+                jitter: 0.5,
+                max_attempts: None,
+            },
+            authentication: ExponentialBackoff {
+                initial: Duration::from_secs(5),
+                max: Duration::from_secs(60),
+                jitter: 0.5,
+                max_attempts: Some(3),
+            },
+        }
+    }
+}
+
+impl RetrySchedules {
+    /// The schedule applying to a disconnection
+    pub fn schedule(&self, reason: DisconnectReason) -> &ExponentialBackoff {
+        match reason {
+            DisconnectReason::ServiceUnavailable => &self.maintenance,
+            DisconnectReason::AuthenticationFailed => &self.authentication,
+            _ => &self.default,
+        }
+    }
+}
+
+impl RetryPolicy for RetrySchedules {
+    fn next_delay(&self, attempt: u32, reason: DisconnectReason) -> Option<Duration> {
+        self.schedule(reason).next_delay(attempt, reason)
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::TokenExpired => write!(f, "SAS token expired"),
+            DisconnectReason::ClientIdTakeover => write!(f, "Closed by the hub, another client may be using the same identity"),
+            DisconnectReason::NetworkLoss(kind) => write!(f, "Network failure: {:?}", kind),
+            DisconnectReason::Requested => write!(f, "Disconnected by the client"),
+            DisconnectReason::ServiceUnavailable => write!(f, "Hub unavailable"),
+            DisconnectReason::AuthenticationFailed => write!(f, "Credentials refused by the hub"),
+        }
+    }
+}
+
+/// Generates the SAS token of a connection, at the hub time estimated by the settings' `clock`, if any
 pub fn generate_sas_token(settings: &ConnectionSettings, key: &str) -> SasToken {
+    let now = settings.clock.as_ref().map_or_else(SystemTime::now, |clock| clock.now());
     match &settings.client_id {
-        ClientIdentity::Device(device) => SasToken::for_device(
+        ClientIdentity::Device(device) => SasToken::for_device_at(
             &settings.hostname,
             &device.device_id,
             key,
             settings.token_ttl,
+            now,
         )
         .expect("Token expected to be valid"),
-        ClientIdentity::Module(module) => SasToken::for_module(
+        ClientIdentity::Module(module) => SasToken::for_module_at(
             &settings.hostname,
             &module.device_id,
             &module.module_id,
             key,
             settings.token_ttl,
+            now,
         )
         .expect("Token expected to be valid"),
     }
@@ -45,6 +647,15 @@ pub trait DeviceClient {
     fn send_d2c(msg: D2CMsg);
 }
 
+/// The status reported to the hub when a direct method handler exceeds the response window
+pub const DMI_TIMEOUT_STATUS: i32 = 504;
+
+/// The status reported to the hub when a direct method cannot be handled because the client is overloaded
+pub const DMI_BUSY_STATUS: i32 = 503;
+
+/// IoT Hub's default direct method response timeout
+pub const DEFAULT_DMI_RESPONSE_WINDOW: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Clone)]
 pub struct DMIRequest {
     pub method_name: String,
@@ -75,6 +686,7 @@ impl DMIResponder {
 
 pub type DMIHandler = fn(DMIRequest, DMIResponder) -> DMIResult;
 
+#[derive(Default)]
 pub struct PacketsNumerator {
     value: u16,
 }
@@ -84,8 +696,1000 @@ impl PacketsNumerator {
         PacketsNumerator { value: 0 }
     }
 
-    pub fn next(&mut self) -> PacketId {
-        self.value += 1;
+    /// A numerator whose first packet ID follows the specified one, e.g. for tests comparing encoded packets
+    pub fn starting_after(value: u16) -> PacketsNumerator {
+        PacketsNumerator { value }
+    }
+
+    /// The next packet ID, wrapping around past 65535. Packet ID 0 is invalid and skipped.
+    pub fn next_id(&mut self) -> PacketId {
+        self.value = self.value.checked_add(1).unwrap_or(1);
         self.value.into()
     }
 }
+
+/// A user-provided generator of request identifiers
+pub type RequestIdGenerator = dyn FnMut() -> String + Send;
+
+/// The strategy used to generate the identifiers (`$rid`) of twin requests
+#[derive(Default)]
+pub enum RequestIdSource {
+    /// A random UUID per request
+    #[default]
+    Uuid,
+
+    /// A monotonic counter, starting after the specified value.
+    /// Cheaper than UUIDs, but only unique within the session.
+    Counter(u64),
+
+    /// A user-provided generator
+    Custom(Box<RequestIdGenerator>),
+}
+
+impl RequestIdSource {
+    /// Generates the next request identifier
+    pub fn next_id(&mut self) -> String {
+        match self {
+            RequestIdSource::Uuid => Uuid::new_v4().to_string(),
+            RequestIdSource::Counter(value) => {
+                *value += 1;
+                value.to_string()
+            }
+            RequestIdSource::Custom(generator) => generator(),
+        }
+    }
+}
+
+impl fmt::Debug for RequestIdSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestIdSource::Uuid => write!(f, "Uuid"),
+            RequestIdSource::Counter(value) => write!(f, "Counter({})", value),
+            RequestIdSource::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Samples telemetry messages for IoT Hub distributed tracing.
+/// Sampled messages are stamped with a Diagnostic-Id and a tracing context, so they can be correlated end-to-end.
+pub struct DiagnosticSampler {
+    sampling_percentage: u8,
+    count: u64,
+}
+
+impl DiagnosticSampler {
+    /// Creates a sampler that stamps the specified percentage of messages, evenly spread.
+    /// Percentages greater than 100 are clamped to 100.
+    pub fn new(sampling_percentage: u8) -> DiagnosticSampler {
+        DiagnosticSampler {
+            sampling_percentage: sampling_percentage.min(100),
+            count: 0,
+        }
+    }
+
+    pub fn sampling_percentage(&self) -> u8 {
+        self.sampling_percentage
+    }
+
+    /// Stamps the tracing properties into the headers if the next message is sampled.
+    /// Returns TRUE if the message was sampled.
+    pub fn stamp(&mut self, headers: &mut Option<HashMap<String, String>>) -> bool {
+        self.count += 1;
+        let percentage = self.sampling_percentage as u64;
+        if (self.count - 1) * percentage / 100 == self.count * percentage / 100 {
+            return false;
+        }
+
+        let trace_id = Uuid::new_v4().to_simple().to_string();
+        let span_id = Uuid::new_v4().to_simple().to_string();
+        let diagnostic_id = format!("00-{}-{}-01", trace_id, &span_id[..16]);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let headers = headers.get_or_insert_with(HashMap::new);
+        let _ = headers.insert(DIAGNOSTIC_ID_PROPERTY.to_owned(), diagnostic_id);
+        let _ = headers.insert(
+            DIAGNOSTIC_CONTEXT_PROPERTY.to_owned(),
+            format!("timestamp={}", timestamp),
+        );
+        true
+    }
+}
+
+/// Stamps a monotonic sequence number into every telemetry message, so backends can detect lost messages
+pub struct TelemetrySequencer {
+    next: u64,
+    last_acknowledged: Option<u64>,
+}
+
+impl TelemetrySequencer {
+    pub fn new() -> TelemetrySequencer {
+        TelemetrySequencer {
+            next: 1,
+            last_acknowledged: None,
+        }
+    }
+
+    /// Stamps the next sequence number into the headers, and returns it
+    pub fn stamp(&mut self, headers: &mut Option<HashMap<String, String>>) -> u64 {
+        let sequence_number = self.next;
+        self.next += 1;
+        let _ = headers
+            .get_or_insert_with(HashMap::new)
+            .insert(SEQUENCE_NUMBER_PROPERTY.to_owned(), sequence_number.to_string());
+        sequence_number
+    }
+
+    /// Records the acknowledgement of the message carrying the specified sequence number
+    pub fn acknowledge(&mut self, sequence_number: u64) {
+        if self.last_acknowledged.is_none_or(|last| sequence_number > last) {
+            self.last_acknowledged = Some(sequence_number);
+        }
+    }
+
+    /// The highest sequence number acknowledged by the hub, if any
+    pub fn last_acknowledged(&self) -> Option<u64> {
+        self.last_acknowledged
+    }
+}
+
+impl Default for TelemetrySequencer {
+    fn default() -> Self {
+        TelemetrySequencer::new()
+    }
+}
+
+/// Detects hub throttling signals and schedules a cool-down for outbound traffic.
+/// Consecutive throttling signals double the cool-down, up to a maximum; a successful response resets it.
+pub struct ThrottleDetector {
+    base_cooldown: Duration,
+    max_cooldown: Duration,
+    consecutive: u32,
+    throttled_until: Option<Instant>,
+}
+
+impl ThrottleDetector {
+    pub fn new(base_cooldown: Duration, max_cooldown: Duration) -> ThrottleDetector {
+        ThrottleDetector {
+            base_cooldown,
+            max_cooldown,
+            consecutive: 0,
+            throttled_until: None,
+        }
+    }
+
+    /// Inspects an incoming message for throttling signals: twin 429 responses,
+    /// and the transient server errors of a hub under maintenance (see `StatusCode::is_transient`).
+    /// Returns TRUE if the message signals throttling.
+    pub fn observe(&mut self, msg: &MsgFromHub, now: Instant) -> bool {
+        let status_code = match msg {
+            MsgFromHub::TwinResponseMessage(res) => res.status_code,
+            MsgFromHub::ReportedPropertiesUpdated(res) => res.status_code,
+            _ => return false,
+        };
+        match status_code {
+            StatusCode::TooManyRequests() => {
+                self.throttle(None, now);
+                true
+            }
+            StatusCode::OK() | StatusCode::NoContent() => {
+                self.consecutive = 0;
+                false
+            }
+            status if status.is_transient() => {
+                self.throttle(None, now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Starts a cool-down, honoring the hub's retry-after hint if one was provided
+    pub fn throttle(&mut self, retry_after: Option<Duration>, now: Instant) {
+        let backoff = self
+            .base_cooldown
+            .checked_mul(1 << self.consecutive.min(16))
+            .unwrap_or(self.max_cooldown)
+            .min(self.max_cooldown);
+        self.consecutive += 1;
+        let cooldown = retry_after.unwrap_or(backoff);
+        let until = now + cooldown;
+        if self.throttled_until.is_none_or(|current| until > current) {
+            self.throttled_until = Some(until);
+        }
+    }
+
+    /// The time left until outbound traffic may resume, if throttled
+    pub fn remaining_cooldown(&self, now: Instant) -> Option<Duration> {
+        self.throttled_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    pub fn is_throttled(&self, now: Instant) -> bool {
+        self.remaining_cooldown(now).is_some()
+    }
+}
+
+impl Default for ThrottleDetector {
+    fn default() -> Self {
+        ThrottleDetector::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Outbound messages held back during a throttling cool-down, to be released in order once it ends
+#[derive(Debug)]
+pub struct ThrottleQueue<T> {
+    held: VecDeque<T>,
+}
+
+impl<T> ThrottleQueue<T> {
+    pub fn new() -> ThrottleQueue<T> {
+        ThrottleQueue {
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Returns the message if it may be sent right away, or holds it while throttled.
+    /// A message is also held while earlier ones are, so that messages are sent in order.
+    pub fn admit(&mut self, msg: T, throttle: &ThrottleDetector, now: Instant) -> Option<T> {
+        if self.held.is_empty() && !throttle.is_throttled(now) {
+            return Some(msg);
+        }
+        self.held.push_back(msg);
+        None
+    }
+
+    /// The held messages, in the order they were admitted, once the cool-down ended
+    pub fn release(&mut self, throttle: &ThrottleDetector, now: Instant) -> Vec<T> {
+        if throttle.is_throttled(now) {
+            return Vec::new();
+        }
+        self.held.drain(..).collect()
+    }
+
+    /// The time by which the held messages are released, None if none are held
+    pub fn release_deadline(&self, throttle: &ThrottleDetector, now: Instant) -> Option<Instant> {
+        if self.held.is_empty() {
+            return None;
+        }
+        Some(now + throttle.remaining_cooldown(now).unwrap_or_default())
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+}
+
+impl<T> Default for ThrottleQueue<T> {
+    fn default() -> Self {
+        ThrottleQueue::new()
+    }
+}
+
+/// What a client does with telemetry exceeding its quota
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// Hold the message until the budget allows it
+    Delay,
+
+    /// Fail the message without sending it
+    Reject,
+}
+
+/// A telemetry budget, set below the device's share of the hub's message quota
+#[derive(Copy, Clone, Debug)]
+pub struct TelemetryQuota {
+    pub per_second: Option<u32>,
+    pub per_day: Option<u64>,
+    pub policy: QuotaPolicy,
+}
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Tracks the telemetry sent against a `TelemetryQuota`, in fixed one-second and one-day windows
+pub struct RateLimiter {
+    quota: TelemetryQuota,
+    second: (Instant, u32),
+    day: (Instant, u64),
+}
+
+impl RateLimiter {
+    pub fn new(quota: TelemetryQuota, now: Instant) -> RateLimiter {
+        RateLimiter {
+            quota,
+            second: (now, 0),
+            day: (now, 0),
+        }
+    }
+
+    pub fn policy(&self) -> QuotaPolicy {
+        self.quota.policy
+    }
+
+    /// Takes a message from the budget.
+    /// Returns the time until the budget allows another message if it is exhausted.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        if now.duration_since(self.second.0) >= Duration::from_secs(1) {
+            self.second = (now, 0);
+        }
+        if now.duration_since(self.day.0) >= DAY {
+            self.day = (now, 0);
+        }
+        if self.quota.per_day.is_some_and(|limit| self.day.1 >= limit) {
+            return Err(DAY - now.duration_since(self.day.0));
+        }
+        if self.quota.per_second.is_some_and(|limit| self.second.1 >= limit) {
+            return Err(Duration::from_secs(1) - now.duration_since(self.second.0));
+        }
+        self.second.1 += 1;
+        self.day.1 += 1;
+        Ok(())
+    }
+
+    /// The messages sent in the current one-day window
+    pub fn sent_today(&self) -> u64 {
+        self.day.1
+    }
+}
+
+/// Configures a `MessageDeduplicator`
+#[derive(Copy, Clone, Debug)]
+pub struct DedupConfig {
+    /// The number of message IDs remembered; the least recently seen are forgotten first
+    pub capacity: usize,
+
+    /// How long a message ID is remembered after it was last seen
+    pub ttl: Duration,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            capacity: 1024,
+            ttl: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Suppresses C2D messages and direct method invocations delivered again across reconnects,
+/// which packet IDs can't detect, by remembering the IDs of the messages recently seen.
+/// C2D messages are identified by their message ID property; those without one are never suppressed.
+pub struct MessageDeduplicator {
+    config: DedupConfig,
+    // least recently seen first
+    seen: VecDeque<(String, Instant)>,
+}
+
+impl MessageDeduplicator {
+    pub fn new(config: DedupConfig) -> MessageDeduplicator {
+        MessageDeduplicator {
+            config,
+            seen: VecDeque::new(),
+        }
+    }
+
+    /// Records an incoming message. Returns TRUE if a message with the same ID was seen within the TTL.
+    pub fn is_duplicate(&mut self, msg: &MsgFromHub, now: Instant) -> bool {
+        let key = match msg {
+            MsgFromHub::CloudToDeviceMessage(c2d) => {
+                match c2d.props.as_ref().and_then(|p| p.get(audit::MESSAGE_ID_PROPERTY)) {
+                    Some(message_id) => format!("c2d/{}", message_id),
+                    None => return false,
+                }
+            }
+            MsgFromHub::ModuleInputMessage(input) => {
+                match input.props.as_ref().and_then(|p| p.get(audit::MESSAGE_ID_PROPERTY)) {
+                    Some(message_id) => format!("input/{}/{}", input.input_name, message_id),
+                    None => return false,
+                }
+            }
+            MsgFromHub::DirectMethodInvocation(dmi) => format!("dmi/{}", dmi.request_id),
+            _ => return false,
+        };
+        let ttl = self.config.ttl;
+        self.seen.retain(|(_, seen_at)| now.duration_since(*seen_at) < ttl);
+        let duplicate = match self.seen.iter().position(|(seen, _)| *seen == key) {
+            Some(index) => {
+                let _ = self.seen.remove(index);
+                true
+            }
+            None => false,
+        };
+        self.seen.push_back((key, now));
+        while self.seen.len() > self.config.capacity {
+            let _ = self.seen.pop_front();
+        }
+        duplicate
+    }
+}
+
+/// What becomes of a C2D message whose handler did not succeed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum C2DFailureAction {
+    /// The message is acknowledged, so the hub will not deliver it again
+    Acknowledge,
+
+    /// The message is left unacknowledged, so the hub delivers it again once the client reconnects
+    Abandon,
+}
+
+/// How a C2D handler finished
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum C2DHandlerOutcome {
+    Succeeded,
+
+    /// The handler returned an error, with its description
+    Failed(String),
+
+    Panicked,
+}
+
+impl C2DHandlerOutcome {
+    /// Runs a handler, catching its panic so that the message can still be completed
+    pub fn run<E: fmt::Display>(handler: impl FnOnce() -> Result<(), E>) -> C2DHandlerOutcome {
+        match catch_unwind(AssertUnwindSafe(handler)) {
+            Ok(Ok(())) => C2DHandlerOutcome::Succeeded,
+            Ok(Err(e)) => C2DHandlerOutcome::Failed(e.to_string()),
+            Err(_) => C2DHandlerOutcome::Panicked,
+        }
+    }
+}
+
+/// Completes the C2D messages delivered with QoS1 once their handler finished.
+/// Handled messages are always acknowledged; failed ones according to the policy.
+/// By default failed messages are acknowledged too, so that a message failing its handler every time
+/// does not hold up the ones after it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct C2DCompletionPolicy {
+    /// What becomes of a message whose handler returned an error
+    pub on_error: C2DFailureAction,
+
+    /// What becomes of a message whose handler panicked
+    pub on_panic: C2DFailureAction,
+}
+
+impl Default for C2DCompletionPolicy {
+    fn default() -> Self {
+        C2DCompletionPolicy {
+            on_error: C2DFailureAction::Acknowledge,
+            on_panic: C2DFailureAction::Acknowledge,
+        }
+    }
+}
+
+impl C2DCompletionPolicy {
+    /// The acknowledgement completing a message, or None if it is left unacknowledged.
+    /// Messages delivered with QoS0 carry no packet ID and are never acknowledged, nor are those delivered with QoS2,
+    /// which the transport acknowledges upon receipt.
+    pub fn completion(&self, outcome: &C2DHandlerOutcome, packet_id: Option<PacketId>) -> Option<AckMsg> {
+        let action = match outcome {
+            C2DHandlerOutcome::Succeeded => C2DFailureAction::Acknowledge,
+            C2DHandlerOutcome::Failed(_) => self.on_error,
+            C2DHandlerOutcome::Panicked => self.on_panic,
+        };
+        match action {
+            C2DFailureAction::Acknowledge => packet_id.map(|packet_id| AckMsg { packet_id }),
+            C2DFailureAction::Abandon => None,
+        }
+    }
+}
+
+/// What a periodic heartbeat reports into the reported properties, and how often
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeartbeatConfig {
+    /// The time between heartbeats
+    pub interval: Duration,
+
+    /// The reported property holding the heartbeat block
+    pub property: String,
+
+    /// The firmware version to report, if any
+    pub firmware_version: Option<String>,
+
+    /// Report the time elapsed since the client was created
+    pub uptime: bool,
+
+    /// Report the send/receive counters of the session
+    pub connection_stats: bool,
+
+    /// Additional properties, reported as is in every heartbeat
+    pub extra: Map<String, Value>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(5 * 60),
+            property: "heartbeat".to_owned(),
+            firmware_version: None,
+            uptime: true,
+            connection_stats: true,
+            extra: Map::new(),
+        }
+    }
+}
+
+/// The session counters reported by a heartbeat
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeartbeatCounters {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub messages_dropped: u64,
+    pub token_renewals: u64,
+}
+
+impl HeartbeatConfig {
+    /// The reported properties patch of a heartbeat sent at the specified time
+    pub fn patch(&self, uptime: Duration, counters: HeartbeatCounters, now: SystemTime) -> Map<String, Value> {
+        let mut block = self.extra.clone();
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let _ = block.insert("timestamp".to_owned(), json!(timestamp));
+        if let Some(firmware_version) = &self.firmware_version {
+            let _ = block.insert("firmwareVersion".to_owned(), json!(firmware_version));
+        }
+        if self.uptime {
+            let _ = block.insert("uptimeSecs".to_owned(), json!(uptime.as_secs()));
+        }
+        if self.connection_stats {
+            let stats = json!({
+                "messagesSent": counters.messages_sent,
+                "messagesReceived": counters.messages_received,
+                "messagesDropped": counters.messages_dropped,
+                "tokenRenewals": counters.token_renewals,
+            });
+            let _ = block.insert("connection".to_owned(), stats);
+        }
+
+        let mut patch = Map::new();
+        let _ = patch.insert(self.property.clone(), Value::Object(block));
+        patch
+    }
+}
+
+/// Estimates the offset of the local clock from the hub's clock, from the enqueued time of C2D messages,
+/// so that devices with a drifting clock don't generate SAS tokens which the hub considers expired.
+///
+/// A message is never enqueued after it is received, so each message bounds the offset from below.
+/// The estimate is the tightest bound among the recent messages, accurate within the delivery latency.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    // hub time minus local time of the recent messages in milliseconds, oldest first
+    samples: Mutex<VecDeque<i64>>,
+}
+
+impl ClockSync {
+    /// How many recent messages the estimate considers, letting it follow a drifting local clock
+    pub const WINDOW: usize = 16;
+
+    pub fn new() -> ClockSync {
+        ClockSync::default()
+    }
+
+    /// Records a time read off the hub's clock, and the local time it was received at
+    pub fn observe(&self, hub_time: SystemTime, local_time: SystemTime) {
+        let offset = match hub_time.duration_since(local_time) {
+            Ok(ahead) => ahead.as_millis() as i64,
+            Err(behind) => -(behind.duration().as_millis() as i64),
+        };
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == ClockSync::WINDOW {
+            let _ = samples.pop_front();
+        }
+        samples.push_back(offset);
+    }
+
+    /// Records the enqueued time of a C2D message received at the specified local time.
+    /// Returns FALSE if the message carries no enqueued time.
+    pub fn observe_msg(&self, msg: &MsgFromHub, local_time: SystemTime) -> bool {
+        match msg {
+            MsgFromHub::CloudToDeviceMessage(c2d) => match c2d.enqueued_time() {
+                Some(enqueued_time) => {
+                    self.observe(enqueued_time, local_time);
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The estimated hub time minus local time in milliseconds, or None before any observation
+    pub fn offset_millis(&self) -> Option<i64> {
+        self.samples.lock().unwrap().iter().copied().max()
+    }
+
+    /// The hub time at the specified local time
+    pub fn correct(&self, local_time: SystemTime) -> SystemTime {
+        match self.offset_millis() {
+            Some(offset) if offset >= 0 => local_time + Duration::from_millis(offset as u64),
+            Some(offset) => local_time - Duration::from_millis(offset.unsigned_abs()),
+            None => local_time,
+        }
+    }
+
+    /// The current hub time
+    pub fn now(&self) -> SystemTime {
+        self.correct(SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raiot_protocol::c2d::{C2DMsg, ENQUEUED_TIME_PROPERTY};
+
+    fn c2d(message_id: &str) -> MsgFromHub {
+        let mut props = HashMap::new();
+        let _ = props.insert(audit::MESSAGE_ID_PROPERTY.to_owned(), message_id.to_owned());
+        MsgFromHub::CloudToDeviceMessage(C2DMsg {
+            packet_id: None,
+            body: None,
+            device_id: "d1".to_owned(),
+            props: Some(props),
+        })
+    }
+
+    #[test]
+    fn test_deduplicator_forgets_expired_and_least_recent_ids() {
+        let now = Instant::now();
+        let mut sut = MessageDeduplicator::new(DedupConfig {
+            capacity: 2,
+            ttl: Duration::from_secs(60),
+        });
+        assert!(!sut.is_duplicate(&c2d("m1"), now));
+        assert!(sut.is_duplicate(&c2d("m1"), now));
+        assert!(!sut.is_duplicate(&c2d("m2"), now));
+        assert!(!sut.is_duplicate(&c2d("m3"), now));
+        // m1 was the least recently seen
+        assert!(!sut.is_duplicate(&c2d("m1"), now));
+        assert!(!sut.is_duplicate(&c2d("m3"), now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_diagnostic_sampler_spreads_samples_evenly() {
+        let mut sut = DiagnosticSampler::new(25);
+        let sampled: Vec<bool> = (0..8)
+            .map(|_| {
+                let mut headers = None;
+                let sampled = sut.stamp(&mut headers);
+                assert_eq!(sampled, headers.is_some());
+                sampled
+            })
+            .collect();
+        assert_eq!(
+            sampled,
+            vec![false, false, false, true, false, false, false, true]
+        );
+        assert_eq!(DiagnosticSampler::new(150).sampling_percentage(), 100);
+    }
+
+    #[test]
+    fn test_diagnostic_id_is_a_traceparent() {
+        let mut sut = DiagnosticSampler::new(100);
+        let mut headers = None;
+        assert!(sut.stamp(&mut headers));
+        let headers = headers.unwrap();
+        let diagnostic_id = &headers[DIAGNOSTIC_ID_PROPERTY];
+        let parts: Vec<&str> = diagnostic_id.split('-').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[1].len(), 32);
+        assert_eq!(parts[2].len(), 16);
+        assert!(headers[DIAGNOSTIC_CONTEXT_PROPERTY].starts_with("timestamp="));
+    }
+
+    #[test]
+    fn test_packets_numerator_seeding_wraps_around() {
+        let mut sut = PacketsNumerator::starting_after(41);
+        assert_eq!(sut.next_id(), 42.into());
+
+        let mut sut = PacketsNumerator::starting_after(u16::MAX - 1);
+        assert_eq!(sut.next_id(), u16::MAX.into());
+        assert_eq!(sut.next_id(), 1.into());
+    }
+
+    #[test]
+    fn test_request_id_sources() {
+        let mut counter = RequestIdSource::Counter(0);
+        assert_eq!(counter.next_id(), "1");
+        assert_eq!(counter.next_id(), "2");
+
+        let mut custom = RequestIdSource::Custom(Box::new(|| "fixed".to_owned()));
+        assert_eq!(custom.next_id(), "fixed");
+
+        let mut uuid = RequestIdSource::default();
+        assert_ne!(uuid.next_id(), uuid.next_id());
+    }
+
+    #[test]
+    fn test_disconnect_reason_inference() {
+        let now = SystemTime::now();
+        let valid = Some(now + Duration::from_secs(3600));
+        assert_eq!(
+            DisconnectReason::infer(ErrorKind::UnexpectedEof, valid, now),
+            DisconnectReason::ClientIdTakeover
+        );
+        assert_eq!(
+            DisconnectReason::infer(ErrorKind::UnexpectedEof, Some(now + Duration::from_secs(5)), now),
+            DisconnectReason::TokenExpired
+        );
+        assert_eq!(
+            DisconnectReason::infer(ErrorKind::ConnectionReset, None, now),
+            DisconnectReason::NetworkLoss(ErrorKind::ConnectionReset)
+        );
+    }
+
+    #[test]
+    fn test_heartbeat_patch_holds_the_configured_content() {
+        let mut sut = HeartbeatConfig {
+            firmware_version: Some("1.2.3".to_owned()),
+            ..HeartbeatConfig::default()
+        };
+        let _ = sut.extra.insert("site".to_owned(), json!("lab"));
+        let counters = HeartbeatCounters {
+            messages_sent: 3,
+            ..HeartbeatCounters::default()
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+
+        let patch = sut.patch(Duration::from_secs(90), counters, now);
+        assert_eq!(
+            patch["heartbeat"],
+            json!({
+                "site": "lab",
+                "timestamp": 1000,
+                "firmwareVersion": "1.2.3",
+                "uptimeSecs": 90,
+                "connection": { "messagesSent": 3, "messagesReceived": 0, "messagesDropped": 0, "tokenRenewals": 0 },
+            })
+        );
+
+        sut.uptime = false;
+        sut.connection_stats = false;
+        sut.property = "status".to_owned();
+        let patch = sut.patch(Duration::from_secs(90), counters, now);
+        assert_eq!(patch["status"], json!({ "site": "lab", "timestamp": 1000, "firmwareVersion": "1.2.3" }));
+    }
+
+    #[test]
+    fn test_token_renewal_precedes_the_expiry() {
+        let sut = TokenRenewal {
+            margin: Duration::from_secs(300),
+            retry_interval: Duration::from_secs(10),
+        };
+        let expiry = SystemTime::now() + Duration::from_secs(3600);
+        assert_eq!(sut.renew_at(expiry, Duration::from_secs(3600)), expiry - Duration::from_secs(300));
+        // short-lived tokens are renewed halfway through
+        assert_eq!(sut.renew_at(expiry, Duration::from_secs(120)), expiry - Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_gateway_only_changes_the_transport_host() {
+        let sut = ConnectionSettings {
+            hostname: "myhub.azure-devices.net".to_owned(),
+            client_id: ClientIdentity::try_from_device_id("device1").unwrap(),
+            credentials: DeviceCredentials::Sas("a2V5".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(sut.transport_hostname(), "myhub.azure-devices.net");
+
+        let sut = sut.with_gateway("edge-gateway.local");
+        assert_eq!(sut.transport_hostname(), "edge-gateway.local");
+        // the token is still scoped to the hub, which the gateway forwards it to
+        let token = String::from(generate_sas_token(&sut, "a2V5"));
+        assert!(token.starts_with("SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fdevice1&"));
+    }
+
+    #[test]
+    fn test_subscription_replay_follows_subscriptions() {
+        let mut sut = SubscriptionReplay::new();
+        assert!(sut.to_msg(1.into()).is_none());
+
+        sut.track(&MultiSub::new(1.into()).with_filter("a/#", DeliveryGuarantees::AtMostOnce).into());
+        sut.track(
+            &MultiSub::new(2.into())
+                .with_filter("a/#", DeliveryGuarantees::AtLeastOnce)
+                .with_filter("b/#", DeliveryGuarantees::AtLeastOnce)
+                .into(),
+        );
+        sut.track(&raiot_protocol::UnsubMsg::new(3.into(), "b/#").into());
+
+        let msg = sut.to_msg(4.into()).unwrap();
+        assert_eq!(msg.packet_id, 4.into());
+        assert_eq!(msg.filters, vec![("a/#".to_owned(), DeliveryGuarantees::AtLeastOnce)]);
+    }
+
+    #[test]
+    fn test_exponential_backoff_is_capped_and_jittered() {
+        let sut = ExponentialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            jitter: 0.5,
+            max_attempts: Some(5),
+        };
+        assert_eq!(sut.delay(1, 0.0), Duration::from_secs(1));
+        assert_eq!(sut.delay(3, 0.0), Duration::from_secs(4));
+        assert_eq!(sut.delay(3, 1.0), Duration::from_secs(2));
+        assert_eq!(sut.delay(30, 0.0), Duration::from_secs(10));
+
+        let delay = sut.next_delay(2, DisconnectReason::ClientIdTakeover).unwrap();
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+        assert!(sut.next_delay(6, DisconnectReason::TokenExpired).is_none());
+    }
+
+    #[test]
+    fn test_retry_schedules_follow_the_refusal() {
+        let sut = RetrySchedules::default();
+        let reason = DisconnectReason::of_refusal(&ConnectError::ServiceUnavailable).unwrap();
+        assert_eq!(reason, DisconnectReason::ServiceUnavailable);
+        let delay = sut.next_delay(1, reason).unwrap();
+        assert!(delay >= Duration::from_millis(7500) && delay <= Duration::from_secs(15));
+        assert!(sut.next_delay(100, reason).is_some());
+
+        let reason = DisconnectReason::of_refusal(&ConnectError::Unauthorized).unwrap();
+        assert!(sut.next_delay(3, reason).is_some());
+        assert!(sut.next_delay(4, reason).is_none());
+
+        assert_eq!(DisconnectReason::of_refusal(&ConnectError::Timeout), None);
+        let delay = sut.next_delay(1, DisconnectReason::NetworkLoss(ErrorKind::TimedOut)).unwrap();
+        assert!(delay <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_connection_status_follows_events() {
+        let mut sut = ConnectionStatus::Connected;
+        let reason = DisconnectReason::NetworkLoss(ErrorKind::ConnectionReset);
+        sut.apply(ConnectionEvent::Reconnecting { reason, attempt: 1 });
+        assert_eq!(sut, ConnectionStatus::Reconnecting);
+        sut.apply(ConnectionEvent::Connected);
+        assert_eq!(sut, ConnectionStatus::Connected);
+        sut.apply(ConnectionEvent::TokenExpired);
+        assert_eq!(sut, ConnectionStatus::Reconnecting);
+        sut.apply(ConnectionEvent::Disconnected { reason });
+        assert_eq!(sut, ConnectionStatus::Disconnected(reason));
+
+        let reason = DisconnectReason::AuthenticationFailed;
+        assert!(reason.requires_reprovisioning());
+        sut.apply(ConnectionEvent::ReprovisionRequired { reason });
+        assert_eq!(sut, ConnectionStatus::Disconnected(DisconnectReason::NetworkLoss(ErrorKind::ConnectionReset)));
+    }
+
+    #[test]
+    fn test_reconnect_planner_backs_off_on_takeovers() {
+        let mut sut = ReconnectPlanner::new(TakeoverPolicy::BackOff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(3),
+        });
+        assert_eq!(sut.next_delay(DisconnectReason::ClientIdTakeover), Ok(Duration::from_secs(1)));
+        assert_eq!(sut.next_delay(DisconnectReason::ClientIdTakeover), Ok(Duration::from_secs(2)));
+        assert_eq!(sut.next_delay(DisconnectReason::ClientIdTakeover), Ok(Duration::from_secs(3)));
+        assert_eq!(sut.next_delay(DisconnectReason::TokenExpired), Ok(Duration::from_secs(0)));
+        assert_eq!(sut.next_delay(DisconnectReason::ClientIdTakeover), Ok(Duration::from_secs(1)));
+
+        let mut sut = ReconnectPlanner::new(TakeoverPolicy::GiveUp);
+        assert_eq!(sut.next_delay(DisconnectReason::ClientIdTakeover), Err(IdentityTakenOver));
+    }
+
+    #[test]
+    fn test_rate_limiter_enforces_both_windows() {
+        let start = Instant::now();
+        let mut sut = RateLimiter::new(
+            TelemetryQuota {
+                per_second: Some(2),
+                per_day: Some(3),
+                policy: QuotaPolicy::Delay,
+            },
+            start,
+        );
+
+        assert!(sut.try_acquire(start).is_ok());
+        assert!(sut.try_acquire(start).is_ok());
+        assert_eq!(sut.try_acquire(start + Duration::from_millis(400)), Err(Duration::from_millis(600)));
+
+        let next_second = start + Duration::from_secs(1);
+        assert!(sut.try_acquire(next_second).is_ok());
+        assert_eq!(sut.try_acquire(next_second), Err(DAY - Duration::from_secs(1)));
+        assert_eq!(sut.sent_today(), 3);
+        assert!(sut.try_acquire(start + DAY).is_ok());
+    }
+
+    #[test]
+    fn test_throttle_cooldown_backs_off_and_resets() {
+        let mut sut = ThrottleDetector::new(Duration::from_secs(1), Duration::from_secs(3));
+        let now = Instant::now();
+        assert!(!sut.is_throttled(now));
+
+        sut.throttle(None, now);
+        assert_eq!(sut.remaining_cooldown(now), Some(Duration::from_secs(1)));
+        sut.throttle(None, now);
+        assert_eq!(sut.remaining_cooldown(now), Some(Duration::from_secs(2)));
+        sut.throttle(None, now);
+        assert_eq!(sut.remaining_cooldown(now), Some(Duration::from_secs(3)));
+        assert!(!sut.is_throttled(now + Duration::from_secs(3)));
+
+        sut.throttle(Some(Duration::from_secs(10)), now);
+        assert_eq!(sut.remaining_cooldown(now), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_throttle_queue_holds_messages_until_the_cooldown_ends() {
+        let mut throttle = ThrottleDetector::new(Duration::from_secs(1), Duration::from_secs(3));
+        let mut sut = ThrottleQueue::new();
+        let now = Instant::now();
+        assert_eq!(sut.admit(1, &throttle, now), Some(1));
+        assert_eq!(sut.release_deadline(&throttle, now), None);
+
+        throttle.throttle(None, now);
+        assert_eq!(sut.admit(2, &throttle, now), None);
+        assert_eq!(sut.admit(3, &throttle, now), None);
+        assert!(sut.release(&throttle, now).is_empty());
+        assert_eq!(
+            sut.release_deadline(&throttle, now),
+            Some(now + Duration::from_secs(1))
+        );
+
+        // held until released, even once the cool-down ended, so as not to overtake the held messages
+        let later = now + Duration::from_secs(1);
+        assert_eq!(sut.admit(4, &throttle, later), None);
+        assert_eq!(sut.release(&throttle, later), vec![2, 3, 4]);
+        assert!(sut.is_empty());
+        assert_eq!(sut.admit(5, &throttle, later), Some(5));
+    }
+
+    #[test]
+    fn test_clock_sync_takes_the_tightest_recent_bound() {
+        let sut = ClockSync::new();
+        let local = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(sut.correct(local), local);
+
+        let mut msg = c2d("m1");
+        assert!(!sut.observe_msg(&msg, local));
+        if let MsgFromHub::CloudToDeviceMessage(c2d) = &mut msg {
+            // enqueued 2 minutes after the local time: the local clock is behind
+            let props = c2d.props.as_mut().unwrap();
+            let _ = props.insert(ENQUEUED_TIME_PROPERTY.to_owned(), "1970-01-12T13:48:40Z".to_owned());
+        }
+        assert!(sut.observe_msg(&msg, local));
+        // a message which was queued for a while bounds the offset more loosely
+        sut.observe(local + Duration::from_secs(30), local);
+        assert_eq!(sut.offset_millis(), Some(120_000));
+        assert_eq!(sut.correct(local), local + Duration::from_secs(120));
+
+        for _ in 0..ClockSync::WINDOW {
+            sut.observe(local - Duration::from_millis(1500), local);
+        }
+        assert_eq!(sut.offset_millis(), Some(-1500));
+        assert_eq!(sut.correct(local), local - Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_c2d_completion_under_qos1() {
+        let packet_id = Some(PacketId::from(7));
+        let failed = C2DHandlerOutcome::run(|| Err("no space left"));
+        assert_eq!(failed, C2DHandlerOutcome::Failed("no space left".to_owned()));
+        let panicked = C2DHandlerOutcome::run::<String>(|| panic!("handler bug"));
+        assert_eq!(panicked, C2DHandlerOutcome::Panicked);
+        let succeeded = C2DHandlerOutcome::run::<String>(|| Ok(()));
+
+        let sut = C2DCompletionPolicy::default();
+        for outcome in &[&succeeded, &failed, &panicked] {
+            assert_eq!(sut.completion(outcome, packet_id).map(|ack| ack.packet_id), packet_id);
+            // QoS0 messages are never acknowledged
+            assert!(sut.completion(outcome, None).is_none());
+        }
+
+        let sut = C2DCompletionPolicy {
+            on_error: C2DFailureAction::Acknowledge,
+            on_panic: C2DFailureAction::Abandon,
+        };
+        assert!(sut.completion(&succeeded, packet_id).is_some());
+        assert!(sut.completion(&failed, packet_id).is_some());
+        assert!(sut.completion(&panicked, packet_id).is_none());
+    }
+}
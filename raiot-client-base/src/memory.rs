@@ -0,0 +1,169 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// How much of a memory budget a reservation may use
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryPriority {
+    /// Leaves a quarter of the budget to the other priorities, and is the first to be shed
+    Low,
+
+    /// May use the whole budget
+    Normal,
+
+    /// Always granted, overdrawing the budget if need be. The holders of low priority reservations
+    /// are then expected to shed them.
+    High,
+}
+
+/// The memory a client may hold in its buffers and queues, in bytes, shared by every component drawing from it.
+/// Clones share the same budget, so the application can keep one to observe the usage.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    state: Arc<BudgetState>,
+}
+
+struct BudgetState {
+    limit: usize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            state: Arc::new(BudgetState {
+                limit,
+                used: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.limit
+    }
+
+    /// The bytes reserved at the moment
+    pub fn used(&self) -> usize {
+        self.state.used.load(Ordering::Relaxed)
+    }
+
+    /// The most bytes ever reserved at once
+    pub fn peak(&self) -> usize {
+        self.state.peak.load(Ordering::Relaxed)
+    }
+
+    /// TRUE if high priority reservations overdrew the budget
+    pub fn is_exceeded(&self) -> bool {
+        self.used() > self.state.limit
+    }
+
+    /// Reserves memory, released once the reservation is dropped
+    ///
+    /// # Errors
+    /// Fails if the reservation would take the usage beyond what its priority may use
+    pub fn try_reserve(&self, bytes: usize, priority: MemoryPriority) -> Result<Reservation, BudgetExceeded> {
+        let ceiling = match priority {
+            MemoryPriority::Low => self.state.limit - self.state.limit / 4,
+            MemoryPriority::Normal => self.state.limit,
+            MemoryPriority::High => usize::MAX,
+        };
+        let reserve = |used: usize| used.checked_add(bytes).filter(|total| *total <= ceiling);
+        match self
+            .state
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, reserve)
+        {
+            Ok(used) => {
+                self.state.peak.fetch_max(used + bytes, Ordering::Relaxed);
+                Ok(Reservation {
+                    budget: self.clone(),
+                    bytes,
+                })
+            }
+            Err(used) => Err(BudgetExceeded {
+                requested: bytes,
+                available: ceiling.saturating_sub(used),
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("limit", &self.limit())
+            .field("used", &self.used())
+            .field("peak", &self.peak())
+            .finish()
+    }
+}
+
+/// Memory drawn from a budget, returned to it when dropped
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.state.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// A reservation was refused: it would take the usage beyond what its priority may use
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BudgetExceeded {
+    pub requested: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "memory budget exceeded: {} bytes requested, {} available",
+            self.requested, self.available
+        )
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_is_shared_by_priority() {
+        let sut = MemoryBudget::new(1000);
+        let normal = sut.try_reserve(600, MemoryPriority::Normal).unwrap();
+        assert_eq!(
+            sut.try_reserve(200, MemoryPriority::Low).unwrap_err(),
+            BudgetExceeded {
+                requested: 200,
+                available: 150
+            }
+        );
+        let low = sut.try_reserve(150, MemoryPriority::Low).unwrap();
+        assert!(sut.try_reserve(300, MemoryPriority::Normal).is_err());
+
+        let high = sut.try_reserve(500, MemoryPriority::High).unwrap();
+        assert!(sut.is_exceeded());
+        assert_eq!((sut.used(), sut.peak()), (1250, 1250));
+
+        drop((normal, high));
+        assert!(!sut.is_exceeded());
+        assert_eq!((sut.used(), sut.peak(), low.bytes()), (150, 1250, 150));
+        drop(low);
+        assert_eq!(sut.used(), 0);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use raiot_protocol::qos::PacketId;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::audit::MESSAGE_ID_PROPERTY;
+
+/// Telemetry staged in the application's storage, waiting to be handed off to the hub
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxEntry {
+    /// Stored along with the entry and sent as its message ID, so a backend can discard an entry delivered twice
+    /// (e.g. if the client crashed after the hub acknowledged it, but before it was removed)
+    pub id: String,
+    pub content: Option<Value>,
+    pub headers: HashMap<String, String>,
+}
+
+impl OutboxEntry {
+    /// An entry with a fresh (UUID) ID
+    pub fn new(content: Option<Value>, headers: HashMap<String, String>) -> OutboxEntry {
+        OutboxEntry {
+            id: Uuid::new_v4().to_string(),
+            content,
+            headers,
+        }
+    }
+
+    /// The headers of the message carrying the entry, including its message ID
+    pub fn message_headers(&self) -> HashMap<String, String> {
+        let mut headers = self.headers.clone();
+        let _ = headers.insert(MESSAGE_ID_PROPERTY.to_owned(), self.id.clone());
+        headers
+    }
+}
+
+/// Telemetry waiting to be sent, kept in the application's own storage (e.g. a table of its database).
+///
+/// The application enqueues entries itself, writing them in the same storage transaction as the changes they
+/// report: a reading is staged if and only if the transaction commits, and survives crashes like the rest of the data.
+/// The clients call back into the outbox to drain it, removing every entry once the hub acknowledged it.
+pub trait Outbox: Send {
+    /// The oldest entries, at most `limit` of them, in the order they were enqueued
+    fn pending(&mut self, limit: usize) -> Result<Vec<OutboxEntry>, OutboxError>;
+
+    /// Removes an entry acknowledged by the hub. The removal should be durable by the time this returns.
+    fn remove(&mut self, id: &str) -> Result<(), OutboxError>;
+}
+
+#[derive(Debug)]
+pub enum OutboxError {
+    /// The application's storage failed
+    Storage(Box<dyn Error + Send + Sync>),
+
+    /// The hub did not acknowledge an entry, which stays in the outbox
+    Undelivered(String),
+}
+
+impl fmt::Display for OutboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboxError::Storage(e) => write!(f, "outbox storage failed: {}", e),
+            OutboxError::Undelivered(reason) => write!(f, "outbox entry not delivered: {}", reason),
+        }
+    }
+}
+
+impl Error for OutboxError {}
+
+/// Drains an outbox over a connection which doesn't wait for acknowledgements, tracking the entries in flight.
+/// An entry is removed only once the hub acknowledged it, and sent again if the connection is lost before that.
+pub struct OutboxDrainer {
+    outbox: Box<dyn Outbox>,
+    batch_size: usize,
+    in_flight: HashMap<PacketId, String>,
+}
+
+impl OutboxDrainer {
+    /// The default limit of entries in flight
+    pub const DEFAULT_BATCH_SIZE: usize = 16;
+
+    pub fn new(outbox: Box<dyn Outbox>, batch_size: usize) -> OutboxDrainer {
+        assert!(batch_size > 0, "The outbox batch size must be positive");
+        OutboxDrainer {
+            outbox,
+            batch_size,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// The pending entries which are not in flight, as many as the batch size allows
+    pub fn next_batch(&mut self) -> Result<Vec<OutboxEntry>, OutboxError> {
+        let room = self.batch_size.saturating_sub(self.in_flight.len());
+        if room == 0 {
+            return Ok(Vec::new());
+        }
+        let mut entries = self.outbox.pending(self.batch_size + self.in_flight.len())?;
+        entries.retain(|entry| !self.in_flight.values().any(|id| *id == entry.id));
+        entries.truncate(room);
+        Ok(entries)
+    }
+
+    /// Records that an entry was handed to the transport in the specified packet
+    pub fn sent(&mut self, id: &str, packet_id: PacketId) {
+        let _ = self.in_flight.insert(packet_id, id.to_owned());
+    }
+
+    /// Removes the entry carried by an acknowledged packet from the outbox.
+    /// Returns FALSE if the packet did not carry an entry.
+    pub fn acknowledged(&mut self, packet_id: PacketId) -> Result<bool, OutboxError> {
+        match self.in_flight.remove(&packet_id) {
+            Some(id) => self.outbox.remove(&id).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// The number of entries sent and not acknowledged yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Returns the outbox, forgetting the entries in flight: they are sent again by the next drainer
+    pub fn into_outbox(self) -> Box<dyn Outbox> {
+        self.outbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct MemoryOutbox(Arc<Mutex<Vec<OutboxEntry>>>);
+
+    impl Outbox for MemoryOutbox {
+        fn pending(&mut self, limit: usize) -> Result<Vec<OutboxEntry>, OutboxError> {
+            Ok(self.0.lock().unwrap().iter().take(limit).cloned().collect())
+        }
+
+        fn remove(&mut self, id: &str) -> Result<(), OutboxError> {
+            self.0.lock().unwrap().retain(|entry| entry.id != id);
+            Ok(())
+        }
+    }
+
+    fn ids(entries: &[OutboxEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_drainer_removes_acknowledged_entries_only() {
+        let storage = MemoryOutbox::default();
+        for id in ["a", "b", "c"] {
+            storage.0.lock().unwrap().push(OutboxEntry {
+                id: id.to_owned(),
+                content: None,
+                headers: HashMap::new(),
+            });
+        }
+        let mut sut = OutboxDrainer::new(Box::new(storage.clone()), 2);
+
+        let batch = sut.next_batch().unwrap();
+        assert_eq!(ids(&batch), vec!["a", "b"]);
+        sut.sent("a", 1.into());
+        sut.sent("b", 2.into());
+        assert!(sut.next_batch().unwrap().is_empty());
+
+        assert!(sut.acknowledged(2.into()).unwrap());
+        assert!(!sut.acknowledged(9.into()).unwrap());
+        assert_eq!(ids(&sut.next_batch().unwrap()), vec!["c"]);
+        assert_eq!(ids(&storage.0.lock().unwrap()), vec!["a", "c"]);
+
+        // entries in flight on a lost connection are sent again
+        let mut sut = OutboxDrainer::new(sut.into_outbox(), 2);
+        assert_eq!(ids(&sut.next_batch().unwrap()), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_entry_is_sent_with_its_id() {
+        let entry = OutboxEntry::new(None, HashMap::new());
+        assert_eq!(entry.message_headers()[MESSAGE_ID_PROPERTY], entry.id);
+        assert_ne!(OutboxEntry::new(None, HashMap::new()).id, entry.id);
+    }
+}
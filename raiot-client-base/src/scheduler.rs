@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+/// The default time budget of each of the send and receive tasks of a single-threaded client
+pub const DEFAULT_TIME_SLICE: Duration = Duration::from_millis(5);
+
+/// Drives a single-threaded client: how long every call processing the connection may spend on I/O,
+/// and how to wait between such calls.
+///
+/// The client runs again when the socket becomes readable (or writable, with data pending), or by the deadline
+/// passed to `wait`, whichever comes first. A superloop or an RTOS task may simply wait until the deadline,
+/// or poll the socket meanwhile. An event loop registers the socket instead, and arms a timer for the deadline.
+pub trait Scheduler {
+    /// The time budget of the send task, and of the receive task, of every call processing the connection
+    fn time_slice(&self) -> Duration {
+        DEFAULT_TIME_SLICE
+    }
+
+    /// Waits between two calls processing the connection, at most until the deadline by which the client must run
+    /// again (e.g. to send a keep-alive, or to fail an expired direct method), if any.
+    /// Returning earlier is always correct: the client just has nothing to do yet.
+    fn wait(&mut self, deadline: Option<Instant>);
+}
+
+/// Sleeps the calling thread until the deadline, waking up every poll interval to check the socket
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SleepScheduler {
+    pub time_slice: Duration,
+    pub poll_interval: Duration,
+}
+
+impl SleepScheduler {
+    /// How long to sleep at `now`
+    pub fn sleep_time(&self, deadline: Option<Instant>, now: Instant) -> Duration {
+        deadline.map_or(self.poll_interval, |deadline| {
+            deadline.saturating_duration_since(now).min(self.poll_interval)
+        })
+    }
+}
+
+impl Default for SleepScheduler {
+    fn default() -> Self {
+        SleepScheduler {
+            time_slice: DEFAULT_TIME_SLICE,
+            poll_interval: Duration::from_millis(5),
+        }
+    }
+}
+
+impl Scheduler for SleepScheduler {
+    fn time_slice(&self) -> Duration {
+        self.time_slice
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) {
+        let sleep_time = self.sleep_time(deadline, Instant::now());
+        if sleep_time > Duration::ZERO {
+            std::thread::sleep(sleep_time);
+        }
+    }
+}
+
+/// Never waits, for event loops which wait for the socket and the deadline themselves
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventLoopScheduler {
+    pub time_slice: Option<Duration>,
+}
+
+impl Scheduler for EventLoopScheduler {
+    fn time_slice(&self) -> Duration {
+        self.time_slice.unwrap_or(DEFAULT_TIME_SLICE)
+    }
+
+    fn wait(&mut self, _deadline: Option<Instant>) {}
+}
+
+/// The earliest of some deadlines, if any
+pub fn earliest(deadlines: impl IntoIterator<Item = Option<Instant>>) -> Option<Instant> {
+    deadlines.into_iter().flatten().min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep_scheduler_wakes_up_by_the_deadline() {
+        let sut = SleepScheduler::default();
+        let now = Instant::now();
+        assert_eq!(sut.sleep_time(None, now), sut.poll_interval);
+        assert_eq!(sut.sleep_time(Some(now + Duration::from_secs(1)), now), sut.poll_interval);
+        assert_eq!(sut.sleep_time(Some(now + Duration::from_millis(2)), now), Duration::from_millis(2));
+        assert_eq!(sut.sleep_time(Some(now - Duration::from_millis(2)), now), Duration::ZERO);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(earliest(vec![None, Some(later), Some(now)]), Some(now));
+        assert_eq!(earliest(vec![None, None]), None);
+    }
+}
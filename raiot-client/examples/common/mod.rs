@@ -0,0 +1,10 @@
+use raiot_cli::Options;
+use raiot_client::{iot_socket::IotSocket, DeviceClient};
+
+/// Connects to the hub with the settings passed on the command line
+pub fn connect(options: &Options) -> DeviceClient {
+    let socket = IotSocket::connect(options.get_connection_settings())
+        .expect("Failed connecting to the hub");
+    println!("Connected!");
+    DeviceClient::new(options.get_identity(), socket)
+}
@@ -0,0 +1,51 @@
+//! An IoT Edge module printing the messages routed to its inputs, and reporting a heartbeat on its `output1` output.
+//! Heartbeats expire after the next one is due, and carry a `priority = 'low'` hint for edgeHub routes to match.
+//!
+//! cargo run -p raiot-client --example edge_module -- -h <hub>.azure-devices.net -d <device> --module <module> -k <key>
+//!
+//! Within the Edge runtime, connect through the edge hub with `--gateway-hostname` and `--trusted-ca-file`.
+
+use std::time::Duration;
+
+use async_std::task;
+use raiot_cli::Options;
+use raiot_protocol::telemetry::EdgeRouteHints;
+use raiot_client::{
+    c2d::{C2DResult, InputMsg},
+    d2c::D2CMsg,
+};
+use serde_json::json;
+
+mod common;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+fn handle_input(msg: InputMsg) -> C2DResult {
+    println!("Input {}: {:?} {:?}", msg.input_name, msg.body, msg.props);
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    if options.module_id.is_none() {
+        panic!("Must provide the module ID (--module)");
+    }
+    let mut client = common::connect(&options);
+    client.set_input_handler(handle_input, None).unwrap();
+
+    let hints = EdgeRouteHints {
+        priority: Some("low".to_owned()),
+        ttl: Some(HEARTBEAT_INTERVAL),
+        ..Default::default()
+    };
+    task::block_on(async {
+        for beat in 1u64.. {
+            let msg = D2CMsg::new(json!({ "heartbeat": beat }));
+            if let Err(e) = client.send_output_with_hints("output1", msg, &hints).await {
+                println!("Failed sending heartbeat: {:?}", e);
+            }
+            task::sleep(HEARTBEAT_INTERVAL).await;
+        }
+    });
+}
@@ -0,0 +1,56 @@
+//! Responds to direct methods: `echo` returns its payload, `count` the number of `echo` invocations so far,
+//! any other method fails with 404, answered by the client. Pressing enter shuts the client down gracefully.
+//!
+//! cargo run -p raiot-client --example methods -- -h <hub>.azure-devices.net -d <device> -k <key>
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use async_std::task;
+use futures::channel::oneshot;
+use futures::FutureExt;
+use raiot_cli::Options;
+use raiot_client::dmi::{DMIRequest, DMIResult};
+use raiot_client::shutdown::run_until_shutdown;
+use serde_json::json;
+
+mod common;
+
+fn echo(req: DMIRequest) -> DMIResult {
+    println!("DMI: echo ({:?} left to respond)", req.remaining());
+    DMIResult {
+        status: 200,
+        payload: req.body,
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    let mut client = common::connect(&options);
+
+    // The handlers are invoked by the client; this thread only waits for enter to shut it down
+    let echoes = Arc::new(AtomicU64::new(0));
+    let counter = echoes.clone();
+    client.on_method("echo", move |req| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        echo(req)
+    });
+    client.on_method("count", move |_| DMIResult {
+        status: 200,
+        payload: Some(json!({ "invocations": echoes.load(Ordering::Relaxed) })),
+    });
+    client.set_connection_event_handler(|event| println!("Connection: {:?}", event));
+
+    println!("Press enter to stop");
+    let (enter, signal) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = io::stdin().read_line(&mut String::new());
+        let _ = enter.send(());
+    });
+    if let Err(e) = task::block_on(run_until_shutdown(client, signal.map(|_| ()))) {
+        println!("Failed shutting down gracefully: {}", e);
+    }
+}
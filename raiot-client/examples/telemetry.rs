@@ -0,0 +1,35 @@
+//! Sends a reading every few seconds, printing how each one was delivered, and the histograms of the session
+//! every ten readings.
+//!
+//! cargo run -p raiot-client --example telemetry -- -h <hub>.azure-devices.net -d <device> -k <key>
+
+use std::time::Duration;
+
+use async_std::task;
+use raiot_cli::Options;
+use raiot_client::d2c::D2CMsg;
+use serde_json::json;
+
+mod common;
+
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+fn main() {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    let mut client = common::connect(&options);
+
+    task::block_on(async {
+        for reading in 1u64.. {
+            let msg = D2CMsg::new(json!({ "reading": reading, "temperature": 20 + reading % 5 }));
+            match client.send_telemetry(msg).await {
+                Ok(delivery) => println!("Delivered: {:?}", delivery),
+                Err(e) => println!("Failed sending reading {}: {:?}", reading, e),
+            }
+            if reading % 10 == 0 {
+                println!("{}", client.stats().dump_histograms());
+            }
+            task::sleep(TELEMETRY_INTERVAL).await;
+        }
+    });
+}
@@ -0,0 +1,57 @@
+//! Sends telemetry at the interval set by the `telemetryInterval` desired property, in seconds,
+//! reading the desired properties again every minute.
+//!
+//! cargo run -p raiot-client --example twin_config -- -h <hub>.azure-devices.net -d <device> -k <key>
+
+use std::time::{Duration, Instant};
+
+use async_std::task;
+use raiot_cli::Options;
+use raiot_client::{d2c::D2CMsg, DeviceClient};
+use raiot_protocol::twin::TwinSection;
+use serde_json::json;
+
+mod common;
+
+const TWIN_READ_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The telemetry interval in the desired properties, if set
+async fn read_interval(client: &mut DeviceClient) -> Option<Duration> {
+    let twin = match client.read_twin_section(TwinSection::DesiredOnly).await {
+        Ok(twin) => twin,
+        Err(e) => {
+            println!("Failed reading the twin: {:?}", e);
+            return None;
+        }
+    };
+    let secs = twin.desired()?.get("telemetryInterval")?.as_u64()?;
+    Some(Duration::from_secs(secs.max(1)))
+}
+
+fn main() {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    let mut client = common::connect(&options);
+
+    task::block_on(async {
+        let mut interval = Duration::from_secs(10);
+        let mut last_twin_read: Option<Instant> = None;
+        loop {
+            if last_twin_read.is_none_or(|read| read.elapsed() >= TWIN_READ_INTERVAL) {
+                if let Some(desired) = read_interval(&mut client).await {
+                    if desired != interval {
+                        println!("Telemetry interval set to {:?}", desired);
+                        interval = desired;
+                    }
+                }
+                last_twin_read = Some(Instant::now());
+            }
+
+            let msg = D2CMsg::new(json!({ "interval": interval.as_secs() }));
+            if let Err(e) = client.send_telemetry(msg).await {
+                println!("Failed sending telemetry: {:?}", e);
+            }
+            task::sleep(interval).await;
+        }
+    });
+}
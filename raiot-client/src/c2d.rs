@@ -1,10 +1,80 @@
 use std::collections::HashMap;
 
+use raiot_errors::ClientError;
+use raiot_protocol::qos::PacketId;
+use raiot_protocol::AckMsg;
+
+use crate::iot_socket::IotSocketTx;
+
 #[derive(Debug, Clone)]
 pub struct C2DMsg {
     pub body: Option<String>,
     pub props: Option<HashMap<String, String>>,
 }
 
-pub type C2DResult = Result<(), ()>;
-pub type C2DHandler = fn(C2DMsg) -> C2DResult;
+/// The outcome of handling a C2D message. A failure is logged; the message is acknowledged either way.
+pub type C2DResult = Result<(), ClientError>;
+/// Runs on the handler pool, possibly for several messages at once
+pub type C2DHandler = dyn Fn(C2DMsg) -> C2DResult + Send + Sync;
+pub type C2DExpiredHandler = dyn Fn(C2DMsg) + Send;
+
+/// A C2D message from `DeviceClient::c2d_messages`, acknowledged only once its consumer completes it
+pub struct C2DDelivery {
+    pub msg: C2DMsg,
+    ack: C2DAck,
+}
+
+impl C2DDelivery {
+    pub(crate) fn new(msg: C2DMsg, packet_id: Option<PacketId>, tx: IotSocketTx) -> C2DDelivery {
+        C2DDelivery {
+            msg,
+            ack: C2DAck { packet_id, tx },
+        }
+    }
+
+    /// Separates the message from its acknowledgement, e.g. to complete it once processed elsewhere
+    pub fn into_parts(self) -> (C2DMsg, C2DAck) {
+        (self.msg, self.ack)
+    }
+
+    /// Completes the message, see `C2DAck::complete`
+    pub fn complete(self) {
+        self.ack.complete()
+    }
+}
+
+/// Completes a C2D message. Dropping it without completing abandons the message.
+pub struct C2DAck {
+    packet_id: Option<PacketId>,
+    tx: IotSocketTx,
+}
+
+impl C2DAck {
+    /// Acknowledges the message, so that the hub does not deliver it again. Messages delivered with QoS0
+    /// carry no packet ID and are never acknowledged, nor are those delivered with QoS2,
+    /// which the transport acknowledges upon receipt.
+    pub fn complete(mut self) {
+        if let Some(packet_id) = self.packet_id {
+            self.tx.send(AckMsg { packet_id });
+        }
+    }
+
+    /// Leaves the message unacknowledged: the hub delivers it again once the client reconnects
+    pub fn abandon(self) {
+        if self.packet_id.is_some() {
+            debug!("Abandoning a C2D message, the hub will deliver it again");
+        }
+    }
+}
+
+/// A message routed to an input of a module
+#[derive(Debug, Clone)]
+pub struct InputMsg {
+    /// The input the message was routed to
+    pub input_name: String,
+    pub body: Option<String>,
+    pub props: Option<HashMap<String, String>>,
+}
+
+/// Handles the messages routed to the inputs of a module, completed like C2D messages
+pub type InputHandler = dyn Fn(InputMsg) -> C2DResult + Send + Sync;
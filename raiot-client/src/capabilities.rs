@@ -0,0 +1,89 @@
+//! The optional IoT Hub features of `DeviceClient`, shared with the other clients.
+//! Its requests return futures of the hub's answer.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use raiot_client_base::capabilities::{C2DCapable, CapabilityError, MethodsCapable, TwinCapable};
+use raiot_protocol::qos::DeliveryGuarantees;
+use raiot_protocol::twin::ReadTwinRes;
+
+use crate::c2d::{C2DExpiredHandler, C2DHandler, InputHandler};
+use crate::dmi::DMIHandler;
+use crate::iot_socket::{MsgTxResult, SendError};
+use crate::{DeviceClient, TwinUpdatesHandler};
+
+/// Completes once the hub acknowledged the unsubscription
+pub type Unsubscribed<'a> = Pin<Box<dyn Future<Output = MsgTxResult> + 'a>>;
+
+impl TwinCapable for DeviceClient {
+    type TwinUpdatesHandler = TwinUpdatesHandler;
+    type TwinRead<'a> = Pin<Box<dyn Future<Output = Result<ReadTwinRes, SendError>> + 'a>>;
+    type Unsubscribed<'a> = Unsubscribed<'a>;
+
+    fn read_twin(&mut self) -> Self::TwinRead<'_> {
+        Box::pin(DeviceClient::read_twin(self))
+    }
+
+    fn sub_twin_updates(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<TwinUpdatesHandler>,
+    ) -> Result<(), CapabilityError> {
+        self.set_twin_updates_handler(handler, mode);
+        Ok(())
+    }
+
+    fn unsub_twin_updates(&mut self) -> Unsubscribed<'_> {
+        Box::pin(DeviceClient::unsub_twin_updates(self))
+    }
+}
+
+impl MethodsCapable for DeviceClient {
+    type DMIHandler = DMIHandler;
+    type Unsubscribed<'a> = Unsubscribed<'a>;
+
+    fn sub_dmi(&mut self, mode: Option<DeliveryGuarantees>, handler: Box<DMIHandler>) -> Result<(), CapabilityError> {
+        self.set_dmi_handler(handler, mode)
+    }
+
+    fn unsub_dmi(&mut self) -> Unsubscribed<'_> {
+        Box::pin(DeviceClient::unsub_dmi(self))
+    }
+
+    fn set_dmi_response_window(&mut self, window: Duration) {
+        DeviceClient::set_dmi_response_window(self, window);
+    }
+}
+
+impl C2DCapable for DeviceClient {
+    type C2DHandler = C2DHandler;
+    type C2DExpiredHandler = C2DExpiredHandler;
+    type InputHandler = InputHandler;
+    type Unsubscribed<'a> = Unsubscribed<'a>;
+
+    fn sub_c2d(&mut self, mode: Option<DeliveryGuarantees>, handler: Box<C2DHandler>) -> Result<(), CapabilityError> {
+        self.set_c2d_handler(handler, mode)
+    }
+
+    fn set_c2d_expired_handler(&mut self, handler: Box<C2DExpiredHandler>) {
+        DeviceClient::set_c2d_expired_handler(self, handler);
+    }
+
+    fn unsub_c2d(&mut self) -> Result<Unsubscribed<'_>, CapabilityError> {
+        Ok(Box::pin(DeviceClient::unsub_c2d(self)?))
+    }
+
+    fn sub_inputs(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<InputHandler>,
+    ) -> Result<(), CapabilityError> {
+        self.set_input_handler(handler, mode)
+    }
+
+    fn unsub_inputs(&mut self) -> Result<Unsubscribed<'_>, CapabilityError> {
+        Ok(Box::pin(DeviceClient::unsub_inputs(self)?))
+    }
+}
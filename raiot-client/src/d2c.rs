@@ -1,7 +1,40 @@
+use crate::iot_socket::Priority;
+use raiot_protocol::telemetry::TelemetryMsg;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct D2CMsg {
     pub content: Option<serde_json::Value>,
     pub headers: Option<HashMap<String, String>>,
+    /// Transmission priority relative to other queued messages
+    pub priority: Priority,
 }
+
+impl D2CMsg {
+    /// A telemetry message with the specified content and no properties, sent with the normal priority
+    pub fn new(content: serde_json::Value) -> D2CMsg {
+        D2CMsg {
+            content: Some(content),
+            headers: None,
+            priority: Priority::Normal,
+        }
+    }
+
+    /// Adds an application property to the message
+    pub fn with_header(mut self, name: &str, value: &str) -> D2CMsg {
+        let _ = self
+            .headers
+            .get_or_insert_with(HashMap::new)
+            .insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Sets the transmission priority of the message relative to other queued messages
+    pub fn with_priority(mut self, priority: Priority) -> D2CMsg {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Outbound middleware applied to every telemetry message before it is sent
+pub type TelemetryEnricher = dyn Fn(&mut TelemetryMsg) + Send;
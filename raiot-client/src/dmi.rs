@@ -1,7 +1,20 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 #[derive(Debug, Clone)]
 pub struct DMIRequest {
     pub method_name: String,
     pub body: Option<serde_json::Value>,
+    /// The hub stops waiting for a response at this point in time
+    pub deadline: Instant,
+}
+
+impl DMIRequest {
+    /// The time left to respond before the invocation times out
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -10,4 +23,92 @@ pub struct DMIResult {
     pub payload: Option<serde_json::Value>,
 }
 
-pub type DMIHandler = fn(DMIRequest) -> DMIResult;
+/// Runs on the handler pool, possibly for several invocations at once
+pub type DMIHandler = dyn Fn(DMIRequest) -> DMIResult + Send + Sync;
+
+/// The status reported to the hub when a method has no handler, and there is no fallback handler
+pub const DMI_NOT_FOUND_STATUS: i32 = 404;
+
+/// The status reported to the hub when no method has a handler
+pub const DMI_NOT_IMPLEMENTED_STATUS: i32 = 501;
+
+/// Routes direct method invocations to the handlers registered by method name, or else to the fallback handler
+#[derive(Default)]
+pub(crate) struct MethodRouter {
+    routes: HashMap<String, Arc<DMIHandler>>,
+    fallback: Option<Arc<DMIHandler>>,
+}
+
+impl MethodRouter {
+    pub fn on(&mut self, method_name: &str, handler: Arc<DMIHandler>) {
+        let _ = self.routes.insert(method_name.to_owned(), handler);
+    }
+
+    pub fn remove(&mut self, method_name: &str) {
+        let _ = self.routes.remove(method_name);
+    }
+
+    pub fn set_fallback(&mut self, handler: Arc<DMIHandler>) {
+        self.fallback = Some(handler);
+    }
+
+    pub fn clear(&mut self) {
+        self.routes.clear();
+        self.fallback = None;
+    }
+
+    /// The handler of a method, or else the status answering it
+    pub fn route(&self, method_name: &str) -> Result<Arc<DMIHandler>, i32> {
+        match (self.routes.get(method_name), &self.fallback) {
+            (Some(handler), _) | (None, Some(handler)) => Ok(handler.clone()),
+            (None, None) if self.routes.is_empty() => Err(DMI_NOT_IMPLEMENTED_STATUS),
+            (None, None) => Err(DMI_NOT_FOUND_STATUS),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond_with(status: i32) -> Arc<DMIHandler> {
+        Arc::new(move |_| DMIResult { status, payload: None })
+    }
+
+    fn routed_status(sut: &MethodRouter, method_name: &str) -> i32 {
+        let request = DMIRequest {
+            method_name: method_name.to_owned(),
+            body: None,
+            deadline: Instant::now(),
+        };
+        match sut.route(method_name) {
+            Ok(handler) => handler(request).status,
+            Err(status) => status,
+        }
+    }
+
+    #[test]
+    fn test_methods_without_handler_fail() {
+        let mut sut = MethodRouter::default();
+        assert_eq!(routed_status(&sut, "reboot"), DMI_NOT_IMPLEMENTED_STATUS);
+
+        sut.on("reboot", respond_with(200));
+        assert_eq!(routed_status(&sut, "reboot"), 200);
+        assert_eq!(routed_status(&sut, "reset"), DMI_NOT_FOUND_STATUS);
+
+        sut.remove("reboot");
+        assert_eq!(routed_status(&sut, "reboot"), DMI_NOT_IMPLEMENTED_STATUS);
+    }
+
+    #[test]
+    fn test_fallback_handles_methods_without_handler() {
+        let mut sut = MethodRouter::default();
+        sut.set_fallback(respond_with(202));
+        sut.on("reboot", respond_with(200));
+        assert_eq!(routed_status(&sut, "reboot"), 200);
+        assert_eq!(routed_status(&sut, "reset"), 202);
+
+        sut.clear();
+        assert_eq!(routed_status(&sut, "reset"), DMI_NOT_IMPLEMENTED_STATUS);
+    }
+}
@@ -1,56 +1,449 @@
-use connect::{ConnectMsg, ConnectRes};
+use connect::{Capabilities, ConnectError, ConnectMsg, ConnectSuccess};
+use futures::channel::mpsc::UnboundedSender;
 use futures::Future;
-use qos::PacketId;
-use raiot_buffers::CircularBuffer;
-use raiot_client_base::ConnectionSettings;
-use raiot_mqtt::packets::MqttPacketizer;
-use raiot_protocol::auth::sas::SasToken;
+use mqtt::packet::{Packet, VariablePacket};
+use mqtt::Encodable;
+use qos::{ExactlyOnceHandshakes, PacketId, QosDefaults};
+use raiot_client_base::audit::{audit_inbound, audit_outbound, AuditOutcome, AuditSink};
+use raiot_client_base::memory::{BudgetExceeded, MemoryBudget, MemoryPriority, Reservation};
+use raiot_client_base::{
+    generate_sas_token, ConnectionEvent, ConnectionSettings, DisconnectReason, QuotaPolicy, RateLimiter,
+    ReconnectPlanner, SubscriptionReplay, ThrottleDetector,
+};
+use raiot_errors::{ClientError, ProtocolError, TransportError};
+use raiot_mqtt::packets::{MqttPacketizer, StreamerError};
 use raiot_protocol::auth::DeviceCredentials;
+use raiot_protocol::encryption::{decrypt_publish, encrypt_publish, PayloadCipher};
+use raiot_protocol::twin::TwinCorrelation;
 use raiot_protocol::*;
 use raiot_streams::IoStream;
-use raiot_streams::{open_nonblocking_stream, ClientCertificate, NonblockingSocket};
-use std::io::ErrorKind;
+pub use raiot_streams::CancelToken;
+use raiot_streams::{open_nonblocking_stream_with_cancel, NonblockingSocket, SendProgress};
+use crate::stats::SessionStats;
+#[cfg(feature = "tokio-transport")]
+use crate::reactor::{self, Reactor, Wakeup};
+use std::io::{self, ErrorKind, Read};
+#[cfg(feature = "tokio-transport")]
+use std::net::TcpStream;
 use std::sync::{
-    mpsc::{channel, Receiver, Sender, TryRecvError},
+    mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError, TrySendError},
     Arc, Condvar, Mutex,
 };
 use std::thread;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    mem,
     pin::Pin,
     task::{Context, Poll, Waker},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
-pub type ConnectionResults = Result<IoStream, ConnectRes>;
+pub type ConnectionResults = Result<(IoStream, Capabilities), ConnectError>;
+
+pub type MsgTxResult = Result<DeliveryInfo, SendError>;
+
+/// A stream the socket exchanges MQTT packets with the hub over
+pub(crate) trait Transport: Read + NonblockingSocket + Send + 'static {
+    /// Closes the stream
+    fn shutdown(&mut self) -> io::Result<()>;
+
+    /// The bytes received and buffered by the stream, which are read without the socket becoming readable
+    #[cfg(feature = "tokio-transport")]
+    fn pending(&self) -> usize;
+
+    /// The underlying TCP socket, waited on by the reactor
+    #[cfg(feature = "tokio-transport")]
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Transport for IoStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        IoStream::shutdown(self)
+    }
+
+    #[cfg(feature = "tokio-transport")]
+    fn pending(&self) -> usize {
+        IoStream::pending(self)
+    }
+
+    #[cfg(feature = "tokio-transport")]
+    fn socket(&self) -> &TcpStream {
+        IoStream::socket(self)
+    }
+}
+
+/// Opens the streams of a socket: the first one, and those replacing it when reconnecting or renewing the token
+pub(crate) trait Connector: Send + 'static {
+    type Stream: Transport;
+
+    fn open(
+        &mut self,
+        settings: &ConnectionSettings,
+        cancel: &CancelToken,
+    ) -> io::Result<Self::Stream>;
+}
+
+/// Opens TLS streams to the hub, through the proxy of the settings if any
+struct TlsConnector;
+
+impl Connector for TlsConnector {
+    type Stream = IoStream;
+
+    fn open(
+        &mut self,
+        settings: &ConnectionSettings,
+        cancel: &CancelToken,
+    ) -> io::Result<IoStream> {
+        open_nonblocking_stream_with_cancel(
+            settings.transport_hostname(),
+            settings.port.into(),
+            settings.timeout,
+            &settings.tls_settings(),
+            settings.proxy.as_ref(),
+            cancel,
+            settings.handshake_poll,
+        )
+    }
+}
+
+/// What the socket does with a message from the hub when the received-message queue is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading from (and writing to) the stream until the reader catches up
+    Block,
+
+    /// Drop the message, counting it in `SessionStats::messages_dropped`.
+    /// Dropped QoS1 messages are not acknowledged, so the hub delivers them again later.
+    DropNewest,
+}
+
+/// The queue holding messages received from the hub until they are read
+#[derive(Copy, Clone, Debug)]
+pub struct ReceiveQueueConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    /// Publications of at least this many bytes (e.g. large twins or C2D payloads) are decoded on a worker thread,
+    /// so that parsing them does not hold up acknowledgements and pings. Messages are delivered in order regardless.
+    /// None decodes every packet on the socket thread.
+    pub decode_offload_threshold: Option<usize>,
+}
+
+impl Default for ReceiveQueueConfig {
+    fn default() -> Self {
+        ReceiveQueueConfig {
+            capacity: 1024,
+            overflow: OverflowPolicy::Block,
+            decode_offload_threshold: None,
+        }
+    }
+}
+
+type DecodeResult = Result<MsgFromHub, ClientError>;
+
+/// Decodes publications on a worker thread, in the order they were received
+struct DecodeWorker {
+    threshold: usize,
+    jobs: Sender<VariablePacket>,
+    results: Receiver<DecodeResult>,
+    in_flight: usize,
+}
+
+impl DecodeWorker {
+    fn spawn(threshold: usize, client_id: ClientIdentity, validate_topics: bool) -> DecodeWorker {
+        let (jobs, job_rx) = channel::<VariablePacket>();
+        let (result_tx, results) = channel();
+        thread::spawn(move || {
+            for packet in job_rx {
+                let decoded = match validate_topics {
+                    true => IotCodec::decode_packet_for(packet, &client_id),
+                    false => IotCodec::decode_packet(packet),
+                };
+                let decoded = decoded.map_err(|e| ProtocolError::from(e).into());
+                if result_tx.send(decoded).is_err() {
+                    break;
+                }
+            }
+        });
+        DecodeWorker {
+            threshold,
+            jobs,
+            results,
+            in_flight: 0,
+        }
+    }
+
+    /// Hands a packet over to the worker if it is a large publication, or if earlier publications are still being decoded
+    /// (so that they are not overtaken). Returns the packet back otherwise.
+    fn offload(
+        &mut self,
+        packet: VariablePacket,
+    ) -> Result<Option<VariablePacket>, TransportError> {
+        let is_publish = matches!(packet, VariablePacket::PublishPacket(_));
+        let large = is_publish && packet.encoded_length() as usize >= self.threshold;
+        if !(large || is_publish && self.in_flight > 0) {
+            return Ok(Some(packet));
+        }
+        if self.jobs.send(packet).is_err() {
+            return Err(self.exited());
+        }
+        self.in_flight += 1;
+        Ok(None)
+    }
+
+    fn try_result(&mut self) -> Option<DecodeResult> {
+        let result = match self.results.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => return Some(Err(self.exited().into())),
+        };
+        self.in_flight -= 1;
+        Some(result)
+    }
+
+    fn wait_result(&mut self) -> Option<DecodeResult> {
+        if self.in_flight == 0 {
+            return None;
+        }
+        let result = match self.results.recv() {
+            Ok(result) => result,
+            Err(_) => return Some(Err(self.exited().into())),
+        };
+        self.in_flight -= 1;
+        Some(result)
+    }
+
+    /// The worker thread is gone, and the publications handed over to it with it
+    fn exited(&mut self) -> TransportError {
+        self.in_flight = 0;
+        io::Error::new(ErrorKind::BrokenPipe, "The decode worker exited").into()
+    }
+}
+
+/// Delivered by the socket to its reader
+#[derive(Debug)]
+pub enum SocketEvent {
+    /// A message from the hub
+    Message(MsgFromHub),
+
+    /// The socket is reconnecting, renewing its token or reconnected.
+    /// A connection lost for good is reported as `Disconnected` instead.
+    StateChanged(ConnectionEvent),
+
+    /// The connection was lost, and not reconnected. Messages awaiting acknowledgement failed with
+    /// `SendError::Disconnected`, messages still queued with `SendError::ConnectionLost`.
+    Disconnected { reason: DisconnectReason },
+}
 
-pub type MsgTxResult = Result<(), ()>;
+/// The audit sink of a socket, shared with the client that wraps it
+pub(crate) type SharedAuditSink = Arc<Mutex<Option<Arc<dyn AuditSink>>>>;
+
+/// The payload cipher of a socket, shared with the client that wraps it
+pub(crate) type SharedPayloadCipher = Arc<Mutex<Option<Arc<dyn PayloadCipher>>>>;
+
+/// Receives the delivery receipts of a socket, shared with the client that wraps it
+pub(crate) type SharedReceiptsTap = Arc<Mutex<Option<UnboundedSender<DeliveryReceipt>>>>;
+
+/// Receives a copy of every MQTT packet read from the hub, shared with the client that wraps the socket
+#[cfg(feature = "raw-mqtt")]
+pub(crate) type SharedRawTap = Arc<Mutex<Option<Sender<VariablePacket>>>>;
+
+/// Bulk messages queued beyond this many are shed, oldest first, while the stream is blocked
+pub const MAX_BULK_BACKLOG: usize = 128;
+
+// the size of each of the write and encoding buffers of a socket
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// How often a socket waiting to reconnect checks whether the application disconnected meanwhile
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The transmission priority of an outgoing message.
+/// When the stream cannot keep up, queued messages are sent in priority order.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Priority {
+    /// Sent before anything else
+    Alarm,
+
+    /// The default priority
+    #[default]
+    Normal,
+
+    /// Sent last, and shed under sustained backpressure
+    Bulk,
+}
+
+/// The reason a message was not delivered to the hub
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SendError {
+    /// Writing the message to the stream failed
+    SendFailed,
+
+    /// No acknowledgement arrived in time
+    TimedOut,
+
+    /// The hub acknowledged the request with a failure code
+    Rejected {
+        /// The return code in the acknowledgement
+        return_code: u8,
+    },
+
+    /// The connection was closed before the message was acknowledged.
+    /// MQTT 3.1.1 PUBACKs carry no reason code, so the hub rejects a publication
+    /// (too large, throttled, unauthorized) by closing the connection.
+    Disconnected,
+
+    /// The connection was lost before the message was written, or before the response to a request arrived.
+    /// Sockets reconnecting (see `RetryPolicy` and `TokenRenewal`) resend what was written but not acknowledged,
+    /// so the message is never resubmitted; it is safe to submit it again on a new connection.
+    ConnectionLost,
+
+    /// A bulk-priority message was dropped because the outbound backlog grew too large
+    Shed,
+
+    /// A telemetry message was rejected because the telemetry quota was exhausted
+    QuotaExceeded,
+
+    /// The message was dropped because the memory budget of the client (see `ConnectionSettings::memory_budget`)
+    /// was exhausted
+    OverBudget,
+
+    /// The operation was cancelled by the application
+    Cancelled,
+
+    /// The hub answered a request with a message of another kind than its response
+    UnexpectedResponse,
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::SendFailed => write!(f, "Send failed"),
+            SendError::TimedOut => write!(f, "Timed out waiting for an acknowledgement"),
+            SendError::Rejected { return_code } => write!(f, "Rejected by the hub (return code {:#04x})", return_code),
+            SendError::Disconnected => write!(f, "Disconnected by the hub"),
+            SendError::ConnectionLost => write!(f, "Connection lost before the message was sent"),
+            SendError::Shed => write!(f, "Shed under backpressure"),
+            SendError::QuotaExceeded => write!(f, "Telemetry quota exceeded"),
+            SendError::OverBudget => write!(f, "Memory budget exceeded"),
+            SendError::Cancelled => write!(f, "Cancelled"),
+            SendError::UnexpectedResponse => write!(f, "Unexpected response from the hub"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// How a message was delivered to the hub
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeliveryInfo {
+    /// The packet ID of the message, if it was sent with one
+    pub packet_id: Option<PacketId>,
+
+    /// TRUE if the hub acknowledged the message, FALSE if it was only written to the stream (at-most-once delivery)
+    pub acknowledged: bool,
+}
+
+#[derive(Debug)]
 enum MsgStatus {
     Pending,
     Sent,
     SendFailed,
     Acknowledged,
-    Rejected,
+    Rejected(u8),
     TimedOut,
+    Disconnected,
+    ConnectionLost,
+    Shed,
+    QuotaExceeded,
+    OverBudget,
+    // final: later updates (e.g. a late acknowledgement) are ignored
+    Cancelled,
 }
 
-impl From<SubRes> for MsgStatus {
-    fn from(resp: SubRes) -> MsgStatus {
-        match resp.result {
+impl From<DeliveryReceipt> for MsgStatus {
+    fn from(receipt: DeliveryReceipt) -> MsgStatus {
+        match receipt.result {
             Ok(_) => MsgStatus::Acknowledged,
-            Err(_) => MsgStatus::Rejected,
+            Err(SubError::Failure { return_code, .. }) => MsgStatus::Rejected(return_code),
+            Err(SubError::Timeout { .. }) => MsgStatus::TimedOut,
         }
     }
 }
 
+#[derive(Debug)]
 struct MessageState {
     status: MsgStatus,
     waker: Option<Waker>,
+    // woken along with the future, for `IotSocketTx::watch_pending`
+    watcher: Option<Waker>,
+}
+
+impl MessageState {
+    fn update(&mut self, status: MsgStatus) {
+        if self.is_cancelled() {
+            return;
+        }
+        self.status = status;
+        for waker in self.waker.take().into_iter().chain(self.watcher.take()) {
+            waker.wake();
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        matches!(self.status, MsgStatus::Cancelled)
+    }
+
+    fn is_in_flight(&self) -> bool {
+        matches!(self.status, MsgStatus::Pending | MsgStatus::Sent)
+    }
+}
+
+/// Identifies an operation awaiting its outcome
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OperationId {
+    /// A request answered by a response carrying the same request ID, e.g. a twin read
+    Request(String),
+
+    /// A message awaiting the acknowledgement of its packet ID
+    Packet(PacketId),
 }
 
+/// An operation awaiting its outcome, as listed by `pending_operations`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingOperation {
+    /// The operation
+    pub id: OperationId,
+
+    /// The time elapsed since the operation was submitted
+    pub age: Duration,
+}
+
+#[derive(Debug)]
+struct TrackedSend {
+    submitted: Instant,
+    state: Arc<Mutex<MessageState>>,
+}
+
+/// The messages awaiting an acknowledgement, by packet ID.
+/// Bounded by the packet ID space, as a reused packet ID replaces the entry of an earlier message.
+type PendingSends = Arc<Mutex<HashMap<PacketId, TrackedSend>>>;
+
 pub struct MessageFuture {
     state: Arc<Mutex<MessageState>>,
-    ack_required: bool,
+    packet_id: Option<PacketId>,
+}
+
+impl MessageFuture {
+    /// The packet ID the message is sent with, if any
+    pub fn packet_id(&self) -> Option<PacketId> {
+        self.packet_id
+    }
+
+    fn delivered(&self, acknowledged: bool) -> MsgTxResult {
+        Ok(DeliveryInfo {
+            packet_id: self.packet_id,
+            acknowledged,
+        })
+    }
 }
 
 impl Future for MessageFuture {
@@ -63,18 +456,26 @@ impl Future for MessageFuture {
                 shared_state.waker = Some(cx.waker().clone());
                 Poll::Pending
             }
-            MsgStatus::SendFailed => Poll::Ready(Err(error!("Send Failed"))),
-            MsgStatus::TimedOut => Poll::Ready(Err(error!("Timeout"))),
+            MsgStatus::SendFailed => Poll::Ready(Err(SendError::SendFailed)),
+            MsgStatus::TimedOut => Poll::Ready(Err(SendError::TimedOut)),
             MsgStatus::Sent => {
-                if self.ack_required {
+                if self.packet_id.is_some() {
                     shared_state.waker = Some(cx.waker().clone());
                     Poll::Pending
                 } else {
-                    Poll::Ready(Ok(()))
+                    Poll::Ready(self.delivered(false))
                 }
             }
-            MsgStatus::Acknowledged => Poll::Ready(Ok(())),
-            MsgStatus::Rejected => Poll::Ready(Err(error!("Rejected"))),
+            MsgStatus::Acknowledged => Poll::Ready(self.delivered(true)),
+            MsgStatus::Rejected(return_code) => Poll::Ready(Err(SendError::Rejected {
+                return_code: *return_code,
+            })),
+            MsgStatus::Disconnected => Poll::Ready(Err(SendError::Disconnected)),
+            MsgStatus::ConnectionLost => Poll::Ready(Err(SendError::ConnectionLost)),
+            MsgStatus::Shed => Poll::Ready(Err(SendError::Shed)),
+            MsgStatus::QuotaExceeded => Poll::Ready(Err(SendError::QuotaExceeded)),
+            MsgStatus::OverBudget => Poll::Ready(Err(SendError::OverBudget)),
+            MsgStatus::Cancelled => Poll::Ready(Err(SendError::Cancelled)),
         }
     }
 }
@@ -82,316 +483,1398 @@ impl Future for MessageFuture {
 struct MessageInFlight {
     msg: MsgToHub,
     state: Arc<Mutex<MessageState>>,
+    priority: Priority,
+    // telemetry which was already counted against the quota
+    quota_admitted: bool,
+    // the memory held while the message is queued or awaiting its acknowledgement
+    reservation: Option<Reservation>,
+    // a publish sent again after a reconnection, flagged as DUP
+    redelivery: bool,
+    // the packet encoded to size the reservation, reused by the first attempt to send the message
+    encoded: Option<VariablePacket>,
+}
+
+/// Outgoing messages waiting for the stream, one lane per priority
+#[derive(Default)]
+struct OutboundLanes {
+    alarm: VecDeque<MessageInFlight>,
+    normal: VecDeque<MessageInFlight>,
+    bulk: VecDeque<MessageInFlight>,
+}
+
+impl OutboundLanes {
+    fn lane(&mut self, priority: Priority) -> &mut VecDeque<MessageInFlight> {
+        match priority {
+            Priority::Alarm => &mut self.alarm,
+            Priority::Normal => &mut self.normal,
+            Priority::Bulk => &mut self.bulk,
+        }
+    }
+
+    fn push(&mut self, msg: MessageInFlight) {
+        self.lane(msg.priority).push_back(msg);
+    }
+
+    /// Removes the oldest bulk messages beyond the backlog limit, and returns them
+    fn shed_bulk(&mut self) -> Vec<MessageInFlight> {
+        let excess = self.bulk.len().saturating_sub(MAX_BULK_BACKLOG);
+        self.bulk.drain(..excess).collect()
+    }
+
+    /// Removes the oldest bulk message, if any
+    fn shed_oldest_bulk(&mut self) -> Option<MessageInFlight> {
+        self.bulk.pop_front()
+    }
+
+    /// Puts back a message that was taken but not sent, ahead of its lane
+    fn push_front(&mut self, msg: MessageInFlight) {
+        self.lane(msg.priority).push_front(msg);
+    }
+
+    fn pop(&mut self) -> Option<MessageInFlight> {
+        self.alarm
+            .pop_front()
+            .or_else(|| self.normal.pop_front())
+            .or_else(|| self.bulk.pop_front())
+    }
+
+    fn len(&self) -> usize {
+        self.alarm.len() + self.normal.len() + self.bulk.len()
+    }
 }
 
 pub struct IotSocket {
     outgoing: IotSocketTx,
     incoming: IotSocketRx,
+    qos: QosDefaults,
+    capabilities: Capabilities,
+    stats: Arc<SessionStats>,
+    audit_sink: SharedAuditSink,
+    cipher: SharedPayloadCipher,
+    receipts: SharedReceiptsTap,
+    #[cfg(feature = "raw-mqtt")]
+    raw_tap: SharedRawTap,
 }
 
 #[derive(Debug, Clone)]
 pub struct IotSocketTx {
     outgoing: Sender<MessageInFlight>,
+    pending: PendingSends,
+    budget: Option<MemoryBudget>,
+    #[cfg(feature = "tokio-transport")]
+    wakeup: Wakeup,
 }
 
 pub struct IotSocketRx {
-    incoming: Receiver<MsgFromHub>,
+    incoming: Receiver<SocketEvent>,
 }
 
 impl IotSocketTx {
     pub fn send<M: Into<MsgToHub>>(&mut self, msg: M) -> MessageFuture {
+        self.send_with_priority(msg, Priority::Normal)
+    }
+
+    /// Queues a message for transmission ahead of (or behind) messages of other priorities
+    pub fn send_with_priority<M: Into<MsgToHub>>(&mut self, msg: M, priority: Priority) -> MessageFuture {
         let state = MessageState {
             waker: None,
+            watcher: None,
             status: MsgStatus::Pending,
         };
 
         let state = Arc::new(Mutex::new(state));
 
         let msg = msg.into();
-        let ack_required = msg.packet_id().is_some();
-        self.outgoing
-            .send(MessageInFlight {
-                msg,
+        let packet_id = msg.packet_id();
+        let encoded = match (&self.budget, &msg) {
+            (None, _) | (Some(_), MsgToHub::Disconnect) => None,
+            (Some(_), msg) => IotCodec::encode_message(msg).ok(),
+        };
+        let reservation = match self.reserve(encoded.as_ref(), priority) {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                debug!("Dropping a message: {}", e);
+                state.lock().unwrap().update(MsgStatus::OverBudget);
+                return MessageFuture { state, packet_id };
+            }
+        };
+        if let Some(packet_id) = packet_id {
+            let tracked = TrackedSend {
+                submitted: Instant::now(),
                 state: state.clone(),
+            };
+            let _ = self.pending.lock().unwrap().insert(packet_id, tracked);
+        }
+        let sent = self.outgoing.send(MessageInFlight {
+            msg,
+            state: state.clone(),
+            priority,
+            quota_admitted: false,
+            reservation,
+            redelivery: false,
+            encoded,
+        });
+        if sent.is_err() {
+            // the socket was closed by a disconnection
+            if let Some(packet_id) = packet_id {
+                let _ = self.pending.lock().unwrap().remove(&packet_id);
+            }
+            state.lock().unwrap().update(MsgStatus::ConnectionLost);
+        }
+        #[cfg(feature = "tokio-transport")]
+        self.wakeup.notify_one();
+
+        MessageFuture { state, packet_id }
+    }
+
+    /// Draws the size of an encoded message from the memory budget, if any. Bulk messages leave some headroom
+    /// to the others, alarms are always admitted; a DISCONNECT, which isn't encoded ahead, takes nothing.
+    fn reserve(
+        &self,
+        encoded: Option<&VariablePacket>,
+        priority: Priority,
+    ) -> Result<Option<Reservation>, BudgetExceeded> {
+        let (budget, packet) = match (&self.budget, encoded) {
+            (Some(budget), Some(packet)) => (budget, packet),
+            _ => return Ok(None),
+        };
+        let priority = match priority {
+            Priority::Alarm => MemoryPriority::High,
+            Priority::Normal => MemoryPriority::Normal,
+            Priority::Bulk => MemoryPriority::Low,
+        };
+        budget.try_reserve(packet.encoded_length() as usize, priority).map(Some)
+    }
+
+    /// The messages sent with a packet ID and still awaiting their acknowledgement, oldest first
+    pub fn pending_operations(&self) -> Vec<PendingOperation> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, tracked| tracked.state.lock().unwrap().is_in_flight());
+        let mut operations: Vec<PendingOperation> = pending
+            .iter()
+            .map(|(packet_id, tracked)| PendingOperation {
+                id: OperationId::Packet(*packet_id),
+                age: tracked.submitted.elapsed(),
             })
-            .unwrap();
+            .collect();
+        operations.sort_by_key(|operation| std::cmp::Reverse(operation.age));
+        operations
+    }
 
-        MessageFuture {
-            state,
-            ack_required,
+    /// Registers a waker woken as each message awaiting its acknowledgement completes, e.g. to wait for all of them.
+    /// Returns FALSE if none is pending.
+    pub(crate) fn watch_pending(&self, waker: &Waker) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, tracked| {
+            let mut state = tracked.state.lock().unwrap();
+            if state.is_in_flight() {
+                state.watcher = Some(waker.clone());
+            }
+            state.is_in_flight()
+        });
+        !pending.is_empty()
+    }
+
+    /// Fails the future of a message awaiting its acknowledgement with `SendError::Cancelled`, and releases its packet ID.
+    /// A message not written yet is not sent at all; a late acknowledgement of a written message is ignored.
+    /// Returns FALSE if no such message is pending.
+    pub fn cancel(&self, packet_id: PacketId) -> bool {
+        match self.pending.lock().unwrap().remove(&packet_id) {
+            Some(tracked) => {
+                let mut state = tracked.state.lock().unwrap();
+                let in_flight = state.is_in_flight();
+                if in_flight {
+                    state.update(MsgStatus::Cancelled);
+                }
+                in_flight
+            }
+            None => false,
         }
     }
 }
 
 impl IotSocketRx {
-    pub fn try_recv(&mut self) -> Option<MsgFromHub> {
-        match self.incoming.try_recv() {
-            Ok(msg) => Some(msg),
-            Err(TryRecvError::Empty) => None,
-            Err(TryRecvError::Disconnected) => {
-                panic!("OMG OMG OMG I'm disco'd from the origin of incoming msgs")
-            }
-        }
+    /// Returns the next event if there is one, None otherwise, including once the socket exited
+    pub fn try_recv(&mut self) -> Option<SocketEvent> {
+        self.incoming.try_recv().ok()
     }
 
-    pub fn recv(&mut self) -> MsgFromHub {
-        match self.incoming.recv() {
-            Ok(msg) => msg,
-            Err(_) => panic!("Hung up!"),
-        }
+    /// Waits for the next event. Returns None once the socket exited, after its `SocketEvent::Disconnected`.
+    pub fn recv(&mut self) -> Option<SocketEvent> {
+        self.incoming.recv().ok()
     }
 }
 impl IotSocket {
+    /// The default delivery guarantees specified in the connection settings
+    pub fn qos_defaults(&self) -> QosDefaults {
+        self.qos
+    }
+
+    /// What the hub agreed to when this socket connected
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The send/receive statistics of this session
+    pub fn stats(&self) -> Arc<SessionStats> {
+        self.stats.clone()
+    }
+
+    /// Sets a sink recording every telemetry, twin, direct method and C2D message sent or received
+    pub fn set_audit_sink(&self, sink: Arc<dyn AuditSink>) {
+        *self.audit_sink.lock().unwrap() = Some(sink);
+    }
+
+    pub(crate) fn shared_audit_sink(&self) -> SharedAuditSink {
+        self.audit_sink.clone()
+    }
+
+    /// Sets a cipher encrypting outgoing telemetry payloads and decrypting incoming C2D payloads which advertise a key
+    pub fn set_payload_cipher(&self, cipher: Arc<dyn PayloadCipher>) {
+        *self.cipher.lock().unwrap() = Some(cipher);
+    }
+
+    pub(crate) fn shared_payload_cipher(&self) -> SharedPayloadCipher {
+        self.cipher.clone()
+    }
+
+    pub(crate) fn shared_receipts_tap(&self) -> SharedReceiptsTap {
+        self.receipts.clone()
+    }
+
+    /// Returns a stream of every MQTT packet read from the hub, before it is decoded.
+    /// Replaces the stream returned by an earlier call.
+    #[cfg(feature = "raw-mqtt")]
+    pub fn raw_packets(&self) -> Receiver<VariablePacket> {
+        let (tx, rx) = channel();
+        *self.raw_tap.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    #[cfg(feature = "raw-mqtt")]
+    pub(crate) fn shared_raw_tap(&self) -> SharedRawTap {
+        self.raw_tap.clone()
+    }
+
     pub fn split(self) -> (IotSocketTx, IotSocketRx) {
         (self.outgoing, self.incoming)
     }
 
-    pub fn connect(settings: ConnectionSettings) -> IotSocket {
+    /// Connects, holding received messages in a queue with the default configuration
+    ///
+    /// # Errors
+    /// Returns the reason the connection attempt failed
+    pub fn connect(settings: ConnectionSettings) -> Result<IotSocket, ConnectError> {
+        IotSocket::connect_with_receive_queue(settings, ReceiveQueueConfig::default())
+    }
+
+    /// Connects, holding received messages in a queue with the specified configuration
+    ///
+    /// # Errors
+    /// Returns the reason the connection attempt failed, see `connect_with_cancel`
+    pub fn connect_with_receive_queue(
+        settings: ConnectionSettings,
+        queue: ReceiveQueueConfig,
+    ) -> Result<IotSocket, ConnectError> {
+        IotSocket::connect_with_cancel(settings, queue, &CancelToken::new())
+    }
+
+    /// Connects like `connect_with_receive_queue`, aborting the TCP, TLS or MQTT connection attempt
+    /// as soon as the token is cancelled (e.g. by an application shutting down)
+    ///
+    /// # Errors
+    /// Returns `ConnectError::Cancelled` if the token was cancelled, or the reason the connection attempt failed.
+    /// Fails with `ConnectError::IOError(ErrorKind::OutOfMemory)` if the memory budget can't hold the buffers.
+    pub fn connect_with_cancel(
+        settings: ConnectionSettings,
+        queue: ReceiveQueueConfig,
+        cancel: &CancelToken,
+    ) -> Result<IotSocket, ConnectError> {
+        IotSocket::connect_with(TlsConnector, settings, queue, cancel)
+    }
+
+    /// Connects like `connect_with_cancel`, over the streams opened by the connector
+    pub(crate) fn connect_with<C: Connector>(
+        mut connector: C,
+        settings: ConnectionSettings,
+        queue: ReceiveQueueConfig,
+        cancel: &CancelToken,
+    ) -> Result<IotSocket, ConnectError> {
         let (tx1, rx1) = channel();
-        let (tx2, rx2) = channel();
-        let socket = IotSocket {
-            outgoing: IotSocketTx { outgoing: tx1 },
-            incoming: IotSocketRx { incoming: rx2 },
-        };
+        let (tx2, rx2) = sync_channel(queue.capacity);
+        let qos = settings.qos;
+        let stats = Arc::new(SessionStats::with_histograms(&settings.histograms));
+        let audit_sink: SharedAuditSink = Arc::new(Mutex::new(None));
+        let cipher: SharedPayloadCipher = Arc::new(Mutex::new(None));
+        let receipts: SharedReceiptsTap = Arc::new(Mutex::new(None));
+        #[cfg(feature = "raw-mqtt")]
+        let raw_tap: SharedRawTap = Arc::new(Mutex::new(None));
+        let ctl_stats = stats.clone();
+        let ctl_audit_sink = audit_sink.clone();
+        let ctl_cipher = cipher.clone();
+        let ctl_receipts = receipts.clone();
+        #[cfg(feature = "raw-mqtt")]
+        let ctl_raw_tap = raw_tap.clone();
+        #[cfg(feature = "tokio-transport")]
+        let wakeup: Wakeup = Arc::new(Default::default());
+        #[cfg(feature = "tokio-transport")]
+        let ctl_wakeup = wakeup.clone();
 
         let settings = settings.clone();
+        let budget = settings.memory_budget.clone();
+        // the encoding buffer counts against the budget like the messages
+        let buffers = match &budget {
+            Some(budget) => match budget.try_reserve(BUFFER_SIZE, MemoryPriority::Normal) {
+                Ok(reservation) => Some(reservation),
+                Err(e) => {
+                    warn!("The memory budget can't hold the buffers of the socket: {}", e);
+                    return Err(ConnectError::IOError(ErrorKind::OutOfMemory));
+                }
+            },
+            None => None,
+        };
 
-        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let pair = Arc::new((Mutex::new(None), Condvar::new()));
         let pair2 = pair.clone();
+        let cancel = cancel.clone();
 
         thread::spawn(move || {
-            let connection_result = connect(&settings);
-
-            let stream = match connection_result {
-                Ok(stream) => stream,
-                Err(e) => panic!("OMG this just happened! {}", e),
+            let connection_result = connect(&mut connector, &settings, &cancel);
+            // a stream the reactor can't wait on is a failed connection
+            #[cfg(feature = "tokio-transport")]
+            let (connection_result, reactor) = match connection_result {
+                Ok(connection) => match Reactor::new(connection.stream.socket(), ctl_wakeup) {
+                    Ok(reactor) => (Ok(connection), Some(reactor)),
+                    Err(e) => (Err(e.into()), None),
+                },
+                Err(e) => (Err(e), None),
             };
 
-            {
+            let connection = {
                 let (lock, cvar) = &*pair2;
                 let mut connected = lock.lock().unwrap();
-                *connected = true;
+                let outcome = connection_result
+                    .as_ref()
+                    .map(|connection| connection.capabilities)
+                    .map_err(|e| *e);
+                *connected = Some(outcome);
                 cvar.notify_one();
-            }
+                match connection_result {
+                    Ok(connection) => connection,
+                    Err(_) => return,
+                }
+            };
 
+            let connected_at = SystemTime::now();
+            let token_expiry = settings.token_expiry(connected_at);
+            let renew_at = settings.token_renewal_time(connected_at);
+            let takeovers = ReconnectPlanner::new(settings.takeover_policy);
+            let rate_limiter = settings
+                .telemetry_quota
+                .map(|quota| RateLimiter::new(quota, Instant::now()));
+            let decoder = queue
+                .decode_offload_threshold
+                .map(|threshold| DecodeWorker::spawn(threshold, settings.client_id.clone(), settings.validate_topics));
+            #[cfg(feature = "tokio-transport")]
+            let reactor = match reactor {
+                Some(reactor) => reactor,
+                None => return,
+            };
             let mut ctl = IotSocketCtl {
                 incoming_queue: tx2,
+                overflow: queue.overflow,
                 outgoing_queue: rx1,
                 settings,
-                stream,
+                cancel,
+                connector,
+                stream: connection.stream,
                 awaiting_acks: HashMap::new(),
-                total_bytes_read: 0,
-                total_bytes_written: 0,
+                sent_at: HashMap::new(),
+                twin_requests: TwinCorrelation::new(),
+                stats: ctl_stats,
+                audit_sink: ctl_audit_sink,
+                cipher: ctl_cipher,
+                receipts: ctl_receipts,
+                #[cfg(feature = "raw-mqtt")]
+                raw_tap: ctl_raw_tap,
+                token_expiry,
+                renew_at,
+                takeovers,
+                subscriptions: SubscriptionReplay::new(),
+                pending_subscriptions: SubscriptionTracker::new(),
+                unacked: HashMap::new(),
                 tx_buf: None,
-                encoding_buf: vec![1u8; 256 * 1024].into_boxed_slice(),
-                packetizer: MqttPacketizer::new(),
-                write_buffer: CircularBuffer::new(256 * 1024),
+                lanes: OutboundLanes::default(),
+                rate_limiter,
+                held_telemetry: VecDeque::new(),
+                tx_length: 0,
+                tx_offset: 0,
+                connected: true,
+                closing: None,
+                abandoned: false,
+                closed: false,
+                exactly_once: ExactlyOnceHandshakes::default(),
+                throttle: ThrottleDetector::default(),
+                encoding_buf: vec![1u8; BUFFER_SIZE].into_boxed_slice(),
+                packetizer: connection.packetizer,
+                decoder,
+                _buffers: buffers,
+                #[cfg(feature = "tokio-transport")]
+                reactor,
             };
             ctl.socket_loop();
         });
 
         let (lock, cvar) = &*pair;
         let mut started = lock.lock().unwrap();
-        while !*started {
-            started = cvar.wait(started).unwrap();
+        loop {
+            match *started {
+                Some(Ok(capabilities)) => {
+                    return Ok(IotSocket {
+                        outgoing: IotSocketTx {
+                            outgoing: tx1,
+                            pending: Arc::new(Mutex::new(HashMap::new())),
+                            budget,
+                            #[cfg(feature = "tokio-transport")]
+                            wakeup,
+                        },
+                        incoming: IotSocketRx { incoming: rx2 },
+                        qos,
+                        capabilities,
+                        stats,
+                        audit_sink,
+                        cipher,
+                        receipts,
+                        #[cfg(feature = "raw-mqtt")]
+                        raw_tap,
+                    })
+                }
+                Some(Err(e)) => return Err(e),
+                None => started = cvar.wait(started).unwrap(),
+            }
         }
-
-        socket
     }
 
     pub fn send<M: Into<MsgToHub>>(&mut self, msg: M) -> MessageFuture {
         self.outgoing.send(msg)
     }
 
-    pub fn try_recv(&mut self) -> Option<MsgFromHub> {
+    pub fn try_recv(&mut self) -> Option<SocketEvent> {
         self.incoming.try_recv()
     }
 }
 
-struct IotSocketCtl {
+struct IotSocketCtl<C: Connector> {
     settings: ConnectionSettings,
+    // the application's token, also aborting the reconnections
+    cancel: CancelToken,
     outgoing_queue: Receiver<MessageInFlight>,
-    incoming_queue: Sender<MsgFromHub>,
-    stream: IoStream,
+    incoming_queue: SyncSender<SocketEvent>,
+    overflow: OverflowPolicy,
+    connector: C,
+    stream: C::Stream,
     awaiting_acks: HashMap<PacketId, Arc<Mutex<MessageState>>>,
-    total_bytes_read: u64,
-    total_bytes_written: u64,
+    sent_at: HashMap<PacketId, Instant>,
+    twin_requests: TwinCorrelation,
+    stats: Arc<SessionStats>,
+    audit_sink: SharedAuditSink,
+    cipher: SharedPayloadCipher,
+    receipts: SharedReceiptsTap,
+    #[cfg(feature = "raw-mqtt")]
+    raw_tap: SharedRawTap,
+    token_expiry: Option<SystemTime>,
+    // when to reconnect with a fresh SAS token, if token renewal is enabled
+    renew_at: Option<SystemTime>,
+    takeovers: ReconnectPlanner,
+    subscriptions: SubscriptionReplay,
+    // the topic filters of the subscription requests awaiting their SUBACK
+    pending_subscriptions: SubscriptionTracker,
+    // written messages awaiting an acknowledgement, sent again on a renewed or reconnected connection
+    unacked: HashMap<PacketId, MessageInFlight>,
     packetizer: MqttPacketizer,
-    write_buffer: CircularBuffer,
+    decoder: Option<DecodeWorker>,
     encoding_buf: Box<[u8]>,
+    // the memory of the encoding buffer, drawn from the memory budget
+    _buffers: Option<Reservation>,
     tx_buf: Option<MessageInFlight>,
+    lanes: OutboundLanes,
+    rate_limiter: Option<RateLimiter>,
+    // telemetry over the quota, sent in order as the budget allows
+    held_telemetry: VecDeque<MessageInFlight>,
+    tx_length: usize,
+    tx_offset: usize,
+    connected: bool,
+    // a DISCONNECT, sent once every message queued before it was sent
+    closing: Option<MessageInFlight>,
+    // every sender was dropped, a DISCONNECT was queued in their place
+    abandoned: bool,
+    // the connection was ended by a DISCONNECT, the socket loop exits
+    closed: bool,
+    exactly_once: ExactlyOnceHandshakes,
+    throttle: ThrottleDetector,
+    #[cfg(feature = "tokio-transport")]
+    reactor: Reactor,
 }
 
-impl IotSocketCtl {
-    pub fn total_bytes_written(&self) -> u64 {
-        self.total_bytes_written
-    }
-
-    pub fn total_bytes_read(&self) -> u64 {
-        self.total_bytes_read
-    }
-
+impl<C: Connector> IotSocketCtl<C> {
     pub fn recv_next(&mut self) -> bool {
+        if !self.connected {
+            return false;
+        }
+        if let Some(decoded) = self.decoder.as_mut().and_then(DecodeWorker::try_result) {
+            return self.handle_decoded(decoded);
+        }
         loop {
-            if let Some(packet) = self.packetizer.get_next_packet().unwrap() {
-                match IotCodec::decode_packet(packet) {
-                    Ok(msg) => {
-                        self.handle_incoming_msg(msg);
-                        return true;
-                    }
-                    Err(e) => {
-                        warn!("Failure decoding message from server: {}", e);
-                        panic!("Failure decoding IoT packet");
-                    }
+            let packet = match self.packetizer.get_next_packet() {
+                Ok(packet) => packet,
+                // the hub sent something which is not MQTT, there is no telling where the next packet starts
+                Err(e) => {
+                    warn!("Invalid packet from server: {}", e);
+                    self.connection_lost(DisconnectReason::NetworkLoss(
+                        TransportError::from(e).kind(),
+                    ));
+                    return false;
+                }
+            };
+            if let Some(packet) = packet {
+                #[cfg(feature = "raw-mqtt")]
+                self.tap(&packet);
+                let (packet, reply) = self.exactly_once.incoming(packet);
+                if let Some(reply) = reply {
+                    self.reply(reply);
+                }
+                let packet = match packet.and_then(|packet| self.decrypt(packet)) {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                if let VariablePacket::PublishPacket(publish) = &packet {
+                    self.stats.record_received_payload(publish.payload_ref().len());
                 }
+                let packet = match self.decoder.as_mut() {
+                    Some(decoder) => match decoder.offload(packet) {
+                        Ok(Some(packet)) => packet,
+                        Ok(None) => return true,
+                        Err(e) => return self.handle_decoded(Err(e.into())),
+                    },
+                    None => packet,
+                };
+                let decoded = if self.settings.validate_topics {
+                    IotCodec::decode_packet_for(packet, &self.settings.client_id)
+                } else {
+                    IotCodec::decode_packet(packet)
+                };
+                return self.handle_decoded(decoded.map_err(|e| ProtocolError::from(e).into()));
             } else {
                 // we don't have a complete packet, keep reading from the buffer
                 match self.packetizer.append_from_reader(&mut self.stream) {
                     // Nothing to read from the socket, go do other things
                     Ok(0) => return false,
                     // Got something from the buffer, keep iterating - we might have a complete packet
-                    Ok(amount) => self.total_bytes_read += amount as u64,
+                    Ok(amount) => self.stats.record_read(amount),
                     Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
                     Err(e) if e.kind() == ErrorKind::Interrupted => return true,
-                    Err(e) => panic!("OMG could NOT read! {:?}", e),
+                    // The hub closed the connection (reported by the stream as UnexpectedEof), or the network failed
+                    Err(e) => {
+                        let reason = DisconnectReason::infer(e.kind(), self.token_expiry, SystemTime::now());
+                        self.connection_lost(reason);
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles a decoded message. A packet which could not be decoded leaves the stream in an unknown state,
+    /// the connection is then dropped (and reconnected if the retry policy allows): returns FALSE.
+    /// So is a connection whose publications were lost with the decode worker, the next ones are decoded
+    /// on the socket thread.
+    fn handle_decoded(&mut self, decoded: DecodeResult) -> bool {
+        match decoded {
+            Ok(msg) => {
+                self.handle_incoming_msg(msg);
+                true
+            }
+            Err(ClientError::Transport(e)) => {
+                warn!("Failure decoding messages from server: {:?}", e);
+                self.decoder = None;
+                self.connection_lost(DisconnectReason::NetworkLoss(e.kind()));
+                false
+            }
+            Err(e) => {
+                warn!("Failure decoding message from server: {:?}", e);
+                self.connection_lost(DisconnectReason::NetworkLoss(ErrorKind::InvalidData));
+                false
+            }
+        }
+    }
+
+    /// Handles the messages still being decoded, as the connection they came from is replaced or dropped
+    fn drain_decoder(&mut self) {
+        while let Some(decoded) = self.decoder.as_mut().and_then(DecodeWorker::wait_result) {
+            match decoded {
+                Ok(msg) => self.handle_incoming_msg(msg),
+                Err(e) => warn!("Failure decoding message from server: {}", e),
+            }
+        }
+    }
+
+    fn connection_lost(&mut self, reason: DisconnectReason) {
+        warn!("Connection lost: {}", reason);
+        if !self.reconnect(reason) {
+            self.handle_disconnect(reason);
+        }
+    }
+
+    #[cfg(feature = "raw-mqtt")]
+    fn tap(&self, packet: &VariablePacket) {
+        let mut tap = self.raw_tap.lock().unwrap();
+        if let Some(tx) = tap.as_ref() {
+            if tx.send(packet.clone()).is_err() {
+                // the stream was dropped
+                *tap = None;
+            }
+        }
+    }
+
+    /// Decrypts publications advertising an encryption key. Returns None if the payload could not be decrypted.
+    fn decrypt(&self, packet: VariablePacket) -> Option<VariablePacket> {
+        let cipher = self.cipher.lock().unwrap();
+        match (packet, cipher.as_deref()) {
+            (VariablePacket::PublishPacket(publish), Some(cipher)) => match decrypt_publish(publish, cipher) {
+                Ok(publish) => Some(publish.into()),
+                Err(e) => {
+                    warn!("Ignoring a message which could not be decrypted: {}", e);
+                    None
                 }
+            },
+            (packet, _) => Some(packet),
+        }
+    }
+
+    /// Encodes a message into the encoding buffer, unless encoded already, encrypting telemetry payloads
+    /// if a cipher is set.
+    /// Fails in strict mode for messages violating IoT Hub constraints, or if the message could not be encoded at all.
+    fn encode(
+        &mut self,
+        msg: &MsgToHub,
+        encoded: Option<VariablePacket>,
+        redelivery: bool,
+    ) -> Result<usize, ClientError> {
+        let packet = match encoded {
+            Some(packet) => IotCodec::check_encoded(msg, &packet, self.settings.codec).map(|()| packet),
+            None => IotCodec::encode_message_with(msg, self.settings.codec),
+        };
+        let packet = packet.map_err(ProtocolError::from)?;
+        let packet = match (packet, msg, self.cipher.lock().unwrap().as_deref()) {
+            (VariablePacket::PublishPacket(publish), MsgToHub::Telemetry(_), Some(cipher)) => {
+                encrypt_publish(&publish, cipher).into()
+            }
+            (packet, _, _) => packet,
+        };
+        let packet = match packet {
+            VariablePacket::PublishPacket(mut publish) if redelivery => {
+                publish.set_dup(true);
+                publish.into()
             }
+            packet => packet,
+        };
+        if let VariablePacket::PublishPacket(publish) = &packet {
+            self.stats.record_sent_payload(publish.payload_ref().len());
         }
+        packet
+            .encode(&mut &mut self.encoding_buf[..])
+            .map_err(|e| TransportError::from(StreamerError::EncodingFailed(e)))?;
+        Ok(packet.encoded_length() as usize)
     }
 
     pub fn send_next(&mut self) -> bool {
         if let Some(msg) = self.take_next_outgoing_msg() {
+            if !self.connected {
+                // nothing left to end gracefully
+                self.closed |= matches!(msg.msg, MsgToHub::Disconnect);
+                self.fail_msg(msg, MsgStatus::ConnectionLost);
+                return true;
+            }
+
+            // a partially written message must be completed, cancelled or not
+            if self.tx_offset == 0 && msg.state.lock().unwrap().is_cancelled() {
+                debug!("Dropping a cancelled message");
+                self.audit_outbound(&msg.msg, AuditOutcome::Dropped);
+                return true;
+            }
+
+            // hold back everything but acknowledgements while the hub is throttling us
+            if self.tx_offset == 0 && !matches!(msg.msg, MsgToHub::Acknowledge(_) | MsgToHub::ExactlyOnce(_)) {
+                if let Some(cooldown) = self.throttle.remaining_cooldown(Instant::now()) {
+                    trace!("Throttled, holding outgoing messages for {:?}", cooldown);
+                    self.tx_buf = Some(msg);
+                    return false;
+                }
+            }
+
+            let mut msg = match self.admit_telemetry(msg) {
+                Some(msg) => msg,
+                None => return true,
+            };
+
             // we have an outgoing message at hand, let's try and send it
             debug!("Sending a message");
 
-            let encoded_length = IotCodec::encode(&msg.msg, &mut self.encoding_buf)
-                .expect("Encoding must work, though in fact it didn't");
+            if self.tx_offset == 0 {
+                // a fresh message (or one we didn't manage to send any of), encode it
+                self.tx_length = match self.encode(&msg.msg, msg.encoded.take(), msg.redelivery) {
+                    Ok(length) => length,
+                    Err(ClientError::Protocol(ProtocolError::Codec(
+                        e @ CodecError::NonConformant(_),
+                    ))) => {
+                        warn!("Not sending a message: {}", e);
+                        msg.state.lock().unwrap().update(MsgStatus::SendFailed);
+                        self.audit_outbound(&msg.msg, AuditOutcome::Failed(e.to_string()));
+                        return true;
+                    }
+                    // the session is in an unknown state
+                    Err(e) => {
+                        warn!("Failure encoding a message: {:?}", e);
+                        msg.state.lock().unwrap().update(MsgStatus::SendFailed);
+                        self.audit_outbound(&msg.msg, AuditOutcome::Failed(e.to_string()));
+                        self.connection_lost(DisconnectReason::NetworkLoss(ErrorKind::InvalidData));
+                        return false;
+                    }
+                };
+            }
 
             if let Some(packet_id) = msg.msg.packet_id() {
-                if !self.awaiting_acks.contains_key(&packet_id) {
+                // a cancelled message released its packet ID
+                let released = self
+                    .awaiting_acks
+                    .get(&packet_id)
+                    .is_none_or(|state| state.lock().unwrap().is_cancelled());
+                if released {
                     self.awaiting_acks.insert(packet_id, msg.state.clone());
                 }
             }
 
-            let send_result = self.stream.try_send(&self.encoding_buf[0..encoded_length]);
+            let send_result = self
+                .stream
+                .try_send(&self.encoding_buf[self.tx_offset..self.tx_length]);
 
             match send_result {
-                Ok(()) => {
+                Ok(SendProgress::Complete) => {
                     debug!("Message sent");
                     let mut state = msg.state.lock().unwrap();
-                    self.total_bytes_written += encoded_length as u64;
-                    state.status = MsgStatus::Sent;
-                    return true;
-                }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    self.tx_buf = Some(msg);
-                    return false;
+                    self.stats.record_written(self.tx_length - self.tx_offset);
+                    self.stats.record_sent();
+                    self.tx_offset = 0;
+                    if let Some(packet_id) = msg.msg.packet_id() {
+                        self.sent_at.insert(packet_id, Instant::now());
+                    }
+                    state.update(MsgStatus::Sent);
+                    self.subscriptions.track(&msg.msg);
+                    self.pending_subscriptions.track(&msg.msg);
+                    if let (Some(packet_id), true) = (msg.msg.packet_id(), self.retransmits()) {
+                        self.unacked.insert(packet_id, MessageInFlight {
+                            msg: msg.msg.clone(),
+                            state: msg.state.clone(),
+                            priority: msg.priority,
+                            quota_admitted: true,
+                            reservation: msg.reservation.take(),
+                            redelivery: msg.redelivery,
+                            encoded: None,
+                        });
+                    }
+                    self.twin_requests.track(&msg.msg);
+                    self.audit_outbound(&msg.msg, AuditOutcome::Sent);
+                    if matches!(msg.msg, MsgToHub::Disconnect) {
+                        drop(state);
+                        self.close();
+                        return false;
+                    }
+                    true
                 }
-                Err(e) if e.kind() == ErrorKind::Interrupted => {
+                Ok(SendProgress::WouldBlock(written)) => {
+                    // keep the rest of the encoded message for the next attempt
+                    self.stats.record_written(written);
+                    self.tx_offset += written;
                     self.tx_buf = Some(msg);
-                    return true;
+                    for shed in self.lanes.shed_bulk() {
+                        debug!("Shedding a bulk message");
+                        self.fail_msg(shed, MsgStatus::Shed);
+                    }
+                    false
                 }
                 Err(e) => {
+                    // the message is sent again over the new connection, or failed with the others
                     debug!("Send failed: {:?}", e);
-                    let mut state = msg.state.lock().unwrap();
-                    state.status = MsgStatus::SendFailed;
-                    return true;
+                    self.tx_offset = 0;
+                    self.tx_buf = Some(msg);
+                    let reason = DisconnectReason::infer(e.kind(), self.token_expiry, SystemTime::now());
+                    self.connection_lost(reason);
+                    false
                 }
             }
         } else {
-            return false;
+            false
         }
     }
 
-    fn take_next_outgoing_msg(&mut self) -> Option<MessageInFlight> {
-        if let None = self.tx_buf {
-            self.tx_buf = match self.outgoing_queue.try_recv() {
-                Ok(msg) => Some(msg),
-                Err(TryRecvError::Empty) => None,
+    /// Moves the messages submitted so far to their lanes, setting aside a DISCONNECT
+    fn drain_outgoing_queue(&mut self) {
+        loop {
+            match self.outgoing_queue.try_recv() {
+                Ok(msg) if matches!(msg.msg, MsgToHub::Disconnect) => self.closing = Some(msg),
+                Ok(msg) => self.lanes.push(msg),
+                Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => {
-                    panic!("OMG OMG OMG I'm disco'd from the origin of TX")
+                    // nothing will be sent anymore, end the connection once the messages queued so far were sent
+                    if !self.abandoned {
+                        debug!("Every sender was dropped, disconnecting");
+                        self.abandoned = true;
+                        self.closing.get_or_insert_with(|| MessageInFlight {
+                            msg: MsgToHub::Disconnect,
+                            state: Arc::new(Mutex::new(MessageState {
+                                waker: None,
+                                watcher: None,
+                                status: MsgStatus::Pending,
+                            })),
+                            priority: Priority::Normal,
+                            quota_admitted: false,
+                            reservation: None,
+                            redelivery: false,
+                            encoded: None,
+                        });
+                    }
+                    break;
                 }
-            };
+            }
         }
+    }
 
-        return self.tx_buf.take();
+    fn take_next_outgoing_msg(&mut self) -> Option<MessageInFlight> {
+        self.drain_outgoing_queue();
+
+        // a partially sent message must be completed first, anything else may be overtaken
+        if self.tx_offset > 0 {
+            return self.tx_buf.take();
+        }
+        if let Some(msg) = self.tx_buf.take() {
+            self.lanes.push_front(msg);
+        }
+        if let (Some(limiter), false) = (self.rate_limiter.as_mut(), self.held_telemetry.is_empty()) {
+            if limiter.try_acquire(Instant::now()).is_ok() {
+                return self.held_telemetry.pop_front().map(|mut msg| {
+                    msg.quota_admitted = true;
+                    msg
+                });
+            }
+        }
+        // telemetry held back by the quota does not delay a disconnection
+        self.lanes.pop().or_else(|| self.closing.take())
+    }
+
+    /// Counts fresh telemetry against the quota. Returns None if the message was held back or rejected.
+    /// Held telemetry waits in order, without holding back other messages.
+    fn admit_telemetry(&mut self, mut msg: MessageInFlight) -> Option<MessageInFlight> {
+        if self.tx_offset > 0 || msg.quota_admitted || !matches!(msg.msg, MsgToHub::Telemetry(_)) {
+            return Some(msg);
+        }
+        let limiter = match self.rate_limiter.as_mut() {
+            Some(limiter) => limiter,
+            None => return Some(msg),
+        };
+        let policy = limiter.policy();
+        let admitted = match policy {
+            // keep the order of telemetry already waiting
+            QuotaPolicy::Delay if !self.held_telemetry.is_empty() => false,
+            _ => limiter.try_acquire(Instant::now()).is_ok(),
+        };
+        if admitted {
+            msg.quota_admitted = true;
+            return Some(msg);
+        }
+        match policy {
+            QuotaPolicy::Delay => {
+                trace!("Telemetry quota exhausted, holding a message");
+                self.stats.record_delayed();
+                self.held_telemetry.push_back(msg);
+            }
+            QuotaPolicy::Reject => {
+                debug!("Telemetry quota exhausted, rejecting a message");
+                self.stats.record_rejected();
+                self.fail_msg(msg, MsgStatus::QuotaExceeded);
+            }
+        }
+        None
     }
 
     fn socket_loop(&mut self) {
         debug!("Starting loop");
-        loop {
+        while !self.closed {
+            let iteration = Instant::now();
+            #[cfg(feature = "tokio-transport")]
+            self.reactor.clear();
+
+            self.shed_over_budget();
+
             // Transmit pending TX messages
             while self.send_next() {}
 
             // Get pending RX messages
             while self.recv_next() {}
 
+            if self.renewal_due() {
+                self.renew_connection();
+            }
+
+            self.record_gauges(iteration.elapsed());
+            #[cfg(not(feature = "tokio-transport"))]
             thread::sleep(Duration::from_millis(1));
+            #[cfg(feature = "tokio-transport")]
+            self.wait();
         }
+        debug!("Socket closed");
     }
 
-    fn handle_incoming_msg(&mut self, msg: MsgFromHub) {
-        match msg {
-            MsgFromHub::SubscriptionResponseMessage(resp) => {
-                self.handle_ack(resp.packet_id, resp.into());
+    /// Waits for the stream or the application, or until the loop has work to do regardless
+    #[cfg(feature = "tokio-transport")]
+    fn wait(&self) {
+        if self.stream.pending() > 0 {
+            // decrypted data left over by a read which stopped short of blocking
+            return;
+        }
+        let now = Instant::now();
+        let cooldown = self.throttle.remaining_cooldown(now);
+        // the rest of a partially written message, or one that would block
+        let writing = self.tx_buf.is_some() && cooldown.is_none();
+        // decode results and the quota are polled
+        let polling = self.decoder.as_ref().is_some_and(|decoder| decoder.in_flight > 0)
+            || !self.held_telemetry.is_empty();
+        let timeout = match polling {
+            true => Duration::from_millis(1),
+            false => {
+                let renewal = self
+                    .renew_at
+                    .map(|renew_at| renew_at.duration_since(SystemTime::now()).unwrap_or_default());
+                cooldown.into_iter().chain(renewal).min().unwrap_or(reactor::MAX_IDLE_WAIT)
+            }
+        };
+        self.reactor.wait(writing, timeout);
+    }
+
+    /// Sheds the oldest bulk messages waiting for the stream while alarms overdraw the memory budget
+    fn shed_over_budget(&mut self) {
+        let budget = match self.settings.memory_budget.clone() {
+            Some(budget) if budget.is_exceeded() => budget,
+            _ => return,
+        };
+        self.drain_outgoing_queue();
+        while budget.is_exceeded() {
+            match self.lanes.shed_oldest_bulk() {
+                Some(shed) => {
+                    debug!("Memory budget exceeded, shedding a bulk message");
+                    self.fail_msg(shed, MsgStatus::Shed);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// TRUE once the token should be renewed, and no message is partially written
+    fn renewal_due(&self) -> bool {
+        let due = self.renew_at.is_some_and(|renew_at| SystemTime::now() >= renew_at);
+        due && self.connected && self.tx_offset == 0 && self.closing.is_none()
+    }
+
+    /// Reconnects with a fresh SAS token, then queues the subscriptions and the messages awaiting an acknowledgement
+    /// ahead of anything else, so that their futures complete on the new connection.
+    /// A failed attempt keeps the current connection, and is retried after the retry interval.
+    fn renew_connection(&mut self) {
+        debug!("Renewing the SAS token");
+        self.notify(ConnectionEvent::TokenExpired);
+        // messages received on the current connection are handled first
+        self.drain_decoder();
+
+        let connected_at = SystemTime::now();
+        // once connected, the hub drops the previous connection in favour of the new one
+        let renewed =
+            connect(&mut self.connector, &self.settings, &self.cancel).and_then(|connection| {
+                self.switch_stream(connection, connected_at)
+                    .map_err(ConnectError::from)
+            });
+        if let Err(e) = renewed {
+            warn!("Failed renewing the SAS token: {}", e);
+            let retry_interval = self.settings.token_renewal.map_or(Duration::ZERO, |r| r.retry_interval);
+            self.renew_at = Some(connected_at + retry_interval);
+            return;
+        }
+        self.stats.record_token_renewal();
+        self.notify(ConnectionEvent::Connected);
+    }
+
+    /// TRUE if written messages are kept until acknowledged, to be sent again on a new connection
+    fn retransmits(&self) -> bool {
+        self.renew_at.is_some() || self.settings.retry_policy.is_some()
+    }
+
+    /// Reconnects a lost connection as the retry and takeover policies allow, then replays like a token renewal.
+    /// Returns FALSE if there is no retry policy, if a policy gave up, or if the application disconnected meanwhile.
+    fn reconnect(&mut self, reason: DisconnectReason) -> bool {
+        let policy = match self.settings.retry_policy.clone() {
+            Some(policy) => policy,
+            None => return false,
+        };
+        let mut takeover_delay = match self.takeovers.next_delay(reason) {
+            Ok(delay) => delay,
+            Err(e) => {
+                warn!("Not reconnecting: {}", e);
+                return false;
             }
-            MsgFromHub::PublicationSucceeded(packet_id) => {
-                self.handle_ack(packet_id, MsgStatus::Acknowledged);
+        };
+        // messages received before the disconnection are delivered first
+        self.drain_decoder();
+
+        // a refused reconnection switches to the retry schedule of the refusal, attempts are counted across schedules
+        let mut reason = reason;
+        let mut attempt = 0u32;
+        let mut reprovisioned = false;
+        loop {
+            attempt += 1;
+            let delay = match policy.next_delay(attempt, reason) {
+                Some(delay) => delay.max(takeover_delay),
+                None if reason.requires_reprovisioning() && !reprovisioned => {
+                    warn!("Giving up reconnecting after {} attempts, the device must be provisioned again", attempt - 1);
+                    self.notify(ConnectionEvent::ReprovisionRequired { reason });
+                    if !self.reprovision() {
+                        return false;
+                    }
+                    // the new hub gets a full retry schedule, but no second registration
+                    reprovisioned = true;
+                    attempt = 0;
+                    continue;
+                }
+                None => {
+                    warn!("Giving up reconnecting after {} attempts", attempt - 1);
+                    return false;
+                }
+            };
+            takeover_delay = Duration::ZERO;
+            self.notify(ConnectionEvent::Reconnecting { reason, attempt });
+            if !self.wait_to_reconnect(delay) {
+                debug!("Disconnected while reconnecting");
+                return false;
             }
-            other => {
-                self.incoming_queue.send(other).unwrap();
+            let connected_at = SystemTime::now();
+            match connect(&mut self.connector, &self.settings, &self.cancel) {
+                Ok(connection) => match self.switch_stream(connection, connected_at) {
+                    Ok(()) => {
+                        info!("Reconnected after {} attempts", attempt);
+                        self.notify(ConnectionEvent::Connected);
+                        return true;
+                    }
+                    Err(e) => warn!("Reconnection attempt {} failed: {}", attempt, e),
+                },
+                Err(ConnectError::Cancelled) => {
+                    debug!("Reconnection cancelled");
+                    return false;
+                }
+                Err(e) => {
+                    warn!("Reconnection attempt {} failed: {}", attempt, e);
+                    if let Some(refusal) = DisconnectReason::of_refusal(&e) {
+                        reason = refusal;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Registers the device again with the settings' reprovisioner, reconnecting to the assigned hub from then on.
+    /// Returns FALSE if there is no reprovisioner, or if the registration failed.
+    fn reprovision(&mut self) -> bool {
+        let reprovisioner = match self.settings.reprovisioner.clone() {
+            Some(reprovisioner) => reprovisioner,
+            None => return false,
+        };
+        match reprovisioner.reprovision(&self.settings) {
+            Ok(settings) => {
+                info!("Provisioned again, assigned to {}", settings.hostname);
+                self.settings = settings;
+                true
+            }
+            Err(e) => {
+                warn!("Failed provisioning the device again: {}", e);
+                false
             }
         }
     }
 
+    /// Waits before a reconnection attempt. Returns FALSE if the application disconnected meanwhile.
+    fn wait_to_reconnect(&mut self, delay: Duration) -> bool {
+        let deadline = Instant::now() + delay;
+        loop {
+            self.drain_outgoing_queue();
+            if self.closing.is_some() || self.cancel.is_cancelled() {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep((deadline - now).min(RECONNECT_POLL_INTERVAL));
+        }
+    }
+
+    /// Carries on over a new connection: the previous stream is closed, a partially read or written packet dropped,
+    /// and the subscriptions and unacknowledged messages replayed
+    fn switch_stream(
+        &mut self,
+        connection: Connection<C::Stream>,
+        connected_at: SystemTime,
+    ) -> io::Result<()> {
+        #[cfg(feature = "tokio-transport")]
+        self.reactor.switch_stream(connection.stream.socket())?;
+        let mut previous = mem::replace(&mut self.stream, connection.stream);
+        if let Err(e) = previous.shutdown() {
+            debug!("Failed shutting down the previous stream: {}", e);
+        }
+        self.packetizer = connection.packetizer;
+        self.tx_offset = 0;
+        self.token_expiry = self.settings.token_expiry(connected_at);
+        self.renew_at = self.settings.token_renewal_time(connected_at);
+        self.replay();
+        Ok(())
+    }
+
+    /// Queues the messages awaiting an acknowledgement in the order they were sent, behind a subscription to every
+    /// topic filter subscribed to so far
+    fn replay(&mut self) {
+        if let Some(msg) = self.tx_buf.take() {
+            self.lanes.push_front(msg);
+        }
+        let mut unacked: Vec<MessageInFlight> = self
+            .unacked
+            .drain()
+            .map(|(_, msg)| msg)
+            .filter(|msg| msg.state.lock().unwrap().is_in_flight())
+            .collect();
+        unacked.sort_by_key(|msg| msg.msg.packet_id().and_then(|packet_id| self.sent_at.get(&packet_id).copied()));
+        for mut msg in unacked.into_iter().rev() {
+            msg.redelivery = true;
+            self.lanes.push_front(msg);
+        }
+
+        // the client numbers its packets upwards, take a free packet ID from the other end of the range
+        let packet_id = (1..=u16::MAX)
+            .rev()
+            .map(PacketId::from)
+            .find(|packet_id| !self.awaiting_acks.contains_key(packet_id));
+        if let Some(msg) = packet_id.and_then(|packet_id| self.subscriptions.to_msg(packet_id)) {
+            let state = MessageState {
+                waker: None,
+                watcher: None,
+                status: MsgStatus::Pending,
+            };
+            self.lanes.push_front(MessageInFlight {
+                msg: msg.into(),
+                state: Arc::new(Mutex::new(state)),
+                priority: Priority::Alarm,
+                quota_admitted: false,
+                reservation: None,
+                redelivery: false,
+                encoded: None,
+            });
+        }
+    }
+
+    /// Closes the stream once the DISCONNECT was written, failing whatever is still pending
+    fn close(&mut self) {
+        debug!("Closing the connection");
+        if let Err(e) = self.stream.shutdown() {
+            debug!("Failed shutting down the stream: {}", e);
+        }
+        self.handle_disconnect(DisconnectReason::Requested);
+        self.closed = true;
+    }
+
+    fn record_gauges(&self, loop_latency: Duration) {
+        let outbound = self.lanes.len() + self.held_telemetry.len() + self.tx_buf.is_some() as usize;
+        self.stats.record_gauges(
+            outbound,
+            self.awaiting_acks.len(),
+            self.packetizer.buffered_len(),
+            loop_latency,
+        );
+    }
+
+    fn handle_incoming_msg(&mut self, msg: MsgFromHub) {
+        let mut msg = self.twin_requests.route(msg);
+        if let MsgFromHub::SubscriptionResponseMessage(res) = &mut msg {
+            // before the response is audited, so that failures carry their topic filter
+            if self.pending_subscriptions.correlate(res) {
+                match &res.result {
+                    Ok(()) => debug!("Subscribed to {:?}", res.topic_filters),
+                    Err(e) => warn!("Subscription to {:?} failed: {}", res.topic_filters, e),
+                }
+            }
+        }
+        self.stats.record_received();
+        audit_inbound(self.audit_sink.lock().unwrap().as_deref(), &msg, AuditOutcome::Received);
+        if let Some(clock) = &self.settings.clock {
+            let _ = clock.observe_msg(&msg, SystemTime::now());
+        }
+        if self.throttle.observe(&msg, Instant::now()) {
+            warn!("Throttled by the hub, cooling down");
+        }
+        match msg.delivery_receipt() {
+            Some(receipt) => {
+                let mut tap = self.receipts.lock().unwrap();
+                if let Some(Err(_)) = tap.as_ref().map(|tx| tx.unbounded_send(receipt.clone())) {
+                    // the stream was dropped
+                    tap.take();
+                }
+                drop(tap);
+                self.handle_ack(receipt.packet_id, receipt.into());
+            }
+            None => self.deliver(msg),
+        }
+    }
+
+    /// Queues a step of a QoS2 handshake, ahead of the application's messages
+    fn reply(&mut self, msg: MsgToHub) {
+        let state = MessageState {
+            waker: None,
+            watcher: None,
+            status: MsgStatus::Pending,
+        };
+        self.lanes.push(MessageInFlight {
+            msg,
+            state: Arc::new(Mutex::new(state)),
+            priority: Priority::Alarm,
+            quota_admitted: false,
+            reservation: None,
+            redelivery: false,
+            encoded: None,
+        });
+    }
+
+    fn deliver(&mut self, msg: MsgFromHub) {
+        let event = SocketEvent::Message(msg);
+        let delivered = match self.overflow {
+            OverflowPolicy::Block => self.incoming_queue.send(event).is_ok(),
+            OverflowPolicy::DropNewest => match self.incoming_queue.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(SocketEvent::Message(msg))) => {
+                    warn!("Received-message queue is full, dropping a message");
+                    self.stats.record_dropped();
+                    audit_inbound(self.audit_sink.lock().unwrap().as_deref(), &msg, AuditOutcome::Dropped);
+                    true
+                }
+                Err(_) => false,
+            },
+        };
+        if !delivered {
+            self.reader_hung_up();
+        }
+    }
+
+    /// Ends the session once the reader of the received messages hung up, e.g. as the client was dropped:
+    /// the message at hand is dropped, the stream closed and the socket loop exits
+    fn reader_hung_up(&mut self) {
+        debug!("The reader of the received messages hung up, closing the connection");
+        if let Err(e) = self.stream.shutdown() {
+            debug!("Failed shutting down the stream: {}", e);
+        }
+        self.connected = false;
+        self.fail_pending();
+        while let Ok(msg) = self.outgoing_queue.try_recv() {
+            self.fail_msg(msg, MsgStatus::ConnectionLost);
+        }
+        self.closed = true;
+    }
+
     fn handle_ack(&mut self, packet_id: PacketId, result: MsgStatus) {
+        if let Some(sent_at) = self.sent_at.remove(&packet_id) {
+            self.stats.record_ack(sent_at.elapsed());
+        }
+        self.unacked.remove(&packet_id);
         if let Some(item) = &self.awaiting_acks.remove(&packet_id) {
-            let mut state = item.lock().unwrap();
-            state.status = result;
-            if let Some(waker) = state.waker.take() {
-                waker.wake();
-            }
+            item.lock().unwrap().update(result);
         }
     }
-}
 
-fn generate_sas_token(settings: &ConnectionSettings, key: &str) -> SasToken {
-    match &settings.client_id {
-        ClientIdentity::Device(device) => SasToken::for_device(
-            &settings.hostname,
-            &device.device_id,
-            key,
-            settings.token_ttl,
-        )
-        .expect("Token expected to be valid"),
-        ClientIdentity::Module(module) => SasToken::for_module(
-            &settings.hostname,
-            &module.device_id,
-            &module.module_id,
-            key,
-            settings.token_ttl,
-        )
-        .expect("Token expected to be valid"),
+    fn handle_disconnect(&mut self, reason: DisconnectReason) {
+        // messages received before the disconnection are delivered first
+        self.drain_decoder();
+        self.connected = false;
+        self.fail_pending();
+        // the reader may have hung up already
+        let _ = self
+            .incoming_queue
+            .send(SocketEvent::Disconnected { reason });
+    }
+
+    /// Fails the messages awaiting their acknowledgement, and those still waiting for the stream
+    fn fail_pending(&mut self) {
+        self.sent_at.clear();
+        self.unacked.clear();
+        self.twin_requests.clear();
+        // subscriptions replayed on the next connection are tracked again once written
+        self.pending_subscriptions = SubscriptionTracker::new();
+        for (_, item) in self.awaiting_acks.drain() {
+            item.lock().unwrap().update(MsgStatus::Disconnected);
+        }
+        if let Some(msg) = self.tx_buf.take() {
+            self.tx_offset = 0;
+            self.fail_msg(msg, MsgStatus::ConnectionLost);
+        }
+        while let Some(msg) = self.lanes.pop() {
+            self.fail_msg(msg, MsgStatus::ConnectionLost);
+        }
+        while let Some(msg) = self.held_telemetry.pop_front() {
+            self.fail_msg(msg, MsgStatus::ConnectionLost);
+        }
+    }
+
+    fn notify(&self, event: ConnectionEvent) {
+        let _ = self.incoming_queue.send(SocketEvent::StateChanged(event));
+    }
+
+    /// Completes a message that will not be sent: shed under backpressure, over the quota, or cut off by a disconnection
+    fn fail_msg(&self, msg: MessageInFlight, status: MsgStatus) {
+        let outcome = match status {
+            MsgStatus::Shed => AuditOutcome::Dropped,
+            MsgStatus::QuotaExceeded => AuditOutcome::Failed(SendError::QuotaExceeded.to_string()),
+            MsgStatus::OverBudget => AuditOutcome::Failed(SendError::OverBudget.to_string()),
+            MsgStatus::ConnectionLost => AuditOutcome::Failed(SendError::ConnectionLost.to_string()),
+            _ => AuditOutcome::Failed(SendError::Disconnected.to_string()),
+        };
+        msg.state.lock().unwrap().update(status);
+        self.audit_outbound(&msg.msg, outcome);
+    }
+
+    fn audit_outbound(&self, msg: &MsgToHub, outcome: AuditOutcome) {
+        audit_outbound(self.audit_sink.lock().unwrap().as_deref(), msg, outcome);
     }
 }
 
-fn connect(settings: &ConnectionSettings) -> ConnectionResults {
-    let now = Instant::now();
-    let client_certificate = match settings.credentials {
-        DeviceCredentials::Certificate(ref cert) => Some(ClientCertificate {
-            bytes: cert.bytes.clone(),
-            password: cert.password.clone(),
-        }),
-        DeviceCredentials::Sas(_) => None,
-    };
+/// A connection accepted by the hub
+struct Connection<S> {
+    stream: S,
+    capabilities: Capabilities,
+    // holds whatever was read past the CONNACK, e.g. publications the hub redelivers to a dirty session
+    packetizer: MqttPacketizer,
+}
 
-    let mut stream = open_nonblocking_stream(
-        &settings.hostname,
-        settings.port.into(),
-        settings.timeout,
-        client_certificate.as_ref(),
-    )
-    .unwrap();
+fn connect<C: Connector>(
+    connector: &mut C,
+    settings: &ConnectionSettings,
+    cancel: &CancelToken,
+) -> Result<Connection<C::Stream>, ConnectError> {
+    let now = Instant::now();
+    let mut stream = connector
+        .open(settings, cancel)
+        .map_err(|e| match cancel.is_cancelled() {
+            true => ConnectError::Cancelled,
+            false => e.into(),
+        })?;
 
     let token = match settings.credentials {
         DeviceCredentials::Sas(ref key) => Some(generate_sas_token(settings, key).into()),
@@ -403,24 +1886,41 @@ fn connect(settings: &ConnectionSettings) -> ConnectionResults {
         server_addr: settings.hostname.clone(),
         sas_token: token,
         session_mode: settings.session_mode,
+        client_id_override: settings.client_id_override.clone(),
     };
 
-    let mut buf = vec![0u8; 1024 * 1024];
     debug!("Connecting MQTT...");
 
-    let encoded_size = IotCodec::encode(&conn.into(), &mut buf).unwrap();
+    let buf = IotCodec::encode_to_vec(&conn.into()).map_err(|_| ConnectError::ProtocolViolation)?;
     debug!("Sending CONN...");
-    &mut stream.send(&buf[0..encoded_size]).unwrap();
+    stream.send_blocking(&buf).map_err(|e| ConnectError::IOError(e.kind()))?;
     debug!("Waiting...");
 
+    // the CONNACK may arrive over several reads, and be followed by other packets in the same read
+    let mut packetizer = MqttPacketizer::new();
     loop {
+        if cancel.is_cancelled() {
+            return Err(ConnectError::Cancelled);
+        }
         if now.elapsed() >= settings.timeout {
-            return Err(ConnectRes::Timeout);
+            return Err(ConnectError::Timeout);
         }
-        let read_res = &mut stream.try_read();
-        match read_res {
+        match stream.try_read() {
             Ok(Some(bytes)) => {
-                return decode_connect_response(&bytes, stream);
+                packetizer
+                    .append_all_bytes(bytes)
+                    .map_err(|_| ConnectError::ProtocolViolation)?;
+                let packet = match packetizer.get_next_packet() {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => continue,
+                    Err(_) => return Err(ConnectError::ProtocolViolation),
+                };
+                let capabilities = Capabilities::negotiated(&decode_connect_response(packet)?);
+                return Ok(Connection {
+                    stream,
+                    capabilities,
+                    packetizer,
+                });
             }
             Ok(None) => {
                 debug!("Nothing to read");
@@ -437,26 +1937,221 @@ fn connect(settings: &ConnectionSettings) -> ConnectionResults {
             }
             Err(e) => {
                 debug!("Some other IO error");
-                return Err(ConnectRes::IOError(e.kind()));
+                return Err(ConnectError::IOError(e.kind()));
             }
         }
     }
 }
 
-fn decode_connect_response(bytes: &Vec<u8>, stream: IoStream) -> ConnectionResults {
-    debug!("decode_connect_response, bytes length: {}", bytes.len());
-    let mut packetizer = MqttPacketizer::new();
-    packetizer.append_all_bytes(&bytes[0..bytes.len()]).unwrap();
-    match IotCodec::decode_packet(packetizer.get_next_packet().unwrap().unwrap()) {
-        Ok(MsgFromHub::ConnectResponseMessage(ConnectRes::Accepted)) => Ok(stream),
-        Ok(MsgFromHub::ConnectResponseMessage(error)) => Err(error),
+/// Decodes the first packet from the hub, which must be a CONNACK
+fn decode_connect_response(packet: VariablePacket) -> Result<ConnectSuccess, ConnectError> {
+    match IotCodec::decode_packet(packet) {
+        Ok(MsgFromHub::ConnectResponseMessage(Ok(success))) => Ok(success),
+        Ok(MsgFromHub::ConnectResponseMessage(Err(error))) => Err(error),
         Ok(_other) => {
             debug!("Unexpected message type");
-            Err(ConnectRes::ProtocolViolation)
+            Err(ConnectError::ProtocolViolation)
         }
         Err(_e) => {
             debug!("Failure decoding response");
-            Err(ConnectRes::ProtocolViolation)
+            Err(ConnectError::ProtocolViolation)
+        }
+    }
+}
+
+// the reactor waits on the TCP socket of the stream, which mock streams don't have
+#[cfg(all(test, not(feature = "tokio-transport")))]
+mod tests {
+    use super::*;
+    use crate::testing::{device_settings, mock_hubs, wait_until, MockConnector};
+    use futures::executor::block_on;
+    use futures::FutureExt;
+    use mqtt::control::variable_header::ConnectReturnCode;
+    use mqtt::packet::{ConnackPacket, PublishPacket, QoSWithPacketIdentifier};
+    use mqtt::TopicName;
+    use raiot_client_base::ExponentialBackoff;
+    use raiot_protocol::messages::telemetry::TelemetryMsg;
+    use raiot_protocol::messages::twin::TwinUpdatesSub;
+    use raiot_protocol::qos::DeliveryGuarantees;
+    use raiot_test_utils::hub::{is_duplicate, MockHub, PubackAction};
+    use raiot_test_utils::MockSocket;
+
+    fn reconnecting_settings() -> ConnectionSettings {
+        let retry_policy = ExponentialBackoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+            jitter: 0.0,
+            max_attempts: Some(3),
+        };
+        ConnectionSettings {
+            retry_policy: Some(Arc::new(retry_policy)),
+            ..device_settings()
+        }
+    }
+
+    fn connect(
+        connector: MockConnector,
+        settings: ConnectionSettings,
+        queue: ReceiveQueueConfig,
+    ) -> IotSocket {
+        IotSocket::connect_with(connector, settings, queue, &CancelToken::new()).unwrap()
+    }
+
+    fn telemetry(packet_id: u16) -> TelemetryMsg {
+        TelemetryMsg {
+            client_id: device_settings().client_id,
+            content: Some(serde_json::json!({ "temperature": 21 })),
+            packet_id: Some(PacketId::from(packet_id)),
+            exactly_once: false,
+            headers: None,
+            serializer: None,
+        }
+    }
+
+    #[test]
+    fn test_reconnection_replays_subscriptions_and_unacknowledged_messages() {
+        let (connector, hubs) = mock_hubs(2);
+        let socket = connect(
+            connector,
+            reconnecting_settings(),
+            ReceiveQueueConfig::default(),
+        );
+        let (mut tx, _rx) = socket.split();
+        let subscription = TwinUpdatesSub {
+            packet_id: PacketId::from(1),
+            mode: DeliveryGuarantees::AtLeastOnce,
+        };
+        block_on(tx.send(subscription)).unwrap();
+
+        hubs[0].with(|hub| hub.push_puback_action(PubackAction::Withhold));
+        let delivery = tx.send(telemetry(2));
+        assert!(wait_until(|| hubs[0].with(|hub| hub.received().len() == 1)));
+        hubs[0].with(MockHub::drop_connection);
+
+        let delivery = block_on(delivery).unwrap();
+        assert!(delivery.acknowledged);
+        hubs[1].with(|hub| {
+            assert_eq!(
+                hub.subscriptions(),
+                ["$iothub/twin/PATCH/properties/desired/#"]
+            );
+            assert_eq!(hub.received().len(), 1);
+            assert!(is_duplicate(&hub.received()[0]));
+        });
+    }
+
+    #[test]
+    fn test_messages_overflowing_the_receive_queue_are_dropped() {
+        let (connector, hubs) = mock_hubs(1);
+        let queue = ReceiveQueueConfig {
+            capacity: 1,
+            overflow: OverflowPolicy::DropNewest,
+            decode_offload_threshold: None,
+        };
+        let mut socket = connect(connector, device_settings(), queue);
+
+        hubs[0].with(|hub| {
+            for payload in [&br#""first""#[..], br#""second""#, br#""third""#] {
+                hub.publish("devices/device1/messages/devicebound/", payload);
+            }
+        });
+        assert!(wait_until(|| socket.stats().messages_dropped() == 2));
+        match socket.try_recv() {
+            Some(SocketEvent::Message(MsgFromHub::CloudToDeviceMessage(c2d))) => {
+                assert_eq!(c2d.body.as_deref(), Some("first"))
+            }
+            other => panic!("Expected the first C2D message, got {:?}", other),
+        }
+        assert!(socket.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_message_failing_to_decode_drops_the_connection() {
+        let (connector, hubs) = mock_hubs(1);
+        let queue = ReceiveQueueConfig {
+            decode_offload_threshold: Some(1),
+            ..ReceiveQueueConfig::default()
+        };
+        let socket = connect(connector, device_settings(), queue);
+        let (_tx, mut rx) = socket.split();
+
+        // the body of C2D messages is JSON
+        hubs[0].with(|hub| hub.publish("devices/device1/messages/devicebound/", b"not JSON"));
+        match rx.recv() {
+            Some(SocketEvent::Disconnected { reason }) => {
+                assert_eq!(
+                    reason,
+                    DisconnectReason::NetworkLoss(ErrorKind::InvalidData)
+                )
+            }
+            other => panic!("Expected a disconnection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_socket_disconnects_once_the_senders_were_dropped() {
+        let (connector, _hubs) = mock_hubs(1);
+        let socket = connect(connector, device_settings(), ReceiveQueueConfig::default());
+        let (tx, mut rx) = socket.split();
+        drop(tx);
+
+        match rx.recv() {
+            Some(SocketEvent::Disconnected { reason }) => {
+                assert_eq!(reason, DisconnectReason::Requested)
+            }
+            other => panic!("Expected a disconnection, got {:?}", other),
+        }
+        assert!(rx.recv().is_none());
+    }
+
+    #[test]
+    fn test_socket_closes_once_the_reader_hung_up() {
+        let (connector, hubs) = mock_hubs(1);
+        let socket = connect(connector, device_settings(), ReceiveQueueConfig::default());
+        let (mut tx, rx) = socket.split();
+        drop(rx);
+
+        hubs[0].with(|hub| hub.publish("devices/device1/messages/devicebound/", br#""hello""#));
+        // messages queued as the connection closes are failed as well, those sent afterwards right away
+        assert!(wait_until(|| {
+            let sent = tx.send(telemetry(1)).now_or_never();
+            sent == Some(Err(SendError::ConnectionLost))
+        }));
+    }
+
+    #[test]
+    fn test_connack_split_across_reads_and_followed_by_a_publication() {
+        let (connector, sockets) = MockConnector::new();
+        let (client, mut server) = MockSocket::create();
+        sockets.send(client).unwrap();
+        server.push_write_ctl(Ok(64 * 1024));
+
+        let mut bytes = Vec::new();
+        let connack: VariablePacket =
+            ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted).into();
+        connack.encode(&mut bytes).unwrap();
+        let publish: VariablePacket = PublishPacket::new(
+            TopicName::new("devices/device1/messages/devicebound/").unwrap(),
+            QoSWithPacketIdentifier::Level1(1),
+            br#""redelivered""#.to_vec(),
+        )
+        .into();
+        publish.encode(&mut bytes).unwrap();
+        server.push_data(&bytes);
+        // the first read stops short of a whole CONNACK, the second one reads the rest of it along with the publication
+        server.push_read_ctl(Ok(1));
+        server.push_read_ctl(Ok(bytes.len() - 1));
+
+        let socket = connect(connector, device_settings(), ReceiveQueueConfig::default());
+        let (_tx, mut rx) = socket.split();
+        match rx.recv() {
+            Some(SocketEvent::Message(MsgFromHub::CloudToDeviceMessage(c2d))) => {
+                assert_eq!(c2d.body.as_deref(), Some("redelivered"))
+            }
+            other => panic!(
+                "Expected the publication read along with the CONNACK, got {:?}",
+                other
+            ),
         }
     }
 }
@@ -2,62 +2,187 @@ extern crate futures;
 #[macro_use]
 extern crate log;
 
-use raiot_client_base::PacketsNumerator;
-use iot_socket::{IotSocket, IotSocketTx, MessageFuture, MsgTxResult};
-use raiot_protocol::auth::{DeviceCredentials, sas::SasToken};
+use raiot_client_base::audit::{audit_inbound, AuditOutcome, AuditSink};
+use raiot_client_base::outbox::{Outbox, OutboxDrainer, OutboxError};
+use raiot_client_base::{
+    C2DCompletionPolicy, C2DHandlerOutcome, DedupConfig, DiagnosticSampler, DisconnectReason, MessageDeduplicator, PacketsNumerator, RequestIdSource, TelemetrySequencer, DEFAULT_DMI_RESPONSE_WINDOW,
+    DMI_BUSY_STATUS, DMI_TIMEOUT_STATUS, HeartbeatConfig, HeartbeatCounters, ConnectionEvent, ConnectionStatus,
+};
+use iot_socket::{
+    DeliveryInfo, IotSocket, IotSocketTx, MessageFuture, MsgTxResult, OperationId,
+    PendingOperation, Priority, SendError, SharedAuditSink, SharedPayloadCipher, SharedReceiptsTap,
+    SocketEvent,
+};
+#[cfg(feature = "raw-mqtt")]
+use iot_socket::SharedRawTap;
+#[cfg(feature = "raw-mqtt")]
+use mqtt::packet::VariablePacket;
 use raiot_protocol::*;
-use raiot_protocol::messages::c2d::*;
 use raiot_protocol::messages::direct_methods::*;
 use raiot_protocol::messages::telemetry::*;
+use raiot_protocol::chunking::split_telemetry;
+use raiot_protocol::connect::Capabilities;
+use raiot_protocol::encryption::PayloadCipher;
+use raiot_protocol::serialization::PayloadSerializer;
 
-
-use raiot_streams::IoStream;
-use raiot_streams::{open_nonblocking_stream, NonblockingSocket, ClientCertificate};
 use std::collections::HashMap;
+use serde_json::{Map, Value};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::Stream;
 use std::future::*;
-use std::io::ErrorKind;
 use std::sync::{
-    mpsc::{channel, Receiver, Sender, TryRecvError},
+    mpsc::{channel, Receiver, RecvTimeoutError, Sender},
     Arc, Mutex,
 };
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::{
     pin::Pin,
     task::{Context, Poll, Waker},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-use qos::{DeliveryGuarantees, PacketId, SessionMode};
-use uuid::Uuid;
-use dmi::{DMIRequest, DMIHandler};
-use c2d::{C2DMsg, C2DHandler};
-use d2c::D2CMsg;
+use qos::{DeliveryGuarantees, PacketId, QosDefaults};
+use dmi::{DMIRequest, DMIResult, MethodRouter};
+use c2d::{C2DDelivery, C2DMsg, C2DExpiredHandler, C2DHandler, C2DResult, InputHandler, InputMsg};
+use d2c::{D2CMsg, TelemetryEnricher};
 use direct_methods::DirectMethodsSub;
+use pool::{HandlerPoolConfig, WorkerPool};
+use stats::SessionStats;
 use twin::*;
 
 pub mod iot_socket;
 pub mod dmi;
 pub mod c2d;
 pub mod d2c;
+pub mod stats;
+pub mod pool;
+pub mod shutdown;
+mod capabilities;
+#[cfg(feature = "tokio-transport")]
+mod reactor;
+#[cfg(all(test, not(feature = "tokio-transport")))]
+mod testing;
+
+pub use capabilities::Unsubscribed;
+pub use raiot_client_base::capabilities::{C2DCapable, CapabilityError, MethodsCapable, TwinCapable};
+pub use raiot_errors::{ClientError, ProtocolError, TransportError};
+
+
+
+/// Inbound middleware applied to every message from the hub before it is dispatched to handlers.
+/// Returning FALSE drops the message.
+pub type InboundInterceptor = dyn FnMut(&mut MsgFromHub) -> bool + Send;
+
+/// How often pending direct methods are checked against their response window
+const DMI_WATCHDOG_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Invoked when the connection to the hub is lost
+pub type DisconnectHandler = dyn Fn(DisconnectReason) + Send;
+
+/// Invoked whenever the state of the connection to the hub changes
+pub type ConnectionEventHandler = dyn Fn(ConnectionEvent) + Send;
 
+/// Invoked for the desired properties updates, unless they are consumed as a stream
+pub type TwinUpdatesHandler = dyn Fn(DesiredPropsUpdated) + Send;
 
+/// The state of the connection, with the application's handler and stream of its changes
+struct ConnectionState {
+    status: ConnectionStatus,
+    handler: Option<Box<ConnectionEventHandler>>,
+    events: Option<Sender<ConnectionEvent>>,
+}
 
-enum DeviceCommand {
-    ReadTwin,
-    SendTelemetry(D2CMsg),
+impl ConnectionState {
+    fn apply(&mut self, event: ConnectionEvent) {
+        self.status.apply(event);
+        if let Some(handler) = &self.handler {
+            handler(event);
+        }
+        if let Some(tx) = self.events.as_ref() {
+            if tx.send(event).is_err() {
+                // the stream was dropped
+                self.events = None;
+            }
+        }
+    }
 }
 
 struct RequestState {
-    result: Option<Result<MsgFromHub, ()>>,
+    submitted: Instant,
+    result: Option<Result<MsgFromHub, SendError>>,
     waker: Option<Waker>,
+    // woken along with the future, for `Settled`
+    watcher: Option<Waker>,
+}
+
+impl RequestState {
+    fn complete(&mut self, result: Result<MsgFromHub, SendError>) {
+        self.result = Some(result);
+        for waker in self.waker.take().into_iter().chain(self.watcher.take()) {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves once no twin request awaits its response, and no message its acknowledgement
+struct Settled<'a> {
+    awaiting_response: &'a Mutex<HashMap<String, Arc<Mutex<RequestState>>>>,
+    tx: &'a IotSocketTx,
+}
+
+impl Future for Settled<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // requests are removed as they complete
+        let requests = self.awaiting_response.lock().unwrap();
+        for request in requests.values() {
+            request.lock().unwrap().watcher = Some(cx.waker().clone());
+        }
+        match self.tx.watch_pending(cx.waker()) || !requests.is_empty() {
+            true => Poll::Pending,
+            false => Poll::Ready(()),
+        }
+    }
 }
 
+/// Resolves to the hub's response to a twin request.
+/// Fails with `SendError::ConnectionLost` if the connection is lost before the response arrives,
+/// or with `SendError::UnexpectedResponse` if the hub answered with another kind of message.
 pub struct TwinFuture {
     state: Arc<Mutex<RequestState>>,
 }
 
 impl Future for TwinFuture {
-    type Output = ReadTwinRes;
+    type Output = Result<ReadTwinRes, SendError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared_state = self.state.lock().unwrap();
+        match shared_state.result.take() {
+            None => {
+                shared_state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Some(Err(e)) => Poll::Ready(Err(e)),
+            Some(Ok(msg)) => match msg {
+                MsgFromHub::TwinResponseMessage(resp) => Poll::Ready(Ok(resp)),
+                other => {
+                    warn!("Unexpected response to a twin request: {}", other);
+                    Poll::Ready(Err(SendError::UnexpectedResponse))
+                }
+            },
+        }
+    }
+}
+
+/// Resolves to the hub's response to a reported properties update.
+/// Fails like `TwinFuture`.
+struct ReportedPropsFuture {
+    state: Arc<Mutex<RequestState>>,
+}
+
+impl Future for ReportedPropsFuture {
+    type Output = Result<UpdateReportedPropsRes, SendError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let mut shared_state = self.state.lock().unwrap();
@@ -66,175 +191,1313 @@ impl Future for TwinFuture {
                 shared_state.waker = Some(cx.waker().clone());
                 Poll::Pending
             }
-            Some(msg) => match msg.unwrap() {
-                MsgFromHub::TwinResponseMessage(resp) => Poll::Ready(resp),
-                _ => panic!("Wrong msg"),
+            Some(Err(e)) => Poll::Ready(Err(e)),
+            Some(Ok(msg)) => match msg {
+                MsgFromHub::ReportedPropertiesUpdated(resp) => Poll::Ready(Ok(resp)),
+                other => {
+                    warn!("Unexpected response to a reported properties update: {}", other);
+                    Poll::Ready(Err(SendError::UnexpectedResponse))
+                }
             },
         }
     }
 }
 
+/// The reason a reported properties update failed
+#[derive(Debug, Copy, Clone)]
+pub enum ReportedPropsError {
+    /// The request was not delivered, or the connection was lost before the response arrived
+    Send(SendError),
+
+    /// The hub answered with a failure status
+    Rejected(StatusCode),
+}
+
+impl ReportedPropsError {
+    /// Returns TRUE if the update may succeed when retried later: it was throttled,
+    /// or failed with a transient server error (e.g. during hub maintenance)
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ReportedPropsError::Rejected(status) if status.is_transient())
+    }
+}
+
+impl From<SendError> for ReportedPropsError {
+    fn from(e: SendError) -> Self {
+        ReportedPropsError::Send(e)
+    }
+}
+
+impl std::fmt::Display for ReportedPropsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportedPropsError::Send(e) => write!(f, "{}", e),
+            ReportedPropsError::Rejected(status) => write!(f, "Rejected by the hub ({:?})", status),
+        }
+    }
+}
+
+impl std::error::Error for ReportedPropsError {}
+
 pub struct DeviceClient {
     tx: IotSocketTx,
     id: ClientIdentity,
+    qos: QosDefaults,
+    capabilities: Capabilities,
     packet_id: PacketsNumerator,
     subscribed_to_twin: bool,
+    subscriptions: SubscriptionSnapshot,
     awaiting_response: Arc<Mutex<HashMap<String, Arc<Mutex<RequestState>>>>>,
-    dmi_handler: Arc<Mutex<Option<DMIHandler>>>,
-    c2d_handler: Arc<Mutex<Option<C2DHandler>>>,
+    methods: Arc<Mutex<MethodRouter>>,
+    dmi_response_window: Arc<Mutex<Duration>>,
+    c2d_handler: Arc<Mutex<Option<Arc<C2DHandler>>>>,
+    c2d_expired_handler: Arc<Mutex<Option<Box<C2DExpiredHandler>>>>,
+    c2d_completion: Arc<Mutex<C2DCompletionPolicy>>,
+    input_handler: Arc<Mutex<Option<Arc<InputHandler>>>>,
+    disconnect_handler: Arc<Mutex<Option<Box<DisconnectHandler>>>>,
+    desired_updates: Arc<Mutex<Option<UnboundedSender<DesiredPropsUpdated>>>>,
+    twin_updates_handler: Arc<Mutex<Option<Box<TwinUpdatesHandler>>>>,
+    c2d_messages: Arc<Mutex<Option<UnboundedSender<C2DDelivery>>>>,
+    receipts: SharedReceiptsTap,
+    connection: Arc<Mutex<ConnectionState>>,
+    diagnostics: DiagnosticSampler,
+    enrichers: Vec<Box<TelemetryEnricher>>,
+    interceptors: Arc<Mutex<Vec<Box<InboundInterceptor>>>>,
+    dedup: Arc<Mutex<Option<MessageDeduplicator>>>,
+    sequencer: Option<TelemetrySequencer>,
+    stats: Arc<SessionStats>,
+    audit_sink: SharedAuditSink,
+    cipher: SharedPayloadCipher,
+    #[cfg(feature = "raw-mqtt")]
+    raw_tap: SharedRawTap,
+    serializer: Arc<Mutex<Option<Arc<dyn PayloadSerializer>>>>,
+    // shared with the heartbeat thread
+    request_ids: Arc<Mutex<RequestIdSource>>,
+    created: Instant,
+    // the receive loop, the direct methods watchdog and the heartbeat thread, joined by `shutdown`
+    threads: Vec<JoinHandle<()>>,
+    // dropped to stop the heartbeat thread
+    heartbeat_stop: Option<Sender<()>>,
+    // set by `disconnect`, a client dropped otherwise disconnects on its own
+    disconnected: bool,
 }
 
 
 
 impl DeviceClient {
-    pub fn set_c2d_handler(&mut self, handler: C2DHandler, mode: DeliveryGuarantees) {
-        let old = self.c2d_handler.lock().unwrap().replace(handler);
-        if old.is_none() {
-            self.tx.send(C2DSub {
-                device_id: match self.id {
-                    ClientIdentity::Device(ref device) => device.clone(),
-                    ClientIdentity::Module(_) => panic!("Cannot subscribe to C2D messages on a module")
-                },
-                packet_id: self.packet_id.next(),
-                mode,
-            });
+    /// Sets the C2D messages handler. A mode of None uses the default C2D delivery guarantees.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotADevice` on a module, which sets an input handler instead
+    pub fn set_c2d_handler<F>(&mut self, handler: F, mode: Option<DeliveryGuarantees>) -> Result<(), CapabilityError>
+    where
+        F: Fn(C2DMsg) -> C2DResult + Send + Sync + 'static,
+    {
+        let device = match self.id {
+            ClientIdentity::Device(ref device) => device,
+            ClientIdentity::Module(_) => return Err(CapabilityError::NotADevice),
+        };
+        self.c2d_handler.lock().unwrap().replace(Arc::new(handler));
+        // the stream of C2D messages may have subscribed already
+        if self
+            .subscriptions
+            .get(SubscriptionKind::CloudToDevice)
+            .is_none()
+        {
+            let msg = IotCodec::device_subscriptions(device)
+                .c2d(self.packet_id.next_id(), mode.unwrap_or(self.qos.c2d));
+            self.subscribe(msg.into());
         }
+        Ok(())
     }
 
-    pub fn set_dmi_handler(&mut self, handler: DMIHandler, mode: DeliveryGuarantees) {
-        let old = self.dmi_handler.lock().unwrap().replace(handler);
-        if old.is_none() {
-            self.tx.send(DirectMethodsSub {
-                packet_id: self.packet_id.next(),
-                mode,
-            });
+    /// Returns a stream of the C2D messages, subscribing to them unless already subscribed. The stream takes
+    /// precedence over the C2D handler, which takes over again once the stream is dropped.
+    /// Subscribed at least once, its messages are acknowledged only once completed by the consumer
+    /// (see `C2DDelivery`): the hub delivers the ones dropped without being completed again.
+    /// Replaces the stream returned by an earlier call. The stream ends once the connection is lost.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotADevice` on a module
+    pub fn c2d_messages(&mut self) -> Result<impl Stream<Item = C2DDelivery>, CapabilityError> {
+        let device = match self.id {
+            ClientIdentity::Device(ref device) => device,
+            ClientIdentity::Module(_) => return Err(CapabilityError::NotADevice),
+        };
+        let (tx, rx) = unbounded();
+        self.c2d_messages.lock().unwrap().replace(tx);
+        if self
+            .subscriptions
+            .get(SubscriptionKind::CloudToDevice)
+            .is_none()
+        {
+            let msg =
+                IotCodec::device_subscriptions(device).c2d(self.packet_id.next_id(), self.qos.c2d);
+            self.subscribe(msg.into());
+        }
+        Ok(rx)
+    }
+
+    /// Sets the handler of the messages routed to the inputs of the module, completed according to the
+    /// C2D completion policy. A mode of None uses the default C2D delivery guarantees.
+    /// Modules receive these instead of C2D messages.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotAModule` on a device
+    pub fn set_input_handler<F>(&mut self, handler: F, mode: Option<DeliveryGuarantees>) -> Result<(), CapabilityError>
+    where
+        F: Fn(InputMsg) -> C2DResult + Send + Sync + 'static,
+    {
+        let module = match self.id {
+            ClientIdentity::Module(ref module) => module.clone(),
+            ClientIdentity::Device(_) => return Err(CapabilityError::NotAModule),
+        };
+        self.input_handler
+            .lock()
+            .unwrap()
+            .replace(Arc::new(handler));
+        if self
+            .subscriptions
+            .get(SubscriptionKind::ModuleInputs)
+            .is_none()
+        {
+            let mode = mode.unwrap_or(self.qos.c2d);
+            let msg =
+                IotCodec::module_subscriptions(&module).inputs(self.packet_id.next_id(), mode);
+            self.subscriptions
+                .insert(ActiveSubscription::module_inputs(&module, mode));
+            self.tx.send(msg);
         }
+        Ok(())
+    }
+
+    /// Drops C2D messages that already expired upon receipt, without acknowledging them,
+    /// passing them to the specified handler instead of the C2D handler
+    pub fn set_c2d_expired_handler<F: Fn(C2DMsg) + Send + 'static>(&mut self, handler: F) {
+        self.c2d_expired_handler.lock().unwrap().replace(Box::new(handler));
+    }
+
+    /// Sets whether C2D messages whose handler failed or panicked are acknowledged or abandoned,
+    /// to be delivered again once the client reconnects. Handled messages are always acknowledged.
+    /// Only applies to messages subscribed to at least once: the hub never delivers the others again.
+    pub fn set_c2d_completion_policy(&mut self, policy: C2DCompletionPolicy) {
+        *self.c2d_completion.lock().unwrap() = policy;
+    }
+
+    /// Sets a handler invoked when the connection to the hub is lost, with the inferred reason
+    pub fn set_disconnect_handler<F: Fn(DisconnectReason) + Send + 'static>(&mut self, handler: F) {
+        self.disconnect_handler.lock().unwrap().replace(Box::new(handler));
+    }
+
+    /// Sets a handler invoked whenever the state of the connection changes, e.g. while reconnecting
+    pub fn set_connection_event_handler<F: Fn(ConnectionEvent) + Send + 'static>(&mut self, handler: F) {
+        self.connection.lock().unwrap().handler.replace(Box::new(handler));
+    }
+
+    /// Returns a stream of the changes of the state of the connection.
+    /// Replaces the stream returned by an earlier call.
+    pub fn connection_events(&self) -> Receiver<ConnectionEvent> {
+        let (tx, rx) = channel();
+        self.connection.lock().unwrap().events = Some(tx);
+        rx
+    }
+
+    /// Returns a stream of the acknowledgements of the publications, subscriptions and unsubscriptions of the client,
+    /// as received from the hub. Replaces the stream returned by an earlier call.
+    /// The stream ends once the connection is lost.
+    pub fn delivery_receipts(&mut self) -> impl Stream<Item = DeliveryReceipt> {
+        let (tx, rx) = unbounded();
+        self.receipts.lock().unwrap().replace(tx);
+        rx
+    }
+
+    /// Returns a stream of the desired properties updates, subscribing to them unless already subscribed.
+    /// Replaces the stream returned by an earlier call. The stream ends once the connection is lost.
+    pub fn desired_property_updates(&mut self) -> impl Stream<Item = DesiredPropsUpdated> {
+        let (tx, rx) = unbounded();
+        self.desired_updates.lock().unwrap().replace(tx);
+        if self.subscriptions.get(SubscriptionKind::TwinUpdates).is_none() {
+            let msg = TwinUpdatesSub {
+                packet_id: self.packet_id.next_id(),
+                mode: self.qos.twin,
+            };
+            self.subscribe(msg.into());
+        }
+        rx
+    }
+
+    /// Sets the desired properties updates handler, subscribing to the updates unless already subscribed.
+    /// The stream returned by `desired_property_updates` takes precedence over the handler.
+    /// A mode of None uses the default twin delivery guarantees.
+    pub fn set_twin_updates_handler<F: Fn(DesiredPropsUpdated) + Send + 'static>(
+        &mut self,
+        handler: F,
+        mode: Option<DeliveryGuarantees>,
+    ) {
+        self.twin_updates_handler.lock().unwrap().replace(Box::new(handler));
+        if self.subscriptions.get(SubscriptionKind::TwinUpdates).is_none() {
+            let msg = TwinUpdatesSub {
+                packet_id: self.packet_id.next_id(),
+                mode: mode.unwrap_or(self.qos.twin),
+            };
+            self.subscribe(msg.into());
+        }
+    }
+
+    /// The current state of the connection to the hub
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.connection.lock().unwrap().status
+    }
+
+    /// Sets the fallback direct methods handler, invoked for the methods without a handler of their own
+    /// (see `on_method`). A mode of None uses the default methods delivery guarantees,
+    /// or those of the current subscription.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::ModeConflict` if already subscribed with other delivery guarantees,
+    /// which the handlers of the other methods rely on
+    pub fn set_dmi_handler<F>(&mut self, handler: F, mode: Option<DeliveryGuarantees>) -> Result<(), CapabilityError>
+    where
+        F: Fn(DMIRequest) -> DMIResult + Send + Sync + 'static,
+    {
+        self.ensure_methods_subscription(mode)?;
+        self.methods.lock().unwrap().set_fallback(Arc::new(handler));
+        Ok(())
+    }
+
+    /// Sets the handler of a direct method, replacing its previous handler.
+    /// Methods without a handler are passed to the fallback handler (see `set_dmi_handler`); without one,
+    /// they fail with 404, or with 501 if no method has a handler.
+    pub fn on_method<F>(&mut self, method_name: &str, handler: F)
+    where
+        F: Fn(DMIRequest) -> DMIResult + Send + Sync + 'static,
+    {
+        // without a mode, there is nothing to conflict with
+        let _ = self.ensure_methods_subscription(None);
+        self.methods.lock().unwrap().on(method_name, Arc::new(handler));
+    }
+
+    /// Drops the handler of a direct method, which is passed to the fallback handler from now on.
+    /// The client stays subscribed, even without handlers, until `unsub_dmi`.
+    pub fn remove_method(&mut self, method_name: &str) {
+        self.methods.lock().unwrap().remove(method_name);
+    }
+
+    fn ensure_methods_subscription(&mut self, mode: Option<DeliveryGuarantees>) -> Result<(), CapabilityError> {
+        match (self.subscriptions.get(SubscriptionKind::DirectMethods), mode) {
+            (Some(subscription), Some(requested)) if subscription.mode != requested => {
+                Err(CapabilityError::ModeConflict {
+                    subscribed: subscription.mode,
+                    requested,
+                })
+            }
+            (Some(_), _) => Ok(()),
+            (None, mode) => {
+                let msg = DirectMethodsSub {
+                    packet_id: self.packet_id.next_id(),
+                    mode: mode.unwrap_or(self.qos.methods),
+                };
+                self.subscribe(msg.into());
+                Ok(())
+            }
+        }
+    }
+
+    /// A snapshot of the subscriptions requested by this client, which can be persisted and passed to `restore`
+    pub fn subscriptions(&self) -> SubscriptionSnapshot {
+        self.subscriptions.clone()
+    }
+
+    /// Re-subscribes to the topics of a snapshot.
+    /// Messages of restored subscriptions without a handler are handled as if no subscription was made.
+    pub fn restore(&mut self, snapshot: &SubscriptionSnapshot) {
+        for subscription in &snapshot.subscriptions {
+            match subscription.to_msg(self.packet_id.next_id(), &self.id) {
+                Some(msg) => {
+                    if subscription.kind == SubscriptionKind::TwinResponses {
+                        self.subscribed_to_twin = true;
+                    }
+                    self.subscribe(msg);
+                }
+                None => warn!("Cannot restore subscription to {}", subscription.topic_filter),
+            }
+        }
+    }
+
+    fn subscribe(&mut self, msg: MsgToHub) -> MessageFuture {
+        if let Some(subscription) = ActiveSubscription::from_msg(&msg) {
+            self.subscriptions.insert(subscription);
+        }
+        self.tx.send(msg)
+    }
+
+    /// Stops receiving C2D messages, drops the C2D handler and ends the stream returned by `c2d_messages`.
+    /// Completes once acknowledged by the hub.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotADevice` on a module
+    pub fn unsub_c2d(&mut self) -> Result<impl Future<Output = MsgTxResult> + '_, CapabilityError> {
+        if let ClientIdentity::Module(_) = self.id {
+            return Err(CapabilityError::NotADevice);
+        }
+        self.c2d_handler.lock().unwrap().take();
+        self.c2d_messages.lock().unwrap().take();
+        Ok(self.unsubscribe(SubscriptionKind::CloudToDevice))
+    }
+
+    /// Stops receiving the messages routed to the inputs of the module and drops the input handler.
+    /// Completes once acknowledged by the hub.
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotAModule` on a device
+    pub fn unsub_inputs(&mut self) -> Result<impl Future<Output = MsgTxResult> + '_, CapabilityError> {
+        if let ClientIdentity::Device(_) = self.id {
+            return Err(CapabilityError::NotAModule);
+        }
+        self.input_handler.lock().unwrap().take();
+        Ok(self.unsubscribe(SubscriptionKind::ModuleInputs))
+    }
+
+    /// Stops receiving direct method invocations and drops the DMI handlers. Completes once acknowledged by the hub.
+    pub async fn unsub_dmi(&mut self) -> MsgTxResult {
+        self.methods.lock().unwrap().clear();
+        self.unsubscribe(SubscriptionKind::DirectMethods).await
+    }
+
+    /// Stops receiving desired properties updates, e.g. subscribed to by `restore`, drops the twin updates handler
+    /// and ends the stream returned by `desired_property_updates`.
+    /// Completes once acknowledged by the hub.
+    pub async fn unsub_twin_updates(&mut self) -> MsgTxResult {
+        self.twin_updates_handler.lock().unwrap().take();
+        self.desired_updates.lock().unwrap().take();
+        self.unsubscribe(SubscriptionKind::TwinUpdates).await
+    }
+
+    async fn unsubscribe(&mut self, kind: SubscriptionKind) -> MsgTxResult {
+        self.subscriptions.remove(kind);
+        let msg = UnsubMsg::for_kind(self.packet_id.next_id(), kind, &self.id).unwrap();
+        self.tx.send(msg).await
     }
 
     pub fn new(id: ClientIdentity, socket: IotSocket) -> DeviceClient {
+        DeviceClient::with_handler_pool(id, socket, HandlerPoolConfig::default())
+    }
+
+    /// Creates a client whose direct method and C2D handlers run on a pool with the specified configuration
+    pub fn with_handler_pool(id: ClientIdentity, socket: IotSocket, pool: HandlerPoolConfig) -> DeviceClient {
+        let qos = socket.qos_defaults();
+        let capabilities = socket.capabilities();
+        let stats = socket.stats();
+        let audit_sink = socket.shared_audit_sink();
+        let cipher = socket.shared_payload_cipher();
+        let receipts = socket.shared_receipts_tap();
+        #[cfg(feature = "raw-mqtt")]
+        let raw_tap = socket.shared_raw_tap();
         let (tx, mut rx) = socket.split();
         let another_tx = tx.clone();
-        let client = DeviceClient {
+        let mut client = DeviceClient {
             tx,
             id,
+            qos,
+            capabilities,
             packet_id: PacketsNumerator::new(),
             subscribed_to_twin: false,
+            subscriptions: SubscriptionSnapshot::new(),
             awaiting_response: Arc::new(Mutex::new(HashMap::new())),
-            dmi_handler: Arc::new(Mutex::new(None)),
+            methods: Arc::new(Mutex::new(MethodRouter::default())),
+            dmi_response_window: Arc::new(Mutex::new(DEFAULT_DMI_RESPONSE_WINDOW)),
             c2d_handler: Arc::new(Mutex::new(None)),
+            c2d_expired_handler: Arc::new(Mutex::new(None)),
+            c2d_completion: Arc::new(Mutex::new(C2DCompletionPolicy::default())),
+            input_handler: Arc::new(Mutex::new(None)),
+            disconnect_handler: Arc::new(Mutex::new(None)),
+            desired_updates: Arc::new(Mutex::new(None)),
+            twin_updates_handler: Arc::new(Mutex::new(None)),
+            c2d_messages: Arc::new(Mutex::new(None)),
+            receipts,
+            connection: Arc::new(Mutex::new(ConnectionState {
+                status: ConnectionStatus::Connected,
+                handler: None,
+                events: None,
+            })),
+            diagnostics: DiagnosticSampler::new(0),
+            enrichers: Vec::new(),
+            interceptors: Arc::new(Mutex::new(Vec::new())),
+            dedup: Arc::new(Mutex::new(None)),
+            sequencer: None,
+            stats,
+            audit_sink,
+            cipher,
+            #[cfg(feature = "raw-mqtt")]
+            raw_tap,
+            serializer: Arc::new(Mutex::new(None)),
+            request_ids: Arc::new(Mutex::new(RequestIdSource::default())),
+            created: Instant::now(),
+            threads: Vec::new(),
+            heartbeat_stop: None,
+            disconnected: false,
         };
 
         let awaiting_response2 = client.awaiting_response.clone();
-        let dmi_handler = client.dmi_handler.clone();
+        let methods = client.methods.clone();
+        let dmi_response_window = client.dmi_response_window.clone();
         let c2d_handler = client.c2d_handler.clone();
+        let c2d_expired_handler = client.c2d_expired_handler.clone();
+        let c2d_completion = client.c2d_completion.clone();
+        let input_handler = client.input_handler.clone();
+        let disconnect_handler = client.disconnect_handler.clone();
+        let desired_updates = client.desired_updates.clone();
+        let twin_updates_handler = client.twin_updates_handler.clone();
+        let c2d_messages = client.c2d_messages.clone();
+        let receipts = client.receipts.clone();
+        let connection = client.connection.clone();
+        let serializer = client.serializer.clone();
+        let interceptors = client.interceptors.clone();
+        let dedup = client.dedup.clone();
+        let audit_sink = client.audit_sink.clone();
+        let stats = client.stats.clone();
+        let pool = WorkerPool::new(pool);
+        let dmi_deadlines: Arc<Mutex<HashMap<String, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
 
-        thread::spawn(move || loop {
-            let msg = rx.recv();
+        // answers the invocations whose handlers exceeded the response window, until the receive loop exits
+        let deadlines = Arc::downgrade(&dmi_deadlines);
+        let mut watchdog_tx = another_tx.clone();
+        let watchdog = thread::spawn(move || loop {
+            thread::sleep(DMI_WATCHDOG_INTERVAL);
+            let deadlines = match deadlines.upgrade() {
+                Some(deadlines) => deadlines,
+                None => break,
+            };
+            let now = Instant::now();
+            let mut expired = Vec::new();
+            deadlines.lock().unwrap().retain(|request_id, deadline| {
+                if *deadline > now {
+                    return true;
+                }
+                expired.push(request_id.clone());
+                false
+            });
+            for request_id in expired {
+                warn!("Direct method handler exceeded the response window");
+                watchdog_tx.send(DirectMethodRes {
+                    packet_id: None,
+                    status: DMI_TIMEOUT_STATUS,
+                    request_id,
+                    payload: None,
+                    serializer: None,
+                });
+            }
+        });
+
+        let receiver = thread::spawn(move || loop {
+            let mut msg = match rx.recv() {
+                // the socket exited
+                None => break,
+                Some(SocketEvent::Message(msg)) => msg,
+                Some(SocketEvent::StateChanged(event)) => {
+                    connection.lock().unwrap().apply(event);
+                    continue;
+                }
+                Some(SocketEvent::Disconnected { reason }) => {
+                    connection.lock().unwrap().apply(ConnectionEvent::Disconnected { reason });
+                    // the responses to pending requests will never arrive
+                    for (_, request) in awaiting_response2.lock().unwrap().drain() {
+                        request.lock().unwrap().complete(Err(SendError::ConnectionLost));
+                    }
+                    // ends the streams of desired properties updates, C2D messages and delivery receipts
+                    desired_updates.lock().unwrap().take();
+                    c2d_messages.lock().unwrap().take();
+                    receipts.lock().unwrap().take();
+                    if let Some(handler) = disconnect_handler.lock().unwrap().as_ref() {
+                        handler(reason);
+                    }
+                    // the socket thread exits once the client disconnected
+                    if reason == DisconnectReason::Requested {
+                        break;
+                    }
+                    continue;
+                }
+            };
             // debug!("READ LOOP got: {:?}", msg);
+            if !interceptors
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .all(|interceptor| interceptor(&mut msg))
+            {
+                debug!("Message dropped by an interceptor");
+                audit_inbound(audit_sink.lock().unwrap().as_deref(), &msg, AuditOutcome::Dropped);
+                continue;
+            }
+            if let Some(dedup) = dedup.lock().unwrap().as_mut() {
+                if dedup.is_duplicate(&msg, Instant::now()) {
+                    debug!("Dropping a message delivered again");
+                    audit_inbound(audit_sink.lock().unwrap().as_deref(), &msg, AuditOutcome::Dropped);
+                    // acknowledged, so that the hub stops delivering it
+                    let packet_id = match msg {
+                        MsgFromHub::CloudToDeviceMessage(c2d) => c2d.packet_id,
+                        MsgFromHub::ModuleInputMessage(input) => input.packet_id,
+                        _ => None,
+                    };
+                    if let Some(packet_id) = packet_id {
+                        another_tx.clone().send(AckMsg { packet_id });
+                    }
+                    continue;
+                }
+            }
             match msg {
                 MsgFromHub::TwinResponseMessage(resp) => {
                     if let Some(x) = awaiting_response2.lock().unwrap().remove(&resp.request_id) {
-                        let mut y = x.lock().unwrap();
-                        y.result = Some(Ok(resp.into()));
-                        if let Some(waker) = y.waker.take() {
-                            waker.wake();
-                        }
+                        x.lock().unwrap().complete(Ok(resp.into()));
+                    }
+                }
+                MsgFromHub::ReportedPropertiesUpdated(resp) => {
+                    if let Some(x) = awaiting_response2.lock().unwrap().remove(&resp.request_id) {
+                        x.lock().unwrap().complete(Ok(MsgFromHub::ReportedPropertiesUpdated(resp)));
                     }
                 }
                 MsgFromHub::DirectMethodInvocation(dmi) => {
-                    let handler_guard = dmi_handler.lock().unwrap();
+                    let deadline = Instant::now() + *dmi_response_window.lock().unwrap();
+                    let route = methods.lock().unwrap().route(&dmi.method_name);
                     let mut tx2 = another_tx.clone();
-                    if let Some(handler) = *handler_guard {
-                        thread::spawn(move || {
-                            let dmi_result = handler(DMIRequest {
-                                method_name: dmi.method_name,
-                                body: dmi.body,
+                    let handler = match route {
+                        Ok(handler) => handler,
+                        Err(status) => {
+                            debug!("Got DMI {} but no handler, answering {}", dmi.method_name, status);
+                            tx2.send(DirectMethodRes {
+                                packet_id: None,
+                                status,
+                                request_id: dmi.request_id,
+                                payload: None,
+                                serializer: None,
                             });
+                            continue;
+                        }
+                    };
+                    let serializer = serializer.lock().unwrap().clone();
+                    let request_id = dmi.request_id.clone();
+                    dmi_deadlines.lock().unwrap().insert(request_id.clone(), deadline);
+                    let deadlines = dmi_deadlines.clone();
+                    let stats = stats.clone();
+                    let accepted = pool.execute(move || {
+                        let request = DMIRequest {
+                            method_name: dmi.method_name,
+                            body: dmi.body,
+                            deadline,
+                        };
+                        let started = Instant::now();
+                        let dmi_result = handler(request);
+                        stats.record_method_duration(started.elapsed());
+                        // a late result is dropped, the watchdog already answered
+                        if deadlines.lock().unwrap().remove(&dmi.request_id).is_some() {
                             tx2.send(DirectMethodRes {
                                 packet_id: None,
                                 status: dmi_result.status,
                                 request_id: dmi.request_id,
                                 payload: dmi_result.payload,
-                            })
-                        });
-                    } else {
-                        debug!("Got DMI but no handler!");
-                        tx2.send(DirectMethodRes {
+                                serializer,
+                            });
+                        }
+                    });
+                    if accepted.is_err() && dmi_deadlines.lock().unwrap().remove(&request_id).is_some() {
+                        warn!("Handler pool is full, rejecting a direct method invocation");
+                        another_tx.clone().send(DirectMethodRes {
                             packet_id: None,
-                            status: 501,
-                            request_id: dmi.request_id,
+                            status: DMI_BUSY_STATUS,
+                            request_id,
                             payload: None,
+                            serializer: None,
                         });
                     }
                 }
                 MsgFromHub::CloudToDeviceMessage(c2d) => {
-                    let handler_guard = c2d_handler.lock().unwrap();
-                    let mut tx2 = another_tx.clone();
-                    if let Some(handler) = *handler_guard {
-                        thread::spawn(move || {
-                            let c2d_result = handler(C2DMsg {
+                    if let Some(handler) = c2d_expired_handler.lock().unwrap().as_ref() {
+                        if c2d.is_expired(SystemTime::now()) {
+                            debug!("Dropping expired C2D msg");
+                            audit_inbound(
+                                audit_sink.lock().unwrap().as_deref(),
+                                &c2d.clone().into(),
+                                AuditOutcome::Dropped,
+                            );
+                            handler(C2DMsg {
                                 props: c2d.props,
                                 body: c2d.body,
                             });
-                            if let Some(packet_id) = c2d.packet_id {
-                                tx2.send(AckMsg { packet_id });
+                            continue;
+                        }
+                    }
+                    // the stream takes precedence over the handler, until dropped
+                    let c2d = {
+                        let mut c2d_messages = c2d_messages.lock().unwrap();
+                        let delivered = match c2d_messages.as_ref() {
+                            Some(tx) => {
+                                let msg = C2DMsg {
+                                    props: c2d.props.clone(),
+                                    body: c2d.body.clone(),
+                                };
+                                tx.unbounded_send(C2DDelivery::new(msg, c2d.packet_id, another_tx.clone()))
+                                    .is_ok()
+                            }
+                            None => false,
+                        };
+                        if delivered {
+                            continue;
+                        }
+                        c2d_messages.take();
+                        c2d
+                    };
+                    let handler = c2d_handler.lock().unwrap().clone();
+                    let mut tx2 = another_tx.clone();
+                    if let Some(handler) = handler {
+                        let completion = *c2d_completion.lock().unwrap();
+                        let accepted = pool.execute(move || {
+                            let packet_id = c2d.packet_id;
+                            let outcome = C2DHandlerOutcome::run(|| {
+                                handler(C2DMsg {
+                                    props: c2d.props,
+                                    body: c2d.body,
+                                })
+                            });
+                            match outcome {
+                                C2DHandlerOutcome::Succeeded => {}
+                                C2DHandlerOutcome::Failed(ref e) => warn!("C2D handler failed: {}", e),
+                                C2DHandlerOutcome::Panicked => warn!("C2D handler panicked"),
+                            }
+                            match completion.completion(&outcome, packet_id) {
+                                Some(ack) => {
+                                    tx2.send(ack);
+                                }
+                                None if packet_id.is_some() => {
+                                    debug!("Abandoning a C2D message, the hub will deliver it again")
+                                }
+                                None => {}
                             }
                         });
+                        if accepted.is_err() {
+                            warn!("Handler pool is full, not acknowledging a C2D message");
+                        }
                     } else {
                         debug!("Got C2D msg but no handler!");
                     }
                 }
+                MsgFromHub::ModuleInputMessage(input) => {
+                    let handler = input_handler.lock().unwrap().clone();
+                    let mut tx2 = another_tx.clone();
+                    if let Some(handler) = handler {
+                        let completion = *c2d_completion.lock().unwrap();
+                        let accepted = pool.execute(move || {
+                            let packet_id = input.packet_id;
+                            let outcome = C2DHandlerOutcome::run(|| {
+                                handler(InputMsg {
+                                    input_name: input.input_name,
+                                    props: input.props,
+                                    body: input.body,
+                                })
+                            });
+                            match outcome {
+                                C2DHandlerOutcome::Succeeded => {}
+                                C2DHandlerOutcome::Failed(ref e) => warn!("Input handler failed: {}", e),
+                                C2DHandlerOutcome::Panicked => warn!("Input handler panicked"),
+                            }
+                            if let Some(ack) = completion.completion(&outcome, packet_id) {
+                                tx2.send(ack);
+                            }
+                        });
+                        if accepted.is_err() {
+                            warn!("Handler pool is full, not acknowledging a module input message");
+                        }
+                    } else {
+                        debug!("Got module input msg but no handler!");
+                    }
+                }
+                MsgFromHub::DesiredPropertiesUpdated(update) => {
+                    // the stream takes precedence over the handler
+                    let mut desired_updates = desired_updates.lock().unwrap();
+                    let unsent = match desired_updates.as_ref().map(|tx| tx.unbounded_send(update)) {
+                        Some(Ok(())) => None,
+                        Some(Err(e)) => {
+                            // the stream was dropped
+                            drop(desired_updates.take());
+                            Some(e.into_inner())
+                        }
+                        None => None,
+                    };
+                    drop(desired_updates);
+                    match (unsent, twin_updates_handler.lock().unwrap().as_ref()) {
+                        (Some(update), Some(handler)) => handler(update),
+                        (Some(_), None) => debug!("Got desired properties update but no stream or handler!"),
+                        (None, _) => {}
+                    }
+                }
+                MsgFromHub::MisroutedMessage(msg) => {
+                    warn!("Ignoring a message addressed to another client: {}", msg.topic);
+                }
                 _ => {}
             }
         });
+        client.threads = vec![receiver, watchdog];
 
         client
     }
 
+    /// Sets the time the hub waits for direct method responses (the invocation's responseTimeoutInSeconds).
+    /// Handlers exceeding it are answered with a timeout status.
+    pub fn set_dmi_response_window(&mut self, window: Duration) {
+        *self.dmi_response_window.lock().unwrap() = window;
+    }
+
+    /// The twin requests awaiting their response and the messages awaiting their acknowledgement, oldest first
+    pub fn pending_operations(&self) -> Vec<PendingOperation> {
+        let mut operations: Vec<PendingOperation> = self
+            .awaiting_response
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(request_id, request)| PendingOperation {
+                id: OperationId::Request(request_id.clone()),
+                age: request.lock().unwrap().submitted.elapsed(),
+            })
+            .collect();
+        operations.extend(self.tx.pending_operations());
+        operations.sort_by_key(|operation| std::cmp::Reverse(operation.age));
+        operations
+    }
+
+    /// Fails a pending operation with `SendError::Cancelled`, e.g. one stuck for longer than the application can wait.
+    /// A cancelled message releases its packet ID; a response or acknowledgement arriving later is ignored.
+    /// Returns FALSE if no such operation is pending.
+    pub fn cancel(&mut self, id: &OperationId) -> bool {
+        match id {
+            OperationId::Request(request_id) => match self.awaiting_response.lock().unwrap().remove(request_id) {
+                Some(request) => {
+                    request.lock().unwrap().complete(Err(SendError::Cancelled));
+                    true
+                }
+                None => false,
+            },
+            OperationId::Packet(packet_id) => self.tx.cancel(*packet_id),
+        }
+    }
+
+    /// What the hub agreed to when the underlying socket connected
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Ends the connection gracefully: every message sent so far is written, followed by an MQTT DISCONNECT,
+    /// then the stream is closed and the socket thread exits. Messages still awaiting an acknowledgement fail with
+    /// `SendError::Disconnected`, and the disconnection handler is invoked with `DisconnectReason::Requested`.
+    ///
+    /// # Errors
+    /// Fails with `SendError::ConnectionLost` if the connection was already lost
+    pub async fn disconnect(mut self) -> Result<(), SendError> {
+        self.disconnected = true;
+        self.tx.send(MsgToHub::Disconnect).await.map(|_| ())
+    }
+
+    /// Shuts the client down gracefully: waits up to the flush timeout for the messages awaiting an acknowledgement
+    /// and the twin requests awaiting their response, disconnects (see `disconnect`), then waits for the threads
+    /// of the client to exit. Handlers still running on the handler pool complete on their own.
+    ///
+    /// # Errors
+    /// Fails with `SendError::ConnectionLost` if the connection was already lost
+    pub async fn shutdown(mut self, flush_timeout: Duration) -> Result<(), SendError> {
+        let settled = Settled {
+            awaiting_response: &self.awaiting_response,
+            tx: &self.tx,
+        };
+        if async_std::future::timeout(flush_timeout, settled)
+            .await
+            .is_err()
+        {
+            debug!("Operations still pending after the flush timeout");
+        }
+        let threads = std::mem::take(&mut self.threads);
+        self.heartbeat_stop = None;
+        self.disconnect().await?;
+        // the receive loop exits once the DISCONNECT was written, the watchdog right after it
+        for thread in threads {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+
+    /// The send/receive statistics of the underlying session
+    pub fn stats(&self) -> Arc<SessionStats> {
+        self.stats.clone()
+    }
+
+    /// Sets a sink recording every telemetry, twin, direct method and C2D message sent or received
+    pub fn set_audit_sink(&mut self, sink: Arc<dyn AuditSink>) {
+        *self.audit_sink.lock().unwrap() = Some(sink);
+    }
+
+    /// Sets a cipher encrypting outgoing telemetry payloads and decrypting incoming C2D payloads which advertise a key
+    pub fn set_payload_cipher(&mut self, cipher: Arc<dyn PayloadCipher>) {
+        *self.cipher.lock().unwrap() = Some(cipher);
+    }
+
+    /// Sends an MQTT packet as is, for hub features not covered by the typed API.
+    /// QoS 1 publications and subscriptions complete once acknowledged by the hub.
+    #[cfg(feature = "raw-mqtt")]
+    pub async fn send_raw_packet(&mut self, packet: VariablePacket) -> MsgTxResult {
+        self.tx.send(MsgToHub::Raw(packet)).await
+    }
+
+    /// Returns a stream of every MQTT packet read from the hub, before it is decoded.
+    /// Replaces the stream returned by an earlier call.
+    #[cfg(feature = "raw-mqtt")]
+    pub fn raw_packets(&self) -> std::sync::mpsc::Receiver<VariablePacket> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        *self.raw_tap.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    /// Suppresses C2D messages and direct method invocations delivered again, e.g. across reconnects
+    pub fn enable_deduplication(&mut self, config: DedupConfig) {
+        *self.dedup.lock().unwrap() = Some(MessageDeduplicator::new(config));
+    }
+
+    /// Sets the serializer of telemetry and direct method response payloads. Defaults to JSON.
+    pub fn set_payload_serializer(&mut self, serializer: Arc<dyn PayloadSerializer>) {
+        *self.serializer.lock().unwrap() = Some(serializer);
+    }
+
+    /// Sets the strategy used to generate twin request identifiers. Defaults to UUIDs.
+    pub fn set_request_id_source(&mut self, source: RequestIdSource) {
+        *self.request_ids.lock().unwrap() = source;
+    }
+
+    /// Sets the percentage of telemetry messages sampled for distributed tracing
+    pub fn set_diagnostic_sampling_percentage(&mut self, percentage: u8) {
+        self.diagnostics = DiagnosticSampler::new(percentage);
+    }
+
+    /// Registers an interceptor applied, in registration order, to every incoming message
+    pub fn add_inbound_interceptor(&mut self, interceptor: Box<InboundInterceptor>) {
+        self.interceptors.lock().unwrap().push(interceptor);
+    }
+
+    /// Registers a hook applied, in registration order, to every outgoing telemetry message
+    pub fn add_telemetry_enricher(&mut self, enricher: Box<TelemetryEnricher>) {
+        self.enrichers.push(enricher);
+    }
+
+    /// Makes packet and request IDs deterministic, so that tests comparing encoded packets are stable:
+    /// packet IDs follow `packet_id`, and request IDs count up from `request_id`
+    #[cfg(any(test, feature = "test-hooks"))]
+    pub fn seed_ids(&mut self, packet_id: u16, request_id: u64) {
+        self.packet_id = PacketsNumerator::starting_after(packet_id);
+        *self.request_ids.lock().unwrap() = RequestIdSource::Counter(request_id);
+    }
+
+    /// Stamps a monotonic sequence number into every outgoing telemetry message
+    pub fn enable_sequence_numbers(&mut self) {
+        if self.sequencer.is_none() {
+            self.sequencer = Some(TelemetrySequencer::new());
+        }
+    }
+
+    /// Periodically reports a heartbeat block into the reported properties, until the connection is lost
+    /// or the client is shut down or dropped. Subscribes to the twin responses first, so that the hub answers
+    /// the heartbeats. Heartbeats are sent with at-most-once delivery guarantees, and replace a heartbeat
+    /// enabled earlier.
+    ///
+    /// # Errors
+    /// Fails if the subscription to the twin responses could not be sent
+    pub async fn enable_heartbeat(&mut self, config: HeartbeatConfig) -> Result<(), SendError> {
+        self.subscribe_to_twin_responses().await?;
+
+        let (stop_tx, stop_rx) = channel::<()>();
+        let mut tx = self.tx.clone();
+        let stats = self.stats.clone();
+        let request_ids = self.request_ids.clone();
+        let created = self.created;
+        let heartbeat = thread::spawn(move || {
+            // nothing is ever sent on the channel: it disconnects once the client drops its end
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(config.interval) {
+                let counters = HeartbeatCounters {
+                    messages_sent: stats.messages_sent(),
+                    messages_received: stats.messages_received(),
+                    messages_dropped: stats.messages_dropped(),
+                    token_renewals: stats.token_renewals(),
+                };
+                let msg = UpdateReportedPropsReq {
+                    request_id: request_ids.lock().unwrap().next_id(),
+                    reported: config.patch(created.elapsed(), counters, SystemTime::now()),
+                    packet_id: None,
+                    compression: None,
+                };
+                match futures::executor::block_on(tx.send(msg)) {
+                    Err(SendError::ConnectionLost) => break,
+                    Err(e) => warn!("Failed sending a heartbeat: {}", e),
+                    Ok(_) => trace!("Heartbeat sent"),
+                }
+            }
+            debug!("Heartbeats stopped");
+        });
+        self.heartbeat_stop = Some(stop_tx);
+        self.threads.push(heartbeat);
+        Ok(())
+    }
+
+    /// The highest sequence number acknowledged by the hub (QoS1 messages only), if sequence numbers are enabled
+    pub fn last_acknowledged_sequence(&self) -> Option<u64> {
+        self.sequencer.as_ref().and_then(|s| s.last_acknowledged())
+    }
+
+    /// Sends a telemetry message using the default telemetry delivery guarantees
     pub async fn send_telemetry(&mut self, msg: D2CMsg) -> MsgTxResult {
-        let msg = TelemetryMsg {
+        let mode = self.qos.telemetry;
+        self.send_telemetry_with_qos(msg, mode).await
+    }
+
+    /// Sends a message to an output of the module, for the hub to route it, using the default telemetry
+    /// delivery guarantees. Other than its output name, the message is sent like telemetry.
+    pub async fn send_output(&mut self, output_name: &str, mut msg: D2CMsg) -> MsgTxResult {
+        let _ = msg
+            .headers
+            .get_or_insert_with(HashMap::new)
+            .insert(OUTPUT_NAME_PROPERTY.to_owned(), output_name.to_owned());
+        self.send_telemetry(msg).await
+    }
+
+    /// Sends a message to an output of the module like `send_output`, with hints for the routes of edgeHub
+    pub async fn send_output_with_hints(
+        &mut self,
+        output_name: &str,
+        mut msg: D2CMsg,
+        hints: &EdgeRouteHints,
+    ) -> MsgTxResult {
+        hints.apply(msg.headers.get_or_insert_with(HashMap::new), SystemTime::now());
+        self.send_output(output_name, msg).await
+    }
+
+    /// Sends a telemetry message using the specified delivery guarantees
+    pub async fn send_telemetry_with_qos(&mut self, msg: D2CMsg, mode: DeliveryGuarantees) -> MsgTxResult {
+        let priority = msg.priority;
+        let (mut msg, sequence_number) = self.prepare_telemetry(msg);
+        self.assign_packet_id(&mut msg, mode);
+
+        let result = self.tx.send_with_priority(msg, priority).await;
+        self.acknowledge_sequence(&result, sequence_number);
+        result
+    }
+
+    /// Sends the pending entries of the outbox in order, one at a time, removing each from the outbox once the hub
+    /// acknowledged it. Entries are delivered at least once, even if the default telemetry guarantees are weaker.
+    /// Returns the number of entries delivered once the outbox is empty.
+    ///
+    /// # Errors
+    /// Fails on the first entry the hub did not acknowledge, which stays in the outbox along with the ones after it,
+    /// or if the outbox failed
+    pub async fn drain_outbox(&mut self, outbox: &mut dyn Outbox) -> Result<usize, OutboxError> {
+        let mode = match self.qos.telemetry {
+            DeliveryGuarantees::AtMostOnce => DeliveryGuarantees::AtLeastOnce,
+            mode => mode,
+        };
+        let mut delivered = 0;
+        loop {
+            let entries = outbox.pending(OutboxDrainer::DEFAULT_BATCH_SIZE)?;
+            if entries.is_empty() {
+                return Ok(delivered);
+            }
+            for entry in entries {
+                let msg = D2CMsg {
+                    content: entry.content.clone(),
+                    headers: Some(entry.message_headers()),
+                    priority: Priority::Normal,
+                };
+                self.send_telemetry_with_qos(msg, mode)
+                    .await
+                    .map_err(|e| OutboxError::Undelivered(format!("{:?}", e)))?;
+                outbox.remove(&entry.id)?;
+                delivered += 1;
+            }
+        }
+    }
+
+    /// Sends a telemetry message, split into correlated chunks of at most `max_chunk_size` payload bytes
+    /// if it is larger (see `split_telemetry`). Chunks are sent one at a time; the first failure aborts the transfer.
+    /// Returns the delivery of the last chunk.
+    pub async fn send_telemetry_chunked(
+        &mut self,
+        msg: D2CMsg,
+        mode: DeliveryGuarantees,
+        max_chunk_size: usize,
+    ) -> MsgTxResult {
+        let priority = msg.priority;
+        let (msg, sequence_number) = self.prepare_telemetry(msg);
+        let transfer_id = self.request_ids.lock().unwrap().next_id();
+        let mut delivered = None;
+        for mut chunk in split_telemetry(msg, max_chunk_size, &transfer_id) {
+            self.assign_packet_id(&mut chunk, mode);
+            delivered = Some(self.tx.send_with_priority(chunk, priority).await?);
+        }
+        // split_telemetry returns at least one chunk, even for a message without payload
+        let result = delivered.ok_or(SendError::SendFailed);
+        self.acknowledge_sequence(&result, sequence_number);
+        result
+    }
+
+    // Returns the message without a packet ID, and its sequence number if sequence numbers are enabled
+    fn prepare_telemetry(&mut self, msg: D2CMsg) -> (TelemetryMsg, Option<u64>) {
+        let mut headers = msg.headers;
+        self.diagnostics.stamp(&mut headers);
+        let sequence_number = self.sequencer.as_mut().map(|s| s.stamp(&mut headers));
+        let mut msg = TelemetryMsg {
             client_id: self.id.clone(),
             content: msg.content,
-            headers: msg.headers,
-            packet_id: Some(self.packet_id.next()),
+            headers,
+            packet_id: None,
+            exactly_once: false,
+            serializer: self.serializer.lock().unwrap().clone(),
+        };
+        for enricher in &self.enrichers {
+            enricher(&mut msg);
+        }
+        (msg, sequence_number)
+    }
+
+    fn assign_packet_id(&mut self, msg: &mut TelemetryMsg, mode: DeliveryGuarantees) {
+        msg.packet_id = match mode {
+            DeliveryGuarantees::AtMostOnce => None,
+            DeliveryGuarantees::AtLeastOnce | DeliveryGuarantees::ExactlyOnce => {
+                Some(self.packet_id.next_id())
+            }
         };
+        msg.exactly_once = mode == DeliveryGuarantees::ExactlyOnce;
+    }
 
-        self.tx.send(msg).await
+    /// Advances the last acknowledged sequence number for messages the hub acknowledged (QoS1), not merely written
+    fn acknowledge_sequence(&mut self, result: &MsgTxResult, sequence_number: Option<u64>) {
+        if let (
+            Ok(DeliveryInfo {
+                acknowledged: true, ..
+            }),
+            Some(sequence_number),
+        ) = (result, sequence_number)
+        {
+            if let Some(sequencer) = self.sequencer.as_mut() {
+                sequencer.acknowledge(sequence_number);
+            }
+        }
+    }
+
+    /// Reads the twin: the device twin, or the module twin when connected as a module
+    ///
+    /// # Errors
+    /// Fails if the request could not be sent, or with `SendError::ConnectionLost` if the connection was lost before the response arrived
+    pub async fn read_twin(&mut self) -> Result<ReadTwinRes, SendError> {
+        self.read_twin_section(TwinSection::Full).await
+    }
+
+    /// Reads the twin, keeping only the specified section of the response body
+    ///
+    /// # Errors
+    /// Fails like `read_twin`
+    pub async fn read_twin_section(&mut self, section: TwinSection) -> Result<ReadTwinRes, SendError> {
+        self.subscribe_to_twin_responses().await?;
+
+        let request_id = self.request_ids.lock().unwrap().next_id();
+        let read_msg = ReadTwinReq {
+            request_id: request_id.clone(),
+            packet_id: self.twin_packet_id(),
+            section,
+        };
+
+        let fut = TwinFuture {
+            state: self.await_response(&request_id),
+        };
+        if let Err(e) = self.tx.send(read_msg).await {
+            let _ = self.awaiting_response.lock().unwrap().remove(&request_id);
+            return Err(e);
+        }
+
+        let mut res = fut.await?;
+        res.retain_section(section);
+        Ok(res)
     }
 
-    pub async fn read_twin(&mut self) -> ReadTwinRes {
+    /// Updates the reported properties of the twin with a patch. Resolves with the new version of the reported
+    /// properties, as returned by the hub.
+    ///
+    /// # Errors
+    /// Fails with `ReportedPropsError::Send` like `read_twin`, or with `ReportedPropsError::Rejected` if the hub
+    /// answered with a failure status
+    pub async fn update_reported_properties(
+        &mut self,
+        patch: Map<String, Value>,
+    ) -> Result<Option<u64>, ReportedPropsError> {
+        self.subscribe_to_twin_responses().await?;
+
+        let request_id = self.request_ids.lock().unwrap().next_id();
+        let update_msg = UpdateReportedPropsReq {
+            request_id: request_id.clone(),
+            reported: patch,
+            packet_id: self.twin_packet_id(),
+            compression: None,
+        };
+
+        let fut = ReportedPropsFuture {
+            state: self.await_response(&request_id),
+        };
+        if let Err(e) = self.tx.send(update_msg).await {
+            let _ = self.awaiting_response.lock().unwrap().remove(&request_id);
+            return Err(e.into());
+        }
+
+        let res = fut.await?;
+        match res.status_code {
+            StatusCode::OK() | StatusCode::NoContent() => Ok(res.version),
+            status => Err(ReportedPropsError::Rejected(status)),
+        }
+    }
+
+    /// Subscribes to the responses to twin requests, unless already subscribed
+    async fn subscribe_to_twin_responses(&mut self) -> Result<(), SendError> {
         if !self.subscribed_to_twin {
             let sub_msg = TwinReadSub {
-                packet_id: self.packet_id.next(),
-                mode: DeliveryGuarantees::AtLeastOnce,
+                packet_id: self.packet_id.next_id(),
+                mode: self.qos.twin,
             };
 
-            self.tx.send(sub_msg).await.unwrap();
+            self.subscribe(sub_msg.into()).await?;
             self.subscribed_to_twin = true;
             debug!("Subscribed to twin!");
         }
+        Ok(())
+    }
 
-        let request_id = Uuid::new_v4().to_string();
-        let read_msg = ReadTwinReq {
-            request_id: request_id.clone(),
-            packet_id: Some(self.packet_id.next()),
-        };
+    fn twin_packet_id(&mut self) -> Option<PacketId> {
+        // only telemetry is sent with QoS2
+        match self.qos.twin {
+            DeliveryGuarantees::AtMostOnce => None,
+            DeliveryGuarantees::AtLeastOnce | DeliveryGuarantees::ExactlyOnce => {
+                Some(self.packet_id.next_id())
+            }
+        }
+    }
 
-        let fut: TwinFuture;
-        {
-            let mut col = self.awaiting_response.lock().unwrap();
-            let request_state = Arc::new(Mutex::new(RequestState {
-                result: None,
-                waker: None,
-            }));
-            fut = TwinFuture {
-                state: request_state.clone(),
-            };
-            col.insert(request_id, request_state);
+    /// Registers a twin request awaiting its response
+    fn await_response(&self, request_id: &str) -> Arc<Mutex<RequestState>> {
+        let request_state = Arc::new(Mutex::new(RequestState {
+            submitted: Instant::now(),
+            result: None,
+            waker: None,
+            watcher: None,
+        }));
+        let mut col = self.awaiting_response.lock().unwrap();
+        col.insert(request_id.to_owned(), request_state.clone());
+        request_state
+    }
+}
+
+impl Drop for DeviceClient {
+    /// A client dropped without `disconnect` or `shutdown` disconnects gracefully on its own,
+    /// once the messages sent so far were written
+    fn drop(&mut self) {
+        if !self.disconnected {
+            // queued right away, nothing awaits it being written
+            drop(self.tx.send(MsgToHub::Disconnect));
         }
+    }
+}
 
-        self.tx.send(read_msg).await.unwrap();
+#[cfg(all(test, not(feature = "tokio-transport")))]
+mod tests {
+    use super::*;
+    use crate::iot_socket::ReceiveQueueConfig;
+    use crate::testing::{device_settings, mock_hubs, RunningHub};
+    use futures::executor::block_on;
+    use futures::FutureExt;
+    use raiot_streams::CancelToken;
+    use raiot_test_utils::hub::PubackAction;
 
-        fut.await
+    fn connect() -> (DeviceClient, Vec<RunningHub>) {
+        let (connector, hubs) = mock_hubs(1);
+        let settings = device_settings();
+        let id = settings.client_id.clone();
+        let socket = IotSocket::connect_with(
+            connector,
+            settings,
+            ReceiveQueueConfig::default(),
+            &CancelToken::new(),
+        )
+        .unwrap();
+        (DeviceClient::new(id, socket), hubs)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_c2d_messages_are_subscribed_to_once() {
+        let (mut client, hubs) = connect();
+        let _messages = client.c2d_messages().unwrap();
+        client.set_c2d_handler(|_| Ok(()), None).unwrap();
+        client.set_c2d_handler(|_| Ok(()), None).unwrap();
+
+        // acknowledged once the hub handled the subscriptions written before it
+        let msg = D2CMsg::new(serde_json::json!({ "temperature": 21 }));
+        block_on(client.send_telemetry_with_qos(msg, DeliveryGuarantees::AtLeastOnce)).unwrap();
+        hubs[0].with(|hub| {
+            assert_eq!(
+                hub.subscriptions(),
+                ["devices/device1/messages/devicebound/#"]
+            )
+        });
+    }
+
+    #[test]
+    fn test_only_acknowledged_telemetry_advances_the_sequence() {
+        let (mut client, _hubs) = connect();
+        client.enable_sequence_numbers();
+        let msg = || D2CMsg::new(serde_json::json!({ "temperature": 21 }));
+
+        block_on(client.send_telemetry_with_qos(msg(), DeliveryGuarantees::AtLeastOnce)).unwrap();
+        assert_eq!(client.last_acknowledged_sequence(), Some(1));
+        // merely written to the stream
+        block_on(client.send_telemetry_with_qos(msg(), DeliveryGuarantees::AtMostOnce)).unwrap();
+        assert_eq!(client.last_acknowledged_sequence(), Some(1));
+    }
+
+    fn telemetry() -> D2CMsg {
+        D2CMsg::new(serde_json::json!({ "temperature": 21 }))
+    }
+
+    #[test]
+    fn test_shutdown_returns_once_messages_are_acknowledged() {
+        let (mut client, _hubs) = connect();
+        // queued, the future dropped before the acknowledgement arrives
+        let sent = client.send_telemetry_with_qos(telemetry(), DeliveryGuarantees::AtLeastOnce);
+        assert!(sent.now_or_never().is_none());
+
+        let started = Instant::now();
+        block_on(client.shutdown(Duration::from_secs(5))).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_acknowledgements_until_the_flush_timeout() {
+        let (mut client, hubs) = connect();
+        hubs[0].with(|hub| hub.push_puback_action(PubackAction::Withhold));
+        let sent = client.send_telemetry_with_qos(telemetry(), DeliveryGuarantees::AtLeastOnce);
+        assert!(sent.now_or_never().is_none());
+
+        let started = Instant::now();
+        block_on(client.shutdown(Duration::from_millis(200))).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_dropped_client_disconnects() {
+        let (mut client, _hubs) = connect();
+        let (tx, rx) = channel();
+        client.set_disconnect_handler(move |reason| {
+            let _ = tx.send(reason);
+        });
+
+        drop(client);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Ok(DisconnectReason::Requested)
+        );
+    }
+}
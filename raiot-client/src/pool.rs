@@ -0,0 +1,88 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// What happens to a message whose handler cannot be queued because all workers are busy and the queue is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Stop reading messages from the hub until a worker frees up
+    Block,
+
+    /// Reject the message: direct methods are answered with a busy status, C2D messages are not acknowledged
+    Reject,
+}
+
+/// The threads running the direct method and C2D handlers of a `DeviceClient`
+#[derive(Copy, Clone, Debug)]
+pub struct HandlerPoolConfig {
+    /// The number of handlers running concurrently
+    pub workers: usize,
+
+    /// The number of messages waiting for a worker
+    pub queue_capacity: usize,
+
+    pub policy: QueuePolicy,
+}
+
+impl Default for HandlerPoolConfig {
+    fn default() -> Self {
+        HandlerPoolConfig {
+            workers: 4,
+            queue_capacity: 64,
+            policy: QueuePolicy::Block,
+        }
+    }
+}
+
+/// Returned when a job is rejected by a full pool
+#[derive(Debug)]
+pub(crate) struct PoolFull;
+
+pub(crate) struct WorkerPool {
+    jobs: SyncSender<Job>,
+    policy: QueuePolicy,
+}
+
+impl WorkerPool {
+    /// Starts the workers. They exit once the pool is dropped and the queue is drained.
+    ///
+    /// # Panics
+    /// Panics if the configuration specifies no workers
+    pub(crate) fn new(config: HandlerPoolConfig) -> WorkerPool {
+        assert!(config.workers > 0, "A handler pool requires at least one worker");
+        let (jobs, queue) = sync_channel::<Job>(config.queue_capacity);
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..config.workers {
+            let queue = queue.clone();
+            thread::spawn(move || Self::work(queue));
+        }
+        WorkerPool {
+            jobs,
+            policy: config.policy,
+        }
+    }
+
+    pub(crate) fn execute<F: FnOnce() + Send + 'static>(&self, job: F) -> Result<(), PoolFull> {
+        let job: Job = Box::new(job);
+        match self.policy {
+            QueuePolicy::Block => self.jobs.send(job).map_err(|_| PoolFull),
+            QueuePolicy::Reject => self.jobs.try_send(job).map_err(|_| PoolFull),
+        }
+    }
+
+    fn work(queue: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            // the lock is released once a job is taken, so other workers can take the next one
+            let job = match queue.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                warn!("A message handler panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Drives the socket loop by readiness, with the `tokio-transport` feature: the loop runs again as soon as the
+//! stream becomes readable (or writable, while a message is partially written), or a message is queued,
+//! instead of polling every millisecond.
+
+use std::io::{self, ErrorKind};
+use std::net;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::Notify;
+
+/// The longest the loop waits without any event, as a safety net for deadlines it is not told about
+pub const MAX_IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// Signalled by the writers of the socket whenever they queue a message
+pub(crate) type Wakeup = Arc<Notify>;
+
+pub(crate) struct Reactor {
+    runtime: Runtime,
+    // a handle to the socket of the stream, registered with the reactor only to wait on
+    socket: TcpStream,
+    wakeup: Wakeup,
+}
+
+impl Reactor {
+    /// Creates a reactor waiting on the socket of a stream, which must be nonblocking
+    pub fn new(socket: &net::TcpStream, wakeup: Wakeup) -> io::Result<Reactor> {
+        let runtime = Builder::new_current_thread().enable_io().enable_time().build()?;
+        let socket = Reactor::register(&runtime, socket)?;
+        Ok(Reactor {
+            runtime,
+            socket,
+            wakeup,
+        })
+    }
+
+    fn register(runtime: &Runtime, socket: &net::TcpStream) -> io::Result<TcpStream> {
+        let _context = runtime.enter();
+        TcpStream::from_std(socket.try_clone()?)
+    }
+
+    /// Waits on the socket of a new stream from now on, e.g. after a reconnection
+    pub fn switch_stream(&mut self, socket: &net::TcpStream) -> io::Result<()> {
+        self.socket = Reactor::register(&self.runtime, socket)?;
+        Ok(())
+    }
+
+    /// Forgets the readiness observed so far. Called before the loop reads and writes until the stream would block,
+    /// so that only the events arriving from then on end the next wait.
+    pub fn clear(&self) {
+        for interest in [Interest::READABLE, Interest::WRITABLE] {
+            let _ = self.socket.try_io(interest, || Err::<(), _>(ErrorKind::WouldBlock.into()));
+        }
+    }
+
+    /// Waits until the stream is readable, or writable if `writing`, a message is queued, or the timeout elapsed.
+    /// Only the socket is waited on: the caller must not wait while the TLS session holds decrypted data.
+    pub fn wait(&self, writing: bool, timeout: Duration) {
+        let interest = match writing {
+            true => Interest::READABLE | Interest::WRITABLE,
+            false => Interest::READABLE,
+        };
+        self.runtime.block_on(async {
+            tokio::select! {
+                ready = self.socket.ready(interest) => {
+                    if let Err(e) = ready {
+                        // the loop runs into the failure reading the stream
+                        debug!("Failed waiting for the stream: {}", e);
+                    }
+                }
+                _ = self.wakeup.notified() => {}
+                _ = tokio::time::sleep(timeout.min(MAX_IDLE_WAIT)) => {}
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wait_ends_on_events_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_nonblocking(true).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let wakeup: Wakeup = Arc::new(Notify::new());
+        let sut = Reactor::new(&client, wakeup.clone()).unwrap();
+
+        // a queued message
+        wakeup.notify_one();
+        let start = Instant::now();
+        sut.wait(false, MAX_IDLE_WAIT);
+        assert!(start.elapsed() < MAX_IDLE_WAIT);
+
+        // the hub's data, answered
+        server.write_all(b"ping").unwrap();
+        let start = Instant::now();
+        sut.wait(false, MAX_IDLE_WAIT);
+        assert!(start.elapsed() < MAX_IDLE_WAIT);
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        client.write_all(b"pong").unwrap();
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // nothing left to wait for
+        sut.clear();
+        let start = Instant::now();
+        sut.wait(false, Duration::from_millis(50));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,75 @@
+//! Graceful shutdown of long-running applications: serving the hub until a signal, then flushing the messages
+//! in flight and disconnecting, instead of looping forever.
+
+use std::future::Future;
+use std::time::Duration;
+
+use crate::iot_socket::SendError;
+use crate::DeviceClient;
+
+/// How long `run_until_shutdown` waits for the operations in flight to complete
+pub const DEFAULT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the hub until the signal resolves (e.g. `termination_signal`), then shuts the client down gracefully
+/// with `DeviceClient::shutdown`. The handlers of the client run on their own threads meanwhile.
+///
+/// # Errors
+/// Fails with `SendError::ConnectionLost` if the connection was lost before the signal
+pub async fn run_until_shutdown<S: Future<Output = ()>>(client: DeviceClient, signal: S) -> Result<(), SendError> {
+    signal.await;
+    info!("Shutting down");
+    client.shutdown(DEFAULT_FLUSH_TIMEOUT).await
+}
+
+/// Resolves once the process receives ctrl-c, or SIGTERM on Unix. Works under any executor: the signals are
+/// awaited on the tokio runtime of the caller, which must have its IO driver enabled, or else on a thread
+/// of their own.
+///
+/// # Errors
+/// Fails if the signal handlers cannot be installed
+#[cfg(feature = "signals")]
+pub fn termination_signal() -> std::io::Result<impl Future<Output = ()>> {
+    use futures::channel::oneshot;
+    use futures::FutureExt;
+    use tokio::runtime::{Builder, Handle};
+
+    // a runtime of our own only if the caller has none
+    let (handle, runtime) = match Handle::try_current() {
+        Ok(handle) => (handle, None),
+        Err(_) => {
+            let runtime = Builder::new_current_thread().enable_io().build()?;
+            (runtime.handle().clone(), Some(runtime))
+        }
+    };
+    #[cfg(unix)]
+    let mut terminate = {
+        let _context = handle.enter();
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?
+    };
+    let (tx, rx) = oneshot::channel();
+    let signal = async move {
+        #[cfg(unix)]
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                if let Err(e) = result {
+                    warn!("Failed waiting for ctrl-c: {}", e);
+                }
+            }
+            _ = terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed waiting for ctrl-c: {}", e);
+        }
+        let _ = tx.send(());
+    };
+    match runtime {
+        Some(runtime) => {
+            std::thread::spawn(move || runtime.block_on(signal));
+        }
+        None => {
+            handle.spawn(signal);
+        }
+    }
+    Ok(rx.map(|_| ()))
+}
@@ -0,0 +1,241 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use raiot_client_base::histogram::{Histogram, HistogramBuckets, HistogramSnapshot};
+
+/// Send/receive counters of a single session, updated by the socket thread.
+/// Obtained from `IotSocket::stats` or `DeviceClient::stats`.
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    acks_received: AtomicU64,
+    messages_dropped: AtomicU64,
+    telemetry_delayed: AtomicU64,
+    telemetry_rejected: AtomicU64,
+    token_renewals: AtomicU64,
+    // zero until the first acknowledgement arrives
+    last_ack_latency_micros: AtomicU64,
+    // gauges, sampled once per iteration of the socket loop
+    outbound_queue_depth: AtomicU64,
+    awaiting_acks: AtomicU64,
+    rx_buffer_fill: AtomicU64,
+    // zero until the first iteration completes
+    last_loop_latency_micros: AtomicU64,
+    max_loop_latency_micros: AtomicU64,
+    histograms: SessionHistograms,
+}
+
+#[derive(Debug)]
+struct SessionHistograms {
+    sent_payload_sizes: Histogram,
+    received_payload_sizes: Histogram,
+    ack_latencies: Histogram,
+    method_durations: Histogram,
+}
+
+impl SessionHistograms {
+    fn new(buckets: &HistogramBuckets) -> SessionHistograms {
+        SessionHistograms {
+            sent_payload_sizes: Histogram::new(buckets.payload_sizes.clone()),
+            received_payload_sizes: Histogram::new(buckets.payload_sizes.clone()),
+            ack_latencies: Histogram::of_durations(&buckets.latencies),
+            method_durations: Histogram::of_durations(&buckets.latencies),
+        }
+    }
+}
+
+impl Default for SessionHistograms {
+    fn default() -> Self {
+        SessionHistograms::new(&HistogramBuckets::default())
+    }
+}
+
+impl SessionStats {
+    /// Stats keeping histograms with the specified buckets
+    pub fn with_histograms(buckets: &HistogramBuckets) -> SessionStats {
+        SessionStats {
+            histograms: SessionHistograms::new(buckets),
+            ..SessionStats::default()
+        }
+    }
+
+    /// Total bytes read from the stream
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes written to the stream
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages completely written to the stream
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages received from the hub, including acknowledgements
+    pub fn messages_received(&self) -> u64 {
+        self.messages_received.load(Ordering::Relaxed)
+    }
+
+    /// Number of acknowledgements received for messages sent in this session
+    pub fn acks_received(&self) -> u64 {
+        self.acks_received.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages from the hub dropped because the received-message queue was full
+    pub fn messages_dropped(&self) -> u64 {
+        self.messages_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of telemetry messages held back because the telemetry quota was exhausted
+    pub fn telemetry_delayed(&self) -> u64 {
+        self.telemetry_delayed.load(Ordering::Relaxed)
+    }
+
+    /// Number of telemetry messages rejected because the telemetry quota was exhausted
+    pub fn telemetry_rejected(&self) -> u64 {
+        self.telemetry_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Number of times the connection was renewed with a fresh SAS token
+    pub fn token_renewals(&self) -> u64 {
+        self.token_renewals.load(Ordering::Relaxed)
+    }
+
+    /// The time between sending the most recently acknowledged message and receiving its acknowledgement
+    pub fn last_ack_latency(&self) -> Option<Duration> {
+        match self.last_ack_latency_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Number of outgoing messages taken from the socket's queue but not yet written, including held back telemetry.
+    /// A growing depth means messages are produced faster than the stream (or the telemetry quota) lets them out.
+    pub fn outbound_queue_depth(&self) -> u64 {
+        self.outbound_queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// Number of messages written and still awaiting an acknowledgement from the hub
+    pub fn awaiting_acks(&self) -> u64 {
+        self.awaiting_acks.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes read from the stream but not yet decoded into a packet
+    pub fn rx_buffer_fill(&self) -> u64 {
+        self.rx_buffer_fill.load(Ordering::Relaxed)
+    }
+
+    /// The time the socket loop spent sending and receiving in its latest iteration, excluding its idle sleep
+    pub fn last_loop_latency(&self) -> Option<Duration> {
+        match self.last_loop_latency_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// The longest time the socket loop spent in a single iteration, excluding its idle sleep
+    pub fn max_loop_latency(&self) -> Option<Duration> {
+        match self.max_loop_latency_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// The payload sizes of the messages sent, in bytes
+    pub fn sent_payload_sizes(&self) -> HistogramSnapshot {
+        self.histograms.sent_payload_sizes.snapshot()
+    }
+
+    /// The payload sizes of the messages received, in bytes
+    pub fn received_payload_sizes(&self) -> HistogramSnapshot {
+        self.histograms.received_payload_sizes.snapshot()
+    }
+
+    /// The times between sending messages and receiving their acknowledgements, in microseconds
+    pub fn ack_latencies(&self) -> HistogramSnapshot {
+        self.histograms.ack_latencies.snapshot()
+    }
+
+    /// The times direct method handlers took, in microseconds
+    pub fn method_durations(&self) -> HistogramSnapshot {
+        self.histograms.method_durations.snapshot()
+    }
+
+    /// Every histogram as text, e.g. to log on demand
+    pub fn dump_histograms(&self) -> String {
+        format!(
+            "sent payload sizes (bytes): {}\nreceived payload sizes (bytes): {}\n\
+             ack latencies (us): {}\nmethod durations (us): {}",
+            self.sent_payload_sizes(),
+            self.received_payload_sizes(),
+            self.ack_latencies(),
+            self.method_durations()
+        )
+    }
+
+    pub(crate) fn record_read(&self, amount: usize) {
+        self.bytes_read.fetch_add(amount as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_written(&self, amount: usize) {
+        self.bytes_written.fetch_add(amount as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_delayed(&self) {
+        self.telemetry_delayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rejected(&self) {
+        self.telemetry_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_token_renewal(&self) {
+        self.token_renewals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_gauges(&self, outbound: usize, awaiting_acks: usize, rx_buffered: usize, loop_latency: Duration) {
+        self.outbound_queue_depth.store(outbound as u64, Ordering::Relaxed);
+        self.awaiting_acks.store(awaiting_acks as u64, Ordering::Relaxed);
+        self.rx_buffer_fill.store(rx_buffered as u64, Ordering::Relaxed);
+        let micros = (loop_latency.as_micros() as u64).max(1);
+        self.last_loop_latency_micros.store(micros, Ordering::Relaxed);
+        self.max_loop_latency_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_ack(&self, latency: Duration) {
+        self.acks_received.fetch_add(1, Ordering::Relaxed);
+        let micros = (latency.as_micros() as u64).max(1);
+        self.last_ack_latency_micros.store(micros, Ordering::Relaxed);
+        self.histograms.ack_latencies.record(micros);
+    }
+
+    pub(crate) fn record_sent_payload(&self, size: usize) {
+        self.histograms.sent_payload_sizes.record(size as u64);
+    }
+
+    pub(crate) fn record_received_payload(&self, size: usize) {
+        self.histograms.received_payload_sizes.record(size as u64);
+    }
+
+    pub(crate) fn record_method_duration(&self, duration: Duration) {
+        self.histograms.method_durations.record_duration(duration);
+    }
+}
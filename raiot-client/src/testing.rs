@@ -0,0 +1,210 @@
+//! Sockets connected to mock hubs, for the tests of the client
+
+use crate::iot_socket::{Connector, Transport};
+use raiot_client_base::ConnectionSettings;
+use raiot_protocol::auth::DeviceCredentials;
+use raiot_protocol::{ClientIdentity, DeviceIdentity};
+use raiot_streams::{CancelToken, NonblockingSocket, SendProgress};
+use raiot_test_utils::hub::MockHub;
+use raiot_test_utils::{MockClientSocket, MockServerSocket, MockSocket};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long `wait_until` waits for a condition
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The client end of a mock socket, reading and writing like an `IoStream`
+pub(crate) struct MockStream {
+    socket: MockClientSocket,
+    read_buffer: Vec<u8>,
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.try_read_into_buffer(buf)
+    }
+}
+
+impl NonblockingSocket for MockStream {
+    fn send_blocking(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            match self.try_send(&buf[written..])? {
+                SendProgress::Complete => break,
+                SendProgress::WouldBlock(length) => {
+                    written += length;
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn try_send(&mut self, buf: &[u8]) -> io::Result<SendProgress> {
+        let mut written = 0;
+        while written < buf.len() {
+            match self.socket.write(&buf[written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(length) => written += length,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    return Ok(SendProgress::WouldBlock(written))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(SendProgress::Complete)
+    }
+
+    fn read_blocking(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.socket.read(buffer) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                other => return other,
+            }
+        }
+    }
+
+    fn try_read(&mut self) -> io::Result<Option<&[u8]>> {
+        if self.read_buffer.is_empty() {
+            self.read_buffer.resize(64 * 1024, 0);
+        }
+        let mut buffer = std::mem::take(&mut self.read_buffer);
+        let length = self.try_read_into_buffer(&mut buffer);
+        self.read_buffer = buffer;
+        match length? {
+            0 => Ok(None),
+            length => Ok(Some(&self.read_buffer[..length])),
+        }
+    }
+
+    fn try_read_into_buffer(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.socket.read(buffer) {
+            // the hub closed the connection
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(length) => Ok(length),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Transport for MockStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connects to the client ends of mock sockets, in the order they were queued.
+/// Fails with `ErrorKind::ConnectionRefused` once they are all used up.
+pub(crate) struct MockConnector {
+    sockets: Receiver<MockClientSocket>,
+}
+
+impl MockConnector {
+    pub(crate) fn new() -> (MockConnector, Sender<MockClientSocket>) {
+        let (tx, sockets) = channel();
+        (MockConnector { sockets }, tx)
+    }
+}
+
+impl Connector for MockConnector {
+    type Stream = MockStream;
+
+    fn open(
+        &mut self,
+        _settings: &ConnectionSettings,
+        _cancel: &CancelToken,
+    ) -> io::Result<MockStream> {
+        match self.sockets.try_recv() {
+            Ok(socket) => Ok(MockStream {
+                socket,
+                read_buffer: Vec::new(),
+            }),
+            Err(_) => Err(ErrorKind::ConnectionRefused.into()),
+        }
+    }
+}
+
+/// A mock hub handling what the client writes on a thread of its own, until dropped
+pub(crate) struct RunningHub {
+    hub: Arc<Mutex<MockHub>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RunningHub {
+    pub(crate) fn new(server: MockServerSocket) -> RunningHub {
+        let hub = Arc::new(Mutex::new(MockHub::new(server)));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let hub = hub.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    hub.lock().unwrap().process();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        RunningHub {
+            hub,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Runs a function on the hub, between two rounds of processing
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut MockHub) -> R) -> R {
+        f(&mut self.hub.lock().unwrap())
+    }
+}
+
+impl Drop for RunningHub {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A connector to the specified number of running mock hubs: the socket connects to the first one,
+/// and to the next one whenever it reconnects
+pub(crate) fn mock_hubs(count: usize) -> (MockConnector, Vec<RunningHub>) {
+    let (connector, sockets) = MockConnector::new();
+    let hubs = (0..count)
+        .map(|_| {
+            let (client, server) = MockSocket::create();
+            sockets.send(client).unwrap();
+            RunningHub::new(server)
+        })
+        .collect();
+    (connector, hubs)
+}
+
+/// The settings of a device connecting to the mock hubs
+pub(crate) fn device_settings() -> ConnectionSettings {
+    ConnectionSettings::new(
+        "hub.example.com",
+        ClientIdentity::Device(DeviceIdentity::new("device1").unwrap()),
+        DeviceCredentials::Sas("a2V5".to_owned()),
+    )
+}
+
+/// Waits a few seconds at most for a condition to hold. Returns FALSE if it never did.
+pub(crate) fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
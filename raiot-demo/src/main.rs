@@ -1,18 +1,17 @@
 #[macro_use] extern crate log;
 
-use raiot_client_base::ConnectionSettings;
+use raiot_client_base::{ConnectionSettings, RetrySchedules};
 use raiot_cli::Options;
 use raiot_protocol::*;
 
-use structopt::StructOpt;
-
-use std::{time::{Instant, Duration}};
+use std::{sync::Arc, time::Duration};
+use futures::{future::{select, Either}, FutureExt};
 use serde_json::json;
 use raiot_client::dmi::*;
 use raiot_client::c2d::*;
 use raiot_client::d2c::D2CMsg;
-use raiot_protocol::auth::{DeviceCredentials, certificate::DeviceCertificate};
-use qos::{SessionMode, DeliveryGuarantees};
+use raiot_client::shutdown::{run_until_shutdown, termination_signal};
+use raiot_protocol::redact::redact;
 
 
 
@@ -22,65 +21,53 @@ pub async fn main() {
     env_logger::init();
     debug!("Starting IoT Hub Device");
 
-    let options = Options::from_args();
+    let options = Options::from_cmd_line();
     debug!("Connecting to {}:{}", options.hostname, options.port);
-    let credentials: DeviceCredentials;
-    if let Some(key) = options.key {
-        credentials = DeviceCredentials::Sas(key)
-    } else {
-        if options.cert_file.is_some() &&
-           options.cert_pass.is_some() {
-            credentials = DeviceCredentials::Certificate(DeviceCertificate {
-                bytes: std::fs::read(std::path::PathBuf::from(options.cert_file.unwrap())).unwrap(),
-                password: options.cert_pass.unwrap()
-            })
-           } else {
-               panic!("Must provide certificate + password, or SAS key");
-           }
-    }
+    let credentials = options.get_credentials();
+    let identity = options.get_identity();
     let settings = ConnectionSettings {
         hostname: options.hostname,
-        client_id: ClientIdentity::from_device_id(&options.device_id),
+        client_id: identity.clone(),
         port: options.port,
-        timeout: Duration::from_secs(30),
-        session_mode: SessionMode::Clean,
         token_ttl: Duration::from_secs(60 * 60 * 24),
-        credentials: credentials
+        credentials,
+        client_id_override: options.mqtt_client_id.map(|client_id| connect::MqttClientId::new(&client_id).unwrap()),
+        gateway_hostname: options.gateway_hostname,
+        retry_policy: Some(Arc::new(RetrySchedules::default())),
+        ..Default::default()
     };
 
-    let socket = raiot_client::iot_socket::IotSocket::connect(settings);
-    
+    let socket = raiot_client::iot_socket::IotSocket::connect(settings)
+        .expect("Failed connecting to the hub");
+
     debug!("Got socket");
 
-    let mut client = raiot_client::DeviceClient::new(ClientIdentity::from_device_id(&options.device_id), socket);
+    let mut client = raiot_client::DeviceClient::new(identity, socket);
  
     debug!("Reading the twin...");
-    let twin = client.read_twin().await;
-    debug!("Got the twin: {:?}", twin);
+    let twin = client.read_twin().await.unwrap();
+    debug!("Got the twin: {:?}", redact(&twin));
 
-    client.set_dmi_handler(handle_direct_method, DeliveryGuarantees::AtMostOnce);
-    client.set_c2d_handler(handle_c2d, DeliveryGuarantees::AtMostOnce);
+    client.set_dmi_handler(handle_direct_method, None).unwrap();
+    client.set_c2d_handler(handle_c2d, None).unwrap();
 
-    let mut last_telemetry_instant = Instant::now();
     let tx_freq= Duration::from_secs(3);
 
-    loop {
-        if last_telemetry_instant.elapsed() > tx_freq {
-            client.send_telemetry(D2CMsg {
-                content: Some(json!({
-                    "hello" : "world"
-                })),
-                headers: None
-            }).await.unwrap();
-            last_telemetry_instant = Instant::now();
-        }
-
-        std::thread::sleep(Duration::from_millis(1));
+    // send telemetry until ctrl-c or SIGTERM, then flush and disconnect
+    let shutdown = termination_signal().expect("Failed installing the signal handlers").shared();
+    while let Either::Right(_) = select(shutdown.clone(), Box::pin(tokio::time::sleep(tx_freq))).await {
+        client.send_telemetry(D2CMsg::new(json!({
+            "hello" : "world"
+        }))).await.unwrap();
+    }
+
+    if let Err(e) = run_until_shutdown(client, shutdown).await {
+        warn!("Failed shutting down gracefully: {}", e);
     }
 }
 
 fn handle_direct_method(req: DMIRequest) -> DMIResult {
-    debug!("Got DMI request: {:?}", req);
+    debug!("Got DMI request: {:?}", redact(&req));
     DMIResult {
         status: 200,
         payload: Some(json!({ "key" : "value" })),
@@ -88,6 +75,6 @@ fn handle_direct_method(req: DMIRequest) -> DMIResult {
 }
 
 fn handle_c2d(msg: C2DMsg) -> C2DResult {
-    debug!("Got C2D Msg: {:?}", msg);
+    debug!("Got C2D Msg: {:?}", redact(&msg));
     Ok(())
 }
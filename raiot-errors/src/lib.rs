@@ -0,0 +1,282 @@
+//! The errors of the raiot clients, in three layers:
+//! - `TransportError`: the TCP/TLS stream or the buffers in front of it failed
+//! - `ProtocolError`: the hub refused the connection, or sent something which is not valid MQTT or IoT Hub protocol
+//! - `ClientError`: any failure of a client operation, wrapping the layer it originated in
+//!
+//! Every layer keeps the error it was converted from as its `source()`, so the whole chain can be logged.
+#![deny(missing_docs)]
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use raiot_mqtt::packets::StreamerError;
+use raiot_protocol::connect::{ConnectError, MissingCapability};
+use raiot_protocol::{CodecError, SubError};
+
+/// A failure of the stream connecting the client to the hub
+#[derive(Debug)]
+pub enum TransportError {
+    /// The stream failed, or was closed by the hub
+    Io(io::Error),
+
+    /// A packet could not be written to the transmit buffer
+    TxBuffer(StreamerError),
+
+    /// The operation did not complete in time
+    TimedOut,
+
+    /// The operation was cancelled by the application
+    Cancelled,
+}
+
+impl TransportError {
+    /// The kind of IO error this error amounts to, for code still reasoning in `std::io` terms
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            TransportError::Io(e) => e.kind(),
+            TransportError::TxBuffer(StreamerError::PacketTooLarge { .. }) => io::ErrorKind::InvalidInput,
+            TransportError::TxBuffer(StreamerError::BufferFull { .. }) => io::ErrorKind::WriteZero,
+            TransportError::TxBuffer(StreamerError::EncodingFailed(_)) => io::ErrorKind::InvalidData,
+            TransportError::TimedOut => io::ErrorKind::TimedOut,
+            TransportError::Cancelled => io::ErrorKind::ConnectionAborted,
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(_) => write!(f, "Stream failure"),
+            TransportError::TxBuffer(_) => write!(f, "Cannot buffer a packet for sending"),
+            TransportError::TimedOut => write!(f, "Timed out"),
+            TransportError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl Error for TransportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransportError::Io(e) => Some(e),
+            TransportError::TxBuffer(e) => Some(e),
+            TransportError::TimedOut | TransportError::Cancelled => None,
+        }
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<io::ErrorKind> for TransportError {
+    fn from(kind: io::ErrorKind) -> Self {
+        TransportError::Io(kind.into())
+    }
+}
+
+impl From<StreamerError> for TransportError {
+    fn from(e: StreamerError) -> Self {
+        TransportError::TxBuffer(e)
+    }
+}
+
+/// A violation of the MQTT or IoT Hub protocol, or a refusal of the hub to follow it with the client
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The hub refused the connection (e.g. authentication failed)
+    ConnectRefused(ConnectError),
+
+    /// A packet from the hub could not be decoded, or a message could not be encoded
+    Codec(CodecError),
+
+    /// The hub refused a subscription, or did not acknowledge it in time
+    Subscription(SubError),
+
+    /// The hub sent an unexpected packet
+    Violation,
+
+    /// The connection lacks a capability the operation requires, which the hub did not agree to
+    MissingCapability(MissingCapability),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::ConnectRefused(_) => write!(f, "Connection refused by the hub"),
+            ProtocolError::Codec(_) => write!(f, "Invalid message"),
+            ProtocolError::Subscription(_) => write!(f, "Subscription failed"),
+            ProtocolError::Violation => write!(f, "Protocol violation"),
+            ProtocolError::MissingCapability(_) => write!(f, "Capability not negotiated"),
+        }
+    }
+}
+
+impl Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProtocolError::ConnectRefused(e) => Some(e),
+            ProtocolError::Codec(e) => Some(e),
+            ProtocolError::Subscription(e) => Some(e),
+            ProtocolError::Violation => None,
+            ProtocolError::MissingCapability(e) => Some(e),
+        }
+    }
+}
+
+impl From<CodecError> for ProtocolError {
+    fn from(e: CodecError) -> Self {
+        ProtocolError::Codec(e)
+    }
+}
+
+impl From<MissingCapability> for ProtocolError {
+    fn from(e: MissingCapability) -> Self {
+        ProtocolError::MissingCapability(e)
+    }
+}
+
+impl From<SubError> for ProtocolError {
+    fn from(e: SubError) -> Self {
+        ProtocolError::Subscription(e)
+    }
+}
+
+/// The failure of a client operation
+#[derive(Debug)]
+pub enum ClientError {
+    /// The stream to the hub failed
+    Transport(TransportError),
+
+    /// The hub refused the connection or violated the protocol
+    Protocol(ProtocolError),
+
+    /// The hub rejected a request with the status code
+    Rejected(u16),
+
+    /// An application handler failed processing a message from the hub
+    Handler(Box<dyn Error + Send + Sync>),
+}
+
+impl ClientError {
+    /// A failure of an application handler
+    pub fn handler<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> ClientError {
+        ClientError::Handler(error.into())
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(e) => write!(f, "Transport error: {}", e),
+            ClientError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            ClientError::Rejected(status) => write!(f, "Rejected by the hub with status {}", status),
+            ClientError::Handler(e) => write!(f, "Handler failed: {}", e),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Transport(e) => Some(e),
+            ClientError::Protocol(e) => Some(e),
+            ClientError::Rejected(_) => None,
+            ClientError::Handler(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<TransportError> for ClientError {
+    fn from(e: TransportError) -> Self {
+        ClientError::Transport(e)
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        ClientError::Protocol(e)
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Transport(e.into())
+    }
+}
+
+impl From<StreamerError> for ClientError {
+    fn from(e: StreamerError) -> Self {
+        ClientError::Transport(e.into())
+    }
+}
+
+impl From<CodecError> for ClientError {
+    fn from(e: CodecError) -> Self {
+        ClientError::Protocol(e.into())
+    }
+}
+
+impl From<MissingCapability> for ClientError {
+    fn from(e: MissingCapability) -> Self {
+        ClientError::Protocol(e.into())
+    }
+}
+
+impl From<SubError> for ClientError {
+    fn from(e: SubError) -> Self {
+        ClientError::Protocol(e.into())
+    }
+}
+
+/// Failures of the connection attempt itself are sorted into the transport layer, refusals into the protocol layer
+impl From<ConnectError> for ClientError {
+    fn from(e: ConnectError) -> Self {
+        match e {
+            ConnectError::IOError(kind) => ClientError::Transport(kind.into()),
+            ConnectError::Timeout => ClientError::Transport(TransportError::TimedOut),
+            ConnectError::Cancelled => ClientError::Transport(TransportError::Cancelled),
+            ConnectError::ProtocolViolation => ClientError::Protocol(ProtocolError::Violation),
+            refused => ClientError::Protocol(ProtocolError::ConnectRefused(refused)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(e: &dyn Error) -> Vec<String> {
+        let mut messages = vec![e.to_string()];
+        let mut source = e.source();
+        while let Some(e) = source {
+            messages.push(e.to_string());
+            source = e.source();
+        }
+        messages
+    }
+
+    #[test]
+    fn test_errors_keep_their_cause_as_source() {
+        let e: ClientError = io::Error::new(io::ErrorKind::ConnectionReset, "reset by peer").into();
+        assert_eq!(
+            chain(&e),
+            vec!["Transport error: Stream failure", "Stream failure", "reset by peer"]
+        );
+
+        let e: ClientError = ConnectError::AuthenticationFailed.into();
+        assert!(matches!(
+            e,
+            ClientError::Protocol(ProtocolError::ConnectRefused(ConnectError::AuthenticationFailed))
+        ));
+        assert_eq!(chain(&e).last().unwrap(), "Authentication failed");
+
+        let e: ClientError = ConnectError::Cancelled.into();
+        match e {
+            ClientError::Transport(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted),
+            other => panic!("Expected a transport error, got {:?}", other),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::io::ErrorKind;
 use std::{
     io::{Read, Write},
@@ -5,13 +6,14 @@ use std::{
     time::Instant,
 };
 
-use crate::packets::{MqttPacketizer, MqttStreamer};
+use crate::packets::{MqttPacketizer, MqttStreamer, StreamerError};
 use log::{debug, trace};
 use mqtt::packet::*;
 use mqtt::{control::variable_header::ConnectReturnCode, packet::ConnackPacket};
 
 pub enum MqttConnectError<S: Read + Write> {
-    WouldBlock(MqttConnectionInProgress<S>),
+    /// The connection in progress, boxed so that the error does not take up the room of a whole connection
+    WouldBlock(Box<MqttConnectionInProgress<S>>),
     ConnectFailed(ConnectReturnCode),
     IOError(ErrorKind),
     ProtocolViolation,
@@ -22,34 +24,170 @@ pub struct MqttConnector<S: Read + Write> {
     tx_buffer_size: usize,
     rx_buffer_size: usize,
     connect_timeout: Duration,
+    keep_alive: Option<Duration>,
 }
 
 pub struct MqttConnection<S: Read + Write> {
     packetizer: MqttPacketizer,
     streamer: MqttStreamer,
     stream: S,
+    last_activity: Instant,
+    last_sent: Instant,
+    last_received: Instant,
+    keep_alive: Option<Duration>,
+    // when the unanswered keep-alive PINGREQ was written, if any
+    ping_sent_at: Option<Instant>,
+    alive: bool,
+    session_present: bool,
+    // packets received while probing, returned by subsequent reads
+    deferred: VecDeque<VariablePacket>,
 }
 
 impl<S: Read + Write> MqttConnection<S> {
+    /// How long a probe sleeps between polls of a blocked stream
+    const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
     /// Writes a packet to the tx buffer.
-    pub fn write(&mut self, packet: &VariablePacket) -> std::io::Result<()> {
+    pub fn write(&mut self, packet: &VariablePacket) -> Result<(), StreamerError> {
         debug!("Writing a packet");
         self.streamer.write_packet(packet)
     }
 
     /// Reads the next packet from the rx buffer, if any.
+    /// The PINGRESP answering a keep-alive PINGREQ is consumed by the connection and never returned.
     pub fn read(&mut self) -> std::io::Result<Option<VariablePacket>> {
-        if let Some(packet) = self.packetizer.get_next_packet()? {
-            Ok(Some(packet))
-        } else {
-            Ok(None)
+        if let Some(packet) = self.deferred.pop_front() {
+            return Ok(Some(packet));
+        }
+        while let Some(packet) = self.packetizer.get_next_packet()? {
+            match packet {
+                VariablePacket::PingrespPacket(_) if self.ping_sent_at.is_some() => {
+                    trace!("Keep-alive PINGRESP received");
+                    self.ping_sent_at = None;
+                }
+                packet => return Ok(Some(packet)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns FALSE once the stream failed or a probe went unanswered.
+    /// A TRUE result only means no failure was observed yet; use `probe` to verify the peer is responsive.
+    pub fn is_alive(&self) -> bool {
+        self.alive
+    }
+
+    /// Returns TRUE if the broker resumed a previous session, as reported by the CONNACK
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// Returns the last time data was sent or received over the stream
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// The keep-alive interval announced to the broker, if any
+    pub fn keep_alive(&self) -> Option<Duration> {
+        self.keep_alive
+    }
+
+    /// When the keep-alive task has to run next: to send a PINGREQ, or to give up on an unanswered one.
+    /// None without keep-alive.
+    pub fn keep_alive_deadline(&self) -> Option<Instant> {
+        let interval = self.keep_alive?;
+        match self.ping_sent_at {
+            Some(sent_at) if self.last_received <= sent_at => Some(sent_at + interval),
+            _ => Some(self.last_sent + interval),
+        }
+    }
+
+    /// TRUE if the tx buffer holds data the stream did not accept yet
+    pub fn has_pending_writes(&self) -> bool {
+        !self.streamer.is_empty()
+    }
+
+    /// The underlying stream, e.g. to register it with an event loop
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    /// Writes a PINGREQ once nothing was sent for a whole keep-alive interval,
+    /// and fails once a PINGREQ went unanswered for a whole interval with nothing received meanwhile.
+    fn keep_alive_task(&mut self) -> std::io::Result<()> {
+        let interval = match self.keep_alive {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        match self.ping_sent_at {
+            Some(sent_at) if self.last_received > sent_at => {
+                // the connection is evidently alive, the PINGRESP may just not have been read yet
+                self.ping_sent_at = None;
+            }
+            Some(sent_at) if sent_at.elapsed() >= interval => {
+                debug!("Keep-alive PINGREQ went unanswered");
+                self.alive = false;
+                return Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "No PINGRESP received, the connection is dead",
+                ));
+            }
+            Some(_) => {}
+            // pending data means the stream is blocked rather than idle
+            None if self.streamer.is_empty() && self.last_sent.elapsed() >= interval => {
+                debug!("Connection idle, sending a keep-alive PINGREQ");
+                self.write(&PingreqPacket::new().into())?;
+                self.ping_sent_at = Some(Instant::now());
+            }
+            None => {}
         }
+        Ok(())
     }
 
-    /// Sends bytes from the tx buffer until blocked or until the alloted time is exhausted
+    /// Sends a PINGREQ and waits for the matching PINGRESP, returning the round trip time.
+    /// Packets received while waiting are kept, and returned by subsequent calls to `read`.
+    ///
+    /// # Errors
+    /// - Returns TimedOut if no PINGRESP arrived within the alloted time, in which case the connection is no longer considered alive
+    /// - Returns any error of the underlying stream
+    pub fn probe(&mut self, timeout: Duration) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        self.write(&PingreqPacket::new().into())?;
+        loop {
+            let remaining = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if remaining > Duration::from_secs(0) => remaining,
+                _ => {
+                    debug!("Probe timed out");
+                    self.alive = false;
+                    return Err(ErrorKind::TimedOut.into());
+                }
+            };
+
+            let _ = self.send_task(remaining)?;
+            let _ = self.recv_task(remaining)?;
+            while let Some(packet) = self.packetizer.get_next_packet()? {
+                match packet {
+                    VariablePacket::PingrespPacket(_) => {
+                        self.ping_sent_at = None;
+                        return Ok(start.elapsed());
+                    }
+                    other => self.deferred.push_back(other),
+                }
+            }
+            std::thread::sleep(Self::PROBE_POLL_INTERVAL);
+        }
+    }
+
+    /// Sends bytes from the tx buffer until blocked or until the alloted time is exhausted.
+    /// With keep-alive enabled, a PINGREQ is sent first if the connection was idle for the keep-alive interval.
     /// Returns the amount of data still pending in the buffer
+    ///
+    /// # Errors
+    /// - Returns TimedOut if a keep-alive PINGREQ went unanswered, in which case the connection is no longer considered alive
+    /// - Returns any error of the underlying stream
     pub fn send_task(&mut self, timeout: Duration) -> std::io::Result<usize> {
         trace!("send_task starting");
+        self.keep_alive_task()?;
         let start = Instant::now();
         loop {
             if start.elapsed() >= timeout {
@@ -65,6 +203,10 @@ impl<S: Read + Write> MqttConnection<S> {
             match self.streamer.write_into(&mut self.stream) {
                 Ok(size) => {
                     debug!("Wrote from TX buffer to socket: {}", size);
+                    if size > 0 {
+                        self.last_activity = Instant::now();
+                        self.last_sent = self.last_activity;
+                    }
                 }
                 Err(e) if e.kind() == ErrorKind::Interrupted => {
                     trace!("Write interrupted");
@@ -75,13 +217,34 @@ impl<S: Read + Write> MqttConnection<S> {
                     return Ok(self.streamer.data_size());
                 }
                 Err(e) => {
+                    self.alive = false;
                     return Err(e);
                 }
             }
         }
     }
 
+    /// Sends the whole tx buffer, waiting for the stream as needed.
+    ///
+    /// # Errors
+    /// - Returns TimedOut if the buffer could not be sent within the alloted time
+    /// - Returns any error of the underlying stream
+    pub fn flush(&mut self, timeout: Duration) -> std::io::Result<()> {
+        let start = Instant::now();
+        loop {
+            let remaining = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if remaining > Duration::from_secs(0) => remaining,
+                _ => return Err(ErrorKind::TimedOut.into()),
+            };
+            if self.send_task(remaining)? == 0 {
+                return Ok(());
+            }
+            std::thread::sleep(Self::PROBE_POLL_INTERVAL);
+        }
+    }
+
     /// Tries to read data from the socket until a complete packet is buffered, or until blocked, or the alloted time is exhausted.
+    /// Returns UnexpectedEof if the peer closed the connection.
     pub fn recv_task(&mut self, timeout: Duration) -> std::io::Result<Option<VariablePacket>> {
         trace!("recv_task starting");
         let start = Instant::now();
@@ -92,9 +255,16 @@ impl<S: Read + Write> MqttConnection<S> {
             }
 
             match self.packetizer.append_from_reader(&mut self.stream) {
-                Ok(_size) => {
+                Ok(0) => {
+                    debug!("Connection closed by the peer");
+                    self.alive = false;
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                Ok(size) => {
                     // Perhaps we go a full packet now?
-                    debug!("read: {:?}", _size);
+                    debug!("read: {:?}", size);
+                    self.last_activity = Instant::now();
+                    self.last_received = self.last_activity;
                 }
                 Err(e) if e.kind() == ErrorKind::Interrupted => {
                     // keep trying!
@@ -106,6 +276,7 @@ impl<S: Read + Write> MqttConnection<S> {
                 }
                 Err(e) => {
                     debug!("read failed");
+                    self.alive = false;
                     return Err(e);
                 }
             }
@@ -119,6 +290,7 @@ pub struct MqttConnectionInProgress<S: Read + Write> {
     stream: S,
     stopwatch: Instant,
     connect_timeout: Duration,
+    keep_alive: Option<Duration>,
 }
 
 impl<S: Read + Write> MqttConnector<S> {
@@ -128,9 +300,17 @@ impl<S: Read + Write> MqttConnector<S> {
             tx_buffer_size: 512 * 1024,
             rx_buffer_size: 512 * 1024,
             connect_timeout: Duration::from_secs(10),
+            keep_alive: None,
         }
     }
 
+    /// Enables keep-alive: the interval is announced to the broker in the CONNECT packet (in whole seconds),
+    /// and the connection sends a PINGREQ whenever it has been idle for that long
+    pub fn with_keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
         self.connect_timeout = timeout;
         self
@@ -148,8 +328,12 @@ impl<S: Read + Write> MqttConnector<S> {
 
     pub fn connect(
         self,
-        connect_packet: ConnectPacket,
+        mut connect_packet: ConnectPacket,
     ) -> std::io::Result<MqttConnectionInProgress<S>> {
+        if let Some(interval) = self.keep_alive {
+            let secs = interval.as_secs() + u64::from(interval.subsec_nanos() > 0);
+            connect_packet.set_keep_alive(secs.min(u64::from(u16::MAX)) as u16);
+        }
         let packetizer = MqttPacketizer::with_buffer_size(self.rx_buffer_size);
         let mut streamer = MqttStreamer::with_buffer_size(self.tx_buffer_size);
         streamer.write_packet(&connect_packet.into())?;
@@ -159,6 +343,7 @@ impl<S: Read + Write> MqttConnector<S> {
             streamer,
             stream,
             connect_timeout: self.connect_timeout,
+            keep_alive: self.keep_alive,
             stopwatch: Instant::now(),
         };
         Ok(conn)
@@ -168,13 +353,13 @@ impl<S: Read + Write> MqttConnector<S> {
 impl<S: Read + Write> MqttConnectionInProgress<S> {
     pub fn complete(mut self) -> Result<MqttConnection<S>, MqttConnectError<S>> {
         if self.stopwatch.elapsed() > self.connect_timeout {
-            return Err(MqttConnectError::IOError(ErrorKind::TimedOut.into()));
+            return Err(MqttConnectError::IOError(ErrorKind::TimedOut));
         }
 
         if !self.streamer.is_empty() {
             match self.send_next() {
                 Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    return Err(MqttConnectError::WouldBlock(self))
+                    return Err(MqttConnectError::WouldBlock(Box::new(self)))
                 }
                 Ok(()) => {
                     // Done sending the CONNECT packet, now we need to wait for CONNACK
@@ -196,18 +381,14 @@ impl<S: Read + Write> MqttConnectionInProgress<S> {
         }
 
         match self.packetizer.get_next_packet() {
-            Ok(None) => {
-                return Err(MqttConnectError::WouldBlock(self));
-            }
-            Ok(Some(VariablePacket::ConnackPacket(packet))) => {
-                return self.process_connack(packet);
-            }
+            Ok(None) => Err(MqttConnectError::WouldBlock(Box::new(self))),
+            Ok(Some(VariablePacket::ConnackPacket(packet))) => self.process_connack(packet),
             Ok(Some(_other_packet)) => {
                 // Any non-CONNACK response is a protocol violation
-                return Err(MqttConnectError::ProtocolViolation);
+                Err(MqttConnectError::ProtocolViolation)
             }
             Err(e) if e.kind() == ErrorKind::InvalidData => {
-                return Err(MqttConnectError::ProtocolViolation);
+                Err(MqttConnectError::ProtocolViolation)
             }
             Err(_e) => {
                 panic!("Some unexpected error!");
@@ -224,6 +405,14 @@ impl<S: Read + Write> MqttConnectionInProgress<S> {
                 packetizer: self.packetizer,
                 streamer: self.streamer,
                 stream: self.stream,
+                last_activity: Instant::now(),
+                last_sent: Instant::now(),
+                last_received: Instant::now(),
+                keep_alive: self.keep_alive,
+                ping_sent_at: None,
+                alive: true,
+                session_present: packet.connack_flags().session_present,
+                deferred: VecDeque::new(),
             }),
             other => Err(MqttConnectError::ConnectFailed(other)),
         }
@@ -308,7 +497,7 @@ mod tests {
         assert!(res.is_err());
         match res.err().unwrap() {
             MqttConnectError::ProtocolViolation => {}
-            _other => assert!(false),
+            _ => panic!("Unexpected connection error"),
         }
     }
 
@@ -361,7 +550,7 @@ mod tests {
         let err: MqttConnectError<MockClientSocket> = res.err().unwrap();
         match err {
             MqttConnectError::ConnectFailed(ConnectReturnCode::NotAuthorized) => {}
-            _other => assert!(false),
+            _ => panic!("Unexpected connection error"),
         }
     }
 
@@ -384,7 +573,7 @@ mod tests {
         let err: MqttConnectError<MockClientSocket> = res.err().unwrap();
         match err {
             MqttConnectError::IOError(ErrorKind::ConnectionAborted) => {}
-            _other => assert!(false),
+            _ => panic!("Unexpected connection error"),
         }
     }
 
@@ -409,7 +598,7 @@ mod tests {
         let err: MqttConnectError<MockClientSocket> = res.err().unwrap();
         match err {
             MqttConnectError::IOError(ErrorKind::TimedOut) => {}
-            _other => assert!(false),
+            _ => panic!("Unexpected connection error"),
         }
     }
 
@@ -432,6 +621,109 @@ mod tests {
         assert_eq!(err.kind(), ErrorKind::InvalidInput);
     }
 
+    #[test]
+    fn test_probe_keeps_packets_received_before_pingresp() {
+        let (client_socket, mut server_socket) = MockSocket::create();
+        let connack = ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted);
+        server_socket.push_packet(&connack.into());
+        server_socket.push_write_ctl(Ok(8 * 1024));
+        // the mock socket reports as many bytes as allowed, so allow exactly the pushed packets
+        server_socket.push_read_ctl(Ok(4));
+        let mut sut = run_to_completion(
+            MqttConnector::create(client_socket)
+                .connect(ConnectPacket::new("clientid"))
+                .unwrap(),
+        )
+        .ok()
+        .unwrap();
+
+        let pubpack = PublishPacket::new(
+            TopicName::new("mytopic").unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            "",
+        );
+        server_socket.push_packet(&pubpack.into());
+        server_socket.push_packet(&PingrespPacket::new().into());
+        server_socket.push_write_ctl(Ok(8 * 1024));
+        server_socket.push_read_ctl(Ok(11 + 2));
+
+        assert!(sut.probe(Duration::from_secs(1)).is_ok());
+        assert!(sut.is_alive());
+        match sut.read().unwrap() {
+            Some(VariablePacket::PublishPacket(_)) => {}
+            _ => panic!("Unexpected connection error"),
+        }
+
+        let err = sut.probe(Duration::from_millis(10)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(!sut.is_alive());
+    }
+
+    #[test]
+    fn test_recv_reports_connection_closed_by_peer() {
+        let (client_socket, mut server_socket) = MockSocket::create();
+        let connack = ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted);
+        server_socket.push_packet(&connack.into());
+        server_socket.push_write_ctl(Ok(8 * 1024));
+        server_socket.push_read_ctl(Ok(4));
+        let mut sut = run_to_completion(
+            MqttConnector::create(client_socket)
+                .connect(ConnectPacket::new("clientid"))
+                .unwrap(),
+        )
+        .ok()
+        .unwrap();
+
+        server_socket.push_read_ctl(Ok(0));
+
+        let err = sut.recv_task(Duration::from_secs(1)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(!sut.is_alive());
+    }
+
+    #[test]
+    fn test_keep_alive_pings_idle_connection_and_detects_dead_peer() {
+        const KEEP_ALIVE: Duration = Duration::from_millis(20);
+        let (client_socket, mut server_socket) = MockSocket::create();
+        let connack = ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted);
+        server_socket.push_packet(&connack.into());
+        server_socket.push_write_ctl(Ok(8 * 1024));
+        server_socket.push_read_ctl(Ok(4));
+        let mut sut = run_to_completion(
+            MqttConnector::create(client_socket)
+                .with_keep_alive(KEEP_ALIVE)
+                .connect(ConnectPacket::new("clientid"))
+                .unwrap(),
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(sut.keep_alive(), Some(KEEP_ALIVE));
+        assert!(sut.keep_alive_deadline().unwrap() <= Instant::now() + KEEP_ALIVE);
+
+        // idle: a PINGREQ is sent, and its PINGRESP is consumed
+        std::thread::sleep(KEEP_ALIVE);
+        server_socket.push_write_ctl(Ok(8 * 1024));
+        assert_eq!(sut.send_task(Duration::from_secs(1)).unwrap(), 0);
+        assert!(sut.keep_alive_deadline().unwrap() > Instant::now());
+        // the CONNECT packet, then the PINGREQ
+        let mut written = [0u8; 1024];
+        let size = std::io::Read::read(&mut server_socket, &mut written).unwrap();
+        assert!(written[..size].ends_with(&[0xC0, 0x00]));
+        server_socket.push_packet(&PingrespPacket::new().into());
+        server_socket.push_read_ctl(Ok(2));
+        assert!(sut.recv_task(Duration::from_secs(1)).unwrap().is_none());
+        assert!(sut.read().unwrap().is_none());
+
+        // the next PINGREQ goes unanswered
+        std::thread::sleep(KEEP_ALIVE);
+        server_socket.push_write_ctl(Ok(8 * 1024));
+        assert_eq!(sut.send_task(Duration::from_secs(1)).unwrap(), 0);
+        std::thread::sleep(KEEP_ALIVE);
+        let err = sut.send_task(Duration::from_secs(1)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(!sut.is_alive());
+    }
+
     fn run_to_completion(
         mut sut: MqttConnectionInProgress<MockClientSocket>,
     ) -> Result<MqttConnection<MockClientSocket>, MqttConnectError<MockClientSocket>> {
@@ -440,7 +732,7 @@ mod tests {
                 Ok(conn) => return Ok(conn),
                 Err(MqttConnectError::WouldBlock(p)) => {
                     // continue trying
-                    sut = p;
+                    sut = *p;
                 }
                 Err(e) => return Err(e),
             }
@@ -456,7 +748,7 @@ mod tests {
                 Err(MqttConnectError::WouldBlock(p)) => {
                     // continue trying
                     std::thread::sleep(Duration::from_millis(100));
-                    sut = p;
+                    sut = *p;
                 }
                 Err(e) => return Err(e),
             }
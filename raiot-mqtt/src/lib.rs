@@ -1,3 +1,3 @@
 pub mod connection;
 pub mod packets;
-// pub mod session;
+pub mod session;
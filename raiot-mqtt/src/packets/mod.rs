@@ -2,4 +2,4 @@ mod packetizer;
 mod streamer;
 
 pub use packetizer::MqttPacketizer;
-pub use streamer::MqttStreamer;
+pub use streamer::{MqttStreamer, StreamerError};
@@ -21,7 +21,7 @@ impl MqttPacketizer {
 
     /// Creates a packetizer with the default buffer size
     pub fn new() -> MqttPacketizer {
-        return MqttPacketizer::with_buffer_size(MqttPacketizer::DEFAULT_BUFFER_SIZE);
+        MqttPacketizer::with_buffer_size(MqttPacketizer::DEFAULT_BUFFER_SIZE)
     }
 
     /// Creates a packetizer with the specified buffer size
@@ -45,6 +45,11 @@ impl MqttPacketizer {
         self.buffer.available_space()
     }
 
+    /// Returns the number of bytes buffered but not yet decoded, e.g. the received part of a large packet
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.valid_length()
+    }
+
     /// Appends bytes to the buffer, accounting for the available space in the buffer
     pub fn append_bytes(&mut self, bytes: &[u8]) -> Result<usize, std::io::Error> {
         let write_size = std::cmp::min(self.available_space(), bytes.len());
@@ -117,6 +122,12 @@ impl MqttPacketizer {
     }
 }
 
+impl Default for MqttPacketizer {
+    fn default() -> Self {
+        MqttPacketizer::new()
+    }
+}
+
 impl Write for MqttPacketizer {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.append_bytes(buf)
@@ -166,12 +177,14 @@ mod tests {
         let result = sut.get_next_packet();
         assert!(result.is_ok());
         assert!(result.unwrap().is_none());
+        assert_eq!(sut.buffered_len(), first_write_size);
 
         let write_size = sut.write(&packet_bytes[first_write_size..]).unwrap();
         assert_eq!(write_size, packet_bytes.len() - first_write_size);
         let result = sut.get_next_packet();
         assert!(result.is_ok());
         assert!(result.unwrap().is_some());
+        assert_eq!(sut.buffered_len(), 0);
     }
 
     #[test]
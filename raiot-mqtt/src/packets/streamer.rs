@@ -1,10 +1,51 @@
-pub use crate::packets::packetizer::MqttPacketizer;
 use raiot_buffers::CircularBuffer;
 
-use mqtt::packet::VariablePacket;
+use mqtt::packet::{VariablePacket, VariablePacketError};
 use mqtt::Encodable;
+use std::error::Error;
+use std::fmt;
 use std::io::{ErrorKind, Read, Write};
 
+/// The reason a packet could not be written into the streamer's buffer
+#[derive(Debug)]
+pub enum StreamerError {
+    /// The packet is bigger than the buffer, and can never be written
+    PacketTooLarge { length: usize, capacity: usize },
+
+    /// There currently isn't enough free space in the buffer, retry once it drains
+    BufferFull { length: usize, available: usize },
+
+    /// The packet could not be encoded
+    EncodingFailed(VariablePacketError),
+}
+
+impl fmt::Display for StreamerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamerError::PacketTooLarge { length, capacity } => {
+                write!(f, "Packet of {} bytes exceeds the buffer size of {} bytes", length, capacity)
+            }
+            StreamerError::BufferFull { length, available } => {
+                write!(f, "Packet of {} bytes does not fit the {} free bytes of the buffer", length, available)
+            }
+            StreamerError::EncodingFailed(e) => write!(f, "Packet encoding failed: {}", e),
+        }
+    }
+}
+
+impl Error for StreamerError {}
+
+impl From<StreamerError> for std::io::Error {
+    fn from(e: StreamerError) -> Self {
+        let kind = match e {
+            StreamerError::PacketTooLarge { .. } => ErrorKind::InvalidInput,
+            StreamerError::BufferFull { .. } => ErrorKind::WriteZero,
+            StreamerError::EncodingFailed(_) => ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
 /// Streams MQTT packets into the underlying buffer
 pub struct MqttStreamer {
     buffer: CircularBuffer,
@@ -20,19 +61,26 @@ impl MqttStreamer {
     /// Attempts to write a packet into the underlying buffer
     ///
     /// # Errors
-    /// - Returns WriteZero if there currently isn't enough free space in the underlying buffer
-    /// - Returns InvalidInput if the packet is bigger than the buffer size (and can never be written)
-    pub fn write_packet(&mut self, packet: &VariablePacket) -> std::io::Result<()> {
+    /// - Returns BufferFull if there currently isn't enough free space in the underlying buffer
+    /// - Returns PacketTooLarge if the packet is bigger than the buffer size (and can never be written)
+    /// - Returns EncodingFailed if the packet could not be encoded
+    pub fn write_packet(&mut self, packet: &VariablePacket) -> Result<(), StreamerError> {
         let length = packet.encoded_length() as usize;
         if length > self.buffer.size() {
-            return Err(ErrorKind::InvalidInput.into());
+            return Err(StreamerError::PacketTooLarge {
+                length,
+                capacity: self.buffer.size(),
+            });
         }
         else if length > self.buffer.available_space() {
-            return Err(ErrorKind::WriteZero.into());
+            return Err(StreamerError::BufferFull {
+                length,
+                available: self.buffer.available_space(),
+            });
         }
 
         packet.encode(&mut self.buffer)
-              .map_err(|_e| ErrorKind::InvalidInput.into())
+              .map_err(StreamerError::EncodingFailed)
     }
 
     /// TRUE if the underlying buffer is empty
@@ -48,3 +96,26 @@ impl MqttStreamer {
         self.buffer.write_into(writer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt::packet::PingreqPacket;
+
+    #[test]
+    fn test_write_packet_errors() {
+        let ping: VariablePacket = PingreqPacket::new().into();
+        let mut sut = MqttStreamer::with_buffer_size(1);
+        match sut.write_packet(&ping) {
+            Err(StreamerError::PacketTooLarge { length: 2, capacity: 1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut sut = MqttStreamer::with_buffer_size(3);
+        sut.write_packet(&ping).unwrap();
+        match sut.write_packet(&ping) {
+            Err(StreamerError::BufferFull { length: 2, available: 1 }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,24 +1,421 @@
-use mqtt::packet::VariablePacket;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use mqtt::packet::*;
 
 use crate::connection::MqttConnection;
-use std::io::{Read, Write};
+use crate::packets::StreamerError;
 
+/// Names the topic filter of the subscription the publications to a topic depend on, if any
+pub type Dependencies = fn(&str) -> Option<&'static str>;
+
+/// An MQTT connection which keeps the QoS1 and QoS2 publications and the subscriptions written over it until they
+/// are acknowledged, so they can be replayed over the next connection of a persistent (dirty) session.
+/// QoS2 publications already received by the hub are replayed by releasing them again (PUBREL).
 pub struct MqttSession<S: Read + Write> {
     connection: MqttConnection<S>,
+    in_flight: InFlightPackets,
+    dependencies: Option<Dependencies>,
+    // replayed publications waiting for the subscriptions they depend on
+    held: VecDeque<PublishPacket>,
 }
 
 impl<S: Read + Write> MqttSession<S> {
-    pub fn write(&mut self, packet: &VariablePacket) -> std::io::Result<()> {}
+    /// Starts tracking the publications of a fresh session
+    pub fn new(connection: MqttConnection<S>) -> MqttSession<S> {
+        MqttSession {
+            connection,
+            in_flight: InFlightPackets::default(),
+            dependencies: None,
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Holds replayed publications which depend on a subscription not acknowledged yet until the hub acknowledges it,
+    /// e.g. so that a request is not answered on a topic the client did not subscribe to yet
+    pub fn with_dependencies(mut self, dependencies: Dependencies) -> MqttSession<S> {
+        self.dependencies = Some(dependencies);
+        self
+    }
+
+    /// Resumes a session over a new connection, writing the publications still unacknowledged
+    /// by the previous connection again, with the DUP flag set and their original packet IDs.
+    pub fn resume(
+        connection: MqttConnection<S>,
+        in_flight: InFlightPackets,
+    ) -> Result<MqttSession<S>, StreamerError> {
+        let mut session = MqttSession::new(connection);
+        session.replay(in_flight)?;
+        Ok(session)
+    }
+
+    /// Writes subscriptions and publications unacknowledged by a previous connection again, subscriptions first,
+    /// ahead of those written over this connection so far. Publications are written with the DUP flag set,
+    /// unless held until the subscription they depend on is acknowledged (see `with_dependencies`).
+    /// Publications which don't fit the tx buffer are held too, and written as it drains.
+    ///
+    /// # Errors
+    /// Fails if the subscriptions or the releases don't fit the tx buffer. The packets are still tracked,
+    /// so that a later session can replay them.
+    pub fn replay(&mut self, in_flight: InFlightPackets) -> Result<(), StreamerError> {
+        let InFlightPackets {
+            packets: mut replayed,
+            mut released,
+            mut subscriptions,
+        } = in_flight;
+        for publish in replayed.iter_mut() {
+            publish.set_dup(true);
+        }
+        let mut result = Ok(());
+        for subscribe in subscriptions.iter() {
+            result = result.and_then(|()| self.connection.write(&subscribe.clone().into()));
+        }
+        // tracked before the publications, which may depend on them
+        subscriptions.append(&mut self.in_flight.subscriptions);
+        self.in_flight.subscriptions = subscriptions;
+
+        let mut overflowed = false;
+        for publish in replayed.iter() {
+            if result.is_err() || overflowed || self.awaits_subscription(publish) {
+                self.held.push_back(publish.clone());
+                continue;
+            }
+            match self.connection.write(&publish.clone().into()) {
+                Ok(()) => {}
+                Err(StreamerError::BufferFull { .. }) => {
+                    overflowed = true;
+                    self.held.push_back(publish.clone());
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        for packet_id in released.iter() {
+            result = result.and_then(|()| self.connection.write(&PubrelPacket::new(*packet_id).into()));
+        }
+
+        replayed.append(&mut self.in_flight.packets);
+        self.in_flight.packets = replayed;
+        released.append(&mut self.in_flight.released);
+        self.in_flight.released = released;
+        result
+    }
+
+    /// Ends the session, returning the publications not acknowledged yet
+    pub fn into_in_flight(self) -> InFlightPackets {
+        self.in_flight
+    }
+
+    /// Writes a packet to the tx buffer, tracking QoS1 and QoS2 publications and subscriptions until acknowledged.
+    pub fn write(&mut self, packet: &VariablePacket) -> Result<(), StreamerError> {
+        self.connection.write(packet)?;
+        match packet {
+            VariablePacket::PublishPacket(publish) => self.in_flight.add(publish),
+            VariablePacket::PubrelPacket(pubrel) => self.in_flight.release(pubrel.packet_identifier()),
+            VariablePacket::SubscribePacket(subscribe) => self.in_flight.subscriptions.push_back(subscribe.clone()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn awaits_subscription(&self, publish: &PublishPacket) -> bool {
+        match self.dependencies.and_then(|dependencies| dependencies(publish.topic_name())) {
+            Some(topic_filter) => self.in_flight.subscribes_to(topic_filter),
+            None => false,
+        }
+    }
+
+    /// Writes the held publications whose subscriptions were acknowledged, in order.
+    /// Those which don't fit the tx buffer are written by a later call.
+    fn write_released(&mut self) -> Result<(), StreamerError> {
+        let mut index = 0;
+        while index < self.held.len() {
+            if self.awaits_subscription(&self.held[index]) {
+                index += 1;
+                continue;
+            }
+            match self.connection.write(&self.held[index].clone().into()) {
+                Ok(()) => {
+                    let _ = self.held.remove(index);
+                }
+                Err(StreamerError::BufferFull { .. }) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
 
-    pub fn read(&mut self) -> std::io::Result<Option<VariablePacket>> {}
+    /// Reads the next packet from the rx buffer, if any. Acknowledged publications are no longer tracked.
+    /// The QoS2 handshake is left to the caller, which must answer a PUBREC with a PUBREL.
+    pub fn read(&mut self) -> std::io::Result<Option<VariablePacket>> {
+        let packet = self.connection.read()?;
+        match &packet {
+            Some(VariablePacket::PubackPacket(puback)) => self.in_flight.acknowledge(puback.packet_identifier()),
+            Some(VariablePacket::PubcompPacket(pubcomp)) => self.in_flight.complete(pubcomp.packet_identifier()),
+            Some(VariablePacket::SubackPacket(suback)) => {
+                self.in_flight.subscribed(suback.packet_identifier());
+                self.write_released()?;
+            }
+            _ => {}
+        }
+        Ok(packet)
+    }
+
+    /// See `MqttConnection::send_task`
+    pub fn send_task(&mut self, timeout: Duration) -> std::io::Result<usize> {
+        self.write_released()?;
+        self.connection.send_task(timeout)
+    }
+
+    /// See `MqttConnection::flush`
+    pub fn flush(&mut self, timeout: Duration) -> std::io::Result<()> {
+        self.connection.flush(timeout)
+    }
+
+    /// See `MqttConnection::recv_task`
+    pub fn recv_task(&mut self, timeout: Duration) -> std::io::Result<Option<VariablePacket>> {
+        self.connection.recv_task(timeout)
+    }
+
+    /// The IDs of the publications not acknowledged yet: QoS1 and QoS2 ones oldest first, then released QoS2 ones
+    pub fn unacknowledged(&self) -> Vec<u16> {
+        self.in_flight.packet_ids()
+    }
+
+    pub fn connection(&self) -> &MqttConnection<S> {
+        &self.connection
+    }
+
+    pub fn connection_mut(&mut self) -> &mut MqttConnection<S> {
+        &mut self.connection
+    }
 }
 
-struct InFlightPackets {
-    packets: std::collections::vec_deque::VecDeque<VariablePacket>,
+/// QoS1 publications awaiting a PUBACK and QoS2 ones awaiting a PUBREC, in the order they were written,
+/// the QoS2 publications released since, awaiting a PUBCOMP, and the subscriptions awaiting a SUBACK
+#[derive(Debug, Default)]
+pub struct InFlightPackets {
+    packets: VecDeque<PublishPacket>,
+    released: VecDeque<u16>,
+    subscriptions: VecDeque<SubscribePacket>,
 }
 
 impl InFlightPackets {
-    fn add(&mut self, packet: VariablePacket) {
-        self.packets.push_back()
+    fn subscribed(&mut self, packet_id: u16) {
+        self.subscriptions.retain(|subscribe| subscribe.packet_identifier() != packet_id);
+    }
+
+    fn subscribes_to(&self, topic_filter: &str) -> bool {
+        self.subscriptions.iter().any(|subscribe| {
+            subscribe.payload_ref().subscribes().iter().any(|(filter, _)| {
+                let filter: &str = filter;
+                filter == topic_filter
+            })
+        })
+    }
+
+    fn add(&mut self, publish: &PublishPacket) {
+        if Self::packet_id(publish).is_some() {
+            self.packets.push_back(publish.clone());
+        }
+    }
+
+    fn acknowledge(&mut self, packet_id: u16) {
+        self.packets.retain(|publish| Self::packet_id(publish) != Some(packet_id));
+    }
+
+    fn release(&mut self, packet_id: u16) {
+        self.acknowledge(packet_id);
+        if !self.released.contains(&packet_id) {
+            self.released.push_back(packet_id);
+        }
+    }
+
+    fn complete(&mut self, packet_id: u16) {
+        self.released.retain(|released| *released != packet_id);
+    }
+
+    fn packet_ids(&self) -> Vec<u16> {
+        self.packets
+            .iter()
+            .filter_map(Self::packet_id)
+            .chain(self.released.iter().copied())
+            .collect()
+    }
+
+    fn packet_id(publish: &PublishPacket) -> Option<u16> {
+        match publish.qos() {
+            QoSWithPacketIdentifier::Level1(packet_id) | QoSWithPacketIdentifier::Level2(packet_id) => Some(packet_id),
+            QoSWithPacketIdentifier::Level0 => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len() + self.released.len() + self.subscriptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty() && self.released.is_empty() && self.subscriptions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{MqttConnectError, MqttConnector};
+    use mqtt::{Encodable, QualityOfService, TopicFilter, TopicName};
+    use raiot_test_utils::hub::{is_duplicate, MockHub, PubackAction};
+    use raiot_test_utils::{MockClientSocket, MockSocket};
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn connect() -> (MqttConnection<MockClientSocket>, MockHub) {
+        let (client_socket, server_socket) = MockSocket::create();
+        let mut hub = MockHub::new(server_socket);
+        let mut sut = MqttConnector::create(client_socket)
+            .connect(ConnectPacket::new("clientid"))
+            .unwrap();
+        loop {
+            match sut.complete() {
+                Ok(connection) => return (connection, hub),
+                Err(MqttConnectError::WouldBlock(in_progress)) => {
+                    hub.process();
+                    sut = *in_progress;
+                }
+                Err(_) => panic!("connection failed"),
+            }
+        }
+    }
+
+    fn publish(packet_id: u16) -> VariablePacket {
+        publish_with_qos(QoSWithPacketIdentifier::Level1(packet_id))
+    }
+
+    fn publish_with_qos(qos: QoSWithPacketIdentifier) -> VariablePacket {
+        PublishPacket::new(TopicName::new("devices/d1/messages/events/").unwrap(), qos, "").into()
+    }
+
+    fn exchange(sut: &mut MqttSession<MockClientSocket>, hub: &mut MockHub) {
+        let _ = sut.send_task(TIMEOUT).unwrap();
+        hub.process();
+        let _ = sut.recv_task(TIMEOUT).unwrap();
+        while sut.read().unwrap().is_some() {}
+    }
+
+    #[test]
+    fn test_unacknowledged_publications_are_replayed_on_resume() {
+        let (connection, mut hub) = connect();
+        let mut sut = MqttSession::new(connection);
+        hub.push_puback_action(PubackAction::Withhold);
+        sut.write(&publish(1)).unwrap();
+        sut.write(&publish(2)).unwrap();
+        exchange(&mut sut, &mut hub);
+        assert_eq!(sut.unacknowledged(), vec![1]);
+
+        let (connection, mut hub) = connect();
+        let mut sut = MqttSession::resume(connection, sut.into_in_flight()).unwrap();
+        exchange(&mut sut, &mut hub);
+
+        assert_eq!(hub.received().len(), 1);
+        assert!(is_duplicate(&hub.received()[0]));
+        assert!(sut.unacknowledged().is_empty());
+    }
+
+    #[test]
+    fn test_released_publications_are_released_again_on_resume() {
+        let (connection, mut hub) = connect();
+        let mut sut = MqttSession::new(connection);
+        sut.write(&publish_with_qos(QoSWithPacketIdentifier::Level2(1))).unwrap();
+        let _ = sut.send_task(TIMEOUT).unwrap();
+        hub.process();
+        let _ = sut.recv_task(TIMEOUT).unwrap();
+        assert!(matches!(sut.read().unwrap(), Some(VariablePacket::PubrecPacket(_))));
+        sut.write(&PubrelPacket::new(1).into()).unwrap();
+        // the connection is lost before the PUBREL is sent
+        assert_eq!(sut.unacknowledged(), vec![1]);
+
+        let (connection, mut hub) = connect();
+        let mut sut = MqttSession::resume(connection, sut.into_in_flight()).unwrap();
+        exchange(&mut sut, &mut hub);
+
+        // the publication is not delivered twice
+        assert!(hub.received().is_empty());
+        assert!(sut.unacknowledged().is_empty());
+    }
+
+    #[test]
+    fn test_replayed_publications_overflowing_the_buffer_are_held() {
+        let (connection, mut hub) = connect();
+        let mut previous = MqttSession::new(connection);
+        hub.push_puback_action(PubackAction::Withhold);
+        hub.push_puback_action(PubackAction::Withhold);
+        previous.write(&publish(1)).unwrap();
+        previous.write(&publish(2)).unwrap();
+        exchange(&mut previous, &mut hub);
+        assert_eq!(previous.unacknowledged(), vec![1, 2]);
+
+        let (client_socket, server_socket) = MockSocket::create();
+        let mut hub = MockHub::new(server_socket);
+        // room for the CONNECT, then a single publication
+        let tx_buffer = ConnectPacket::new("clientid").encoded_length() + publish(1).encoded_length();
+        let mut in_progress = MqttConnector::create(client_socket)
+            .with_tx_buffer(tx_buffer as usize)
+            .connect(ConnectPacket::new("clientid"))
+            .unwrap();
+        let connection = loop {
+            match in_progress.complete() {
+                Ok(connection) => break connection,
+                Err(MqttConnectError::WouldBlock(next)) => {
+                    hub.process();
+                    in_progress = *next;
+                }
+                Err(_) => panic!("connection failed"),
+            }
+        };
+        let mut sut = MqttSession::resume(connection, previous.into_in_flight()).unwrap();
+        exchange(&mut sut, &mut hub);
+        exchange(&mut sut, &mut hub);
+
+        assert_eq!(hub.received().len(), 2);
+        assert!(hub.received().iter().all(is_duplicate));
+        assert!(sut.unacknowledged().is_empty());
+    }
+
+    fn twin_dependencies(topic_name: &str) -> Option<&'static str> {
+        match topic_name.starts_with("$iothub/twin/GET/") {
+            true => Some("$iothub/twin/res/#"),
+            false => None,
+        }
+    }
+
+    #[test]
+    fn test_replayed_publications_wait_for_their_subscriptions() {
+        let (connection, _) = connect();
+        let mut previous = MqttSession::new(connection);
+        let filter = (TopicFilter::new("$iothub/twin/res/#").unwrap(), QualityOfService::Level0);
+        previous.write(&SubscribePacket::new(1, vec![filter]).into()).unwrap();
+        let twin_get = PublishPacket::new(
+            TopicName::new("$iothub/twin/GET/?$rid=1").unwrap(),
+            QoSWithPacketIdentifier::Level1(2),
+            "",
+        );
+        previous.write(&twin_get.into()).unwrap();
+        // the connection is lost before anything is sent
+        assert_eq!(previous.unacknowledged(), vec![2]);
+
+        let (connection, mut hub) = connect();
+        let mut sut = MqttSession::new(connection).with_dependencies(twin_dependencies);
+        sut.replay(previous.into_in_flight()).unwrap();
+        let _ = sut.send_task(TIMEOUT).unwrap();
+        hub.process();
+        // only the subscription was sent
+        assert!(hub.received().is_empty());
+
+        let _ = sut.recv_task(TIMEOUT).unwrap();
+        assert!(matches!(sut.read().unwrap(), Some(VariablePacket::SubackPacket(_))));
+        exchange(&mut sut, &mut hub);
+        assert_eq!(hub.received().len(), 1);
+        assert_eq!(hub.received()[0].topic_name(), "$iothub/twin/GET/?$rid=1");
+        assert!(sut.unacknowledged().is_empty());
     }
 }
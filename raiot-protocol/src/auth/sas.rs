@@ -1,9 +1,9 @@
-use chrono::prelude::*;
 use hmac::{Hmac, Mac};
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use sha2::Sha256;
 use std::error::Error;
-use std::time::Duration;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use url::form_urlencoded::byte_serialize;
 
 // TODO proper URL encoding of device and module IDs
@@ -11,22 +11,63 @@ use url::form_urlencoded::byte_serialize;
 
 type TokenResult = Result<SasToken, Box<dyn Error>>;
 
+/// The shortest TTL a SAS token can be generated with
+pub const MIN_TOKEN_TTL: Duration = Duration::from_secs(1);
+
+/// The longest TTL a SAS token can be generated with. Longer-lived tokens are almost certainly a unit mistake.
+pub const MAX_TOKEN_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Why a SAS token could not be generated for a TTL
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TokenTtlError {
+    /// The TTL is shorter than `MIN_TOKEN_TTL`
+    TooShort(Duration),
+    /// The TTL is longer than `MAX_TOKEN_TTL`
+    TooLong(Duration),
+}
+
+impl fmt::Display for TokenTtlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenTtlError::TooShort(ttl) => write!(f, "SAS token TTL {:?} is shorter than {:?}", ttl, MIN_TOKEN_TTL),
+            TokenTtlError::TooLong(ttl) => write!(f, "SAS token TTL {:?} is longer than {:?}", ttl, MAX_TOKEN_TTL),
+        }
+    }
+}
+
+impl Error for TokenTtlError {}
+
 /// Represents a single SAS token of a device or module
 #[derive(Clone, Debug)]
 pub struct SasToken {
     value: String,
+    expiry: SystemTime,
 }
 
 impl SasToken {
     /// Generates a SAS token for a device connection
+    ///
+    /// # Errors
+    /// Returns an error if the key is not valid base64 or the TTL is out of `MIN_TOKEN_TTL..=MAX_TOKEN_TTL`
     pub fn for_device(server_addr: &str, device_id: &str, key: &str, ttl: Duration) -> TokenResult {
-        assert!(ttl.as_secs() > 0);
-        let encoded_device_id = utf8_percent_encode(&device_id, NON_ALPHANUMERIC).to_string();
+        SasToken::for_device_at(server_addr, device_id, key, ttl, SystemTime::now())
+    }
+
+    /// Generates a SAS token for a device connection as if generated at the specified time,
+    /// e.g. the local time corrected for the drift of the device clock
+    ///
+    /// # Errors
+    /// Returns an error if the key is not valid base64 or the TTL is out of `MIN_TOKEN_TTL..=MAX_TOKEN_TTL`
+    pub fn for_device_at(server_addr: &str, device_id: &str, key: &str, ttl: Duration, now: SystemTime) -> TokenResult {
+        let encoded_device_id = utf8_percent_encode(device_id, NON_ALPHANUMERIC).to_string();
         let resource_uri = format!("{}/devices/{}", &server_addr, &encoded_device_id);
-        get_sas_token(&key, &resource_uri, ttl)
+        get_sas_token(key, &resource_uri, ttl, now)
     }
 
     /// Generates a SAS token for a device module connection
+    ///
+    /// # Errors
+    /// Returns an error if the key is not valid base64 or the TTL is out of `MIN_TOKEN_TTL..=MAX_TOKEN_TTL`
     pub fn for_module(
         server_addr: &str,
         device_id: &str,
@@ -34,16 +75,61 @@ impl SasToken {
         key: &str,
         ttl: Duration,
     ) -> TokenResult {
-        assert!(ttl.as_secs() > 0);
+        SasToken::for_module_at(server_addr, device_id, module_id, key, ttl, SystemTime::now())
+    }
 
-        let encoded_device_id = utf8_percent_encode(&device_id, NON_ALPHANUMERIC).to_string();
-        let encoded_module_id = utf8_percent_encode(&module_id, NON_ALPHANUMERIC).to_string();
+    /// Generates a SAS token for a device module connection as if generated at the specified time,
+    /// e.g. the local time corrected for the drift of the device clock
+    ///
+    /// # Errors
+    /// Returns an error if the key is not valid base64 or the TTL is out of `MIN_TOKEN_TTL..=MAX_TOKEN_TTL`
+    pub fn for_module_at(
+        server_addr: &str,
+        device_id: &str,
+        module_id: &str,
+        key: &str,
+        ttl: Duration,
+        now: SystemTime,
+    ) -> TokenResult {
+        let encoded_device_id = utf8_percent_encode(device_id, NON_ALPHANUMERIC).to_string();
+        let encoded_module_id = utf8_percent_encode(module_id, NON_ALPHANUMERIC).to_string();
         let resource_uri = format!(
             "{}/devices/{}/modules/{}",
             &server_addr, &encoded_device_id, &encoded_module_id
         );
-        get_sas_token(&key, &resource_uri, ttl)
+        get_sas_token(key, &resource_uri, ttl, now)
+    }
+
+    /// Generates a SAS token for registering a device with the Device Provisioning Service,
+    /// signed with the key of its individual enrollment (or derived from its group enrollment, see `derive_device_key`)
+    ///
+    /// # Errors
+    /// Returns an error if the key is not valid base64 or the TTL is out of `MIN_TOKEN_TTL..=MAX_TOKEN_TTL`
+    pub fn for_registration(id_scope: &str, registration_id: &str, key: &str, ttl: Duration) -> TokenResult {
+        let resource_uri = format!("{}/registrations/{}", id_scope, registration_id);
+        let mut token = get_sas_token(key, &resource_uri, ttl, SystemTime::now())?;
+        token.value.push_str("&skn=registration");
+        Ok(token)
     }
+
+    /// The instant the hub stops accepting the token. The expiry of a token is carried in whole seconds,
+    /// so this is the requested TTL rounded up to the next second: the token is never shorter-lived than asked.
+    pub fn expiry(&self) -> SystemTime {
+        self.expiry
+    }
+}
+
+/// Derives the key of a device from the key of its enrollment group, as done by DPS group enrollments
+/// and by IoT Central, whose applications hand out a group SAS key rather than per-device keys.
+///
+/// # Errors
+/// Returns an error if the group key is not valid base64
+pub fn derive_device_key(group_key: &str, registration_id: &str) -> Result<String, base64::DecodeError> {
+    type HmacSha256 = Hmac<Sha256>;
+    let group_key = base64::decode(group_key)?;
+    let mut mac = HmacSha256::new_varkey(&group_key).expect("HMAC can take key of any size");
+    mac.input(registration_id.as_bytes());
+    Ok(base64::encode(&mac.result().code()))
 }
 
 impl From<SasToken> for String {
@@ -52,12 +138,24 @@ impl From<SasToken> for String {
     }
 }
 
-fn get_sas_token(key: &str, resource_uri: &str, ttl: Duration) -> TokenResult {
+/// Seconds since the epoch at which a token generated at `now` with the TTL expires, rounded up
+fn expiry_timestamp(now: SystemTime, ttl: Duration) -> Result<u64, TokenTtlError> {
+    if ttl < MIN_TOKEN_TTL {
+        return Err(TokenTtlError::TooShort(ttl));
+    }
+    if ttl > MAX_TOKEN_TTL {
+        return Err(TokenTtlError::TooLong(ttl));
+    }
+    let expiry = now.duration_since(UNIX_EPOCH).unwrap_or_default() + ttl;
+    Ok(expiry.as_secs() + u64::from(expiry.subsec_nanos() > 0))
+}
+
+fn get_sas_token(key: &str, resource_uri: &str, ttl: Duration, now: SystemTime) -> TokenResult {
     type HmacSha256 = Hmac<Sha256>;
+    let expiry = expiry_timestamp(now, ttl)?;
     let key = base64::decode(key)?;
-    let expiry: DateTime<Utc> = Utc::now() + chrono::Duration::from_std(ttl).unwrap();
     let encoded_uri: String = byte_serialize(resource_uri.as_bytes()).collect();
-    let string_to_sign = format!("{}\n{}", encoded_uri, &expiry.timestamp().to_string());
+    let string_to_sign = format!("{}\n{}", encoded_uri, expiry);
     let mut mac = HmacSha256::new_varkey(&key).expect("HMAC can take key of any size");
     mac.input(string_to_sign.as_bytes());
     let hash = mac.result().code();
@@ -67,7 +165,50 @@ fn get_sas_token(key: &str, resource_uri: &str, ttl: Duration) -> TokenResult {
         "SharedAccessSignature sr={}&sig={}&se={}",
         encoded_uri,
         encoded_signature,
-        expiry.timestamp()
+        expiry
     );
-    return Ok(SasToken { value: token });
+    Ok(SasToken {
+        value: token,
+        expiry: UNIX_EPOCH + Duration::from_secs(expiry),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_key_is_derived_from_group_key() {
+        let group_key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+        let key = derive_device_key(group_key, "device-1").unwrap();
+        assert_eq!(key, "RMt/vH6618dIqXOfPQmgeanMrOxw2/w49k23Foj+FWg=");
+        assert!(derive_device_key("not base64!", "device-1").is_err());
+    }
+
+    #[test]
+    fn test_token_expiry_is_rounded_up_to_whole_seconds() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_000_250);
+        assert_eq!(expiry_timestamp(now, Duration::from_millis(1500)), Ok(1002));
+        assert_eq!(expiry_timestamp(now, Duration::from_millis(750)), Err(TokenTtlError::TooShort(Duration::from_millis(750))));
+        assert_eq!(expiry_timestamp(UNIX_EPOCH, Duration::from_secs(30)), Ok(30));
+        assert!(expiry_timestamp(now, MAX_TOKEN_TTL + Duration::from_secs(1)).is_err());
+
+        let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+        let before = SystemTime::now();
+        let token = SasToken::for_device("hub.azure-devices.net", "device-1", key, Duration::from_secs(20)).unwrap();
+        assert!(token.expiry() >= before + Duration::from_secs(20));
+        assert!(token.expiry() <= before + Duration::from_secs(22));
+        let se = token.expiry().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(String::from(token).ends_with(&format!("&se={}", se)));
+        assert!(SasToken::for_device("hub.azure-devices.net", "device-1", key, Duration::ZERO).is_err());
+
+        let skewed = SystemTime::now() + Duration::from_secs(600);
+        let token = SasToken::for_device_at("hub.azure-devices.net", "device-1", key, Duration::from_secs(20), skewed);
+        assert!(token.unwrap().expiry() >= skewed + Duration::from_secs(20));
+
+        let token = SasToken::for_registration("0ne0001", "device-1", key, Duration::from_secs(20)).unwrap();
+        let token = String::from(token);
+        assert!(token.starts_with("SharedAccessSignature sr=0ne0001%2Fregistrations%2Fdevice-1&sig="));
+        assert!(token.ends_with("&skn=registration"));
+    }
 }
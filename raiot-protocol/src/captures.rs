@@ -0,0 +1,203 @@
+//! Conformance of the decoder with packets shaped like those IoT Hub sends, kept under `captures/` byte for byte.
+//! The packets are synthetic (see `captures/README.md`). Every capture must decode to a hub message;
+//! the tests below pin down what each one decodes to.
+
+use crate::c2d::C2DMsg;
+use crate::connect::ConnectError;
+use crate::twin::StatusCode;
+use crate::{IotCodec, MsgFromHub, SubError};
+use mqtt::packet::VariablePacket;
+use mqtt::Decodable;
+use serde_json::json;
+
+macro_rules! capture {
+    ($name:literal) => {
+        // coerced to a slice by the type of CAPTURES: slicing isn't allowed in a constant
+        ($name, include_bytes!(concat!("../captures/", $name, ".bin")))
+    };
+}
+
+/// Every capture, by name
+const CAPTURES: &[(&str, &[u8])] = &[
+    capture!("connack_accepted"),
+    capture!("connack_session_present"),
+    capture!("connack_identifier_rejected"),
+    capture!("connack_server_unavailable"),
+    capture!("connack_bad_credentials"),
+    capture!("connack_not_authorized"),
+    capture!("suback_granted"),
+    capture!("suback_partially_rejected"),
+    capture!("puback"),
+    capture!("unsuback"),
+    capture!("twin_get_200"),
+    capture!("twin_patch_204"),
+    capture!("twin_throttled_429"),
+    capture!("twin_bad_request_400"),
+    capture!("twin_not_found_404"),
+    capture!("twin_server_error_500"),
+    capture!("twin_gateway_timeout_504"),
+    capture!("twin_desired_patch"),
+    capture!("c2d_bare"),
+    capture!("c2d_system_properties"),
+    capture!("c2d_escaped_properties"),
+    capture!("c2d_at_most_once"),
+    capture!("module_input"),
+    capture!("method_invocation"),
+    capture!("method_escaped_name"),
+];
+
+fn decode(name: &str) -> MsgFromHub {
+    let (_, mut bytes) = *CAPTURES
+        .iter()
+        .find(|(capture, _)| *capture == name)
+        .unwrap_or_else(|| panic!("no capture named {}", name));
+    let packet = VariablePacket::decode(&mut bytes).unwrap_or_else(|e| panic!("{} is not MQTT: {:?}", name, e));
+    assert!(bytes.is_empty(), "{} has trailing bytes", name);
+    IotCodec::decode_packet(packet).unwrap_or_else(|e| panic!("{} failed to decode: {:?}", name, e))
+}
+
+fn decode_c2d(name: &str) -> C2DMsg {
+    match decode(name) {
+        MsgFromHub::CloudToDeviceMessage(msg) => msg,
+        other => panic!("{} decoded to {:?}", name, other),
+    }
+}
+
+fn twin_status(name: &str) -> StatusCode {
+    match decode(name) {
+        MsgFromHub::TwinResponseMessage(res) => res.status_code,
+        other => panic!("{} decoded to {:?}", name, other),
+    }
+}
+
+#[test]
+fn test_every_capture_decodes_to_a_hub_message() {
+    for (name, _) in CAPTURES {
+        if let MsgFromHub::UnknownMessage() = decode(name) {
+            panic!("{} is not recognized as a hub message", name);
+        }
+    }
+}
+
+#[test]
+fn test_connack_variants() {
+    let connect_result = |name| match decode(name) {
+        MsgFromHub::ConnectResponseMessage(res) => res,
+        other => panic!("{} decoded to {:?}", name, other),
+    };
+    assert!(!connect_result("connack_accepted").unwrap().session_present);
+    assert!(connect_result("connack_session_present").unwrap().session_present);
+    assert!(matches!(
+        connect_result("connack_identifier_rejected"),
+        Err(ConnectError::AuthenticationFailed)
+    ));
+    assert!(matches!(
+        connect_result("connack_server_unavailable"),
+        Err(ConnectError::ServiceUnavailable)
+    ));
+    assert!(matches!(
+        connect_result("connack_bad_credentials"),
+        Err(ConnectError::AuthenticationFailed)
+    ));
+    assert!(matches!(connect_result("connack_not_authorized"), Err(ConnectError::Unauthorized)));
+}
+
+#[test]
+fn test_acknowledgements() {
+    match decode("suback_partially_rejected") {
+        MsgFromHub::SubscriptionResponseMessage(res) => {
+            assert_eq!(res.return_codes, vec![0, 1, 0x80]);
+            assert!(matches!(res.result, Err(SubError::Failure { return_code: 0x80, .. })));
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+    assert!(matches!(decode("suback_granted"), MsgFromHub::SubscriptionResponseMessage(res) if res.result.is_ok()));
+    assert!(matches!(decode("puback"), MsgFromHub::PublicationSucceeded(packet_id) if packet_id == 7.into()));
+    assert!(matches!(decode("unsuback"), MsgFromHub::UnsubscriptionSucceeded(packet_id) if packet_id == 8.into()));
+}
+
+#[test]
+fn test_twin_response_status_codes() {
+    match decode("twin_get_200") {
+        MsgFromHub::TwinResponseMessage(res) => {
+            assert!(matches!(res.status_code, StatusCode::OK()));
+            assert_eq!(res.request_id, "1");
+            assert_eq!(res.desired().unwrap()["telemetryInterval"], json!(30));
+            assert_eq!(res.reported().unwrap()["$version"], json!(7));
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+    match decode("twin_patch_204") {
+        MsgFromHub::TwinResponseMessage(res) => {
+            assert!(matches!(res.status_code, StatusCode::NoContent()));
+            assert_eq!(res.version, Some(8));
+            assert!(res.body.is_none());
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+    assert!(matches!(twin_status("twin_throttled_429"), StatusCode::TooManyRequests()));
+    assert!(matches!(twin_status("twin_bad_request_400"), StatusCode::BadRequest()));
+    assert!(matches!(twin_status("twin_not_found_404"), StatusCode::UnknownStatusCode(404)));
+    assert!(matches!(twin_status("twin_server_error_500"), StatusCode::ServerError(500)));
+    assert!(twin_status("twin_gateway_timeout_504").is_transient());
+
+    match decode("twin_desired_patch") {
+        MsgFromHub::DesiredPropertiesUpdated(update) => {
+            assert_eq!(update.desired_properties_version, 12);
+            assert_eq!(update.body["telemetryInterval"], json!(60));
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
+
+#[test]
+fn test_c2d_property_encodings() {
+    let bare = decode_c2d("c2d_bare");
+    assert_eq!(bare.body.as_deref(), Some("ping"));
+    assert!(bare.props.unwrap().is_empty());
+
+    let msg = decode_c2d("c2d_system_properties");
+    let props = msg.props.as_ref().unwrap();
+    assert_eq!(props["$.mid"], "3c1f5a2e-8d7b-4e19-a0c6-52b9d4f7e013");
+    assert_eq!(props["$.to"], "/devices/device1/messages/deviceBound");
+    assert_eq!(props["$.ct"], "application/json");
+    assert_eq!(props["iothub-ack"], "full");
+    assert!(msg.expiry_time().is_some());
+
+    let props = decode_c2d("c2d_escaped_properties").props.unwrap();
+    assert_eq!(props["k=1"], "v&w");
+    assert_eq!(props["note"], "two words");
+    assert_eq!(props["place"], "café");
+    assert_eq!(props["flag"], "");
+    assert_eq!(props["empty"], "");
+
+    let msg = decode_c2d("c2d_at_most_once");
+    assert!(msg.packet_id.is_none());
+    assert_eq!(msg.body.as_deref(), Some("fire and forget"));
+}
+
+#[test]
+fn test_module_inputs_and_method_invocations() {
+    match decode("module_input") {
+        MsgFromHub::ModuleInputMessage(msg) => {
+            assert_eq!(msg.input_name, "input1");
+            assert_eq!(msg.props.unwrap()["$.cdid"], "device2");
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+    match decode("method_invocation") {
+        MsgFromHub::DirectMethodInvocation(dmi) => {
+            assert_eq!(dmi.method_name, "reboot");
+            assert_eq!(dmi.body, Some(json!({ "delaySeconds": 5 })));
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+    match decode("method_escaped_name") {
+        MsgFromHub::DirectMethodInvocation(dmi) => {
+            assert_eq!(dmi.method_name, "set mode");
+            assert_eq!(dmi.request_id, "2");
+            assert!(dmi.body.is_none());
+        }
+        other => panic!("unexpected message: {:?}", other),
+    }
+}
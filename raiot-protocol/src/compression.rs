@@ -0,0 +1,102 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Compresses serialized payloads, announcing the result with a content encoding (e.g. "gzip")
+pub trait PayloadCompressor: Debug + Send + Sync {
+    /// The content encoding of the compressed payloads
+    fn content_encoding(&self) -> &str;
+
+    /// Compresses a serialized payload
+    fn compress(&self, payload: &[u8]) -> Vec<u8>;
+}
+
+/// Compresses payloads of at least `threshold` bytes, leaving smaller payloads (which would hardly shrink) as they are.
+/// A compressed payload which is not smaller than the original one is dropped in favor of the original.
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    /// The compression applied to large payloads
+    pub compressor: Arc<dyn PayloadCompressor>,
+
+    /// The size, in bytes, from which payloads are compressed
+    pub threshold: usize,
+}
+
+impl CompressionPolicy {
+    /// Creates a policy compressing payloads of at least `threshold` bytes
+    pub fn new(compressor: Arc<dyn PayloadCompressor>, threshold: usize) -> CompressionPolicy {
+        CompressionPolicy { compressor, threshold }
+    }
+
+    /// Compresses the payload if the policy calls for it.
+    /// Returns the payload to send, and its content encoding if it was compressed.
+    pub fn apply(&self, payload: Vec<u8>) -> (Vec<u8>, Option<&str>) {
+        if payload.len() < self.threshold {
+            return (payload, None);
+        }
+        let compressed = self.compressor.compress(&payload);
+        match compressed.len() < payload.len() {
+            true => (compressed, Some(self.compressor.content_encoding())),
+            false => (payload, None),
+        }
+    }
+}
+
+/// Compresses payloads with gzip (RFC 1952)
+#[cfg(feature = "gzip")]
+#[derive(Debug, Clone, Copy)]
+pub struct GzipCompressor {
+    level: u32,
+}
+
+#[cfg(feature = "gzip")]
+impl GzipCompressor {
+    /// Creates a compressor with the specified level, from 0 (no compression) to 9 (best compression)
+    pub fn new(level: u32) -> GzipCompressor {
+        GzipCompressor { level: level.min(9) }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Default for GzipCompressor {
+    fn default() -> Self {
+        GzipCompressor::new(6)
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl PayloadCompressor for GzipCompressor {
+    fn content_encoding(&self) -> &str {
+        "gzip"
+    }
+
+    fn compress(&self, payload: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(self.level));
+        encoder.write_all(payload).expect("Writing to memory does not fail");
+        encoder.finish().expect("Writing to memory does not fail")
+    }
+}
+
+#[cfg(all(test, feature = "gzip"))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_only_large_payloads_are_compressed() {
+        let sut = CompressionPolicy::new(Arc::new(GzipCompressor::default()), 64);
+
+        let (payload, encoding) = sut.apply(br#"{"a":1}"#.to_vec());
+        assert_eq!(payload, br#"{"a":1}"#.to_vec());
+        assert_eq!(encoding, None);
+
+        let large = format!(r#"{{"readings":[{}]}}"#, vec!["20.5"; 100].join(",")).into_bytes();
+        let (payload, encoding) = sut.apply(large.clone());
+        assert_eq!(encoding, Some("gzip"));
+        assert!(payload.len() < large.len());
+
+        let mut decompressed = Vec::new();
+        let _ = flate2::read::GzDecoder::new(&payload[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, large);
+    }
+}
@@ -0,0 +1,111 @@
+use mqtt::packet::VariablePacket;
+use std::fmt;
+
+use crate::messages::MsgToHub;
+
+/// The longest topic name accepted in strict mode. Telemetry properties are carried in the topic name,
+/// so this bounds the property bag of a message.
+pub const MAX_TOPIC_LEN: usize = 8 * 1024;
+
+/// The system properties a client may set. Any other property name starting with `$` is reserved.
+pub const SYSTEM_PROPERTIES: &[&str] = &[
+    "$.mid", "$.cid", "$.uid", "$.ct", "$.ce", "$.exp", "$.to", "$.diagid", "$.diagctx", "$.on",
+];
+
+/// The characters allowed in property names, besides ASCII letters and digits
+const PROPERTY_NAME_SYMBOLS: &str = "!#$%&'*+-.^_`|~";
+
+/// A message constraint of IoT Hub violated by a message, detected by the codec in strict mode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// A property name is empty, or contains characters IoT Hub does not accept
+    InvalidPropertyName,
+
+    /// A property name starts with `$`, which is reserved for the system properties and twin metadata
+    ReservedPropertyName,
+
+    /// The topic name is longer than `MAX_TOPIC_LEN`
+    TopicTooLong,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::InvalidPropertyName => write!(f, "Invalid property name"),
+            Violation::ReservedPropertyName => write!(f, "Reserved property name"),
+            Violation::TopicTooLong => write!(f, "Topic name longer than {} bytes", MAX_TOPIC_LEN),
+        }
+    }
+}
+
+/// Checks the name of a telemetry property: system properties are allowed, other names starting with `$` are not
+///
+/// # Errors
+/// Returns the violated constraint
+pub fn check_property_name(name: &str) -> Result<(), Violation> {
+    if name.starts_with('$') {
+        return match SYSTEM_PROPERTIES.contains(&name) {
+            true => Ok(()),
+            false => Err(Violation::ReservedPropertyName),
+        };
+    }
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || PROPERTY_NAME_SYMBOLS.contains(c));
+    match valid {
+        true => Ok(()),
+        false => Err(Violation::InvalidPropertyName),
+    }
+}
+
+/// Checks the properties of a message before it is encoded
+///
+/// # Errors
+/// Returns the first violated constraint
+pub fn check_message(message: &MsgToHub) -> Result<(), Violation> {
+    match message {
+        #[cfg(feature = "telemetry")]
+        MsgToHub::Telemetry(msg) => msg
+            .headers
+            .iter()
+            .flat_map(|headers| headers.keys())
+            .try_for_each(|name| check_property_name(name)),
+
+        #[cfg(feature = "twin")]
+        MsgToHub::UpdateReportedProperties(msg) => match msg.reported.keys().any(|name| name.starts_with('$')) {
+            true => Err(Violation::ReservedPropertyName),
+            false => Ok(()),
+        },
+
+        _ => Ok(()),
+    }
+}
+
+/// Checks an encoded message
+///
+/// # Errors
+/// Returns the violated constraint
+pub fn check_packet(packet: &VariablePacket) -> Result<(), Violation> {
+    match packet {
+        VariablePacket::PublishPacket(publish) if publish.topic_name().len() > MAX_TOPIC_LEN => {
+            Err(Violation::TopicTooLong)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_property_names() {
+        assert_eq!(check_property_name("temperature-unit"), Ok(()));
+        assert_eq!(check_property_name("$.ct"), Ok(()));
+        assert_eq!(check_property_name("$.custom"), Err(Violation::ReservedPropertyName));
+        assert_eq!(check_property_name(""), Err(Violation::InvalidPropertyName));
+        assert_eq!(check_property_name("unit (si)"), Err(Violation::InvalidPropertyName));
+        assert_eq!(check_property_name("température"), Err(Violation::InvalidPropertyName));
+    }
+}
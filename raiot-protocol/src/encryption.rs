@@ -0,0 +1,131 @@
+use mqtt::packet::{Packet, PublishPacket};
+use mqtt::TopicName;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::fmt::{self, Debug};
+
+use crate::query;
+use crate::topics::HubTopic;
+
+/// The message property advertising the ID of the key an encrypted payload can be decrypted with
+pub const KEY_ID_PROPERTY: &str = "encryption-key-id";
+
+/// Encrypts and decrypts message payloads on top of TLS, for applications required to encrypt end-to-end.
+/// Applied to telemetry after encoding and to C2D messages before decoding.
+pub trait PayloadCipher: Debug + Send + Sync {
+    /// The ID of the key new payloads are encrypted with
+    fn key_id(&self) -> &str;
+
+    /// Encrypts a payload with the current key
+    fn encrypt(&self, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Decrypts a payload encrypted with the specified key
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>, CipherError>;
+}
+
+/// A payload that could not be decrypted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CipherError {
+    /// The cipher does not have the key the payload was encrypted with
+    UnknownKey(String),
+
+    /// The payload is corrupt or was not encrypted with the advertised key
+    InvalidCiphertext,
+}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherError::UnknownKey(key_id) => write!(f, "Unknown encryption key: {}", key_id),
+            CipherError::InvalidCiphertext => write!(f, "Invalid ciphertext"),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+/// Encrypts the payload of an encoded publication, adding the key ID to its property bag
+pub fn encrypt_publish(packet: &PublishPacket, cipher: &dyn PayloadCipher) -> PublishPacket {
+    let topic = packet.topic_name();
+    let separator = if topic.ends_with('/') { "" } else { "&" };
+    let topic = format!(
+        "{}{}{}={}",
+        topic,
+        separator,
+        utf8_percent_encode(KEY_ID_PROPERTY, NON_ALPHANUMERIC),
+        utf8_percent_encode(cipher.key_id(), NON_ALPHANUMERIC)
+    );
+    let topic = TopicName::new(topic).expect("Appending a property keeps the topic name valid");
+    PublishPacket::new(topic, packet.qos(), cipher.encrypt(packet.payload_ref()))
+}
+
+/// Decrypts the payload of a C2D or module input publication whose property bag names a key.
+/// Other publications are returned unchanged.
+///
+/// # Errors
+/// Returns an error if the payload could not be decrypted
+pub fn decrypt_publish(packet: PublishPacket, cipher: &dyn PayloadCipher) -> Result<PublishPacket, CipherError> {
+    let key_id = match HubTopic::parse(packet.topic_name()) {
+        HubTopic::CloudToDevice { properties, .. } | HubTopic::ModuleInput { properties, .. } => {
+            query::find(properties, KEY_ID_PROPERTY)
+        }
+        _ => None,
+    };
+    let key_id = match key_id {
+        Some(key_id) => key_id.into_owned(),
+        None => return Ok(packet),
+    };
+    let payload = cipher.decrypt(&key_id, packet.payload_ref())?;
+    let topic = TopicName::new(packet.topic_name()).expect("A decoded topic name is valid");
+    Ok(PublishPacket::new(topic, packet.qos(), payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt::packet::QoSWithPacketIdentifier;
+
+    #[derive(Debug)]
+    struct XorCipher(u8);
+
+    impl PayloadCipher for XorCipher {
+        fn key_id(&self) -> &str {
+            "key 1"
+        }
+
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            plaintext.iter().map(|byte| byte ^ self.0).collect()
+        }
+
+        fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>, CipherError> {
+            match key_id {
+                "key 1" => Ok(self.encrypt(ciphertext)),
+                other => Err(CipherError::UnknownKey(other.to_owned())),
+            }
+        }
+    }
+
+    fn publish(topic: &str, payload: &[u8]) -> PublishPacket {
+        PublishPacket::new(TopicName::new(topic).unwrap(), QoSWithPacketIdentifier::Level0, payload)
+    }
+
+    #[test]
+    fn test_encrypted_payloads_advertise_their_key() {
+        let cipher = XorCipher(0x5a);
+
+        let encrypted = encrypt_publish(&publish("devices/d1/messages/events/a=1", b"hello"), &cipher);
+        assert_eq!(encrypted.topic_name(), "devices/d1/messages/events/a=1&encryption%2Dkey%2Did=key%201");
+        assert_ne!(encrypted.payload_ref(), b"hello");
+
+        let c2d = publish("devices/d1/messages/devicebound/encryption-key-id=key%201", encrypted.payload_ref());
+        assert_eq!(decrypt_publish(c2d, &cipher).unwrap().payload_ref(), b"hello");
+
+        let plain = publish("devices/d1/messages/devicebound/", b"hello");
+        assert_eq!(decrypt_publish(plain, &cipher).unwrap().payload_ref(), b"hello");
+
+        let unknown = publish("devices/d1/messages/devicebound/encryption-key-id=key2", b"");
+        assert_eq!(
+            decrypt_publish(unknown, &cipher).err(),
+            Some(CipherError::UnknownKey("key2".to_owned()))
+        );
+    }
+}
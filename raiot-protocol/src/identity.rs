@@ -1,5 +1,68 @@
+use std::convert::TryFrom;
 use std::fmt;
 
+/// The maximum length of a device or module ID
+pub const MAX_ID_LENGTH: usize = 128;
+
+/// Special characters allowed in device and module IDs, in addition to ASCII alphanumerics.
+/// IoT Hub also allows `+` and `#`, which are rejected since they are MQTT wildcards: the topic filters
+/// the client subscribes to would match the topics of other clients.
+const ALLOWED_SPECIAL_CHARS: &str = "-:.%_*?!(),=@$'";
+
+/// The reason a device or module ID is invalid
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IdentityError {
+    /// The ID is empty
+    Empty,
+
+    /// The ID is longer than the maximum of 128 characters. Holds the actual length.
+    TooLong(usize),
+
+    /// The ID contains a character which is not allowed
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityError::Empty => write!(f, "ID is empty"),
+            IdentityError::TooLong(length) => write!(
+                f,
+                "ID is {} characters long, the maximum is {}",
+                length, MAX_ID_LENGTH
+            ),
+            IdentityError::InvalidCharacter(c) => {
+                write!(f, "ID contains an invalid character: {:?}", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+/// Validates a device or module ID according to the IoT Hub rules, less the MQTT wildcards:
+/// up to 128 ASCII alphanumeric characters, or any of `- : . % _ * ? ! ( ) , = @ $ '`.
+///
+/// Note that IDs are case-sensitive: "MyDevice" and "mydevice" are two distinct identities.
+pub fn validate_id(id: &str) -> Result<(), IdentityError> {
+    if id.is_empty() {
+        return Err(IdentityError::Empty);
+    }
+
+    if let Some(c) = id
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !ALLOWED_SPECIAL_CHARS.contains(*c))
+    {
+        return Err(IdentityError::InvalidCharacter(c));
+    }
+
+    if id.len() > MAX_ID_LENGTH {
+        return Err(IdentityError::TooLong(id.len()));
+    }
+
+    Ok(())
+}
+
 /// A device identity
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct DeviceIdentity {
@@ -7,11 +70,22 @@ pub struct DeviceIdentity {
     pub device_id: String,
 }
 
-impl From<String> for DeviceIdentity {
-    fn from(device_id: String) -> Self {
-        DeviceIdentity {
-            device_id: device_id,
-        }
+impl DeviceIdentity {
+    /// Creates a device identity, validating the device ID
+    pub fn new(device_id: &str) -> Result<DeviceIdentity, IdentityError> {
+        validate_id(device_id)?;
+        Ok(DeviceIdentity {
+            device_id: device_id.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for DeviceIdentity {
+    type Error = IdentityError;
+
+    fn try_from(device_id: String) -> Result<Self, Self::Error> {
+        validate_id(&device_id)?;
+        Ok(DeviceIdentity { device_id })
     }
 }
 
@@ -31,6 +105,18 @@ pub struct ModuleIdentity {
     pub module_id: String,
 }
 
+impl ModuleIdentity {
+    /// Creates a module identity, validating both the device ID and the module ID
+    pub fn new(device_id: &str, module_id: &str) -> Result<ModuleIdentity, IdentityError> {
+        validate_id(device_id)?;
+        validate_id(module_id)?;
+        Ok(ModuleIdentity {
+            device_id: device_id.to_owned(),
+            module_id: module_id.to_owned(),
+        })
+    }
+}
+
 impl fmt::Display for ModuleIdentity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}/{}", self.device_id, self.module_id)
@@ -48,19 +134,26 @@ pub enum ClientIdentity {
 }
 
 impl ClientIdentity {
-    /// Creates a Device Identity from the specified device_id
-    pub fn from_device_id(device_id: &str) -> ClientIdentity {
-        ClientIdentity::Device(DeviceIdentity {
-            device_id: device_id.to_owned(),
-        })
+    /// Creates a Device Identity from the specified device_id, validating it
+    pub fn try_from_device_id(device_id: &str) -> Result<ClientIdentity, IdentityError> {
+        DeviceIdentity::new(device_id).map(ClientIdentity::Device)
     }
 
-    /// Creates a Module Identity from the specified device_id and module_id
-    pub fn from_module_id(device_id: &str, module_id: &str) -> ClientIdentity {
-        ClientIdentity::Module(ModuleIdentity {
-            device_id: device_id.to_owned(),
-            module_id: module_id.to_owned(),
-        })
+    /// Creates a Module Identity from the specified device_id and module_id, validating both
+    pub fn try_from_module_id(
+        device_id: &str,
+        module_id: &str,
+    ) -> Result<ClientIdentity, IdentityError> {
+        ModuleIdentity::new(device_id, module_id).map(ClientIdentity::Module)
+    }
+}
+
+/// A device identity, validating the device ID
+impl TryFrom<&str> for ClientIdentity {
+    type Error = IdentityError;
+
+    fn try_from(device_id: &str) -> Result<Self, Self::Error> {
+        ClientIdentity::try_from_device_id(device_id)
     }
 }
 
@@ -72,3 +165,39 @@ impl fmt::Display for ClientIdentity {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_ids() {
+        assert!(validate_id("my-device_01").is_ok());
+        assert!(validate_id("a:b.c-d%e_f*g?h!i(j)k,l=m@n$o'p").is_ok());
+        assert!(validate_id(&"x".repeat(MAX_ID_LENGTH)).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_ids() {
+        assert_eq!(validate_id(""), Err(IdentityError::Empty));
+        assert_eq!(
+            validate_id(&"x".repeat(MAX_ID_LENGTH + 1)),
+            Err(IdentityError::TooLong(MAX_ID_LENGTH + 1))
+        );
+        assert_eq!(
+            validate_id("my/device"),
+            Err(IdentityError::InvalidCharacter('/'))
+        );
+        assert_eq!(
+            validate_id("dévice"),
+            Err(IdentityError::InvalidCharacter('é'))
+        );
+        assert!(ModuleIdentity::new("device", "my module").is_err());
+        // MQTT wildcards
+        assert_eq!(validate_id("device+1"), Err(IdentityError::InvalidCharacter('+')));
+        assert_eq!(
+            DeviceIdentity::try_from("device#1".to_owned()),
+            Err(IdentityError::InvalidCharacter('#'))
+        );
+    }
+}
@@ -2,26 +2,35 @@ use mqtt;
 use serde::Deserialize;
 use serde_json;
 
+use crate::conformance::{self, Violation};
 use crate::messages::{MsgFromHub, MsgToHub};
 use crate::*;
-use crate::{connect::ConnectMsg, connect::ConnectRes, messages::MsgFromHub::PublicationSucceeded};
+use crate::{
+    connect::ConnectError, connect::ConnectMsg, connect::ConnectRes, connect::ConnectSuccess,
+    connect::IOT_HUB_API_VERSION, messages::MsgFromHub::PublicationSucceeded,
+};
 use log::debug;
-use messages::AckMsg;
+use messages::{AckMsg, ExactlyOnceMsg, MisroutedMsg};
 use mqtt::control::variable_header::ConnectReturnCode;
 use mqtt::packet::suback::SubscribeReturnCode;
 use mqtt::packet::*;
 use mqtt::Decodable;
 use mqtt::{Encodable, QualityOfService, TopicFilter, TopicName};
-use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use percent_encoding::percent_decode_str;
 use qos::{DeliveryGuarantees, PacketId, SessionMode};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use subscription::SubRes;
-use url::{form_urlencoded, Url};
+use serialization::{
+    JsonSerializer, PayloadSerializer, CONTENT_ENCODING_PROPERTY, CONTENT_TYPE_PROPERTY,
+};
+use serde_json::Value;
+use std::sync::Arc;
+use subscription::{MultiSub, SubRes, UnsubMsg, SUBACK_FAILURE};
+use topics::HubTopic;
 
 #[cfg(feature = "c2d")]
-use messages::c2d::{C2DMsg, C2DSub};
+use messages::c2d::{C2DMsg, C2DSub, ModuleInputMsg};
 
 #[cfg(feature = "direct-methods")]
 use messages::direct_methods::{DirectMethodReq, DirectMethodRes, DirectMethodsSub};
@@ -78,6 +87,17 @@ pub enum CodecError {
     /// The twin version identifier is invalid
     #[cfg(feature = "twin")]
     InvalidVersionIdentifier,
+
+    /// The message violates an IoT Hub constraint, detected in strict mode
+    NonConformant(Violation),
+}
+
+/// Options of the codec
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CodecOptions {
+    /// Reject messages violating IoT Hub constraints at encode time (see the `conformance` module),
+    /// rather than having the hub reject them
+    pub strict: bool,
 }
 
 
@@ -89,75 +109,75 @@ pub trait MqttEncodable {
 
 impl MqttEncodable for ConnectMsg {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_connect_message(&self).into()
+        IotCodec::encode_connect_message(self).into()
     }
 }
 
 impl MqttEncodable for AckMsg {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_ack_message(&self).into()
+        IotCodec::encode_ack_message(self).into()
     }
 }
 
 #[cfg(feature = "telemetry")]
 impl MqttEncodable for TelemetryMsg {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_telemetry_message(&self).into()
+        IotCodec::encode_telemetry_message(self).into()
     }
 }
 
 #[cfg(feature = "c2d")]
 impl MqttEncodable for C2DSub {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_c2d_messages_subscription(&self).into()
+        IotCodec::encode_c2d_messages_subscription(self).into()
     }
 }
 
 #[cfg(feature = "direct-methods")]
 impl MqttEncodable for DirectMethodsSub {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_c2d_methods_subscription(&self).into()
+        IotCodec::encode_c2d_methods_subscription(self).into()
     }
 }
 
 #[cfg(feature = "direct-methods")]
 impl MqttEncodable for DirectMethodRes {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_direct_method_response(&self).into()
+        IotCodec::encode_direct_method_response(self).into()
     }
 }
 
 #[cfg(feature = "twin")]
 impl MqttEncodable for ReadTwinReq {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_read_twin(&self).into()
+        IotCodec::encode_read_twin(self).into()
     }
 }
 
 #[cfg(feature = "twin")]
 impl MqttEncodable for TwinReadSub {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_twin_subscription(&self).into()
+        IotCodec::encode_twin_subscription(self).into()
     }
 }
 
 #[cfg(feature = "twin")]
 impl MqttEncodable for TwinUpdatesSub {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_twin_updates_subscription(&self).into()
+        IotCodec::encode_twin_updates_subscription(self).into()
     }
 }
 
 #[cfg(feature = "twin")]
 impl MqttEncodable for UpdateReportedPropsReq {
     fn encode(&self) -> VariablePacket {
-        IotCodec::encode_twin_update(&self).into()
+        IotCodec::encode_twin_update(self).into()
     }
 }
 
 
 impl CodecError {
-    fn get_text<'a>(self: &'a CodecError) -> &'a str {
+    fn get_text(self: &CodecError) -> &str {
         match self {
             CodecError::UnexpectedMqttPacketType => "Unexpected MQTT Packet Type",
             CodecError::InvalidMqttPacket => "Invalid MQTT Packet",
@@ -173,27 +193,115 @@ impl CodecError {
             CodecError::MissingStatusCode => "Missing Status Code",
             #[cfg(feature = "twin")]
             CodecError::InvalidVersionIdentifier => "Invalid Twin Version Identifier",
+            CodecError::NonConformant(_) => "Message Violates IoT Hub Constraints",
         }
     }
 }
 
 impl fmt::Display for CodecError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        return write!(f, "{}", &self.get_text());
+        if let CodecError::NonConformant(violation) = self {
+            return write!(f, "{}: {}", &self.get_text(), violation);
+        }
+        write!(f, "{}", &self.get_text())
     }
 }
 
+
 impl Error for CodecError {
     fn description(&self) -> &str {
-        return "Codec failure";
+        "Codec failure"
     }
 
     fn cause(&self) -> Option<&dyn Error> {
-        return None;
+        None
+    }
+}
+
+/// Builds the subscription requests of a device, deriving their topic filters from its identity.
+/// Obtained from `IotCodec::device_subscriptions`.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceSubscriptions<'a> {
+    device: &'a DeviceIdentity,
+}
+
+impl DeviceSubscriptions<'_> {
+    /// A request to receive the C2D messages of the device
+    #[cfg(feature = "c2d")]
+    pub fn c2d(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> C2DSub {
+        C2DSub {
+            packet_id,
+            device_id: self.device.clone(),
+            mode,
+        }
+    }
+
+    /// A request to receive direct method invocations
+    #[cfg(feature = "direct-methods")]
+    pub fn methods(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> DirectMethodsSub {
+        DirectMethodsSub { packet_id, mode }
+    }
+
+    /// A request to receive twin read and update responses
+    #[cfg(feature = "twin")]
+    pub fn twin_reads(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> TwinReadSub {
+        TwinReadSub { packet_id, mode }
+    }
+
+    /// A request to receive desired properties updates
+    #[cfg(feature = "twin")]
+    pub fn twin_updates(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> TwinUpdatesSub {
+        TwinUpdatesSub { packet_id, mode }
+    }
+}
+
+/// Builds the subscription requests of a module, deriving their topic filters from its identity.
+/// Modules receive messages on their inputs instead of C2D messages, so there is no C2D subscription to build.
+/// Obtained from `IotCodec::module_subscriptions`.
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleSubscriptions<'a> {
+    module: &'a ModuleIdentity,
+}
+
+impl ModuleSubscriptions<'_> {
+    /// A request to receive the messages routed to any input of the module
+    pub fn inputs(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> MultiSub {
+        MultiSub::new(packet_id).with_filter(
+            topics::module_inputs_filter(&self.module.device_id, &self.module.module_id),
+            mode,
+        )
+    }
+
+    /// A request to receive direct method invocations
+    #[cfg(feature = "direct-methods")]
+    pub fn methods(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> DirectMethodsSub {
+        DirectMethodsSub { packet_id, mode }
+    }
+
+    /// A request to receive twin read and update responses
+    #[cfg(feature = "twin")]
+    pub fn twin_reads(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> TwinReadSub {
+        TwinReadSub { packet_id, mode }
+    }
+
+    /// A request to receive desired properties updates
+    #[cfg(feature = "twin")]
+    pub fn twin_updates(&self, packet_id: PacketId, mode: DeliveryGuarantees) -> TwinUpdatesSub {
+        TwinUpdatesSub { packet_id, mode }
     }
 }
 
 impl IotCodec {
+    /// The subscription requests of the specified device
+    pub fn device_subscriptions(device: &DeviceIdentity) -> DeviceSubscriptions<'_> {
+        DeviceSubscriptions { device }
+    }
+
+    /// The subscription requests of the specified module
+    pub fn module_subscriptions(module: &ModuleIdentity) -> ModuleSubscriptions<'_> {
+        ModuleSubscriptions { module }
+    }
+
     /// Encodes a MsgToHub into the provided buffer. Returns the encoded message size, or an error.
     ///
     /// # Arguments
@@ -207,7 +315,21 @@ impl IotCodec {
         let packet = Self::encode_message(message)?;
         packet.encode(&mut buf).unwrap();
         let length = packet.encoded_length();
-        return Ok(length as usize);
+        Ok(length as usize)
+    }
+
+    /// Encodes a MsgToHub into a newly allocated vector, sized exactly to the encoded message
+    ///
+    /// # Arguments
+    ///
+    /// * message - the IoT message to encode
+    pub fn encode_to_vec(message: &MsgToHub) -> Result<Vec<u8>, CodecError> {
+        let packet = Self::encode_message(message)?;
+        let mut buf = Vec::with_capacity(packet.encoded_length() as usize);
+        packet
+            .encode(&mut buf)
+            .map_err(|_e| CodecError::InvalidMqttPacket)?;
+        Ok(buf)
     }
 
     /// Decodes a single message from hub from the provided buffer
@@ -215,52 +337,93 @@ impl IotCodec {
     /// # Errors
     /// Returns an error if the buffer contains an invalid MQTT packet, or if the MQTT packet translates to an invalid IoT Hub packet
     pub fn decode(bytes: &[u8]) -> DecodingResult {
-        let mut buf = &bytes[..];
+        let mut buf = bytes;
         let decode_res = VariablePacket::decode(&mut buf);
         
         decode_res
             .map_err(|_e| CodecError::InvalidMqttPacket)
-            .map(|packet| Self::decode_packet(packet.into()))?
+            .map(Self::decode_packet)?
+    }
+
+    /// Encodes an IoT message to an MQTT packet, checking it against IoT Hub constraints in strict mode
+    ///
+    /// # Errors
+    /// Returns `CodecError::NonConformant` if strict mode is enabled and the message violates a constraint
+    pub fn encode_message_with(message: &MsgToHub, options: CodecOptions) -> Result<VariablePacket, CodecError> {
+        if options.strict {
+            conformance::check_message(message).map_err(CodecError::NonConformant)?;
+        }
+        let packet = Self::encode_message(message)?;
+        if options.strict {
+            conformance::check_packet(&packet).map_err(CodecError::NonConformant)?;
+        }
+        Ok(packet)
+    }
+
+    /// Checks a message already encoded by `encode_message` against IoT Hub constraints in strict mode,
+    /// like `encode_message_with` does
+    ///
+    /// # Errors
+    /// Returns `CodecError::NonConformant` if strict mode is enabled and the message violates a constraint
+    pub fn check_encoded(message: &MsgToHub, packet: &VariablePacket, options: CodecOptions) -> Result<(), CodecError> {
+        if options.strict {
+            conformance::check_message(message).map_err(CodecError::NonConformant)?;
+            conformance::check_packet(packet).map_err(CodecError::NonConformant)?;
+        }
+        Ok(())
     }
 
     /// Encodes an IoT message to an MQTT packet
     pub fn encode_message(message: &MsgToHub) -> Result<VariablePacket, CodecError> {
         let encoded: VariablePacket = match message {
-            MsgToHub::Connect(ref msg) => Self::encode_connect_message(&msg).into(),
+            MsgToHub::Connect(ref msg) => Self::encode_connect_message(msg).into(),
+
+            MsgToHub::Acknowledge(ref msg) => Self::encode_ack_message(msg).into(),
 
-            MsgToHub::Acknowledge(ref msg) => Self::encode_ack_message(&msg).into(),
+            MsgToHub::ExactlyOnce(ref msg) => Self::encode_exactly_once_message(msg),
 
             #[cfg(feature = "twin")]
-            MsgToHub::ReadTwin(ref msg) => Self::encode_read_twin(&msg).into(),
+            MsgToHub::ReadTwin(ref msg) => Self::encode_read_twin(msg).into(),
 
             #[cfg(feature = "telemetry")]
-            MsgToHub::Telemetry(ref msg) => Self::encode_telemetry_message(&msg).into(),
+            MsgToHub::Telemetry(ref msg) => Self::encode_telemetry_message(msg).into(),
 
             #[cfg(feature = "c2d")]
-            MsgToHub::SubscribeToC2D(ref msg) => {
-                Self::encode_c2d_messages_subscription(&msg).into()
-            }
+            MsgToHub::SubscribeToC2D(ref msg) => Self::encode_c2d_messages_subscription(msg).into(),
 
             #[cfg(feature = "direct-methods")]
             MsgToHub::SubscribeToMethods(ref msg) => {
-                Self::encode_c2d_methods_subscription(&msg).into()
+                Self::encode_c2d_methods_subscription(msg).into()
             }
 
             #[cfg(feature = "twin")]
-            MsgToHub::SubscribeToTwinReads(ref msg) => Self::encode_twin_subscription(&msg).into(),
+            MsgToHub::SubscribeToTwinReads(ref msg) => Self::encode_twin_subscription(msg).into(),
 
             #[cfg(feature = "direct-methods")]
             MsgToHub::DirectMethodResponse(ref msg) => {
-                Self::encode_direct_method_response(&msg).into()
+                Self::encode_direct_method_response(msg).into()
             }
 
             #[cfg(feature = "twin")]
             MsgToHub::SubscribeToTwinUpdates(ref msg) => {
-                Self::encode_twin_updates_subscription(&msg).into()
+                Self::encode_twin_updates_subscription(msg).into()
             }
 
             #[cfg(feature = "twin")]
-            MsgToHub::UpdateReportedProperties(ref msg) => Self::encode_twin_update(&msg).into(),
+            MsgToHub::UpdateReportedProperties(ref msg) => Self::encode_twin_update(msg).into(),
+
+            MsgToHub::SubscribeToMany(ref msg) => Self::encode_multi_subscription(msg)?.into(),
+
+            MsgToHub::Unsubscribe(ref msg) => Self::encode_unsubscription(msg)?.into(),
+
+            MsgToHub::Disconnect => DisconnectPacket::new().into(),
+
+            #[cfg(feature = "raw-mqtt")]
+            MsgToHub::Raw(ref packet) => packet.clone(),
+
+            // the payloads of the disabled features can't be constructed
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("A message of a disabled feature"),
         };
 
         Ok(encoded)
@@ -272,38 +435,106 @@ impl IotCodec {
     ///
     /// * packet - the MQTT packet to decode
     pub fn decode_packet(packet: VariablePacket) -> DecodingResult {
-        return match packet {
+        match packet {
             VariablePacket::ConnackPacket(ref connack) => Self::decode_connack_packet(connack),
             VariablePacket::PublishPacket(ref publ) => Self::decode_publish_packet(publ),
             VariablePacket::PubackPacket(ref puback) => Self::decode_puback_packet(puback),
+            VariablePacket::PubcompPacket(ref pubcomp) => Ok(PublicationSucceeded(pubcomp.packet_identifier().into())),
             VariablePacket::SubackPacket(ref suback) => Self::decode_suback_packet(suback),
+            VariablePacket::UnsubackPacket(ref unsuback) => {
+                Ok(MsgFromHub::UnsubscriptionSucceeded(unsuback.packet_identifier().into()))
+            }
             _other_packet => Err(CodecError::UnexpectedMqttPacketType),
-        };
+        }
     }
 
-    fn decode_connack_packet(packet: &ConnackPacket) -> DecodingResult {
-        let resp = match packet.connect_return_code() {
-            ConnectReturnCode::ConnectionAccepted => ConnectRes::Accepted,
-            ConnectReturnCode::BadUserNameOrPassword => ConnectRes::AuthenticationFailed,
-            ConnectReturnCode::ServiceUnavailable => ConnectRes::ServiceUnavailable,
-            ConnectReturnCode::UnacceptableProtocolVersion => {
-                ConnectRes::UnacceptableProtocolVersion
+    /// Decodes an MQTT packet like `decode_packet`, additionally verifying that C2D and module input messages
+    /// are addressed to the specified identity. Messages addressed to another device or module are reported
+    /// as `MsgFromHub::MisroutedMessage`, which is useful when several clients share a gateway connection.
+    ///
+    /// # Arguments
+    ///
+    /// * packet - the MQTT packet to decode
+    /// * identity - the identity of the receiving client
+    pub fn decode_packet_for(packet: VariablePacket, identity: &ClientIdentity) -> DecodingResult {
+        if let VariablePacket::PublishPacket(ref publ) = packet {
+            if !Self::is_addressed_to(HubTopic::parse(publ.topic_name()), identity) {
+                return Ok(MsgFromHub::MisroutedMessage(MisroutedMsg {
+                    topic: publ.topic_name().to_owned(),
+                    packet_id: qos_to_packet_id(publ.qos()),
+                }));
             }
-            ConnectReturnCode::IdentifierRejected => ConnectRes::AuthenticationFailed,
-            ConnectReturnCode::NotAuthorized => ConnectRes::Unauthorized,
-            ConnectReturnCode::Reserved(code) => ConnectRes::MqttReservedErrorCode(code),
-        };
+        }
+        Self::decode_packet(packet)
+    }
+
+    // topics which don't name a device (twin, methods) are always addressed to the connected client
+    fn is_addressed_to(topic: HubTopic<'_>, identity: &ClientIdentity) -> bool {
+        match (topic, identity) {
+            (HubTopic::CloudToDevice { device_id, .. }, ClientIdentity::Device(device)) => {
+                device_id == device.device_id
+            }
+            (HubTopic::CloudToDevice { .. }, ClientIdentity::Module(_)) => false,
+            (
+                HubTopic::ModuleInput {
+                    device_id,
+                    module_id,
+                    ..
+                },
+                ClientIdentity::Module(module),
+            ) => device_id == module.device_id && module_id == module.module_id,
+            (HubTopic::ModuleInput { .. }, ClientIdentity::Device(_)) => false,
+            _ => true,
+        }
+    }
+
+    fn decode_connack_packet(packet: &ConnackPacket) -> DecodingResult {
+        let resp = Self::decode_connect_return_code(
+            packet.connect_return_code(),
+            packet.connack_flags().session_present,
+        );
 
         Ok(MsgFromHub::ConnectResponseMessage(resp))
     }
 
+    /// Translates an MQTT CONNACK return code into the IoT Hub's connection response
+    pub fn decode_connect_return_code(
+        code: ConnectReturnCode,
+        session_present: bool,
+    ) -> ConnectRes {
+        match code {
+            ConnectReturnCode::ConnectionAccepted => Ok(ConnectSuccess { session_present }),
+            ConnectReturnCode::BadUserNameOrPassword => Err(ConnectError::AuthenticationFailed),
+            ConnectReturnCode::ServiceUnavailable => Err(ConnectError::ServiceUnavailable),
+            ConnectReturnCode::UnacceptableProtocolVersion => {
+                Err(ConnectError::UnacceptableProtocolVersion)
+            }
+            ConnectReturnCode::IdentifierRejected => Err(ConnectError::AuthenticationFailed),
+            ConnectReturnCode::NotAuthorized => Err(ConnectError::Unauthorized),
+            ConnectReturnCode::Reserved(code) => Err(ConnectError::MqttReservedErrorCode(code)),
+        }
+    }
+
     fn decode_suback_packet(packet: &SubackPacket) -> DecodingResult {
+        let return_codes: Vec<u8> = packet
+            .payload_ref()
+            .subscribes()
+            .iter()
+            .map(|code| match code {
+                SubscribeReturnCode::MaximumQoSLevel0 => 0,
+                SubscribeReturnCode::MaximumQoSLevel1 => 1,
+                SubscribeReturnCode::MaximumQoSLevel2 => 2,
+                SubscribeReturnCode::Failure => SUBACK_FAILURE,
+            })
+            .collect();
         Ok(SubRes {
             packet_id: packet.packet_identifier().into(),
-            result: match packet.payload_ref().subscribes()[0] {
-                SubscribeReturnCode::Failure => Err(SubError::Failure),
-                _other => Ok(()),
+            result: match return_codes.contains(&SUBACK_FAILURE) {
+                true => Err(SubError::rejected(SUBACK_FAILURE)),
+                false => Ok(()),
             },
+            topic_filters: Vec::new(),
+            return_codes,
         }
         .into())
     }
@@ -313,42 +544,55 @@ impl IotCodec {
     }
 
     fn decode_publish_packet(packet: &PublishPacket) -> DecodingResult {
-        // TODO improve algorithm performance (better branching)
-        // TODO pass rest of topic to decode method
-        #[cfg(feature = "twin")]
-        if packet.topic_name().starts_with("$iothub/twin/res/") {
-            return Self::decode_twin_response(packet);
-        }
+        match HubTopic::parse(packet.topic_name()) {
+            #[cfg(feature = "twin")]
+            HubTopic::TwinResponse { status, query } => Self::decode_twin_response(packet, status, query),
 
-        #[cfg(feature = "twin")]
-        if packet
-            .topic_name()
-            .starts_with("$iothub/twin/PATCH/properties/desired/")
-        {
-            return Self::decode_desired_properties_update(packet);
-        }
+            #[cfg(feature = "twin")]
+            HubTopic::DesiredPropertiesUpdate { query } => {
+                Self::decode_desired_properties_update(packet, query)
+            }
 
-        #[cfg(feature = "direct-methods")]
-        if packet.topic_name().starts_with("$iothub/methods/POST/") {
-            return Self::decode_direct_method_invocation(packet);
-        }
+            #[cfg(feature = "direct-methods")]
+            HubTopic::MethodInvocation { method_name, query } => {
+                Self::decode_direct_method_invocation(packet, method_name, query)
+            }
 
-        #[cfg(feature = "c2d")]
-        if packet.topic_name().starts_with("devices/") {
-            return Self::decode_c2d_message(packet);
-        }
+            #[cfg(feature = "c2d")]
+            HubTopic::CloudToDevice {
+                device_id,
+                properties,
+            } => Self::decode_c2d_message(packet, device_id, properties),
 
-        return Ok(MsgFromHub::UnknownMessage());
+            #[cfg(feature = "c2d")]
+            HubTopic::ModuleInput {
+                device_id,
+                module_id,
+                input_name,
+                properties,
+            } => Self::decode_module_input_message(packet, device_id, module_id, input_name, properties),
+
+            _ => Ok(MsgFromHub::UnknownMessage()),
+        }
     }
 
     fn encode_ack_message(msg: &AckMsg) -> PubackPacket {
         PubackPacket::new(msg.packet_id.into())
     }
 
+    fn encode_exactly_once_message(msg: &ExactlyOnceMsg) -> VariablePacket {
+        match *msg {
+            ExactlyOnceMsg::Received(packet_id) => PubrecPacket::new(packet_id.into()).into(),
+            ExactlyOnceMsg::Release(packet_id) => PubrelPacket::new(packet_id.into()).into(),
+            ExactlyOnceMsg::Complete(packet_id) => PubcompPacket::new(packet_id.into()).into(),
+        }
+    }
+
     fn encode_connect_message(msg: &ConnectMsg) -> ConnectPacket {
-        let client_identifier = match &msg.client_id {
-            ClientIdentity::Device(device) => device.device_id.clone(),
-            ClientIdentity::Module(module) => format!("{}/{}", module.device_id, module.module_id),
+        let client_identifier = match (&msg.client_id_override, &msg.client_id) {
+            (Some(client_id), _) => client_id.as_str().to_owned(),
+            (None, ClientIdentity::Device(device)) => device.device_id.clone(),
+            (None, ClientIdentity::Module(module)) => format!("{}/{}", module.device_id, module.module_id),
         };
 
         let mut packet = ConnectPacket::new(&client_identifier);
@@ -359,45 +603,37 @@ impl IotCodec {
 
         let username = match &msg.client_id {
             ClientIdentity::Device(device) => format!(
-                "{}/{}/api-version=2018-06-30",
-                msg.server_addr, device.device_id
+                "{}/{}/api-version={}",
+                msg.server_addr, device.device_id, IOT_HUB_API_VERSION
             ),
             ClientIdentity::Module(module) => format!(
-                "{}/{}/{}/api-version=2018-06-30",
-                msg.server_addr, module.device_id, module.module_id
+                "{}/{}/{}/api-version={}",
+                msg.server_addr, module.device_id, module.module_id, IOT_HUB_API_VERSION
             ),
         };
         packet.set_user_name(Some(username));
         if let Some(ref token) = msg.sas_token {
             packet.set_password(Some(token.to_owned()));
         }
-        return packet;
+        packet
     }
 
     #[cfg(feature = "c2d")]
-    fn decode_c2d_message(packet: &PublishPacket) -> DecodingResult {
-        let body = deserialize_message_body(&packet)?;
+    fn decode_c2d_message(packet: &PublishPacket, device_id: &str, properties: &str) -> DecodingResult {
+        let body = deserialize_message_body(packet)?;
 
-        let topic = packet.topic_name();
-        debug!("C2D Topic name: {:?}", topic);
+        debug!("C2D Topic name: {:?}", packet.topic_name());
 
-        let mut segments = topic.split('/');
-        if let None = segments.next() {
-            return Err(CodecError::InvalidTopic);
+        if device_id.is_empty() {
+            return Err(CodecError::MissingDeviceId);
         }
+        let device_id = device_id.to_owned();
 
-        let device_id = match segments.next() {
-            Some(id) => id.to_owned(),
-            None => return Err(CodecError::MissingDeviceId),
-        };
-
-        let mut props: Option<HashMap<String, String>> = None;
-        if let Some(value) = segments.skip(2).next() {
-            let vals: HashMap<String, String> = form_urlencoded::parse(value.as_bytes())
+        let props: Option<HashMap<String, String>> = Some(
+            query::pairs(properties)
                 .map(|(key, value)| (key.into_owned(), value.into_owned()))
-                .collect();
-            props = Some(vals);
-        }
+                .collect(),
+        );
 
         let packet_id = qos_to_packet_id(packet.qos());
 
@@ -411,28 +647,50 @@ impl IotCodec {
         Ok(message.into())
     }
 
-    #[cfg(feature = "direct-methods")]
-    fn decode_direct_method_invocation(packet: &PublishPacket) -> DecodingResult {
-        let topic = packet.topic_name();
-        let parsed_url = Url::parse(&("mqtt://".to_owned() + topic)).unwrap();
-        let mut hash_query: HashMap<_, _> = parsed_url.query_pairs().into_owned().collect();
-        let request_id = 
-            hash_query.remove("$rid")
-            .ok_or_else(|| CodecError::MissingRid.into())?;
-        let body = deserialize_message_body(&packet)?;
-        let mut segments = parsed_url.path_segments().unwrap();
-        if let None = segments.next() {
-            return Err(CodecError::InvalidTopic);
-        }
-        if let None = segments.next() {
-            return Err(CodecError::InvalidTopic);
+    #[cfg(feature = "c2d")]
+    fn decode_module_input_message(
+        packet: &PublishPacket,
+        device_id: &str,
+        module_id: &str,
+        input_name: &str,
+        properties: &str,
+    ) -> DecodingResult {
+        let body = deserialize_message_body(packet)?;
+
+        if device_id.is_empty() {
+            return Err(CodecError::MissingDeviceId);
         }
 
-        let method_name = match segments.next() {
-            Some(name) => percent_decode_str(name).decode_utf8().unwrap().into_owned(),
-            None => return Err(CodecError::MissingMethodName),
+        let props: Option<HashMap<String, String>> = Some(
+            query::pairs(properties)
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect(),
+        );
+
+        let message = ModuleInputMsg {
+            packet_id: qos_to_packet_id(packet.qos()),
+            body,
+            device_id: device_id.to_owned(),
+            module_id: module_id.to_owned(),
+            input_name: input_name.to_owned(),
+            props,
         };
 
+        Ok(message.into())
+    }
+
+    #[cfg(feature = "direct-methods")]
+    fn decode_direct_method_invocation(packet: &PublishPacket, method_name: &str, query: &str) -> DecodingResult {
+        let request_id = query::find(query, "$rid")
+            .ok_or(CodecError::MissingRid)?
+            .into_owned();
+        let body = deserialize_message_body(packet)?;
+
+        if method_name.is_empty() {
+            return Err(CodecError::MissingMethodName);
+        }
+        let method_name = percent_decode_str(method_name).decode_utf8_lossy().into_owned();
+
         let message = DirectMethodReq {
             body,
             request_id,
@@ -444,22 +702,19 @@ impl IotCodec {
     }
 
     #[cfg(feature = "twin")]
-    fn decode_desired_properties_update(packet: &PublishPacket) -> DecodingResult {
-        let topic = packet.topic_name();
-        let parsed_url = Url::parse(&("mqtt://".to_owned() + topic)).unwrap();
-        let hash_query: HashMap<_, _> = parsed_url.query_pairs().into_owned().collect();
-        let version = match hash_query.get("$version") {
+    fn decode_desired_properties_update(packet: &PublishPacket, query: &str) -> DecodingResult {
+        let version = match query::find(query, "$version") {
             Some(version) => version,
             None => return Err(CodecError::MissingVersion),
         };
-        let version = version.parse::<u64>().map_err(|_e| CodecError::InvalidVersionIdentifier.into())?;
-        let body = 
-            deserialize_message_body(&packet)?
-            .ok_or_else(|| CodecError::InvalidMessageBody)?;
+        let version = version
+            .parse::<u64>()
+            .map_err(|_e| CodecError::InvalidVersionIdentifier)?;
+        let body = deserialize_message_body(packet)?.ok_or(CodecError::InvalidMessageBody)?;
 
         let message = DesiredPropsUpdated {
             packet_id: qos_to_packet_id(packet.qos()),
-            body: body,
+            body,
             desired_properties_version: version,
         };
 
@@ -467,39 +722,34 @@ impl IotCodec {
     }
 
     #[cfg(feature = "twin")]
-    fn decode_twin_response(packet: &PublishPacket) -> DecodingResult {
-        let topic = packet.topic_name();
-
-        let parsed_url = Url::parse(&("mqtt://".to_owned() + topic)).unwrap();
-        let mut hash_query: HashMap<_, _> = parsed_url.query_pairs().into_owned().collect();
-        let rid = match hash_query.remove("$rid") {
-            Some(rid) => rid,
+    fn decode_twin_response(packet: &PublishPacket, status: &str, query: &str) -> DecodingResult {
+        let rid = match query::find(query, "$rid") {
+            Some(rid) => rid.into_owned(),
             None => return Err(CodecError::MissingRid),
         };
 
-        // TODO slicing is the wrong way to go as it might provide an invalid string... 
-        match topic[17..20].parse::<u16>() {
-            Err(_) => return Err(CodecError::MissingStatusCode),
+        match status.parse::<u16>() {
+            Err(_) => Err(CodecError::MissingStatusCode),
             Ok(code) => {
                 let body = match code {
-                    200 => deserialize_message_body(&packet)?,
+                    200 => deserialize_message_body(packet)?,
                     _other => None,
                 };
 
-                return Ok(MsgFromHub::TwinResponseMessage(ReadTwinRes {
+                Ok(MsgFromHub::TwinResponseMessage(ReadTwinRes {
                     packet_id: qos_to_packet_id(packet.qos()),
                     request_id: rid,
                     status_code: Self::get_status_code(code), // TODO move out of "self" ?
-                    body: body,
-                    version: Self::extract_version(&hash_query)?,
-                }));
+                    body,
+                    version: Self::extract_version(query)?,
+                }))
             }
         }
     }
 
     #[cfg(feature = "twin")]
-    fn extract_version(map: &HashMap<String, String>) -> Result<Option<u64>, CodecError> {
-        let version = match map.get("$version") {
+    fn extract_version(query: &str) -> Result<Option<u64>, CodecError> {
+        let version = match query::find(query, "$version") {
             Some(version_string) => {
                 return match version_string.parse::<u64>() {
                     Ok(version) => Ok(Some(version)),
@@ -509,147 +759,204 @@ impl IotCodec {
             None => Ok(None),
         };
 
-        return version;
+        version
     }
 
     #[cfg(feature = "twin")]
     fn get_status_code(code: u16) -> StatusCode {
-        return match code {
+        match code {
             429 => StatusCode::TooManyRequests(),
             200 => StatusCode::OK(),
             204 => StatusCode::NoContent(),
+            400 => StatusCode::BadRequest(),
             500..=599 => StatusCode::ServerError(code),
             other => StatusCode::UnknownStatusCode(other),
-        };
+        }
     }
 
     #[cfg(feature = "telemetry")]
     fn encode_telemetry_message(message: &TelemetryMsg) -> PublishPacket {
-        let qos_and_id = packet_id_to_qos(message.packet_id);
+        let qos_and_id = match (message.packet_id, message.exactly_once) {
+            (Some(packet_id), true) => QoSWithPacketIdentifier::Level2(packet_id.into()),
+            (packet_id, _) => packet_id_to_qos(packet_id),
+        };
 
-        let mut channel = match &message.client_id {
-            ClientIdentity::Device(device) => {
-                format!("devices/{}/messages/events/", device.device_id)
+        let mut channel = topics::telemetry(&message.client_id);
+
+        let mut headers = message.headers.clone();
+        if let Some(serializer) = &message.serializer {
+            let headers = headers.get_or_insert_with(HashMap::new);
+            let _ = headers.insert(CONTENT_TYPE_PROPERTY.to_owned(), serializer.content_type().to_owned());
+            if let Some(encoding) = serializer.content_encoding() {
+                let _ = headers.insert(CONTENT_ENCODING_PROPERTY.to_owned(), encoding.to_owned());
             }
-            ClientIdentity::Module(module) => format!(
-                "devices/{}/modules/{}/messages/events/",
-                module.device_id, module.module_id
-            ),
-        };
+        }
 
-        if let Some(headers) = &message.headers {
-            // TODO there has to be a built-in way to do this thing...
-            let mut bag = String::new();
-            let mut first = true;
-            for (key, value) in headers {
-                if !first {
-                    bag.push('&');
-                }
-                let encoded_key = utf8_percent_encode(key, NON_ALPHANUMERIC).to_string();
-                bag.push_str(&encoded_key);
-                bag.push('=');
-                let encoded_value = utf8_percent_encode(value, NON_ALPHANUMERIC).to_string();
-                bag.push_str(&encoded_value);
-                first = false;
+        if let Some(headers) = &headers {
+            let mut bag = take_property_bag_scratch();
+            {
+                let mut serializer = form_urlencoded::Serializer::new(&mut bag);
+                let _ = serializer.extend_pairs(headers);
+                let _ = serializer.finish();
             }
-            channel.push_str(&bag);
+            // '+' is a wildcard, not allowed in topic names. Literal pluses are already escaped, so these are spaces.
+            let mut parts = bag.split('+');
+            channel.push_str(parts.next().unwrap_or_default());
+            for part in parts {
+                channel.push_str("%20");
+                channel.push_str(part);
+            }
+            return_property_bag_scratch(bag);
         }
 
         let channel = TopicName::new(channel).expect("Topic name must be valid");
         let payload = match &message.content {
-            Some(value) => value.to_string().into_bytes(),
+            Some(value) => serialize_payload(&message.serializer, value),
             None => Vec::new(),
         };
-        let publish_packet = PublishPacket::new(channel, qos_and_id, payload);
-        return publish_packet;
+
+        PublishPacket::new(channel, qos_and_id, payload)
     }
 
     #[cfg(feature = "twin")]
     fn encode_twin_update(message: &UpdateReportedPropsReq) -> PublishPacket {
         let qos_and_id = packet_id_to_qos(message.packet_id);
-        let payload = serde_json::to_string(&message.reported).unwrap();
-        let topic_name = format!(
-            "$iothub/twin/PATCH/properties/reported/?$rid={}",
-            message.request_id
-        );
-        let chan = TopicName::new(topic_name).unwrap(); // TODO
-        let packet = PublishPacket::new(chan, qos_and_id, payload);
-        return packet;
+        // IoT Hub only accepts JSON reported properties, whatever the payload serializer of the client
+        let payload = serde_json::to_vec(&message.reported).unwrap();
+        let (payload, topic) = match &message.compression {
+            Some(compression) => match compression.apply(payload) {
+                (payload, Some(encoding)) => (payload, topics::twin_reported_encoded(&message.request_id, encoding)),
+                (payload, None) => (payload, topics::twin_reported(&message.request_id)),
+            },
+            None => (payload, topics::twin_reported(&message.request_id)),
+        };
+        let chan = TopicName::new(topic).unwrap(); // TODO
+        PublishPacket::new(chan, qos_and_id, payload)
     }
 
     #[cfg(feature = "twin")]
     fn encode_read_twin(message: &ReadTwinReq) -> PublishPacket {
         let chan =
-            TopicName::new(format!("$iothub/twin/GET/?$rid={}", message.request_id)).unwrap(); // TODO
+            TopicName::new(topics::twin_get(&message.request_id)).unwrap(); // TODO
         let qos_and_id = packet_id_to_qos(message.packet_id);
-        let publish_packet = PublishPacket::new(chan, qos_and_id, Vec::new());
-        return publish_packet;
+        PublishPacket::new(chan, qos_and_id, Vec::new())
     }
 
     #[cfg(feature = "twin")]
     fn encode_twin_subscription(message: &TwinReadSub) -> SubscribePacket {
-        let topic_filter = "$iothub/twin/res/#";
-        return Self::encode_subscription(message.packet_id.into(), topic_filter, message.mode);
+        let topic_filter = message.topic_filter();
+        Self::encode_subscription(message.packet_id, &[(&topic_filter, message.mode)])
+            .expect("Hub topic filters are valid")
     }
 
     #[cfg(feature = "twin")]
     fn encode_twin_updates_subscription(message: &TwinUpdatesSub) -> SubscribePacket {
-        let topic_filter = "$iothub/twin/PATCH/properties/desired/#";
-        return Self::encode_subscription(message.packet_id.into(), topic_filter, message.mode);
+        let topic_filter = message.topic_filter();
+        Self::encode_subscription(message.packet_id, &[(&topic_filter, message.mode)])
+            .expect("Hub topic filters are valid")
     }
 
     #[cfg(feature = "c2d")]
     fn encode_c2d_messages_subscription(message: &C2DSub) -> SubscribePacket {
-        let topic_filter = &format!("devices/{}/messages/devicebound/#", message.device_id);
-        Self::encode_subscription(message.packet_id, topic_filter, message.mode)
+        let topic_filter = message.topic_filter();
+        Self::encode_subscription(message.packet_id, &[(&topic_filter, message.mode)])
+            .expect("Hub topic filters are valid")
     }
 
     #[cfg(feature = "direct-methods")]
     fn encode_c2d_methods_subscription(message: &DirectMethodsSub) -> SubscribePacket {
-        return Self::encode_subscription(
-            message.packet_id,
-            "$iothub/methods/POST/#",
-            message.mode,
-        );
+        let topic_filter = message.topic_filter();
+        Self::encode_subscription(message.packet_id, &[(&topic_filter, message.mode)])
+            .expect("Hub topic filters are valid")
     }
 
-    fn encode_subscription(
-        packet_id: PacketId,
-        topic_filter: &str,
-        mode: DeliveryGuarantees,
-    ) -> SubscribePacket {
-        let qos = match mode {
-            DeliveryGuarantees::AtLeastOnce => QualityOfService::Level1,
-            DeliveryGuarantees::AtMostOnce => QualityOfService::Level0,
-        };
+    fn encode_multi_subscription(message: &MultiSub) -> Result<SubscribePacket, CodecError> {
+        if message.filters.is_empty() {
+            return Err(CodecError::InvalidTopic);
+        }
+        let filters: Vec<(&str, DeliveryGuarantees)> = message
+            .filters
+            .iter()
+            .map(|(topic_filter, mode)| (topic_filter.as_str(), *mode))
+            .collect();
+        Self::encode_subscription(message.packet_id, &filters)
+    }
 
-        let filter: (TopicFilter, QualityOfService) =
-            (TopicFilter::new(topic_filter).unwrap(), qos);
+    fn encode_unsubscription(message: &UnsubMsg) -> Result<UnsubscribePacket, CodecError> {
+        let filters = message
+            .topic_filters
+            .iter()
+            .map(|topic_filter| TopicFilter::new(topic_filter.as_str()).map_err(|_| CodecError::InvalidTopic))
+            .collect::<Result<Vec<TopicFilter>, CodecError>>()?;
+        Ok(UnsubscribePacket::new(message.packet_id.into(), filters))
+    }
 
-        let mut filters: Vec<(TopicFilter, QualityOfService)> = Vec::new();
-        filters.push(filter);
+    fn encode_subscription(
+        packet_id: PacketId,
+        filters: &[(&str, DeliveryGuarantees)],
+    ) -> Result<SubscribePacket, CodecError> {
+        let filters = filters
+            .iter()
+            .map(|(topic_filter, mode)| {
+                let qos = match mode {
+                    DeliveryGuarantees::ExactlyOnce => QualityOfService::Level2,
+                    DeliveryGuarantees::AtLeastOnce => QualityOfService::Level1,
+                    DeliveryGuarantees::AtMostOnce => QualityOfService::Level0,
+                };
+                let topic_filter = TopicFilter::new(*topic_filter).map_err(|_| CodecError::InvalidTopic)?;
+                Ok((topic_filter, qos))
+            })
+            .collect::<Result<Vec<(TopicFilter, QualityOfService)>, CodecError>>()?;
 
         debug!("Encoded sub {:?}", packet_id);
-        return SubscribePacket::new(packet_id.into(), filters);
+        Ok(SubscribePacket::new(packet_id.into(), filters))
     }
 
     #[cfg(feature = "direct-methods")]
     fn encode_direct_method_response(message: &DirectMethodRes) -> PublishPacket {
-        let topic_name = format!(
-            "$iothub/methods/res/{}/?$rid={}",
-            message.status, message.request_id
-        );
-        let topic_name = TopicName::new(topic_name).expect("Topic name must be legal");
+        let topic_name = TopicName::new(topics::method_response(message.status, &message.request_id)).expect("Topic name must be legal");
 
         let payload = match &message.payload {
-            Some(x) => x.to_string(),
-            None => "".to_owned(),
+            Some(x) => serialize_payload(&message.serializer, x),
+            None => Vec::new(),
         };
 
         let qos = packet_id_to_qos(message.packet_id);
 
-        let packet = PublishPacket::new(topic_name, qos, payload);
-        return packet;
+        PublishPacket::new(topic_name, qos, payload)
+    }
+}
+
+#[cfg(any(feature = "telemetry", feature = "direct-methods"))]
+/// The most scratch strings kept for building property bags, per thread
+#[cfg(feature = "telemetry")]
+const PROPERTY_BAG_POOL_SIZE: usize = 4;
+
+#[cfg(feature = "telemetry")]
+thread_local! {
+    static PROPERTY_BAG_POOL: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(feature = "telemetry")]
+fn take_property_bag_scratch() -> String {
+    PROPERTY_BAG_POOL.with(|pool| pool.borrow_mut().pop().unwrap_or_default())
+}
+
+#[cfg(feature = "telemetry")]
+fn return_property_bag_scratch(mut scratch: String) {
+    scratch.clear();
+    PROPERTY_BAG_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < PROPERTY_BAG_POOL_SIZE {
+            pool.push(scratch);
+        }
+    });
+}
+
+fn serialize_payload(serializer: &Option<Arc<dyn PayloadSerializer>>, value: &Value) -> Vec<u8> {
+    match serializer {
+        Some(serializer) => serializer.serialize(value),
+        None => JsonSerializer.serialize(value),
     }
 }
 
@@ -657,7 +964,8 @@ fn qos_to_packet_id(qos: QoSWithPacketIdentifier) -> Option<PacketId> {
     match qos {
         QoSWithPacketIdentifier::Level0 => None,
         QoSWithPacketIdentifier::Level1(pkid) => Some(pkid.into()),
-        QoSWithPacketIdentifier::Level2(pkid) => Some(pkid.into()),
+        // acknowledged by the transport, see `ExactlyOnceHandshakes`
+        QoSWithPacketIdentifier::Level2(_) => None,
     }
 }
 
@@ -675,4 +983,210 @@ fn deserialize_message_body<'packet, T>(packet: &'packet PublishPacket) -> Resul
         Ok(json) => Ok(json),
         Err(_e) => Err(CodecError::InvalidMessageBody),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect::{MqttClientId, MqttClientIdError};
+
+    #[test]
+    fn test_encode_to_vec_matches_encode() {
+        let msg: MsgToHub = ConnectMsg {
+            client_id: ClientIdentity::try_from_device_id("device1").unwrap(),
+            server_addr: "hub.azure-devices.net".to_owned(),
+            sas_token: Some("token".to_owned()),
+            session_mode: SessionMode::Clean,
+            client_id_override: None,
+        }
+        .into();
+
+        let mut buf = [0u8; 1024];
+        let length = IotCodec::encode(&msg, &mut buf).unwrap();
+        let encoded = IotCodec::encode_to_vec(&msg).unwrap();
+
+        assert_eq!(encoded.len(), length);
+        assert_eq!(encoded.capacity(), length);
+        assert_eq!(&encoded[..], &buf[..length]);
+    }
+
+    #[test]
+    fn test_disconnect_is_encoded_as_mqtt_disconnect() {
+        assert_eq!(MsgToHub::Disconnect.packet_id(), None);
+        assert_eq!(IotCodec::encode_to_vec(&MsgToHub::Disconnect).unwrap(), vec![0xE0, 0x00]);
+    }
+
+    #[test]
+    fn test_client_id_override_replaces_the_derived_client_id() {
+        let msg: MsgToHub = ConnectMsg {
+            client_id: ClientIdentity::try_from_device_id("device1").unwrap(),
+            server_addr: "hub.azure-devices.net".to_owned(),
+            sas_token: None,
+            session_mode: SessionMode::Clean,
+            client_id_override: Some(MqttClientId::new("tenant1/device1").unwrap()),
+        }
+        .into();
+
+        match IotCodec::encode_message(&msg).unwrap() {
+            VariablePacket::ConnectPacket(packet) => {
+                assert_eq!(packet.client_identifier(), "tenant1/device1");
+                assert_eq!(
+                    packet.user_name(),
+                    Some("hub.azure-devices.net/device1/api-version=2018-06-30")
+                );
+            }
+            other => panic!("Unexpected packet: {:?}", other),
+        }
+        assert_eq!(MqttClientId::new(""), Err(MqttClientIdError::Empty));
+        assert_eq!(MqttClientId::new("a\0b"), Err(MqttClientIdError::InvalidCharacter('\0')));
+    }
+
+    #[cfg(feature = "telemetry")]
+    #[test]
+    fn test_telemetry_properties_are_form_urlencoded() {
+        let mut headers = HashMap::new();
+        let _ = headers.insert("query".to_owned(), "a=1&b=2 c".to_owned());
+        let msg = TelemetryMsg {
+            client_id: ClientIdentity::try_from_device_id("device1").unwrap(),
+            content: None,
+            packet_id: None,
+            exactly_once: false,
+            headers: Some(headers),
+            serializer: None,
+        };
+
+        for _ in 0..2 {
+            let packet = IotCodec::encode_telemetry_message(&msg);
+            assert_eq!(
+                packet.topic_name(),
+                "devices/device1/messages/events/query=a%3D1%26b%3D2%20c"
+            );
+            let properties = packet.topic_name().trim_start_matches("devices/device1/messages/events/");
+            assert_eq!(query::find(properties, "query").unwrap(), "a=1&b=2 c");
+        }
+    }
+
+    #[test]
+    fn test_unsubscriptions_are_acknowledged_by_unsuback() {
+        let client_id = ClientIdentity::try_from_device_id("device1").unwrap();
+        let msg: MsgToHub = UnsubMsg::for_kind(4.into(), SubscriptionKind::CloudToDevice, &client_id)
+            .unwrap()
+            .into();
+        assert_eq!(msg.packet_id(), Some(4.into()));
+
+        match IotCodec::encode_message(&msg).unwrap() {
+            VariablePacket::UnsubscribePacket(packet) => {
+                assert_eq!(packet.packet_identifier(), 4);
+                assert_eq!(packet.payload_ref().subscribes().len(), 1);
+                assert_eq!(&packet.payload_ref().subscribes()[0][..], "devices/device1/messages/devicebound/#");
+            }
+            other => panic!("Unexpected packet: {:?}", other),
+        }
+
+        let unsuback = VariablePacket::UnsubackPacket(UnsubackPacket::new(4));
+        match IotCodec::decode_packet(unsuback).unwrap() {
+            MsgFromHub::UnsubscriptionSucceeded(packet_id) => assert_eq!(packet_id, 4.into()),
+            other => panic!("Unexpected message: {}", other),
+        }
+    }
+
+    #[cfg(feature = "telemetry")]
+    #[test]
+    fn test_strict_mode_rejects_reserved_properties_and_long_topics() {
+        let mut headers = HashMap::new();
+        let _ = headers.insert("$custom".to_owned(), "1".to_owned());
+        let mut msg = TelemetryMsg {
+            client_id: ClientIdentity::try_from_device_id("device1").unwrap(),
+            content: None,
+            packet_id: None,
+            exactly_once: false,
+            headers: Some(headers),
+            serializer: None,
+        };
+        let strict = CodecOptions { strict: true };
+
+        assert!(IotCodec::encode_message_with(&msg.clone().into(), CodecOptions::default()).is_ok());
+        assert!(matches!(
+            IotCodec::encode_message_with(&msg.clone().into(), strict),
+            Err(CodecError::NonConformant(Violation::ReservedPropertyName))
+        ));
+
+        let mut headers = HashMap::new();
+        let _ = headers.insert("note".to_owned(), "x".repeat(conformance::MAX_TOPIC_LEN));
+        msg.headers = Some(headers);
+        assert!(matches!(
+            IotCodec::encode_message_with(&msg.into(), strict),
+            Err(CodecError::NonConformant(Violation::TopicTooLong))
+        ));
+    }
+
+    #[test]
+    fn test_module_input_subscriptions_are_derived_from_the_identity() {
+        let module = ModuleIdentity::new("device1", "module1").unwrap();
+        let msg: MsgToHub = IotCodec::module_subscriptions(&module)
+            .inputs(3.into(), DeliveryGuarantees::AtLeastOnce)
+            .into();
+        assert_eq!(msg.topic_filters(), vec!["devices/device1/modules/module1/inputs/#".to_owned()]);
+
+        match IotCodec::encode_message(&msg).unwrap() {
+            VariablePacket::SubscribePacket(packet) => {
+                assert_eq!(packet.packet_identifier(), 3);
+                assert_eq!(packet.payload_ref().subscribes().len(), 1);
+            }
+            other => panic!("Unexpected packet: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_packet_for_reports_misrouted_messages() {
+        let device = ClientIdentity::try_from_device_id("device1").unwrap();
+        let publish = |topic: &str| -> VariablePacket {
+            PublishPacket::new(
+                TopicName::new(topic).unwrap(),
+                QoSWithPacketIdentifier::Level1(7),
+                br#""hello""#.to_vec(),
+            )
+            .into()
+        };
+
+        match IotCodec::decode_packet_for(publish("devices/device2/messages/devicebound/"), &device) {
+            Ok(MsgFromHub::MisroutedMessage(msg)) => {
+                assert_eq!(msg.topic, "devices/device2/messages/devicebound/");
+                assert_eq!(msg.packet_id, Some(7.into()));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(
+            IotCodec::decode_packet_for(publish("devices/device1/modules/m1/inputs/in1/"), &device),
+            Ok(MsgFromHub::MisroutedMessage(_))
+        ));
+        #[cfg(feature = "c2d")]
+        assert!(matches!(
+            IotCodec::decode_packet_for(publish("devices/device1/messages/devicebound/"), &device),
+            Ok(MsgFromHub::CloudToDeviceMessage(_))
+        ));
+    }
+
+    #[cfg(feature = "c2d")]
+    #[test]
+    fn test_decode_module_input_message() {
+        let packet = PublishPacket::new(
+            TopicName::new("devices/device1/modules/module1/inputs/input1/%24.mid=7&k=v").unwrap(),
+            QoSWithPacketIdentifier::Level1(9),
+            br#""hello""#.to_vec(),
+        );
+
+        match IotCodec::decode_packet(packet.into()) {
+            Ok(MsgFromHub::ModuleInputMessage(msg)) => {
+                assert_eq!(msg.device_id, "device1");
+                assert_eq!(msg.module_id, "module1");
+                assert_eq!(msg.input_name, "input1");
+                assert_eq!(msg.packet_id, Some(9.into()));
+                assert_eq!(msg.body.as_deref(), Some("hello"));
+                let props = msg.props.unwrap();
+                assert_eq!(props["$.mid"], "7");
+                assert_eq!(props["k"], "v");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -11,7 +11,6 @@
     coherence_leak_check,
     conflicting_repr_hints,
     confusable_idents,
-    const_err,
     dead_code,
     deprecated_in_future,
     elided_lifetimes_in_paths,
@@ -19,9 +18,7 @@
     explicit_outlives_requirements,
     exported_private_dependencies,
     ill_formed_attribute_input,
-    illegal_floating_point_literal_pattern,
     improper_ctypes,
-    indirect_structural_match,
     inline_no_sanitize,
     broken_intra_doc_links,
     invalid_codeblock_attributes,
@@ -38,8 +35,6 @@
     missing_debug_implementations,
     missing_doc_code_examples,
     missing_docs,
-    missing_fragment_specifier,
-    mutable_borrow_reservation_conflict,
     mutable_transmutes,
     no_mangle_const_items,
     no_mangle_generic_items,
@@ -48,7 +43,6 @@
     non_shorthand_field_patterns,
     non_snake_case,
     non_upper_case_globals,
-    order_dependent_trait_objects,
     overflowing_literals,
     overlapping_range_endpoints,
     path_statements,
@@ -58,7 +52,6 @@
     pub_use_of_private_extern_crate,
     redundant_semicolons,
     renamed_and_removed_lints,
-    safe_packed_borrows,
     single_use_lifetimes,
     soft_unstable,
     stable_features,
@@ -67,7 +60,6 @@
     trivial_numeric_casts,
     type_alias_bounds,
     tyvar_behind_raw_pointer,
-    unaligned_references,
     uncommon_codepoints,
     unconditional_panic,
     unconditional_recursion,
@@ -103,18 +95,13 @@
     unused_variables,
     variant_size_differences,
     warnings,
-    where_clauses_object_safety,
     while_true,
     arithmetic_overflow,
     array_into_iter,
     deprecated,
     incomplete_features,
-    private_in_public,
 )]
 
-use log;
-use serde_json;
-
 /// Device and module identities
 pub mod identity;
 
@@ -130,7 +117,33 @@ pub mod qos;
 /// Authentication methods
 pub mod auth;
 
+/// IoT Hub topic names and filters
+pub mod topics;
+
+/// Payload serialization formats
+pub mod serialization;
+
+/// Payload compression
+pub mod compression;
+
+/// Masking of credentials and payloads in log output
+pub mod redact;
+
+/// Validation of messages against IoT Hub constraints, for the codec's strict mode
+pub mod conformance;
+
+/// End-to-end encryption of telemetry and C2D payloads
+#[cfg(any(feature = "c2d", feature = "telemetry"))]
+pub mod encryption;
+
+#[cfg(any(feature = "c2d", feature = "twin", feature = "direct-methods", feature = "telemetry"))]
+mod query;
+
+#[cfg(all(test, feature = "c2d", feature = "twin", feature = "direct-methods"))]
+mod captures;
+
 pub use crate::identity::*;
 pub use crate::iot_codec::*;
 pub use crate::messages::*;
 pub use crate::subscription::*;
+pub use crate::receipt::*;
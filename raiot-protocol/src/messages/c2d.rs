@@ -1,5 +1,14 @@
 use crate::{qos::DeliveryGuarantees, qos::PacketId, DeviceIdentity, PropertyBag};
 use std::fmt::{self, Formatter};
+use std::time::SystemTime;
+
+/// The C2D system property holding the absolute expiry time of the message (ISO 8601)
+#[cfg(feature = "c2d")]
+pub const EXPIRY_TIME_PROPERTY: &str = "$.exp";
+
+/// The C2D property holding the time the hub enqueued the message (ISO 8601), by the hub's clock
+#[cfg(feature = "c2d")]
+pub const ENQUEUED_TIME_PROPERTY: &str = "iothub-enqueuedtime";
 
 /// Represents a request to subscribe to C2D messages
 #[cfg(feature = "c2d")]
@@ -15,6 +24,14 @@ pub struct C2DSub {
     pub mode: DeliveryGuarantees,
 }
 
+#[cfg(feature = "c2d")]
+impl C2DSub {
+    /// The topic filter registered by this subscription
+    pub fn topic_filter(&self) -> String {
+        crate::topics::c2d_filter(&self.device_id.device_id)
+    }
+}
+
 /// Represents a single C2D message
 #[cfg(feature = "c2d")]
 #[derive(Clone, Debug)]
@@ -33,6 +50,65 @@ pub struct C2DMsg {
     pub props: Option<PropertyBag>,
 }
 
+#[cfg(feature = "c2d")]
+impl C2DMsg {
+    /// The absolute expiry time of the message, if set and valid
+    pub fn expiry_time(&self) -> Option<SystemTime> {
+        self.time_property(EXPIRY_TIME_PROPERTY)
+    }
+
+    /// The time the hub enqueued the message, if set and valid
+    pub fn enqueued_time(&self) -> Option<SystemTime> {
+        self.time_property(ENQUEUED_TIME_PROPERTY)
+    }
+
+    /// Returns TRUE if the message expired at or before the specified time
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expiry_time().is_some_and(|expiry| expiry <= now)
+    }
+
+    fn time_property(&self, name: &str) -> Option<SystemTime> {
+        let value = self.props.as_ref()?.get(name)?;
+        chrono::DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(SystemTime::from)
+    }
+}
+
+/// Represents a single message routed to an input of a module
+#[cfg(feature = "c2d")]
+#[derive(Clone, Debug)]
+pub struct ModuleInputMsg {
+    /// Packet Identifier
+    /// Only present if QoS1 is used
+    pub packet_id: Option<PacketId>,
+
+    /// The message body (if any)
+    pub body: Option<String>,
+
+    /// The recipient device ID
+    pub device_id: String,
+
+    /// The recipient module ID
+    pub module_id: String,
+
+    /// The input the message was routed to
+    pub input_name: String,
+
+    /// Message properties, if any
+    pub props: Option<PropertyBag>,
+}
+
+impl fmt::Display for ModuleInputMsg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Input: {}, Props: {:?}, Body: {:?}, PacketID: {:?}",
+            self.input_name, self.props, self.body, self.packet_id
+        )
+    }
+}
+
 impl fmt::Display for C2DMsg {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
@@ -42,3 +118,28 @@ impl fmt::Display for C2DMsg {
         )
     }
 }
+
+#[cfg(all(test, feature = "c2d"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_expiry_time_is_parsed() {
+        let mut props = PropertyBag::new();
+        let _ = props.insert(EXPIRY_TIME_PROPERTY.to_owned(), "2020-01-01T00:00:10.000Z".to_owned());
+        let _ = props.insert(ENQUEUED_TIME_PROPERTY.to_owned(), "2020-01-01T00:00:02.500Z".to_owned());
+        let msg = C2DMsg {
+            packet_id: None,
+            body: None,
+            device_id: "device1".to_owned(),
+            props: Some(props),
+        };
+
+        let expiry = SystemTime::UNIX_EPOCH + Duration::from_secs(1_577_836_810);
+        assert_eq!(msg.expiry_time(), Some(expiry));
+        assert!(!msg.is_expired(expiry - Duration::from_secs(1)));
+        assert!(msg.is_expired(expiry));
+        assert_eq!(msg.enqueued_time(), Some(expiry - Duration::from_millis(7500)));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::messages::telemetry::TelemetryMsg;
+use crate::serialization::{JsonSerializer, PayloadSerializer};
+use crate::PropertyBag;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The maximum size of a device-to-cloud message accepted by IoT Hub, properties included
+pub const MAX_D2C_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// The property correlating the chunks of a payload
+pub const CHUNK_TRANSFER_ID_PROPERTY: &str = "chunk-transfer-id";
+
+/// The property carrying the zero-based position of a chunk
+pub const CHUNK_SEQUENCE_PROPERTY: &str = "chunk-sequence";
+
+/// The property carrying the number of chunks a payload was split into
+pub const CHUNK_TOTAL_PROPERTY: &str = "chunk-total";
+
+/// Splits a telemetry message whose serialized payload exceeds `max_chunk_size` bytes into chunks,
+/// correlated by the transfer ID and numbered by the chunk properties. Smaller messages are returned as is,
+/// those without a payload included: there is always at least one chunk.
+/// Chunks keep the headers and content type of the message, but carry no packet ID:
+/// one must be assigned to each chunk sent with QoS 1.
+/// Leave room for the properties when choosing `max_chunk_size`, the hub limit applies to the whole message.
+///
+/// # Panics
+/// Panics if `max_chunk_size` is zero
+pub fn split_telemetry(msg: TelemetryMsg, max_chunk_size: usize, transfer_id: &str) -> Vec<TelemetryMsg> {
+    assert!(max_chunk_size > 0, "Chunks must hold at least one byte");
+    let payload = match (&msg.content, &msg.serializer) {
+        (Some(value), Some(serializer)) => serializer.serialize(value),
+        (Some(value), None) => JsonSerializer.serialize(value),
+        (None, _) => return vec![msg],
+    };
+    if payload.len() <= max_chunk_size {
+        return vec![msg];
+    }
+    let format: &dyn PayloadSerializer = match &msg.serializer {
+        Some(serializer) => serializer.as_ref(),
+        None => &JsonSerializer,
+    };
+    let total = payload.len().div_ceil(max_chunk_size);
+    payload
+        .chunks(max_chunk_size)
+        .enumerate()
+        .map(|(sequence, data)| {
+            let mut headers = msg.headers.clone().unwrap_or_default();
+            let _ = headers.insert(CHUNK_TRANSFER_ID_PROPERTY.to_owned(), transfer_id.to_owned());
+            let _ = headers.insert(CHUNK_SEQUENCE_PROPERTY.to_owned(), sequence.to_string());
+            let _ = headers.insert(CHUNK_TOTAL_PROPERTY.to_owned(), total.to_string());
+            let chunk = ChunkPayload {
+                data: data.to_vec(),
+                content_type: format.content_type().to_owned(),
+                content_encoding: format.content_encoding().map(str::to_owned),
+            };
+            TelemetryMsg {
+                client_id: msg.client_id.clone(),
+                content: Some(Value::Null),
+                packet_id: None,
+                exactly_once: msg.exactly_once,
+                headers: Some(headers),
+                serializer: Some(Arc::new(chunk)),
+            }
+        })
+        .collect()
+}
+
+/// Writes the bytes of a chunk, already serialized, under the content type of the whole payload
+struct ChunkPayload {
+    data: Vec<u8>,
+    content_type: String,
+    content_encoding: Option<String>,
+}
+
+impl fmt::Debug for ChunkPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChunkPayload({} bytes, {})", self.data.len(), self.content_type)
+    }
+}
+
+impl PayloadSerializer for ChunkPayload {
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn content_encoding(&self) -> Option<&str> {
+        self.content_encoding.as_deref()
+    }
+
+    fn serialize(&self, _value: &Value) -> Vec<u8> {
+        self.data.clone()
+    }
+}
+
+/// A chunk which cannot be reassembled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    /// A chunk property is missing or not a number
+    InvalidProperty(&'static str),
+
+    /// The chunk does not match the transfer it belongs to (e.g. a different total, or a sequence past it)
+    Inconsistent(String),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::InvalidProperty(name) => write!(f, "Invalid chunk property: {}", name),
+            ChunkError::Inconsistent(transfer_id) => write!(f, "Inconsistent chunk of transfer {}", transfer_id),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+/// Reassembles the payloads split by `split_telemetry`, on the service side.
+/// Chunks may arrive in any order, and the chunks of several transfers may interleave.
+#[derive(Debug, Default)]
+pub struct ChunkReassembler {
+    transfers: HashMap<String, Vec<Option<Vec<u8>>>>,
+}
+
+impl ChunkReassembler {
+    /// Creates a reassembler with no transfers in progress
+    pub fn new() -> ChunkReassembler {
+        ChunkReassembler::default()
+    }
+
+    /// Accepts the properties and payload of a received message.
+    /// Returns the whole payload once the last chunk of a transfer arrived, or right away for a message which was not split.
+    ///
+    /// # Errors
+    /// Returns an error if the chunk properties are invalid, or disagree with earlier chunks of the transfer
+    pub fn accept(&mut self, properties: &PropertyBag, payload: &[u8]) -> Result<Option<Vec<u8>>, ChunkError> {
+        let transfer_id = match properties.get(CHUNK_TRANSFER_ID_PROPERTY) {
+            Some(transfer_id) => transfer_id,
+            None => return Ok(Some(payload.to_vec())),
+        };
+        let sequence = Self::number(properties, CHUNK_SEQUENCE_PROPERTY)?;
+        let total = Self::number(properties, CHUNK_TOTAL_PROPERTY)?;
+        let chunks = self
+            .transfers
+            .entry(transfer_id.clone())
+            .or_insert_with(|| vec![None; total]);
+        if chunks.len() != total || sequence >= total {
+            return Err(ChunkError::Inconsistent(transfer_id.clone()));
+        }
+        chunks[sequence] = Some(payload.to_vec());
+        if chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let chunks = self.transfers.remove(transfer_id).unwrap_or_default();
+        Ok(Some(chunks.into_iter().flatten().flatten().collect()))
+    }
+
+    /// The number of transfers still missing chunks
+    pub fn pending(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Abandons a transfer, e.g. one whose remaining chunks are not expected anymore
+    pub fn discard(&mut self, transfer_id: &str) {
+        let _ = self.transfers.remove(transfer_id);
+    }
+
+    fn number(properties: &PropertyBag, name: &'static str) -> Result<usize, ChunkError> {
+        properties
+            .get(name)
+            .and_then(|value| value.parse().ok())
+            .ok_or(ChunkError::InvalidProperty(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientIdentity;
+    use serde_json::json;
+
+    #[test]
+    fn test_split_payloads_are_reassembled_in_any_order() {
+        let msg = TelemetryMsg {
+            client_id: ClientIdentity::try_from_device_id("d1").unwrap(),
+            content: Some(json!("0123456789")),
+            packet_id: None,
+            exactly_once: false,
+            headers: None,
+            serializer: None,
+        };
+        let chunks = split_telemetry(msg, 5, "t1");
+        assert_eq!(chunks.len(), 3);
+
+        let mut sut = ChunkReassembler::new();
+        let mut reassembled = None;
+        for chunk in chunks.iter().rev() {
+            let payload = chunk.serializer.as_ref().unwrap().serialize(&Value::Null);
+            assert!(payload.len() <= 5);
+            assert!(reassembled.is_none());
+            reassembled = sut.accept(chunk.headers.as_ref().unwrap(), &payload).unwrap();
+        }
+        assert_eq!(reassembled, Some(br#""0123456789""#.to_vec()));
+        assert_eq!(sut.pending(), 0);
+    }
+
+    #[test]
+    fn test_messages_without_payload_are_a_single_chunk() {
+        let msg = TelemetryMsg {
+            client_id: ClientIdentity::try_from_device_id("d1").unwrap(),
+            content: None,
+            packet_id: None,
+            exactly_once: false,
+            headers: None,
+            serializer: None,
+        };
+        let chunks = split_telemetry(msg, 5, "t1");
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].content.is_none());
+        assert!(chunks[0].headers.is_none());
+    }
+}
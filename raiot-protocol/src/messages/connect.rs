@@ -1,6 +1,12 @@
 use crate::{identity::ClientIdentity, qos::SessionMode};
 use core::fmt::{self, Display};
 
+/// The IoT Hub API version requested by every connection
+pub const IOT_HUB_API_VERSION: &str = "2018-06-30";
+
+/// The MQTT protocol level of every connection (MQTT 3.1.1)
+pub const MQTT_PROTOCOL_LEVEL: u8 = 4;
+
 /// A request to connect to the IoT Hub
 #[derive(Clone, Debug)]
 pub struct ConnectMsg {
@@ -16,14 +22,174 @@ pub struct ConnectMsg {
 
     /// The session mode of the new connection
     pub session_mode: SessionMode,
+
+    /// The MQTT client ID to connect with, instead of the one derived from `client_id`.
+    /// For brokers and gateways requiring prefixed or tenant-scoped client IDs.
+    pub client_id_override: Option<MqttClientId>,
+}
+
+/// The longest client ID an MQTT string can hold, in bytes
+pub const MAX_MQTT_CLIENT_ID_LENGTH: usize = 65535;
+
+/// An MQTT client ID, validated according to the MQTT 3.1.1 rules for UTF-8 strings
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttClientId(String);
+
+impl MqttClientId {
+    /// Validates a client ID.
+    /// Empty IDs, which ask the server to assign one, are rejected: IoT Hub requires the client to identify itself.
+    ///
+    /// # Errors
+    /// Returns an error if the ID is empty, too long, or contains a character MQTT strings cannot hold
+    pub fn new(client_id: &str) -> Result<MqttClientId, MqttClientIdError> {
+        if client_id.is_empty() {
+            return Err(MqttClientIdError::Empty);
+        }
+        if client_id.len() > MAX_MQTT_CLIENT_ID_LENGTH {
+            return Err(MqttClientIdError::TooLong(client_id.len()));
+        }
+        if let Some(c) = client_id.chars().find(|c| *c == '\0' || c.is_control()) {
+            return Err(MqttClientIdError::InvalidCharacter(c));
+        }
+        Ok(MqttClientId(client_id.to_owned()))
+    }
+
+    /// The client ID
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for MqttClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The reason a client ID is not a valid MQTT client ID
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum MqttClientIdError {
+    /// The ID is empty
+    Empty,
+
+    /// The ID is longer than an MQTT string can hold. Holds the actual length, in bytes.
+    TooLong(usize),
+
+    /// The ID contains a control character, which MQTT strings must not hold
+    InvalidCharacter(char),
+}
+
+impl Display for MqttClientIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttClientIdError::Empty => write!(f, "Client ID is empty"),
+            MqttClientIdError::TooLong(length) => write!(
+                f,
+                "Client ID is {} bytes long, the maximum is {}",
+                length, MAX_MQTT_CLIENT_ID_LENGTH
+            ),
+            MqttClientIdError::InvalidCharacter(c) => {
+                write!(f, "Client ID contains an invalid character: {:?}", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MqttClientIdError {}
+
+/// The IoT Hub's response to the connection request
+pub type ConnectRes = Result<ConnectSuccess, ConnectError>;
+
+/// A successful connection
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub struct ConnectSuccess {
+    /// TRUE if the hub resumed a previous (dirty) session
+    pub session_present: bool,
+}
+
+/// What the hub agreed to when the connection was established.
+/// Client APIs depending on an optional feature check it, failing with `MissingCapability` instead of misbehaving.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The MQTT protocol level accepted by the hub
+    pub protocol_level: u8,
+
+    /// The IoT Hub API version of the connection
+    pub api_version: &'static str,
+
+    /// TRUE if the hub resumed a previous (dirty) session
+    pub session_present: bool,
+
+    /// TRUE if the connection announced an IoT Plug and Play model ID.
+    /// The connection request carries no model ID yet, so this is always FALSE.
+    pub pnp_enabled: bool,
+}
+
+impl Capabilities {
+    /// The capabilities of a connection, as acknowledged by the hub
+    pub fn negotiated(response: &ConnectSuccess) -> Capabilities {
+        Capabilities {
+            protocol_level: MQTT_PROTOCOL_LEVEL,
+            api_version: IOT_HUB_API_VERSION,
+            session_present: response.session_present,
+            pnp_enabled: false,
+        }
+    }
+
+    /// Returns TRUE if the connection supports the capability
+    pub fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::SessionResumption => self.session_present,
+            Capability::PlugAndPlay => self.pnp_enabled,
+        }
+    }
+
+    /// Fails fast when an API needs a capability the connection lacks
+    ///
+    /// # Errors
+    /// Returns the missing capability
+    pub fn require(&self, capability: Capability) -> Result<(), MissingCapability> {
+        match self.supports(capability) {
+            true => Ok(()),
+            false => Err(MissingCapability(capability)),
+        }
+    }
+}
+
+/// An optional feature of a connection
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// The hub kept the state of a previous session: its subscriptions and unacknowledged QoS1 messages
+    SessionResumption,
+
+    /// The connection announced an IoT Plug and Play model ID
+    PlugAndPlay,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::SessionResumption => write!(f, "session resumption (the hub did not resume a session)"),
+            Capability::PlugAndPlay => write!(f, "IoT Plug and Play (no model ID was announced)"),
+        }
+    }
 }
 
-/// Represents the IoT Hub's response to the connection request
-#[derive(Clone, Debug, Copy)]
-pub enum ConnectRes {
-    /// The connection succeeded
-    Accepted,
+/// An API was called on a connection lacking a capability it requires
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub struct MissingCapability(pub Capability);
 
+impl Display for MissingCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The connection does not support {}", self.0)
+    }
+}
+
+impl std::error::Error for MissingCapability {}
+
+/// The reason a connection attempt failed
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum ConnectError {
     /// Authentication failure (i.e. incorrect credentials, or device is not defined in this hub, etc.)
     AuthenticationFailed,
 
@@ -47,10 +213,57 @@ pub enum ConnectRes {
 
     /// IO Error
     IOError(std::io::ErrorKind),
+
+    /// The connection attempt was cancelled by the application
+    Cancelled,
 }
 
-impl Display for ConnectRes {
+impl Display for ConnectError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            ConnectError::AuthenticationFailed => write!(f, "Authentication failed"),
+            ConnectError::Unauthorized => write!(f, "Not authorized"),
+            ConnectError::ServiceUnavailable => write!(f, "Service unavailable"),
+            ConnectError::Timeout => write!(f, "Connection timed out"),
+            ConnectError::UnacceptableProtocolVersion => {
+                write!(f, "Unacceptable MQTT protocol version")
+            }
+            ConnectError::MqttReservedErrorCode(code) => {
+                write!(f, "MQTT reserved error code {}", code)
+            }
+            ConnectError::ProtocolViolation => write!(f, "Protocol violation"),
+            ConnectError::IOError(kind) => write!(f, "IO error: {:?}", kind),
+            ConnectError::Cancelled => write!(f, "Connection attempt cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<std::io::Error> for ConnectError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => ConnectError::Timeout,
+            kind => ConnectError::IOError(kind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_capabilities_fail_fast_when_missing() {
+        let fresh = Capabilities::negotiated(&ConnectSuccess { session_present: false });
+        let resumed = Capabilities::negotiated(&ConnectSuccess { session_present: true });
+
+        assert_eq!(
+            fresh.require(Capability::SessionResumption),
+            Err(MissingCapability(Capability::SessionResumption))
+        );
+        assert_eq!(resumed.require(Capability::SessionResumption), Ok(()));
+        assert!(!resumed.supports(Capability::PlugAndPlay));
+        assert_eq!(resumed.api_version, IOT_HUB_API_VERSION);
     }
 }
@@ -1,6 +1,8 @@
 use std::fmt::{self, Formatter};
+use std::sync::Arc;
 
 use crate::qos::{DeliveryGuarantees, PacketId};
+use crate::serialization::PayloadSerializer;
 
 /// A subscription request to receive direct method invocation requests
 #[cfg(feature = "direct-methods")]
@@ -13,6 +15,14 @@ pub struct DirectMethodsSub {
     pub mode: DeliveryGuarantees,
 }
 
+#[cfg(feature = "direct-methods")]
+impl DirectMethodsSub {
+    /// The topic filter registered by this subscription
+    pub fn topic_filter(&self) -> String {
+        crate::topics::METHODS_POST_FILTER.to_owned()
+    }
+}
+
 /// A request from the IoT Hub to invoke a specific method on the device
 #[cfg(feature = "direct-methods")]
 #[derive(Clone, Debug)]
@@ -55,4 +65,7 @@ pub struct DirectMethodRes {
 
     /// Packet identifier
     pub packet_id: Option<PacketId>,
+
+    /// Serializes the payload. None sends JSON without a content type.
+    pub serializer: Option<Arc<dyn PayloadSerializer>>,
 }
@@ -4,10 +4,17 @@ pub mod subscription;
 /// Connection flow messages
 pub mod connect;
 
+/// Acknowledgements of device requests
+pub mod receipt;
+
 /// Device-to-cloud telemetry messages
 #[cfg(feature = "telemetry")]
 pub mod telemetry;
 
+/// Splitting of large device-to-cloud payloads into correlated chunks, and their reassembly
+#[cfg(feature = "telemetry")]
+pub mod chunking;
+
 /// Cloud-to-device messages
 #[cfg(feature = "c2d")]
 pub mod c2d;
@@ -39,6 +46,31 @@ use crate::messages::telemetry::*;
 
 use crate::messages::subscription::*;
 
+/// The payload of the messages of the disabled features, which are never constructed.
+/// The variants remain, so that matches compile whichever features another crate of the build enables.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Disabled {}
+
+#[cfg(not(feature = "telemetry"))]
+use Disabled as TelemetryMsg;
+
+#[cfg(not(feature = "c2d"))]
+use {Disabled as C2DMsg, Disabled as C2DSub, Disabled as ModuleInputMsg};
+
+#[cfg(not(feature = "direct-methods"))]
+use {Disabled as DirectMethodReq, Disabled as DirectMethodRes, Disabled as DirectMethodsSub};
+
+#[cfg(not(feature = "twin"))]
+use {
+    Disabled as DesiredPropsUpdated, Disabled as ReadTwinReq, Disabled as ReadTwinRes, Disabled as TwinReadSub,
+    Disabled as TwinUpdatesSub, Disabled as UpdateReportedPropsReq, Disabled as UpdateReportedPropsRes,
+};
+
+#[cfg(feature = "raw-mqtt")]
+type RawPacket = mqtt::packet::VariablePacket;
+#[cfg(not(feature = "raw-mqtt"))]
+type RawPacket = Disabled;
+
 /// Message properties data structure
 pub type PropertyBag = HashMap<String, String>;
 
@@ -49,7 +81,33 @@ pub struct AckMsg {
     pub packet_id: PacketId,
 }
 
-/// Represents a single message from the IoT Hub to the device
+/// A step of a QoS2 (exactly once) handshake, answering a packet of the hub.
+/// These are sent by the transport, see `ExactlyOnceHandshakes`.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub enum ExactlyOnceMsg {
+    /// PUBREC: a publication of the hub was received, and will not be passed on again
+    Received(PacketId),
+
+    /// PUBREL: the hub received a publication of the device, which can be released
+    Release(PacketId),
+
+    /// PUBCOMP: the hub released its publication, ending the handshake
+    Complete(PacketId),
+}
+
+/// A C2D or module input message addressed to another device or module than the client's,
+/// as reported by `IotCodec::decode_packet_for`
+#[derive(Clone, Debug)]
+pub struct MisroutedMsg {
+    /// The topic the message was published to
+    pub topic: String,
+
+    /// The packet identifier, for QoS 1 messages
+    pub packet_id: Option<PacketId>,
+}
+
+/// Represents a single message from the IoT Hub to the device.
+/// The variants are the same whatever the crate's features: the payloads of the disabled ones are `Disabled`.
 #[derive(Clone, Debug)]
 pub enum MsgFromHub {
     /// The codec did not recognize the decoded message.
@@ -59,20 +117,24 @@ pub enum MsgFromHub {
     /// The response to a connection attempt
     ConnectResponseMessage(ConnectRes),
 
-    /// The response to a Twin Read request
-    #[cfg(feature = "twin")]
+    /// The response to a Twin Read request, or to a twin request the client did not correlate (see `TwinCorrelation`)
     TwinResponseMessage(ReadTwinRes),
 
+    /// The response to a Reported Properties update, as routed by `TwinCorrelation`
+    ReportedPropertiesUpdated(UpdateReportedPropsRes),
+
     /// An event representing an update to the twin's desired properties
-    #[cfg(feature = "twin")]
     DesiredPropertiesUpdated(DesiredPropsUpdated),
 
     /// A C2D message
     #[cfg(feature = "c2d")]
     CloudToDeviceMessage(C2DMsg),
 
+    /// A message routed to an input of the module
+    #[cfg(feature = "c2d")]
+    ModuleInputMessage(ModuleInputMsg),
+
     /// A direct method invocation request
-    #[cfg(feature = "direct-methods")]
     DirectMethodInvocation(DirectMethodReq),
 
     /// The response to a subscription request
@@ -80,6 +142,12 @@ pub enum MsgFromHub {
 
     /// Publication acknowledgement
     PublicationSucceeded(PacketId),
+
+    /// Unsubscription acknowledgement (UNSUBACK)
+    UnsubscriptionSucceeded(PacketId),
+
+    /// A message addressed to another identity. Only reported when decoding with `IotCodec::decode_packet_for`.
+    MisroutedMessage(MisroutedMsg),
 }
 
 impl Display for MsgFromHub {
@@ -92,6 +160,9 @@ impl Display for MsgFromHub {
             MsgFromHub::PublicationSucceeded(packet_id) => {
                 write!(f, "Publication succeeded: {}", packet_id)
             }
+            MsgFromHub::UnsubscriptionSucceeded(packet_id) => {
+                write!(f, "Unsubscription succeeded: {}", packet_id)
+            }
             #[cfg(feature = "twin")]
             MsgFromHub::TwinResponseMessage(resp) => write!(
                 f,
@@ -99,6 +170,12 @@ impl Display for MsgFromHub {
                 resp.status_code, resp.version, resp.body
             ),
             #[cfg(feature = "twin")]
+            MsgFromHub::ReportedPropertiesUpdated(resp) => write!(
+                f,
+                "Reported properties update response: {:?} {:?}",
+                resp.status_code, resp.version
+            ),
+            #[cfg(feature = "twin")]
             MsgFromHub::DesiredPropertiesUpdated(msg) => write!(
                 f,
                 "Desired properties updated, version: {}",
@@ -106,10 +183,13 @@ impl Display for MsgFromHub {
             ),
             #[cfg(feature = "c2d")]
             MsgFromHub::CloudToDeviceMessage(_msg) => write!(f, "C2D Msg"),
+            #[cfg(feature = "c2d")]
+            MsgFromHub::ModuleInputMessage(msg) => write!(f, "Module input msg, input: {}", msg.input_name),
             #[cfg(feature = "direct-methods")]
             MsgFromHub::DirectMethodInvocation(dmi) => {
                 write!(f, "Direct MEthod invocation, method: {}", dmi.method_name)
             }
+            MsgFromHub::MisroutedMessage(msg) => write!(f, "Misrouted msg, topic: {}", msg.topic),
             MsgFromHub::UnknownMessage() => write!(f, "Unknown msg"),
             _other => write!(f, "Some other msg"),
         }
@@ -118,45 +198,53 @@ impl Display for MsgFromHub {
 
 impl From<ConnectRes> for MsgFromHub {
     fn from(response: ConnectRes) -> Self {
-        return MsgFromHub::ConnectResponseMessage(response);
+        MsgFromHub::ConnectResponseMessage(response)
     }
 }
 
 #[cfg(feature = "c2d")]
 impl From<C2DMsg> for MsgFromHub {
     fn from(c2d: C2DMsg) -> Self {
-        return MsgFromHub::CloudToDeviceMessage(c2d);
+        MsgFromHub::CloudToDeviceMessage(c2d)
+    }
+}
+
+#[cfg(feature = "c2d")]
+impl From<ModuleInputMsg> for MsgFromHub {
+    fn from(input: ModuleInputMsg) -> Self {
+        MsgFromHub::ModuleInputMessage(input)
     }
 }
 
 #[cfg(feature = "twin")]
 impl From<ReadTwinRes> for MsgFromHub {
     fn from(response: ReadTwinRes) -> Self {
-        return MsgFromHub::TwinResponseMessage(response);
+        MsgFromHub::TwinResponseMessage(response)
     }
 }
 
 #[cfg(feature = "twin")]
 impl From<DesiredPropsUpdated> for MsgFromHub {
     fn from(update_notification: DesiredPropsUpdated) -> Self {
-        return MsgFromHub::DesiredPropertiesUpdated(update_notification);
+        MsgFromHub::DesiredPropertiesUpdated(update_notification)
     }
 }
 
 #[cfg(feature = "direct-methods")]
 impl From<DirectMethodReq> for MsgFromHub {
     fn from(invocation: DirectMethodReq) -> Self {
-        return MsgFromHub::DirectMethodInvocation(invocation);
+        MsgFromHub::DirectMethodInvocation(invocation)
     }
 }
 
 impl From<SubRes> for MsgFromHub {
     fn from(response: SubRes) -> Self {
-        return MsgFromHub::SubscriptionResponseMessage(response);
+        MsgFromHub::SubscriptionResponseMessage(response)
     }
 }
 
-/// Represnets a message from the device to the IoT hub
+/// Represnets a message from the device to the IoT hub.
+/// The variants are the same whatever the crate's features, like those of `MsgFromHub`.
 #[derive(Clone, Debug)]
 pub enum MsgToHub {
     /// A connection attempt
@@ -165,25 +253,23 @@ pub enum MsgToHub {
     /// An ACK message (in response to an incoming message with QoS1)
     Acknowledge(AckMsg),
 
+    /// A step of a QoS2 handshake
+    ExactlyOnce(ExactlyOnceMsg),
+
     /// A device-to-cloud telemetry message
-    #[cfg(feature = "telemetry")]
     Telemetry(TelemetryMsg),
 
     /// A request to read the twin
-    #[cfg(feature = "twin")]
     ReadTwin(ReadTwinReq),
 
     /// A request to receive twin read results.
     /// This subscription must be completed before sending a ReadTwin request.
-    #[cfg(feature = "twin")]
     SubscribeToTwinReads(TwinReadSub),
 
     /// A request to receive twin reported properties update notifications
-    #[cfg(feature = "twin")]
     SubscribeToTwinUpdates(TwinUpdatesSub),
 
     /// A notification about a change in the twin's reported properties
-    #[cfg(feature = "twin")]
     UpdateReportedProperties(UpdateReportedPropsReq),
 
     /// A request to receive cloud-to-device messages
@@ -191,12 +277,22 @@ pub enum MsgToHub {
     SubscribeToC2D(C2DSub),
 
     /// A request to receive direct method invocation requests
-    #[cfg(feature = "direct-methods")]
     SubscribeToMethods(DirectMethodsSub),
 
+    /// A request to subscribe to several topic filters at once
+    SubscribeToMany(MultiSub),
+
+    /// A request to stop receiving the messages of topic filters
+    Unsubscribe(UnsubMsg),
+
     /// The result of a direct method invocation
-    #[cfg(feature = "direct-methods")]
     DirectMethodResponse(DirectMethodRes),
+
+    /// A graceful end of the connection. The hub closes the connection once it receives it.
+    Disconnect,
+
+    /// An MQTT packet sent as is, for hub features not covered by the typed messages
+    Raw(RawPacket),
 }
 
 impl MsgToHub {
@@ -207,6 +303,9 @@ impl MsgToHub {
 
             MsgToHub::Acknowledge(msg) => Some(msg.packet_id),
 
+            // answers a packet of the hub, nothing awaits the hub's answer
+            MsgToHub::ExactlyOnce(_) => None,
+
             #[cfg(feature = "twin")]
             MsgToHub::ReadTwin(msg) => msg.packet_id,
 
@@ -230,74 +329,141 @@ impl MsgToHub {
 
             #[cfg(feature = "twin")]
             MsgToHub::UpdateReportedProperties(msg) => msg.packet_id,
+
+            MsgToHub::SubscribeToMany(msg) => Some(msg.packet_id),
+
+            MsgToHub::Unsubscribe(msg) => Some(msg.packet_id),
+
+            MsgToHub::Disconnect => None,
+
+            #[cfg(feature = "raw-mqtt")]
+            MsgToHub::Raw(packet) => raw_packet_id(packet),
+
+            // the payloads of the disabled features can't be constructed
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Return the topic filters registered by the specified message, if it is a subscription request
+    pub fn topic_filters(&self) -> Vec<String> {
+        match self {
+            #[cfg(feature = "c2d")]
+            MsgToHub::SubscribeToC2D(msg) => vec![msg.topic_filter()],
+
+            #[cfg(feature = "direct-methods")]
+            MsgToHub::SubscribeToMethods(msg) => vec![msg.topic_filter()],
+
+            #[cfg(feature = "twin")]
+            MsgToHub::SubscribeToTwinReads(msg) => vec![msg.topic_filter()],
+
+            #[cfg(feature = "twin")]
+            MsgToHub::SubscribeToTwinUpdates(msg) => vec![msg.topic_filter()],
+
+            MsgToHub::SubscribeToMany(msg) => msg.topic_filters(),
+
+            _ => Vec::new(),
         }
     }
 }
 
+#[cfg(feature = "raw-mqtt")]
+fn raw_packet_id(packet: &mqtt::packet::VariablePacket) -> Option<PacketId> {
+    use mqtt::packet::{QoSWithPacketIdentifier, VariablePacket};
+    match packet {
+        VariablePacket::PublishPacket(publish) => match publish.qos() {
+            QoSWithPacketIdentifier::Level1(packet_id) => Some(packet_id.into()),
+            QoSWithPacketIdentifier::Level2(packet_id) => Some(packet_id.into()),
+            QoSWithPacketIdentifier::Level0 => None,
+        },
+        VariablePacket::SubscribePacket(subscribe) => Some(subscribe.packet_identifier().into()),
+        VariablePacket::UnsubscribePacket(unsubscribe) => Some(unsubscribe.packet_identifier().into()),
+        _ => None,
+    }
+}
+
 impl From<ConnectMsg> for MsgToHub {
     fn from(msg: ConnectMsg) -> Self {
-        return MsgToHub::Connect(msg);
+        MsgToHub::Connect(msg)
+    }
+}
+
+impl From<MultiSub> for MsgToHub {
+    fn from(msg: MultiSub) -> Self {
+        MsgToHub::SubscribeToMany(msg)
+    }
+}
+
+impl From<UnsubMsg> for MsgToHub {
+    fn from(msg: UnsubMsg) -> Self {
+        MsgToHub::Unsubscribe(msg)
     }
 }
 
 impl From<AckMsg> for MsgToHub {
     fn from(msg: AckMsg) -> Self {
-        return MsgToHub::Acknowledge(msg);
+        MsgToHub::Acknowledge(msg)
+    }
+}
+
+impl From<ExactlyOnceMsg> for MsgToHub {
+    fn from(msg: ExactlyOnceMsg) -> Self {
+        MsgToHub::ExactlyOnce(msg)
     }
 }
 
 #[cfg(feature = "telemetry")]
 impl From<TelemetryMsg> for MsgToHub {
     fn from(msg: TelemetryMsg) -> Self {
-        return MsgToHub::Telemetry(msg);
+        MsgToHub::Telemetry(msg)
     }
 }
 
 #[cfg(feature = "twin")]
 impl From<ReadTwinReq> for MsgToHub {
     fn from(msg: ReadTwinReq) -> Self {
-        return MsgToHub::ReadTwin(msg);
+        MsgToHub::ReadTwin(msg)
     }
 }
 
 #[cfg(feature = "twin")]
 impl From<TwinReadSub> for MsgToHub {
     fn from(msg: TwinReadSub) -> Self {
-        return MsgToHub::SubscribeToTwinReads(msg);
+        MsgToHub::SubscribeToTwinReads(msg)
     }
 }
 
 #[cfg(feature = "twin")]
 impl From<TwinUpdatesSub> for MsgToHub {
     fn from(msg: TwinUpdatesSub) -> Self {
-        return MsgToHub::SubscribeToTwinUpdates(msg);
+        MsgToHub::SubscribeToTwinUpdates(msg)
     }
 }
 
 #[cfg(feature = "twin")]
 impl From<UpdateReportedPropsReq> for MsgToHub {
     fn from(msg: UpdateReportedPropsReq) -> Self {
-        return MsgToHub::UpdateReportedProperties(msg);
+        MsgToHub::UpdateReportedProperties(msg)
     }
 }
 
 #[cfg(feature = "c2d")]
 impl From<C2DSub> for MsgToHub {
     fn from(msg: C2DSub) -> Self {
-        return MsgToHub::SubscribeToC2D(msg);
+        MsgToHub::SubscribeToC2D(msg)
     }
 }
 
 #[cfg(feature = "direct-methods")]
 impl From<DirectMethodsSub> for MsgToHub {
     fn from(msg: DirectMethodsSub) -> Self {
-        return MsgToHub::SubscribeToMethods(msg);
+        MsgToHub::SubscribeToMethods(msg)
     }
 }
 
 #[cfg(feature = "direct-methods")]
 impl From<DirectMethodRes> for MsgToHub {
     fn from(msg: DirectMethodRes) -> Self {
-        return MsgToHub::DirectMethodResponse(msg);
+        MsgToHub::DirectMethodResponse(msg)
     }
 }
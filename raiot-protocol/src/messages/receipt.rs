@@ -0,0 +1,102 @@
+use crate::messages::subscription::{SubError, SubRes};
+use crate::messages::MsgFromHub;
+use crate::qos::PacketId;
+
+/// The kind of request acknowledged by a delivery receipt
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReceiptKind {
+    /// A QoS1 publication (PUBACK), or a QoS2 one (PUBCOMP)
+    Publish,
+
+    /// A subscription request (SUBACK)
+    Subscribe,
+
+    /// An unsubscription request (UNSUBACK)
+    Unsubscribe,
+}
+
+/// An acknowledgement received from the hub for a request sent by the device
+#[derive(Clone, Debug)]
+pub struct DeliveryReceipt {
+    /// The ID of the acknowledged packet
+    pub packet_id: PacketId,
+
+    /// The kind of the acknowledged request
+    pub kind: ReceiptKind,
+
+    /// The result reported by the hub
+    pub result: Result<(), SubError>,
+}
+
+impl DeliveryReceipt {
+    /// Returns TRUE if the hub accepted the request
+    pub fn is_success(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+impl From<SubRes> for DeliveryReceipt {
+    fn from(response: SubRes) -> Self {
+        DeliveryReceipt {
+            packet_id: response.packet_id,
+            kind: ReceiptKind::Subscribe,
+            result: response.result,
+        }
+    }
+}
+
+impl MsgFromHub {
+    /// Returns the delivery receipt carried by this message, if the message is an acknowledgement
+    pub fn delivery_receipt(&self) -> Option<DeliveryReceipt> {
+        match self {
+            MsgFromHub::SubscriptionResponseMessage(response) => Some(DeliveryReceipt {
+                packet_id: response.packet_id,
+                kind: ReceiptKind::Subscribe,
+                result: response.result.clone(),
+            }),
+            MsgFromHub::PublicationSucceeded(packet_id) => Some(DeliveryReceipt {
+                packet_id: *packet_id,
+                kind: ReceiptKind::Publish,
+                result: Ok(()),
+            }),
+            MsgFromHub::UnsubscriptionSucceeded(packet_id) => Some(DeliveryReceipt {
+                packet_id: *packet_id,
+                kind: ReceiptKind::Unsubscribe,
+                result: Ok(()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acknowledgements_carry_receipts() {
+        let receipt = MsgFromHub::PublicationSucceeded(7.into()).delivery_receipt().unwrap();
+        assert_eq!(receipt.packet_id.value(), 7);
+        assert_eq!(receipt.kind, ReceiptKind::Publish);
+        assert!(receipt.is_success());
+
+        let response = SubRes {
+            packet_id: 8.into(),
+            result: Err(SubError::rejected(0x80)),
+            topic_filters: Vec::new(),
+            return_codes: vec![0x80],
+        };
+        let receipt = MsgFromHub::SubscriptionResponseMessage(response)
+            .delivery_receipt()
+            .unwrap();
+        assert_eq!(receipt.packet_id.value(), 8);
+        assert_eq!(receipt.kind, ReceiptKind::Subscribe);
+        assert!(!receipt.is_success());
+
+        let receipt = MsgFromHub::UnsubscriptionSucceeded(9.into()).delivery_receipt().unwrap();
+        assert_eq!(receipt.kind, ReceiptKind::Unsubscribe);
+        assert!(receipt.is_success());
+
+        assert!(MsgFromHub::UnknownMessage().delivery_receipt().is_none());
+    }
+}
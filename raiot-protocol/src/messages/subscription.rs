@@ -1,25 +1,486 @@
-use enum_display_derive::Display;
+use serde::{Deserialize, Serialize};
 
-use std::fmt::Display;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use crate::qos::PacketId;
+use crate::identity::{ClientIdentity, ModuleIdentity};
+use crate::messages::MsgToHub;
+use crate::qos::{DeliveryGuarantees, PacketId};
 
 /// The response to a subscription attempt
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct SubRes {
     /// The ID of the subscription packet
     pub packet_id: PacketId,
 
     /// The result of the subscription attempt
     pub result: Result<(), SubError>,
+
+    /// The topic filters of the matching subscription request.
+    /// SUBACK packets do not carry them, so they are empty until correlated by a `SubscriptionTracker`.
+    pub topic_filters: Vec<String>,
+
+    /// The SUBACK return codes, one per topic filter of the request, in order
+    pub return_codes: Vec<u8>,
+}
+
+/// A request to subscribe to several topic filters, possibly wildcards, in a single round trip.
+/// E.g. a module subscribing to its inputs, direct methods and twin topics at once.
+#[derive(Clone, Debug)]
+pub struct MultiSub {
+    /// Identifies of this packet, which will appear in the matching Acknowledgement message
+    pub packet_id: PacketId,
+
+    /// The topic filters, with the subscription mode of each
+    pub filters: Vec<(String, DeliveryGuarantees)>,
+}
+
+impl MultiSub {
+    /// A request without topic filters yet
+    pub fn new(packet_id: PacketId) -> MultiSub {
+        MultiSub {
+            packet_id,
+            filters: Vec::new(),
+        }
+    }
+
+    /// Adds a topic filter
+    pub fn with_filter(mut self, topic_filter: impl Into<String>, mode: DeliveryGuarantees) -> MultiSub {
+        self.filters.push((topic_filter.into(), mode));
+        self
+    }
+
+    /// Adds the topic filters of a single-topic subscription request, e.g. `DirectMethodsSub`.
+    /// Messages which are not subscription requests add nothing.
+    pub fn with_subscription(mut self, msg: &MsgToHub) -> MultiSub {
+        if let Some(subscription) = ActiveSubscription::from_msg(msg) {
+            self.filters.push((subscription.topic_filter, subscription.mode));
+        }
+        self
+    }
+
+    /// The topic filters registered by this subscription
+    pub fn topic_filters(&self) -> Vec<String> {
+        self.filters.iter().map(|(topic_filter, _)| topic_filter.clone()).collect()
+    }
 }
 
+/// A request to stop receiving the messages of topic filters, acknowledged by an UNSUBACK
+#[derive(Clone, Debug)]
+pub struct UnsubMsg {
+    /// Identifies of this packet, which will appear in the matching Acknowledgement message
+    pub packet_id: PacketId,
+
+    /// The topic filters to unsubscribe from, as they were subscribed to
+    pub topic_filters: Vec<String>,
+}
+
+impl UnsubMsg {
+    /// A request to unsubscribe from a single topic filter
+    pub fn new(packet_id: PacketId, topic_filter: impl Into<String>) -> UnsubMsg {
+        UnsubMsg {
+            packet_id,
+            topic_filters: vec![topic_filter.into()],
+        }
+    }
+
+    /// A request ending the subscription of the specified kind. Returns None for C2D subscriptions of modules.
+    pub fn for_kind(packet_id: PacketId, kind: SubscriptionKind, client_id: &ClientIdentity) -> Option<UnsubMsg> {
+        kind.topic_filter(client_id).map(|topic_filter| UnsubMsg::new(packet_id, topic_filter))
+    }
+}
+
+/// The SUBACK return code of a rejected subscription
+pub const SUBACK_FAILURE: u8 = 0x80;
+
 /// Subscription error
-#[derive(Copy, Debug, Clone, Display)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SubError {
     /// Timed-out waiting for SUBACK
-    Timeout,
+    Timeout {
+        /// The topic filter of the subscription, if known
+        topic_filter: Option<String>,
+
+        /// The time waited for SUBACK
+        elapsed: Duration,
+    },
 
     /// Server indicated failure
-    Failure
-}
\ No newline at end of file
+    Failure {
+        /// The return code in SUBACK
+        return_code: u8,
+
+        /// The topic filter of the subscription. Unknown until correlated by a `SubscriptionTracker`.
+        topic_filter: Option<String>,
+
+        /// The time between sending SUBSCRIBE and receiving SUBACK. Unknown until correlated by a `SubscriptionTracker`.
+        elapsed: Option<Duration>,
+    },
+}
+
+impl SubError {
+    /// A failure reported by the hub, without context
+    pub fn rejected(return_code: u8) -> SubError {
+        SubError::Failure {
+            return_code,
+            topic_filter: None,
+            elapsed: None,
+        }
+    }
+
+    /// The topic filter of the failed subscription, if known
+    pub fn topic_filter(&self) -> Option<&str> {
+        match self {
+            SubError::Timeout { topic_filter, .. } | SubError::Failure { topic_filter, .. } => {
+                topic_filter.as_deref()
+            }
+        }
+    }
+
+    /// The time the subscription attempt took, if known
+    pub fn elapsed(&self) -> Option<Duration> {
+        match self {
+            SubError::Timeout { elapsed, .. } => Some(*elapsed),
+            SubError::Failure { elapsed, .. } => *elapsed,
+        }
+    }
+}
+
+impl fmt::Display for SubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubError::Timeout { .. } => write!(f, "Timeout")?,
+            SubError::Failure { return_code, .. } => write!(f, "Failure (return code {:#04x})", return_code)?,
+        }
+        if let Some(topic_filter) = self.topic_filter() {
+            write!(f, ", topic filter: {}", topic_filter)?;
+        }
+        if let Some(elapsed) = self.elapsed() {
+            write!(f, ", after {:?}", elapsed)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SubError {}
+
+/// Correlates subscription responses with the topic filters of their requests
+#[derive(Debug, Default)]
+pub struct SubscriptionTracker {
+    pending: HashMap<PacketId, (Vec<String>, Instant)>,
+}
+
+impl SubscriptionTracker {
+    /// Creates a tracker with no pending subscriptions
+    pub fn new() -> SubscriptionTracker {
+        SubscriptionTracker::default()
+    }
+
+    /// Records the topic filters of an outgoing subscription request. Other messages are ignored.
+    pub fn track(&mut self, msg: &MsgToHub) {
+        let topic_filters = msg.topic_filters();
+        if let (Some(packet_id), false) = (msg.packet_id(), topic_filters.is_empty()) {
+            let _ = self.pending.insert(packet_id, (topic_filters, Instant::now()));
+        }
+    }
+
+    /// Fills in the topic filters of a subscription response, and the context of its error, if any.
+    /// Returns FALSE if the response does not match any tracked request.
+    pub fn correlate(&mut self, res: &mut SubRes) -> bool {
+        match self.pending.remove(&res.packet_id) {
+            Some((topic_filters, sent_at)) => {
+                if let Err(SubError::Failure {
+                    topic_filter,
+                    elapsed,
+                    ..
+                }) = &mut res.result
+                {
+                    // the first rejected filter, by its position in the request
+                    let rejected = res.return_codes.iter().position(|code| *code == SUBACK_FAILURE);
+                    *topic_filter = topic_filters.get(rejected.unwrap_or(0)).cloned();
+                    *elapsed = Some(sent_at.elapsed());
+                }
+                res.topic_filters = topic_filters;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The IoT Hub feature a subscription serves
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SubscriptionKind {
+    /// C2D messages
+    CloudToDevice,
+
+    /// Direct method invocations
+    DirectMethods,
+
+    /// Twin read/update responses
+    TwinResponses,
+
+    /// Desired properties notifications
+    TwinUpdates,
+
+    /// Messages routed to the inputs of a module
+    ModuleInputs,
+}
+
+impl SubscriptionKind {
+    /// The topic filter subscribed to by the specified client for this feature.
+    /// Returns None for C2D messages of modules, which receive module inputs instead, and for module inputs of devices.
+    pub fn topic_filter(&self, client_id: &ClientIdentity) -> Option<String> {
+        match (self, client_id) {
+            (SubscriptionKind::CloudToDevice, ClientIdentity::Device(device)) => {
+                Some(crate::topics::c2d_filter(&device.device_id))
+            }
+            (SubscriptionKind::CloudToDevice, ClientIdentity::Module(_)) => None,
+            (SubscriptionKind::DirectMethods, _) => Some(crate::topics::METHODS_POST_FILTER.to_owned()),
+            (SubscriptionKind::TwinResponses, _) => Some(crate::topics::TWIN_RESPONSE_FILTER.to_owned()),
+            (SubscriptionKind::TwinUpdates, _) => Some(crate::topics::TWIN_DESIRED_FILTER.to_owned()),
+            (SubscriptionKind::ModuleInputs, ClientIdentity::Module(module)) => Some(
+                crate::topics::module_inputs_filter(&module.device_id, &module.module_id),
+            ),
+            (SubscriptionKind::ModuleInputs, ClientIdentity::Device(_)) => None,
+        }
+    }
+}
+
+/// A single subscription
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ActiveSubscription {
+    /// The feature the subscription serves
+    pub kind: SubscriptionKind,
+
+    /// The subscribed topic filter
+    pub topic_filter: String,
+
+    /// The subscription QoS
+    pub mode: DeliveryGuarantees,
+}
+
+impl ActiveSubscription {
+    /// Describes a subscription to the inputs of the specified module.
+    /// Input subscriptions are requested with a `MultiSub`, which `from_msg` can't tell apart from others.
+    pub fn module_inputs(module: &ModuleIdentity, mode: DeliveryGuarantees) -> ActiveSubscription {
+        ActiveSubscription {
+            kind: SubscriptionKind::ModuleInputs,
+            topic_filter: crate::topics::module_inputs_filter(&module.device_id, &module.module_id),
+            mode,
+        }
+    }
+
+    /// Describes the subscription requested by a message. Returns None for other messages.
+    pub fn from_msg(msg: &MsgToHub) -> Option<ActiveSubscription> {
+        let (kind, topic_filter, mode) = match msg {
+            #[cfg(feature = "c2d")]
+            MsgToHub::SubscribeToC2D(msg) => (SubscriptionKind::CloudToDevice, msg.topic_filter(), msg.mode),
+
+            #[cfg(feature = "direct-methods")]
+            MsgToHub::SubscribeToMethods(msg) => (SubscriptionKind::DirectMethods, msg.topic_filter(), msg.mode),
+
+            #[cfg(feature = "twin")]
+            MsgToHub::SubscribeToTwinReads(msg) => (SubscriptionKind::TwinResponses, msg.topic_filter(), msg.mode),
+
+            #[cfg(feature = "twin")]
+            MsgToHub::SubscribeToTwinUpdates(msg) => (SubscriptionKind::TwinUpdates, msg.topic_filter(), msg.mode),
+
+            _ => return None,
+        };
+        Some(ActiveSubscription {
+            kind,
+            topic_filter,
+            mode,
+        })
+    }
+
+    /// Builds the request re-establishing this subscription.
+    /// Returns None if the feature is disabled, for C2D subscriptions of modules, or input subscriptions of devices.
+    pub fn to_msg(&self, packet_id: PacketId, client_id: &ClientIdentity) -> Option<MsgToHub> {
+        let mode = self.mode;
+        match self.kind {
+            #[cfg(feature = "c2d")]
+            SubscriptionKind::CloudToDevice => match client_id {
+                ClientIdentity::Device(device_id) => Some(
+                    crate::c2d::C2DSub {
+                        packet_id,
+                        device_id: device_id.clone(),
+                        mode,
+                    }
+                    .into(),
+                ),
+                ClientIdentity::Module(_) => None,
+            },
+
+            #[cfg(feature = "direct-methods")]
+            SubscriptionKind::DirectMethods => {
+                Some(crate::direct_methods::DirectMethodsSub { packet_id, mode }.into())
+            }
+
+            #[cfg(feature = "twin")]
+            SubscriptionKind::TwinResponses => Some(crate::twin::TwinReadSub { packet_id, mode }.into()),
+
+            #[cfg(feature = "twin")]
+            SubscriptionKind::TwinUpdates => Some(crate::twin::TwinUpdatesSub { packet_id, mode }.into()),
+
+            SubscriptionKind::ModuleInputs => match client_id {
+                ClientIdentity::Module(module) => {
+                    Some(crate::IotCodec::module_subscriptions(module).inputs(packet_id, mode).into())
+                }
+                ClientIdentity::Device(_) => None,
+            },
+
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = (packet_id, client_id, mode);
+                None
+            }
+        }
+    }
+}
+
+/// A serializable snapshot of the subscriptions of a client, at most one per kind
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionSnapshot {
+    /// The subscriptions
+    pub subscriptions: Vec<ActiveSubscription>,
+}
+
+impl SubscriptionSnapshot {
+    /// An empty snapshot
+    pub fn new() -> SubscriptionSnapshot {
+        SubscriptionSnapshot::default()
+    }
+
+    /// Adds a subscription, replacing any subscription of the same kind
+    pub fn insert(&mut self, subscription: ActiveSubscription) {
+        self.remove(subscription.kind);
+        self.subscriptions.push(subscription);
+    }
+
+    /// Removes the subscription of the specified kind
+    pub fn remove(&mut self, kind: SubscriptionKind) {
+        self.subscriptions.retain(|subscription| subscription.kind != kind);
+    }
+
+    /// The subscription of the specified kind, if any
+    pub fn get(&self, kind: SubscriptionKind) -> Option<&ActiveSubscription> {
+        self.subscriptions.iter().find(|subscription| subscription.kind == kind)
+    }
+}
+
+#[cfg(all(test, feature = "direct-methods"))]
+mod tests {
+    use super::*;
+    use crate::direct_methods::DirectMethodsSub;
+    use crate::qos::DeliveryGuarantees;
+
+    #[test]
+    fn test_responses_are_correlated_with_requested_filters() {
+        let mut sut = SubscriptionTracker::new();
+        sut.track(
+            &DirectMethodsSub {
+                packet_id: 3.into(),
+                mode: DeliveryGuarantees::AtMostOnce,
+            }
+            .into(),
+        );
+
+        let mut res = SubRes {
+            packet_id: 3.into(),
+            result: Ok(()),
+            topic_filters: Vec::new(),
+            return_codes: vec![0],
+        };
+        assert!(sut.correlate(&mut res));
+        assert_eq!(res.topic_filters, vec!["$iothub/methods/POST/#".to_owned()]);
+        assert!(!sut.correlate(&mut res));
+    }
+
+    #[test]
+    fn test_failures_are_correlated_with_context() {
+        let mut sut = SubscriptionTracker::new();
+        sut.track(
+            &DirectMethodsSub {
+                packet_id: 4.into(),
+                mode: DeliveryGuarantees::AtMostOnce,
+            }
+            .into(),
+        );
+
+        let mut res = SubRes {
+            packet_id: 4.into(),
+            result: Err(SubError::rejected(SUBACK_FAILURE)),
+            topic_filters: Vec::new(),
+            return_codes: vec![SUBACK_FAILURE],
+        };
+        assert!(sut.correlate(&mut res));
+
+        let error = res.result.unwrap_err();
+        assert_eq!(error.topic_filter(), Some("$iothub/methods/POST/#"));
+        assert!(error.elapsed().is_some());
+        assert!(error.to_string().starts_with("Failure (return code 0x80), topic filter: $iothub/methods/POST/#"));
+    }
+
+    #[test]
+    fn test_multi_subscription_failures_name_the_rejected_filter() {
+        let mut sut = SubscriptionTracker::new();
+        let msg = MultiSub::new(5.into())
+            .with_subscription(
+                &DirectMethodsSub {
+                    packet_id: 5.into(),
+                    mode: DeliveryGuarantees::AtMostOnce,
+                }
+                .into(),
+            )
+            .with_filter("devices/d1/modules/m1/inputs/#", DeliveryGuarantees::AtLeastOnce);
+        sut.track(&msg.into());
+
+        let mut res = SubRes {
+            packet_id: 5.into(),
+            result: Err(SubError::rejected(SUBACK_FAILURE)),
+            topic_filters: Vec::new(),
+            return_codes: vec![0, SUBACK_FAILURE],
+        };
+        assert!(sut.correlate(&mut res));
+        assert_eq!(res.topic_filters.len(), 2);
+        assert_eq!(res.result.unwrap_err().topic_filter(), Some("devices/d1/modules/m1/inputs/#"));
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let msg: MsgToHub = DirectMethodsSub {
+            packet_id: 1.into(),
+            mode: DeliveryGuarantees::AtLeastOnce,
+        }
+        .into();
+        let mut snapshot = SubscriptionSnapshot::new();
+        snapshot.insert(ActiveSubscription::from_msg(&msg).unwrap());
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored: SubscriptionSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, snapshot);
+
+        let subscription = restored.get(SubscriptionKind::DirectMethods).unwrap();
+        let client_id = ClientIdentity::try_from_device_id("device1").unwrap();
+        let msg = subscription.to_msg(2.into(), &client_id).unwrap();
+        assert_eq!(msg.packet_id(), Some(2.into()));
+        assert_eq!(msg.topic_filters(), vec![subscription.topic_filter.clone()]);
+    }
+
+    #[test]
+    fn test_module_inputs_are_restored_for_modules_only() {
+        let module = ModuleIdentity::new("device1", "module1").unwrap();
+        let subscription = ActiveSubscription::module_inputs(&module, DeliveryGuarantees::AtLeastOnce);
+
+        let msg = subscription.to_msg(4.into(), &ClientIdentity::Module(module)).unwrap();
+        assert_eq!(msg.packet_id(), Some(4.into()));
+        assert_eq!(msg.topic_filters(), vec!["devices/device1/modules/module1/inputs/#".to_owned()]);
+
+        let device = ClientIdentity::try_from_device_id("device1").unwrap();
+        assert!(subscription.to_msg(5.into(), &device).is_none());
+        assert!(SubscriptionKind::ModuleInputs.topic_filter(&device).is_none());
+    }
+}
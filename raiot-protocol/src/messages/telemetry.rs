@@ -1,4 +1,7 @@
+use crate::serialization::PayloadSerializer;
 use crate::{qos::PacketId, ClientIdentity, PropertyBag};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// A device-to-cloud message
 #[derive(Clone, Debug)]
@@ -13,6 +16,108 @@ pub struct TelemetryMsg {
     /// Packet ID
     pub packet_id: Option<PacketId>,
 
+    /// Sends the message with QoS2 rather than QoS1, if it has a packet ID
+    pub exactly_once: bool,
+
     /// Message headers
     pub headers: Option<PropertyBag>,
+
+    /// Serializes the content and sets its content type. None sends JSON without a content type.
+    pub serializer: Option<Arc<dyn PayloadSerializer>>,
+}
+
+/// The system property carrying the distributed tracing Diagnostic-Id (W3C `traceparent`)
+#[cfg(feature = "telemetry")]
+pub const DIAGNOSTIC_ID_PROPERTY: &str = "$.diagid";
+
+/// The system property carrying the distributed tracing context (W3C `tracestate`)
+#[cfg(feature = "telemetry")]
+pub const DIAGNOSTIC_CONTEXT_PROPERTY: &str = "$.diagctx";
+
+/// The system property naming the output a module sends a message to, for the hub to route it by
+#[cfg(feature = "telemetry")]
+pub const OUTPUT_NAME_PROPERTY: &str = "$.on";
+
+/// The property carrying the per-client telemetry sequence number.
+/// IoT Hub drops unknown system properties, so the sequence number is sent as an application property.
+#[cfg(feature = "telemetry")]
+pub const SEQUENCE_NUMBER_PROPERTY: &str = "sequence-number";
+
+/// The system property identifying a message
+#[cfg(feature = "telemetry")]
+pub const MESSAGE_ID_PROPERTY: &str = "$.mid";
+
+/// The system property correlating a message with another one, e.g. the message it answers
+#[cfg(feature = "telemetry")]
+pub const CORRELATION_ID_PROPERTY: &str = "$.cid";
+
+/// The system property holding the absolute expiry time of a message sent to a module output (ISO 8601),
+/// like the one of C2D messages
+#[cfg(feature = "telemetry")]
+pub const OUTPUT_EXPIRY_TIME_PROPERTY: &str = "$.exp";
+
+/// The application property carrying the routing priority hint of a message sent to a module output
+#[cfg(feature = "telemetry")]
+pub const ROUTE_PRIORITY_PROPERTY: &str = "priority";
+
+/// Hints for the routes of edgeHub, the local hub of IoT Edge devices, sent as properties of a message
+/// sent to a module output.
+/// edgeHub sets the priority and the time-to-live of a route in the route itself (`priority`, `timeToLiveSecs`),
+/// so routes select messages by their hints, e.g. `FROM /messages/modules/sensor/outputs/* WHERE priority = 'high'`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg(feature = "telemetry")]
+pub struct EdgeRouteHints {
+    /// Sent as the `priority` application property
+    pub priority: Option<String>,
+
+    /// How long the message is relevant for, sent as its absolute expiry time
+    pub ttl: Option<Duration>,
+
+    /// Sent as the message ID, e.g. for the receiver to deduplicate messages
+    pub message_id: Option<String>,
+
+    /// Sent as the correlation ID
+    pub correlation_id: Option<String>,
+}
+
+#[cfg(feature = "telemetry")]
+impl EdgeRouteHints {
+    /// Adds the hints to the properties of a message sent at `now`
+    pub fn apply(&self, headers: &mut PropertyBag, now: SystemTime) {
+        if let Some(priority) = &self.priority {
+            let _ = headers.insert(ROUTE_PRIORITY_PROPERTY.to_owned(), priority.clone());
+        }
+        if let Some(ttl) = self.ttl {
+            let expiry = chrono::DateTime::<chrono::Utc>::from(now + ttl);
+            let expiry = expiry.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            let _ = headers.insert(OUTPUT_EXPIRY_TIME_PROPERTY.to_owned(), expiry);
+        }
+        if let Some(message_id) = &self.message_id {
+            let _ = headers.insert(MESSAGE_ID_PROPERTY.to_owned(), message_id.clone());
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            let _ = headers.insert(CORRELATION_ID_PROPERTY.to_owned(), correlation_id.clone());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "telemetry"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edge_route_hints_are_sent_as_properties() {
+        let sut = EdgeRouteHints {
+            priority: Some("high".to_owned()),
+            ttl: Some(Duration::from_secs(10)),
+            message_id: Some("m1".to_owned()),
+            correlation_id: None,
+        };
+        let mut headers = PropertyBag::new();
+        sut.apply(&mut headers, SystemTime::UNIX_EPOCH + Duration::from_secs(1_577_836_800));
+        assert_eq!(headers["priority"], "high");
+        assert_eq!(headers["$.exp"], "2020-01-01T00:00:10.000Z");
+        assert_eq!(headers["$.mid"], "m1");
+        assert!(!headers.contains_key("$.cid"));
+    }
 }
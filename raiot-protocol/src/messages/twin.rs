@@ -1,26 +1,57 @@
+use crate::compression::CompressionPolicy;
+use crate::messages::{MsgFromHub, MsgToHub};
 use crate::qos::{DeliveryGuarantees, PacketId};
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 use std::collections::HashMap;
 
 
-/// The Twin
+/// The Twin. A section left out of a partial read is empty.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg(feature = "twin")]
 pub struct Twin {
     /// Desired properties section
+    #[serde(default)]
     pub desired: HashMap<String, Value>,
     
     /// Reported properties section
+    #[serde(default)]
     pub reported: HashMap<String, Value>,
 }
 
+/// The sections of the twin kept from a read response
+#[cfg(feature = "twin")]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TwinSection {
+    /// Both the desired and the reported properties
+    #[default]
+    Full,
+
+    /// Only the desired properties
+    DesiredOnly,
+
+    /// Only the reported properties
+    ReportedOnly,
+}
+
+#[cfg(feature = "twin")]
+impl TwinSection {
+    /// The key of the kept section in the twin document, or None if the whole document is kept
+    fn key(self) -> Option<&'static str> {
+        match self {
+            TwinSection::Full => None,
+            TwinSection::DesiredOnly => Some("desired"),
+            TwinSection::ReportedOnly => Some("reported"),
+        }
+    }
+}
+
 #[cfg(feature = "twin")]
 impl Twin {
     /// New Twin
     pub fn new(twin_json: &str) -> Twin {
         let result: Twin = serde_json::from_str(twin_json).unwrap();
-        return result;
+        result
     }
 }
 
@@ -36,6 +67,14 @@ pub struct TwinReadSub {
     pub mode: DeliveryGuarantees,
 }
 
+#[cfg(feature = "twin")]
+impl TwinReadSub {
+    /// The topic filter registered by this subscription
+    pub fn topic_filter(&self) -> String {
+        crate::topics::TWIN_RESPONSE_FILTER.to_owned()
+    }
+}
+
 /// A command message requesting the IoT Hub to respond with the content of the Twin
 #[cfg(feature = "twin")]
 #[derive(Clone, Debug)]
@@ -45,6 +84,10 @@ pub struct ReadTwinReq {
 
     /// Packet ID
     pub packet_id: Option<PacketId>,
+
+    /// The sections to keep from the response. The hub always sends the full twin,
+    /// which is filtered once decoded.
+    pub section: TwinSection,
 }
 
 /// Twin read response message
@@ -67,6 +110,30 @@ pub struct ReadTwinRes {
     pub version: Option<u64>,
 }
 
+#[cfg(feature = "twin")]
+impl ReadTwinRes {
+    /// Drops the sections of the body not included in the specified section
+    pub fn retain_section(&mut self, section: TwinSection) {
+        if let (Some(key), Some(Value::Object(body))) = (section.key(), self.body.as_mut()) {
+            body.retain(|k, _| k == key);
+        }
+    }
+
+    /// The desired properties section of the body, if present
+    pub fn desired(&self) -> Option<&Map<String, Value>> {
+        self.section("desired")
+    }
+
+    /// The reported properties section of the body, if present
+    pub fn reported(&self) -> Option<&Map<String, Value>> {
+        self.section("reported")
+    }
+
+    fn section(&self, key: &str) -> Option<&Map<String, Value>> {
+        self.body.as_ref()?.get(key)?.as_object()
+    }
+}
+
 /// Subscribe to Twin update notifications
 #[cfg(feature = "twin")]
 #[derive(Copy, Clone, Debug)]
@@ -78,6 +145,14 @@ pub struct TwinUpdatesSub {
     pub mode: DeliveryGuarantees,
 }
 
+#[cfg(feature = "twin")]
+impl TwinUpdatesSub {
+    /// The topic filter registered by this subscription
+    pub fn topic_filter(&self) -> String {
+        crate::topics::TWIN_DESIRED_FILTER.to_owned()
+    }
+}
+
 /// Event message specifying the twin's Desired Properties section was updated
 #[cfg(feature = "twin")]
 #[derive(Clone, Debug)]
@@ -104,6 +179,94 @@ pub struct UpdateReportedPropsReq {
 
     /// Packet ID
     pub packet_id: Option<PacketId>,
+
+    /// Compresses large payloads, announcing the content encoding in the topic.
+    /// Only for endpoints which decompress reported properties (e.g. an IoT Edge module): IoT Hub itself expects plain JSON.
+    pub compression: Option<CompressionPolicy>,
+}
+
+/// Response to a Reported Properties update
+#[cfg(feature = "twin")]
+#[derive(Clone, Debug)]
+pub struct UpdateReportedPropsRes {
+    /// Packet ID
+    pub packet_id: Option<PacketId>,
+
+    /// The request identifier specified in the UpdateReportedPropsReq
+    pub request_id: String,
+
+    /// Response status code
+    pub status_code: StatusCode,
+
+    /// The new version of the Reported Properties section
+    pub version: Option<u64>,
+}
+
+/// The kind of a pending twin request.
+/// The hub answers GET and PATCH requests on the same topic, so only the request tells their responses apart.
+#[cfg(feature = "twin")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TwinRequestKind {
+    /// A twin read
+    Get,
+
+    /// A Reported Properties update
+    PatchReported,
+}
+
+/// Correlates twin responses with the requests they answer, so that a Reported Properties update acknowledgement
+/// is decoded as `MsgFromHub::ReportedPropertiesUpdated` rather than as a twin read response.
+/// Responses to untracked requests are left as `MsgFromHub::TwinResponseMessage`.
+#[cfg(feature = "twin")]
+#[derive(Debug, Default)]
+pub struct TwinCorrelation {
+    pending: HashMap<String, TwinRequestKind>,
+}
+
+#[cfg(feature = "twin")]
+impl TwinCorrelation {
+    /// Creates a table with no pending requests
+    pub fn new() -> TwinCorrelation {
+        TwinCorrelation::default()
+    }
+
+    /// Records an outgoing message if it is a twin request
+    pub fn track(&mut self, msg: &MsgToHub) {
+        let (request_id, kind) = match msg {
+            MsgToHub::ReadTwin(req) => (&req.request_id, TwinRequestKind::Get),
+            MsgToHub::UpdateReportedProperties(req) => (&req.request_id, TwinRequestKind::PatchReported),
+            _ => return,
+        };
+        let _ = self.pending.insert(request_id.clone(), kind);
+    }
+
+    /// Routes a twin response by the kind of its request, forgetting the request.
+    /// Other messages are returned as is.
+    pub fn route(&mut self, msg: MsgFromHub) -> MsgFromHub {
+        let res = match msg {
+            MsgFromHub::TwinResponseMessage(res) => res,
+            other => return other,
+        };
+        match self.pending.remove(&res.request_id) {
+            Some(TwinRequestKind::PatchReported) => MsgFromHub::ReportedPropertiesUpdated(UpdateReportedPropsRes {
+                packet_id: res.packet_id,
+                request_id: res.request_id,
+                status_code: res.status_code,
+                version: res.version,
+            }),
+            Some(TwinRequestKind::Get) | None => MsgFromHub::TwinResponseMessage(res),
+        }
+    }
+
+    /// The kind of a pending request, if it was tracked and not answered yet
+    pub fn kind_of(&self, request_id: &str) -> Option<TwinRequestKind> {
+        self.pending.get(request_id).copied()
+    }
+
+    /// Forgets all pending requests, e.g. once the connection is lost and their responses will never arrive
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
 }
 
 /// Response code
@@ -128,3 +291,82 @@ pub enum StatusCode {
     /// Server sent some unknown status code
     UnknownStatusCode(u16),
 }
+
+#[cfg(feature = "twin")]
+impl StatusCode {
+    /// Returns TRUE for failures which clear up by themselves, so the request may be retried later:
+    /// throttling, and the server errors of an overloaded hub or of a hub under maintenance
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            StatusCode::TooManyRequests() | StatusCode::ServerError(500) | StatusCode::ServerError(502..=504)
+        )
+    }
+}
+
+#[cfg(all(test, feature = "twin"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_retain_section() {
+        let mut res = ReadTwinRes {
+            packet_id: None,
+            request_id: "1".to_owned(),
+            status_code: StatusCode::OK(),
+            body: Some(json!({ "desired": { "a": 1 }, "reported": { "b": 2 } })),
+            version: None,
+        };
+        res.retain_section(TwinSection::DesiredOnly);
+        assert_eq!(res.desired().unwrap().get("a"), Some(&json!(1)));
+        assert!(res.reported().is_none());
+
+        let twin: Twin = serde_json::from_value(res.body.unwrap()).unwrap();
+        assert!(twin.reported.is_empty());
+    }
+
+    #[test]
+    fn test_transient_status_codes() {
+        assert!(StatusCode::TooManyRequests().is_transient());
+        assert!(StatusCode::ServerError(503).is_transient());
+        assert!(StatusCode::ServerError(500).is_transient());
+        assert!(!StatusCode::ServerError(501).is_transient());
+        assert!(!StatusCode::BadRequest().is_transient());
+        assert!(!StatusCode::UnknownStatusCode(404).is_transient());
+    }
+
+    #[test]
+    fn test_twin_responses_are_routed_by_request_kind() {
+        let response = |request_id: &str| {
+            MsgFromHub::TwinResponseMessage(ReadTwinRes {
+                packet_id: None,
+                request_id: request_id.to_owned(),
+                status_code: StatusCode::NoContent(),
+                body: None,
+                version: Some(7),
+            })
+        };
+        let mut sut = TwinCorrelation::new();
+        sut.track(&MsgToHub::ReadTwin(ReadTwinReq {
+            request_id: "get".to_owned(),
+            packet_id: None,
+            section: TwinSection::Full,
+        }));
+        sut.track(&MsgToHub::UpdateReportedProperties(UpdateReportedPropsReq {
+            request_id: "patch".to_owned(),
+            reported: Map::new(),
+            packet_id: None,
+            compression: None,
+        }));
+        assert_eq!(sut.kind_of("patch"), Some(TwinRequestKind::PatchReported));
+
+        match sut.route(response("patch")) {
+            MsgFromHub::ReportedPropertiesUpdated(res) => assert_eq!(res.version, Some(7)),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(matches!(sut.route(response("get")), MsgFromHub::TwinResponseMessage(_)));
+        assert!(matches!(sut.route(response("patch")), MsgFromHub::TwinResponseMessage(_)));
+        assert_eq!(sut.kind_of("get"), None);
+    }
+}
@@ -1,6 +1,11 @@
 use fmt::Display;
+use mqtt::packet::{QoSWithPacketIdentifier, VariablePacket};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fmt;
 
+use crate::messages::{ExactlyOnceMsg, MsgToHub};
+
 /// Represents a single packet identifier
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub struct PacketId {
@@ -33,12 +38,43 @@ impl Display for PacketId {
 }
 
 /// The subscription's delivery guarantees (QoS level)
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DeliveryGuarantees {
     /// QoS0 - messages will be delivered without requiring an ACK
     AtMostOnce,
     /// QoS1 - messages will require an ACK. If an ACK is not received, the message will be re-delivered.
     AtLeastOnce,
+    /// QoS2 - messages will be delivered exactly once, using a PUBREC/PUBREL/PUBCOMP handshake.
+    /// IoT Hub itself only supports QoS0 and QoS1; use this with brokers which support QoS2.
+    ExactlyOnce,
+}
+
+/// The delivery guarantees used by each IoT Hub feature, unless overridden per call
+#[derive(Debug, Copy, Clone)]
+pub struct QosDefaults {
+    /// Device-to-cloud telemetry messages
+    pub telemetry: DeliveryGuarantees,
+
+    /// Cloud-to-device messages subscription. Defaults to at most once: subscribe at least once for the hub
+    /// to deliver again the messages left unacknowledged.
+    pub c2d: DeliveryGuarantees,
+
+    /// Direct methods subscription and responses
+    pub methods: DeliveryGuarantees,
+
+    /// Twin subscriptions and requests
+    pub twin: DeliveryGuarantees,
+}
+
+impl Default for QosDefaults {
+    fn default() -> Self {
+        QosDefaults {
+            telemetry: DeliveryGuarantees::AtLeastOnce,
+            c2d: DeliveryGuarantees::AtMostOnce,
+            methods: DeliveryGuarantees::AtMostOnce,
+            twin: DeliveryGuarantees::AtLeastOnce,
+        }
+    }
 }
 
 /// Determines if we start a clean session, or resume the previous session (dirty).
@@ -53,3 +89,93 @@ pub enum SessionMode {
     /// Start a dirty session (retransmit any previously-unacknowledged messages)
     Dirty,
 }
+
+/// The QoS2 (exactly once) handshakes of a connection, run by the transport underneath the application.
+/// Publications of the hub are acknowledged with a PUBREC upon receipt, and passed on only once however many times
+/// the hub sends them, until the hub releases them. Publications of the device are released once the hub received
+/// them; the PUBCOMP ending their handshake is passed on as their acknowledgement.
+#[derive(Debug, Default)]
+pub struct ExactlyOnceHandshakes {
+    // publications of the hub passed on and not released yet
+    received: HashSet<u16>,
+}
+
+impl ExactlyOnceHandshakes {
+    /// Runs a packet from the hub through the handshakes. Returns the packet to decode, unless it only advanced
+    /// a handshake or repeated a publication already passed on, and the reply to send to the hub, if any.
+    pub fn incoming(&mut self, packet: VariablePacket) -> (Option<VariablePacket>, Option<MsgToHub>) {
+        if let VariablePacket::PublishPacket(publish) = &packet {
+            if let QoSWithPacketIdentifier::Level2(packet_id) = publish.qos() {
+                let reply = MsgToHub::ExactlyOnce(ExactlyOnceMsg::Received(packet_id.into()));
+                return match self.received.insert(packet_id) {
+                    true => (Some(packet), Some(reply)),
+                    false => (None, Some(reply)),
+                };
+            }
+        }
+        match packet {
+            VariablePacket::PubrecPacket(pubrec) => {
+                let packet_id = pubrec.packet_identifier().into();
+                (None, Some(MsgToHub::ExactlyOnce(ExactlyOnceMsg::Release(packet_id))))
+            }
+            VariablePacket::PubrelPacket(pubrel) => {
+                let _ = self.received.remove(&pubrel.packet_identifier());
+                let packet_id = pubrel.packet_identifier().into();
+                (None, Some(MsgToHub::ExactlyOnce(ExactlyOnceMsg::Complete(packet_id))))
+            }
+            packet => (Some(packet), None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt::packet::{PubcompPacket, PubrecPacket, PubrelPacket, PublishPacket};
+    use mqtt::TopicName;
+
+    fn publish(qos: QoSWithPacketIdentifier) -> VariablePacket {
+        PublishPacket::new(TopicName::new("devices/d1/messages/devicebound/").unwrap(), qos, "").into()
+    }
+
+    fn reply_of(handshake: (Option<VariablePacket>, Option<MsgToHub>)) -> Option<ExactlyOnceMsg> {
+        match handshake.1 {
+            Some(MsgToHub::ExactlyOnce(msg)) => Some(msg),
+            None => None,
+            Some(other) => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_publications_of_the_hub_are_passed_on_once() {
+        let mut sut = ExactlyOnceHandshakes::default();
+        let (packet, reply) = sut.incoming(publish(QoSWithPacketIdentifier::Level2(5)));
+        assert!(packet.is_some());
+        assert!(matches!(reply, Some(MsgToHub::ExactlyOnce(ExactlyOnceMsg::Received(id))) if id.value() == 5));
+
+        // delivered again before the hub received the PUBREC
+        let (packet, reply) = sut.incoming(publish(QoSWithPacketIdentifier::Level2(5)));
+        assert!(packet.is_none());
+        assert!(reply.is_some());
+
+        let released = sut.incoming(PubrelPacket::new(5).into());
+        assert!(released.0.is_none());
+        assert_eq!(reply_of(released), Some(ExactlyOnceMsg::Complete(5.into())));
+
+        // the packet ID is free for the next publication
+        assert!(sut.incoming(publish(QoSWithPacketIdentifier::Level2(5))).0.is_some());
+        assert!(reply_of(sut.incoming(publish(QoSWithPacketIdentifier::Level1(6)))).is_none());
+    }
+
+    #[test]
+    fn test_publications_of_the_device_are_released() {
+        let mut sut = ExactlyOnceHandshakes::default();
+        let received = sut.incoming(PubrecPacket::new(9).into());
+        assert!(received.0.is_none());
+        assert_eq!(reply_of(received), Some(ExactlyOnceMsg::Release(9.into())));
+
+        let (packet, reply) = sut.incoming(PubcompPacket::new(9).into());
+        assert!(matches!(packet, Some(VariablePacket::PubcompPacket(_))));
+        assert!(reply.is_none());
+    }
+}
@@ -0,0 +1,55 @@
+use percent_encoding::percent_decode_str;
+use std::borrow::Cow;
+
+/// Iterates over the decoded key-value pairs of a query string (`key1=value1&key2=value2`)
+pub(crate) fn pairs(query: &str) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(index) => (decode(&pair[..index]), decode(&pair[index + 1..])),
+            None => (decode(pair), Cow::Borrowed("")),
+        })
+}
+
+/// Finds the decoded value of the first occurrence of `key` in a query string
+pub(crate) fn find<'a>(query: &'a str, key: &str) -> Option<Cow<'a, str>> {
+    pairs(query).find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Decodes a form-urlencoded query component, allocating only when escapes are present
+fn decode(component: &str) -> Cow<'_, str> {
+    if component.contains('+') {
+        let replaced = component.replace('+', " ");
+        return Cow::Owned(percent_decode_str(&replaced).decode_utf8_lossy().into_owned());
+    }
+    percent_decode_str(component).decode_utf8_lossy()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let query = "$rid=abc&$version=4";
+        assert_eq!(find(query, "$rid").unwrap(), "abc");
+        assert_eq!(find(query, "$version").unwrap(), "4");
+        assert!(find(query, "missing").is_none());
+    }
+
+    #[test]
+    fn test_pairs_are_decoded() {
+        let decoded: Vec<_> = pairs("a%20b=c+d&flag&&e=%2F")
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        assert_eq!(
+            decoded,
+            vec![
+                ("a b".to_owned(), "c d".to_owned()),
+                ("flag".to_owned(), "".to_owned()),
+                ("e".to_owned(), "/".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::{self, Debug, Display};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Logged values longer than this many bytes are truncated, unless verbose logging is enabled
+pub const MAX_LOGGED_LEN: usize = 256;
+
+const MASK: &str = "***";
+
+/// Markers that precede a secret in Debug output. Secrets in quoted fields end at the closing quote,
+/// the others at the next quote, separator or whitespace.
+const SECRET_MARKERS: &[&str] = &[
+    "Sas(\"",
+    "password: \"",
+    "password: Some(\"",
+    "sas_token: Some(\"",
+    "SharedAccessSignature ",
+    "SharedAccessKey=",
+    "sig=",
+];
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables verbose logging, which logs payloads in full.
+/// Credentials are masked either way.
+pub fn set_verbose_logging(enabled: bool) {
+    VERBOSE.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns TRUE if verbose logging is enabled
+pub fn verbose_logging() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Wraps a value for logging: its Debug output is printed with credentials masked and long payloads truncated
+pub fn redact<T: Debug + ?Sized>(value: &T) -> Redacted<'_, T> {
+    Redacted(value)
+}
+
+/// A value wrapped for logging, see [`redact`]
+pub struct Redacted<'a, T: ?Sized>(&'a T);
+
+impl<T: Debug + ?Sized> Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = mask_secrets(&format!("{:?}", self.0));
+        if !verbose_logging() {
+            truncate(&mut text, MAX_LOGGED_LEN);
+        }
+        f.write_str(&text)
+    }
+}
+
+impl<T: Debug + ?Sized> Display for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+fn mask_secrets(text: &str) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((start, marker)) = find_marker(rest) {
+        let secret_start = start + marker.len();
+        masked.push_str(&rest[..secret_start]);
+        masked.push_str(MASK);
+        rest = &rest[secret_start..];
+        let secret_end = if marker.ends_with('"') {
+            rest.find('"')
+        } else {
+            rest.find(|c: char| c == '"' || c == ';' || c == '&' || c.is_whitespace())
+        };
+        let secret_end = secret_end.unwrap_or(rest.len());
+        rest = &rest[secret_end..];
+    }
+    masked.push_str(rest);
+    masked
+}
+
+fn find_marker(text: &str) -> Option<(usize, &'static str)> {
+    SECRET_MARKERS
+        .iter()
+        .filter_map(|marker| text.find(marker).map(|index| (index, *marker)))
+        .min_by_key(|(index, _)| *index)
+}
+
+fn truncate(text: &mut String, max_len: usize) {
+    if text.len() <= max_len {
+        return;
+    }
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let omitted = text.len() - end;
+    text.truncate(end);
+    text.push_str(&format!("... ({} bytes omitted)", omitted));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Connect {
+        password: Option<String>,
+    }
+
+    #[test]
+    fn test_credentials_are_masked() {
+        let connect = Connect {
+            password: Some("SharedAccessSignature sr=hub%2Fdevices%2Fd1&sig=abc%3D&se=1600000000".to_owned()),
+        };
+        let logged = format!("{:?}", redact(&connect));
+        assert_eq!(logged, "Connect { password: Some(\"***\") }");
+        // only the logged copy is masked
+        assert!(connect.password.unwrap().contains("sig=abc"));
+
+        let logged = format!("{}", redact("HostName=hub;DeviceId=d1;SharedAccessKey=c2VjcmV0"));
+        assert_eq!(logged, "\"HostName=hub;DeviceId=d1;SharedAccessKey=***\"");
+    }
+
+    #[test]
+    fn test_long_payloads_are_truncated() {
+        let payload = "x".repeat(MAX_LOGGED_LEN * 2);
+        let logged = format!("{:?}", redact(&payload));
+        assert!(logged.starts_with("\"xxx"));
+        assert!(logged.ends_with(&format!("... ({} bytes omitted)", MAX_LOGGED_LEN + 2)));
+    }
+}
@@ -0,0 +1,94 @@
+use serde_json::Value;
+use std::fmt::Debug;
+
+/// The telemetry system property carrying the content type of the payload
+pub const CONTENT_TYPE_PROPERTY: &str = "$.ct";
+
+/// The telemetry system property carrying the content encoding of the payload
+pub const CONTENT_ENCODING_PROPERTY: &str = "$.ce";
+
+/// Turns message payloads (telemetry, direct method responses, reported properties) into bytes.
+/// Messages without a serializer are sent as JSON, without a content type.
+pub trait PayloadSerializer: Debug + Send + Sync {
+    /// The MIME type of the serialized payloads, set as the content type of telemetry messages
+    fn content_type(&self) -> &str;
+
+    /// The character encoding of the serialized payloads, if they are text
+    fn content_encoding(&self) -> Option<&str> {
+        None
+    }
+
+    /// Serializes a payload. Payloads are JSON documents, which every format can represent.
+    fn serialize(&self, value: &Value) -> Vec<u8>;
+}
+
+/// Serializes payloads as UTF-8 JSON
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonSerializer;
+
+impl PayloadSerializer for JsonSerializer {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn content_encoding(&self) -> Option<&str> {
+        Some("utf-8")
+    }
+
+    fn serialize(&self, value: &Value) -> Vec<u8> {
+        value.to_string().into_bytes()
+    }
+}
+
+/// Serializes payloads as CBOR (RFC 8949)
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborSerializer;
+
+#[cfg(feature = "cbor")]
+impl PayloadSerializer for CborSerializer {
+    fn content_type(&self) -> &str {
+        "application/cbor"
+    }
+
+    fn serialize(&self, value: &Value) -> Vec<u8> {
+        serde_cbor::to_vec(value).expect("JSON values are representable in CBOR")
+    }
+}
+
+/// Serializes payloads as MessagePack
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackSerializer;
+
+#[cfg(feature = "msgpack")]
+impl PayloadSerializer for MessagePackSerializer {
+    fn content_type(&self) -> &str {
+        "application/msgpack"
+    }
+
+    fn serialize(&self, value: &Value) -> Vec<u8> {
+        rmp_serde::to_vec_named(value).expect("JSON values are representable in MessagePack")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_serializer() {
+        let sut = JsonSerializer;
+        assert_eq!(sut.serialize(&json!({ "a": 1 })), br#"{"a":1}"#.to_vec());
+        assert_eq!(sut.content_type(), "application/json");
+        assert_eq!(sut.content_encoding(), Some("utf-8"));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_serializer() {
+        // map(1) { text(1) "a": unsigned(1) }
+        assert_eq!(CborSerializer.serialize(&json!({ "a": 1 })), vec![0xa1, 0x61, 0x61, 0x01]);
+    }
+}
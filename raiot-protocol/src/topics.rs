@@ -0,0 +1,253 @@
+use crate::identity::ClientIdentity;
+
+/// Prefix of twin read/update responses: `$iothub/twin/res/{status}/?$rid={request id}`
+pub const TWIN_RESPONSE_PREFIX: &str = "$iothub/twin/res/";
+
+/// Filter matching all twin responses
+pub const TWIN_RESPONSE_FILTER: &str = "$iothub/twin/res/#";
+
+/// Prefix of twin read requests: `$iothub/twin/GET/?$rid={request id}`
+pub const TWIN_GET_PREFIX: &str = "$iothub/twin/GET/";
+
+/// Prefix of reported properties updates: `$iothub/twin/PATCH/properties/reported/?$rid={request id}`
+pub const TWIN_REPORTED_PREFIX: &str = "$iothub/twin/PATCH/properties/reported/";
+
+/// Prefix of desired properties notifications: `$iothub/twin/PATCH/properties/desired/?$version={version}`
+pub const TWIN_DESIRED_PREFIX: &str = "$iothub/twin/PATCH/properties/desired/";
+
+/// Filter matching all desired properties notifications
+pub const TWIN_DESIRED_FILTER: &str = "$iothub/twin/PATCH/properties/desired/#";
+
+/// Prefix of direct method invocations: `$iothub/methods/POST/{method name}/?$rid={request id}`
+pub const METHODS_POST_PREFIX: &str = "$iothub/methods/POST/";
+
+/// Filter matching all direct method invocations
+pub const METHODS_POST_FILTER: &str = "$iothub/methods/POST/#";
+
+/// Prefix of direct method responses: `$iothub/methods/res/{status}/?$rid={request id}`
+pub const METHODS_RESPONSE_PREFIX: &str = "$iothub/methods/res/";
+
+const DEVICES_PREFIX: &str = "devices/";
+const C2D_SEGMENT: &str = "/messages/devicebound/";
+const INPUTS_SEGMENT: &str = "/inputs/";
+
+/// The topic telemetry of the specified client is published to, before the property bag
+pub fn telemetry(client_id: &ClientIdentity) -> String {
+    match client_id {
+        ClientIdentity::Device(device) => format!("devices/{}/messages/events/", device.device_id),
+        ClientIdentity::Module(module) => format!(
+            "devices/{}/modules/{}/messages/events/",
+            module.device_id, module.module_id
+        ),
+    }
+}
+
+/// Filter matching the C2D messages of the specified device
+pub fn c2d_filter(device_id: &str) -> String {
+    format!("devices/{}/messages/devicebound/#", device_id)
+}
+
+/// Filter matching the messages routed to the inputs of the specified module
+pub fn module_inputs_filter(device_id: &str, module_id: &str) -> String {
+    format!("devices/{}/modules/{}/inputs/#", device_id, module_id)
+}
+
+/// The topic of a twin read request
+pub fn twin_get(request_id: &str) -> String {
+    format!("{}?$rid={}", TWIN_GET_PREFIX, request_id)
+}
+
+/// The topic of a reported properties update request
+pub fn twin_reported(request_id: &str) -> String {
+    format!("{}?$rid={}", TWIN_REPORTED_PREFIX, request_id)
+}
+
+/// The topic of a reported properties update request whose payload has the specified content encoding (e.g. "gzip")
+pub fn twin_reported_encoded(request_id: &str, content_encoding: &str) -> String {
+    format!(
+        "{}?$rid={}&{}={}",
+        TWIN_REPORTED_PREFIX,
+        request_id,
+        crate::serialization::CONTENT_ENCODING_PROPERTY,
+        content_encoding
+    )
+}
+
+/// The topic of a twin response, as published by the hub
+pub fn twin_response(status: u16, request_id: &str) -> String {
+    format!("{}{}/?$rid={}", TWIN_RESPONSE_PREFIX, status, request_id)
+}
+
+/// The topic of a direct method response
+pub fn method_response(status: i32, request_id: &str) -> String {
+    format!("{}{}/?$rid={}", METHODS_RESPONSE_PREFIX, status, request_id)
+}
+
+/// The topic filter of the subscription receiving the responses to publications to the specified topic, if any.
+/// Twin requests are answered on the twin responses topic, which must be subscribed to before they are sent.
+pub fn response_filter(topic_name: &str) -> Option<&'static str> {
+    match topic_name.starts_with(TWIN_GET_PREFIX) || topic_name.starts_with(TWIN_REPORTED_PREFIX) {
+        true => Some(TWIN_RESPONSE_FILTER),
+        false => None,
+    }
+}
+
+/// A topic name published by the hub, split into the parts identifying the message.
+/// Parts are returned as they appear on the wire, without percent-decoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HubTopic<'a> {
+    /// A response to a twin read or update request
+    TwinResponse {
+        /// The status code segment
+        status: &'a str,
+
+        /// The query string (`$rid`, `$version`)
+        query: &'a str,
+    },
+
+    /// A desired properties notification
+    DesiredPropertiesUpdate {
+        /// The query string (`$version`)
+        query: &'a str,
+    },
+
+    /// A direct method invocation
+    MethodInvocation {
+        /// The method name segment
+        method_name: &'a str,
+
+        /// The query string (`$rid`)
+        query: &'a str,
+    },
+
+    /// A C2D message
+    CloudToDevice {
+        /// The device ID segment
+        device_id: &'a str,
+
+        /// The property bag
+        properties: &'a str,
+    },
+
+    /// A message routed to a module input
+    ModuleInput {
+        /// The device ID segment
+        device_id: &'a str,
+
+        /// The module ID segment
+        module_id: &'a str,
+
+        /// The input name segment
+        input_name: &'a str,
+
+        /// The property bag
+        properties: &'a str,
+    },
+
+    /// Anything else
+    Unknown,
+}
+
+impl<'a> HubTopic<'a> {
+    /// Identifies a topic name published by the hub
+    pub fn parse(topic: &'a str) -> HubTopic<'a> {
+        let (path, query) = topic.split_once('?').unwrap_or((topic, ""));
+
+        if let Some(rest) = path.strip_prefix(TWIN_RESPONSE_PREFIX) {
+            return HubTopic::TwinResponse {
+                status: first_segment(rest),
+                query,
+            };
+        }
+        if path.starts_with(TWIN_DESIRED_PREFIX) {
+            return HubTopic::DesiredPropertiesUpdate { query };
+        }
+        if let Some(rest) = path.strip_prefix(METHODS_POST_PREFIX) {
+            return HubTopic::MethodInvocation {
+                method_name: first_segment(rest),
+                query,
+            };
+        }
+        if let Some(rest) = topic.strip_prefix(DEVICES_PREFIX) {
+            return Self::parse_device_topic(rest);
+        }
+        HubTopic::Unknown
+    }
+
+    // {device id}/messages/devicebound/{properties}
+    // {device id}/modules/{module id}/inputs/{input name}/{properties}
+    fn parse_device_topic(rest: &'a str) -> HubTopic<'a> {
+        if let Some((device_id, properties)) = rest.split_once(C2D_SEGMENT) {
+            if !device_id.contains('/') {
+                return HubTopic::CloudToDevice {
+                    device_id,
+                    properties,
+                };
+            }
+        }
+        if let Some((device_id, rest)) = rest.split_once("/modules/") {
+            if let Some((module_id, rest)) = rest.split_once(INPUTS_SEGMENT) {
+                let (input_name, properties) = rest.split_once('/').unwrap_or((rest, ""));
+                return HubTopic::ModuleInput {
+                    device_id,
+                    module_id,
+                    input_name,
+                    properties,
+                };
+            }
+        }
+        HubTopic::Unknown
+    }
+}
+
+fn first_segment(path: &str) -> &str {
+    path.split('/').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builders_and_parser_agree() {
+        assert_eq!(
+            HubTopic::parse(&twin_response(204, "abc")),
+            HubTopic::TwinResponse {
+                status: "204",
+                query: "$rid=abc"
+            }
+        );
+        assert_eq!(
+            HubTopic::parse("$iothub/methods/POST/reboot/?$rid=1"),
+            HubTopic::MethodInvocation {
+                method_name: "reboot",
+                query: "$rid=1"
+            }
+        );
+        assert_eq!(
+            HubTopic::parse("devices/d1/messages/devicebound/%24.mid=7&k=v"),
+            HubTopic::CloudToDevice {
+                device_id: "d1",
+                properties: "%24.mid=7&k=v"
+            }
+        );
+        assert_eq!(
+            HubTopic::parse("devices/d1/modules/m1/inputs/in1/k=v"),
+            HubTopic::ModuleInput {
+                device_id: "d1",
+                module_id: "m1",
+                input_name: "in1",
+                properties: "k=v"
+            }
+        );
+        assert_eq!(HubTopic::parse("devices/d1/messages/events/"), HubTopic::Unknown);
+    }
+
+    #[test]
+    fn test_twin_requests_depend_on_the_responses_subscription() {
+        assert_eq!(response_filter(&twin_get("1")), Some(TWIN_RESPONSE_FILTER));
+        assert_eq!(response_filter(&twin_reported_encoded("2", "gzip")), Some(TWIN_RESPONSE_FILTER));
+        assert_eq!(response_filter(&method_response(200, "3")), None);
+        assert_eq!(response_filter("devices/d1/messages/events/"), None);
+    }
+}
@@ -0,0 +1,628 @@
+//! Device registration with the Azure IoT Hub Device Provisioning Service (DPS) over MQTT
+//!
+//! A device registers with its enrollment, attested by a symmetric key or an X509 certificate, and the service
+//! assigns it to a hub. `provision` runs the whole flow and returns the `ConnectionSettings` of the assigned hub.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use mqtt::packet::suback::SubscribeReturnCode;
+use mqtt::packet::{Packet, VariablePacket};
+use raiot_client_base::{ConnectionSettings, PacketsNumerator, Reprovisioner, RequestIdSource, RetrySchedules};
+use raiot_errors::{ClientError, ProtocolError, TransportError};
+use raiot_mqtt::connection::{MqttConnectError, MqttConnection, MqttConnector};
+use raiot_mqtt::packets::StreamerError;
+use raiot_protocol::auth::certificate::DeviceCertificate;
+use raiot_protocol::auth::sas::SasToken;
+use raiot_protocol::auth::DeviceCredentials;
+use raiot_protocol::connect::ConnectError;
+use raiot_protocol::qos::PacketId;
+use raiot_protocol::{ClientIdentity, CodecError, IdentityError, IotCodec, SubError};
+use raiot_streams::{open_nonblocking_stream, ClientCertificate, ProxySettings, TlsSettings};
+use serde_json::Value;
+
+/// The DPS MQTT topics and payloads
+pub mod protocol;
+
+use crate::protocol::{RegistrationOperation, Response, DEFAULT_RETRY_AFTER};
+
+/// How the device proves its identity to the service
+#[derive(Clone, Debug)]
+pub enum Attestation {
+    /// The key of an individual enrollment, or the device key derived from a group enrollment key
+    /// (see `raiot_protocol::auth::sas::derive_device_key`). The assigned hub accepts the same key.
+    SymmetricKey(String),
+
+    /// The device certificate, presented in the TLS handshake to both the service and the assigned hub
+    X509(DeviceCertificate),
+}
+
+/// The settings of a registration
+#[derive(Clone, Debug)]
+pub struct ProvisioningSettings {
+    /// The service endpoint, usually `protocol::DEFAULT_ENDPOINT`
+    pub endpoint: String,
+    pub port: u16,
+    /// The ID scope of the DPS instance
+    pub id_scope: String,
+    /// The registration ID of the enrollment, which is also the device ID unless the service assigns another
+    pub registration_id: String,
+    pub attestation: Attestation,
+    /// A custom payload passed to the allocation policy, if any
+    pub payload: Option<Value>,
+    /// The timeout of the TCP/TLS/MQTT connection, to the service and later to the assigned hub
+    pub timeout: Duration,
+    /// How long to wait for the assignment, including throttling and status queries
+    pub registration_timeout: Duration,
+    /// The TTL of the SAS tokens, for the registration and later for the assigned hub
+    pub token_ttl: Duration,
+    /// The HTTP proxy the connections are tunneled through, to the service and later to the assigned hub
+    pub proxy: Option<ProxySettings>,
+}
+
+impl ProvisioningSettings {
+    /// Settings registering at the global endpoint, with the defaults of the CLI
+    pub fn new(id_scope: &str, registration_id: &str, attestation: Attestation) -> ProvisioningSettings {
+        ProvisioningSettings {
+            endpoint: protocol::DEFAULT_ENDPOINT.to_owned(),
+            port: 8883,
+            id_scope: id_scope.to_owned(),
+            registration_id: registration_id.to_owned(),
+            attestation,
+            payload: None,
+            timeout: Duration::from_secs(30),
+            registration_timeout: Duration::from_secs(60),
+            token_ttl: Duration::from_secs(60 * 60),
+            proxy: None,
+        }
+    }
+
+    /// The settings of a connection to the assigned hub
+    ///
+    /// # Errors
+    /// Returns `InvalidDeviceId` if the assigned device ID is not a valid IoT Hub device ID
+    pub fn connection_settings(&self, assignment: &Assignment) -> Result<ConnectionSettings, ProvisioningError> {
+        Ok(ConnectionSettings {
+            hostname: assignment.assigned_hub.clone(),
+            port: self.port,
+            client_id: ClientIdentity::try_from_device_id(&assignment.device_id)
+                .map_err(ProvisioningError::InvalidDeviceId)?,
+            timeout: self.timeout,
+            token_ttl: self.token_ttl,
+            credentials: match &self.attestation {
+                Attestation::SymmetricKey(key) => DeviceCredentials::Sas(key.clone()),
+                Attestation::X509(certificate) => DeviceCredentials::Certificate(certificate.clone()),
+            },
+            retry_policy: Some(Arc::new(RetrySchedules::default())),
+            proxy: self.proxy.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// The settings of a connection to the hub a device was reassigned to: the current settings,
+    /// directed at the assigned hub
+    ///
+    /// # Errors
+    /// Returns `DeviceIdChanged` if the device was assigned another device ID, which a running client can't take on
+    pub fn reassigned_settings(
+        &self,
+        current: &ConnectionSettings,
+        assignment: &Assignment,
+    ) -> Result<ConnectionSettings, ProvisioningError> {
+        let device_id = match &current.client_id {
+            ClientIdentity::Device(device) => &device.device_id,
+            ClientIdentity::Module(module) => &module.device_id,
+        };
+        if *device_id != assignment.device_id {
+            return Err(ProvisioningError::DeviceIdChanged {
+                previous: device_id.clone(),
+                assigned: assignment.device_id.clone(),
+            });
+        }
+        Ok(ConnectionSettings {
+            hostname: assignment.assigned_hub.clone(),
+            ..current.clone()
+        })
+    }
+}
+
+/// Registers the device again once its hub keeps refusing its credentials, e.g. after the service moved it
+/// to another hub. Opt in by setting the provisioning settings as the `reprovisioner` of the connection settings.
+impl Reprovisioner for ProvisioningSettings {
+    fn reprovision(&self, current: &ConnectionSettings) -> Result<ConnectionSettings, Box<dyn Error + Send + Sync>> {
+        let assignment = register(self)?;
+        Ok(self.reassigned_settings(current, &assignment)?)
+    }
+}
+
+/// The hub a device was assigned to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    /// The hostname of the assigned hub
+    pub assigned_hub: String,
+
+    /// The identity of the device in the assigned hub
+    pub device_id: String,
+
+    /// The custom payload returned by the allocation policy, if any
+    pub payload: Option<Value>,
+}
+
+/// Errors returned by the registration
+#[derive(Debug)]
+pub enum ProvisioningError {
+    /// The connection to the service failed, or the service violated the protocol
+    Client(ClientError),
+
+    /// The symmetric key is not valid base64, or the token TTL is out of range
+    InvalidKey,
+
+    /// The service rejected a request with the status code (e.g. 401 for an unknown enrollment)
+    Rejected {
+        /// The status code of the response
+        status: u16,
+
+        /// The error message of the response, if any
+        message: Option<String>,
+    },
+
+    /// The registration completed without an assignment (e.g. the enrollment is disabled)
+    RegistrationFailed {
+        /// The status of the registration: `failed` or `disabled`
+        status: String,
+
+        /// The error message of the registration, if any
+        message: Option<String>,
+    },
+
+    /// The assigned device ID is not a valid IoT Hub device ID
+    InvalidDeviceId(IdentityError),
+
+    /// Registering again assigned the device another device ID
+    DeviceIdChanged {
+        /// The device ID the client is connected with
+        previous: String,
+
+        /// The device ID assigned by the service
+        assigned: String,
+    },
+}
+
+impl fmt::Display for ProvisioningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProvisioningError::Client(e) => write!(f, "{}", e),
+            ProvisioningError::InvalidKey => write!(f, "Invalid symmetric key or token TTL"),
+            ProvisioningError::Rejected { status, message } => {
+                write!(f, "Rejected by the provisioning service with status {}", status)?;
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => Ok(()),
+                }
+            }
+            ProvisioningError::RegistrationFailed { status, message } => {
+                write!(f, "Registration {}", status)?;
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => Ok(()),
+                }
+            }
+            ProvisioningError::InvalidDeviceId(e) => write!(f, "Invalid assigned device ID: {}", e),
+            ProvisioningError::DeviceIdChanged { previous, assigned } => {
+                write!(f, "Assigned device ID {} instead of {}", assigned, previous)
+            }
+        }
+    }
+}
+
+impl Error for ProvisioningError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProvisioningError::Client(e) => Some(e),
+            ProvisioningError::InvalidDeviceId(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ClientError> for ProvisioningError {
+    fn from(e: ClientError) -> Self {
+        ProvisioningError::Client(e)
+    }
+}
+
+impl From<TransportError> for ProvisioningError {
+    fn from(e: TransportError) -> Self {
+        ProvisioningError::Client(e.into())
+    }
+}
+
+impl From<ProtocolError> for ProvisioningError {
+    fn from(e: ProtocolError) -> Self {
+        ProvisioningError::Client(e.into())
+    }
+}
+
+impl From<CodecError> for ProvisioningError {
+    fn from(e: CodecError) -> Self {
+        ProvisioningError::Client(e.into())
+    }
+}
+
+impl From<io::Error> for ProvisioningError {
+    fn from(e: io::Error) -> Self {
+        ProvisioningError::Client(e.into())
+    }
+}
+
+impl From<StreamerError> for ProvisioningError {
+    fn from(e: StreamerError) -> Self {
+        ProvisioningError::Client(e.into())
+    }
+}
+
+enum SubscriptionState {
+    Unsubscribed,
+    Subscribing(PacketId),
+    Subscribed,
+}
+
+#[derive(Clone, Debug)]
+enum Request {
+    Register(Value),
+    QueryStatus(String),
+}
+
+/// Registers a device over an established MQTT connection to the service
+///
+/// All operations are nonblocking: requests are buffered and transmitted by `poll`,
+/// which also follows the responses of the service until the device is assigned to a hub.
+pub struct ProvisioningSession<S: Read + Write> {
+    connection: MqttConnection<S>,
+    packets_numerator: PacketsNumerator,
+    request_ids: RequestIdSource,
+    responses: SubscriptionState,
+    // the next request and when to send it, delayed by retry-after
+    next: Option<(Request, Instant)>,
+    // the request awaiting a response, with its identifier
+    in_flight: Option<(String, Request)>,
+}
+
+impl<S: Read + Write> ProvisioningSession<S> {
+    /// Creates a registration session over a connected MQTT connection
+    pub fn new(connection: MqttConnection<S>) -> ProvisioningSession<S> {
+        ProvisioningSession {
+            connection,
+            packets_numerator: PacketsNumerator::new(),
+            request_ids: RequestIdSource::Counter(0),
+            responses: SubscriptionState::Unsubscribed,
+            next: None,
+            in_flight: None,
+        }
+    }
+
+    /// Requests the registration of the device, subscribing to the responses first
+    pub fn register(&mut self, registration_id: &str, payload: Option<&Value>) -> Result<(), ProvisioningError> {
+        if let SubscriptionState::Unsubscribed = self.responses {
+            let packet_id = self.packets_numerator.next_id();
+            self.connection.write(&protocol::subscribe_packet(packet_id).into())?;
+            self.responses = SubscriptionState::Subscribing(packet_id);
+        }
+        let body = protocol::register_payload(registration_id, payload);
+        self.next = Some((Request::Register(body), Instant::now()));
+        self.in_flight = None;
+        Ok(())
+    }
+
+    /// Receives data and sends the requests which are due, until the device is assigned or the socket would block.
+    /// Each transfer direction is limited to the specified duration.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<Assignment>, ProvisioningError> {
+        let _ = self.connection.recv_task(timeout)?;
+        while let Some(packet) = self.connection.read()? {
+            if let Some(assignment) = self.process_packet(packet)? {
+                return Ok(Some(assignment));
+            }
+        }
+        self.send_due_request()?;
+        let _ = self.connection.send_task(timeout)?;
+        Ok(None)
+    }
+
+    fn send_due_request(&mut self) -> Result<(), ProvisioningError> {
+        if let SubscriptionState::Subscribed = self.responses {
+            if let Some((_, at)) = self.next {
+                if at <= Instant::now() {
+                    let (request, _) = self.next.take().expect("Checked above");
+                    let request_id = self.request_ids.next_id();
+                    let packet_id = self.packets_numerator.next_id();
+                    let packet = match &request {
+                        Request::Register(body) => {
+                            protocol::publish_packet(protocol::register_topic(&request_id), packet_id, body)?
+                        }
+                        Request::QueryStatus(operation_id) => protocol::publish_packet(
+                            protocol::status_topic(&request_id, operation_id),
+                            packet_id,
+                            &Value::Null,
+                        )?,
+                    };
+                    debug!("Sending DPS request {}: {:?}", request_id, request);
+                    self.connection.write(&packet.into())?;
+                    self.in_flight = Some((request_id, request));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn process_packet(&mut self, packet: VariablePacket) -> Result<Option<Assignment>, ProvisioningError> {
+        match packet {
+            VariablePacket::SubackPacket(suback) => {
+                if let SubscriptionState::Subscribing(packet_id) = self.responses {
+                    if packet_id.value() == suback.packet_identifier() {
+                        let codes = suback.payload_ref().subscribes();
+                        if codes.contains(&SubscribeReturnCode::Failure) {
+                            return Err(ClientError::from(SubError::rejected(0x80)).into());
+                        }
+                        self.responses = SubscriptionState::Subscribed;
+                    }
+                }
+                Ok(None)
+            }
+            VariablePacket::PublishPacket(publish) => {
+                match Response::parse(publish.topic_name(), publish.payload_ref()) {
+                    Some(response) => self.process_response(response),
+                    None => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn process_response(&mut self, response: Response) -> Result<Option<Assignment>, ProvisioningError> {
+        let request = match self.in_flight.take() {
+            Some((request_id, request)) if request_id == response.request_id => request,
+            other => {
+                debug!("Ignoring a DPS response to request {}", response.request_id);
+                self.in_flight = other;
+                return Ok(None);
+            }
+        };
+        let retry_at = Instant::now() + response.retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+
+        match response.status {
+            200 | 202 => {}
+            429 => {
+                debug!("DPS request throttled, retrying after {:?}", response.retry_after);
+                self.next = Some((request, retry_at));
+                return Ok(None);
+            }
+            status => {
+                return Err(ProvisioningError::Rejected {
+                    status,
+                    message: response.message(),
+                })
+            }
+        }
+
+        let operation: RegistrationOperation = serde_json::from_value(response.body)
+            .map_err(|_| CodecError::InvalidMessageBody)?;
+        if operation.status == "assigning" {
+            self.next = Some((Request::QueryStatus(operation.operation_id), retry_at));
+            return Ok(None);
+        }
+
+        let state = operation
+            .registration_state
+            .ok_or(CodecError::InvalidMessageBody)?;
+        match (state.status.as_str(), state.assigned_hub, state.device_id) {
+            ("assigned", Some(assigned_hub), Some(device_id)) => Ok(Some(Assignment {
+                assigned_hub,
+                device_id,
+                payload: state.payload,
+            })),
+            ("assigned", _, _) => Err(CodecError::InvalidMessageBody.into()),
+            (status, _, _) => Err(ProvisioningError::RegistrationFailed {
+                status: status.to_owned(),
+                message: state.error_message,
+            }),
+        }
+    }
+}
+
+/// How long the blocking registration sleeps between polls of the connection
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Registers the device, blocking until it is assigned to a hub or `registration_timeout` elapses
+///
+/// # Errors
+/// Returns `ClientError::Transport(TransportError::TimedOut)` if the device was not assigned in time
+pub fn register(settings: &ProvisioningSettings) -> Result<Assignment, ProvisioningError> {
+    let start = Instant::now();
+
+    let tls = TlsSettings {
+        client_certificate: match &settings.attestation {
+            Attestation::X509(certificate) => Some(ClientCertificate {
+                bytes: certificate.bytes.clone(),
+                password: certificate.password.clone(),
+            }),
+            Attestation::SymmetricKey(_) => None,
+        },
+        trusted_ca_certs: None,
+    };
+    let token = match &settings.attestation {
+        Attestation::SymmetricKey(key) => Some(String::from(
+            SasToken::for_registration(&settings.id_scope, &settings.registration_id, key, settings.token_ttl)
+                .map_err(|_| ProvisioningError::InvalidKey)?,
+        )),
+        Attestation::X509(_) => None,
+    };
+
+    let stream = open_nonblocking_stream(
+        &settings.endpoint,
+        settings.port.into(),
+        settings.timeout,
+        &tls,
+        settings.proxy.as_ref(),
+    )?
+    .inner();
+
+    let connect = protocol::connect_packet(&settings.id_scope, &settings.registration_id, token);
+    let mut in_progress = MqttConnector::create(stream)
+        .with_timeout(settings.timeout.saturating_sub(start.elapsed()))
+        .connect(connect)?;
+    let connection = loop {
+        match in_progress.complete() {
+            Ok(connection) => break connection,
+            Err(MqttConnectError::WouldBlock(next)) => in_progress = *next,
+            Err(MqttConnectError::ConnectFailed(rc)) => {
+                let refused = IotCodec::decode_connect_return_code(rc, false)
+                    .err()
+                    .unwrap_or(ConnectError::ProtocolViolation);
+                return Err(ClientError::from(refused).into());
+            }
+            Err(MqttConnectError::IOError(kind)) => return Err(TransportError::from(kind).into()),
+            Err(MqttConnectError::ProtocolViolation) => return Err(ProtocolError::Violation.into()),
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    let mut session = ProvisioningSession::new(connection);
+    session.register(&settings.registration_id, settings.payload.as_ref())?;
+    let deadline = Instant::now() + settings.registration_timeout;
+    while Instant::now() < deadline {
+        if let Some(assignment) = session.poll(POLL_INTERVAL)? {
+            debug!("Device assigned to {}", assignment.assigned_hub);
+            return Ok(assignment);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Err(TransportError::TimedOut.into())
+}
+
+/// Registers the device like `register`, returning the settings of a connection to the assigned hub
+pub fn provision(settings: &ProvisioningSettings) -> Result<ConnectionSettings, ProvisioningError> {
+    let assignment = register(settings)?;
+    settings.connection_settings(&assignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raiot_test_utils::dps::{DpsRequest, DpsResponse, MockDps};
+    use raiot_test_utils::{MockClientSocket, MockSocket};
+    use serde_json::json;
+
+    const TIMEOUT: Duration = Duration::from_millis(5);
+
+    fn connected_session() -> (ProvisioningSession<MockClientSocket>, MockDps) {
+        let (client, server) = MockSocket::create();
+        let mut dps = MockDps::new(server);
+        let connect = protocol::connect_packet("0ne0001", "device-1", Some("token".to_owned()));
+        let mut in_progress = MqttConnector::create(client).connect(connect).unwrap();
+        let connection = loop {
+            match in_progress.complete() {
+                Ok(connection) => break connection,
+                Err(MqttConnectError::WouldBlock(next)) => in_progress = *next,
+                Err(_) => panic!("Connection failed"),
+            }
+            dps.process();
+        };
+        (ProvisioningSession::new(connection), dps)
+    }
+
+    fn run(sut: &mut ProvisioningSession<MockClientSocket>, dps: &mut MockDps) -> Result<Assignment, ProvisioningError> {
+        for _ in 0..100 {
+            if let Some(assignment) = sut.poll(TIMEOUT)? {
+                return Ok(assignment);
+            }
+            dps.process();
+        }
+        panic!("Registration did not complete");
+    }
+
+    #[test]
+    fn test_registration_follows_throttling_and_operation_status() {
+        let (mut sut, mut dps) = connected_session();
+        dps.push_response(DpsResponse::Throttled { retry_after: 0 });
+        dps.push_response(DpsResponse::Assigning {
+            operation_id: "op1".to_owned(),
+            retry_after: 0,
+        });
+        dps.push_response(DpsResponse::Assigned {
+            operation_id: "op1".to_owned(),
+            assigned_hub: "hub.azure-devices.net".to_owned(),
+            device_id: "device-1".to_owned(),
+        });
+
+        sut.register("device-1", Some(&json!({ "model": "thermostat" }))).unwrap();
+        let assignment = run(&mut sut, &mut dps).unwrap();
+        assert_eq!(assignment.assigned_hub, "hub.azure-devices.net");
+        assert_eq!(assignment.device_id, "device-1");
+
+        let requests = dps.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[1],
+            DpsRequest::Register {
+                request_id: "2".to_owned(),
+                payload: json!({ "registrationId": "device-1", "payload": { "model": "thermostat" } }),
+            }
+        );
+        assert_eq!(
+            requests[2],
+            DpsRequest::QueryStatus {
+                request_id: "3".to_owned(),
+                operation_id: "op1".to_owned(),
+            }
+        );
+
+        let settings = ProvisioningSettings::new("0ne0001", "device-1", Attestation::SymmetricKey("a2V5".to_owned()));
+        let connection = settings.connection_settings(&assignment).unwrap();
+        assert_eq!(connection.hostname, "hub.azure-devices.net");
+        assert!(matches!(connection.client_id, ClientIdentity::Device(ref device) if device.device_id == "device-1"));
+        assert!(matches!(connection.credentials, DeviceCredentials::Sas(ref key) if key == "a2V5"));
+    }
+
+    #[test]
+    fn test_rejected_registration_returns_error() {
+        let (mut sut, mut dps) = connected_session();
+        dps.push_response(DpsResponse::Failed { status: 401 });
+
+        sut.register("device-1", None).unwrap();
+        match run(&mut sut, &mut dps) {
+            Err(ProvisioningError::Rejected { status: 401, message }) => {
+                assert_eq!(message.as_deref(), Some("Scripted failure"))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reassignment_keeps_the_connection_settings() {
+        let settings = ProvisioningSettings::new("0ne0001", "device-1", Attestation::SymmetricKey("a2V5".to_owned()));
+        let assignment = |hub: &str, device_id: &str| Assignment {
+            assigned_hub: hub.to_owned(),
+            device_id: device_id.to_owned(),
+            payload: None,
+        };
+        let mut current = settings.connection_settings(&assignment("hub-a.azure-devices.net", "device-1")).unwrap();
+        current.validate_topics = true;
+
+        let moved = settings
+            .reassigned_settings(&current, &assignment("hub-b.azure-devices.net", "device-1"))
+            .unwrap();
+        assert_eq!(moved.hostname, "hub-b.azure-devices.net");
+        assert!(moved.validate_topics);
+
+        match settings.reassigned_settings(&current, &assignment("hub-b.azure-devices.net", "device-2")) {
+            Err(ProvisioningError::DeviceIdChanged { previous, assigned }) => {
+                assert_eq!((previous.as_str(), assigned.as_str()), ("device-1", "device-2"))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use mqtt::packet::*;
+use mqtt::{QualityOfService, TopicFilter, TopicName};
+use raiot_protocol::qos::PacketId;
+use raiot_protocol::CodecError;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// The global endpoint of the Device Provisioning Service
+pub const DEFAULT_ENDPOINT: &str = "global.azure-devices-provisioning.net";
+
+/// The DPS API version announced by the client
+pub const DPS_API_VERSION: &str = "2019-03-31";
+
+/// Topic filter of the responses to registration requests and operation status queries
+pub const RESPONSE_FILTER: &str = "$dps/registrations/res/#";
+
+/// How long to wait before querying the status of an operation, or retrying a throttled request,
+/// when the service doesn't specify it
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(3);
+
+const RESPONSE_PREFIX: &str = "$dps/registrations/res/";
+const REGISTER_PREFIX: &str = "$dps/registrations/PUT/iotdps-register/";
+const STATUS_PREFIX: &str = "$dps/registrations/GET/iotdps-get-operationstatus/";
+
+/// The MQTT username of a registration
+pub fn username(id_scope: &str, registration_id: &str) -> String {
+    format!("{}/registrations/{}/api-version={}", id_scope, registration_id, DPS_API_VERSION)
+}
+
+/// The topic of a registration request
+pub fn register_topic(request_id: &str) -> String {
+    format!("{}?$rid={}", REGISTER_PREFIX, request_id)
+}
+
+/// The topic of an operation status query
+pub fn status_topic(request_id: &str, operation_id: &str) -> String {
+    format!("{}?$rid={}&operationId={}", STATUS_PREFIX, request_id, operation_id)
+}
+
+/// The body of a registration request, with the custom payload passed to the allocation policy, if any
+pub fn register_payload(registration_id: &str, payload: Option<&Value>) -> Value {
+    match payload {
+        Some(payload) => json!({ "registrationId": registration_id, "payload": payload }),
+        None => json!({ "registrationId": registration_id }),
+    }
+}
+
+/// The CONNECT packet of a registration. The token is None for X509 attestation, authenticated by the TLS handshake.
+pub fn connect_packet(id_scope: &str, registration_id: &str, sas_token: Option<String>) -> ConnectPacket {
+    let mut packet = ConnectPacket::new(registration_id);
+    packet.set_clean_session(true);
+    packet.set_user_name(Some(username(id_scope, registration_id)));
+    packet.set_password(sas_token);
+    packet
+}
+
+/// The SUBSCRIBE packet of the responses
+pub fn subscribe_packet(packet_id: PacketId) -> SubscribePacket {
+    let filter = TopicFilter::new(RESPONSE_FILTER).expect("Response filter expected to be valid");
+    SubscribePacket::new(packet_id.into(), vec![(filter, QualityOfService::Level1)])
+}
+
+/// A PUBLISH packet of a request
+///
+/// # Errors
+/// Returns `InvalidTopic` if the request or operation identifier can't be part of a topic name
+pub fn publish_packet(topic: String, packet_id: PacketId, body: &Value) -> Result<PublishPacket, CodecError> {
+    let topic = TopicName::new(topic).map_err(|_| CodecError::InvalidTopic)?;
+    Ok(PublishPacket::new(
+        topic,
+        QoSWithPacketIdentifier::Level1(packet_id.into()),
+        body.to_string(),
+    ))
+}
+
+/// A response of the service, to a registration request or an operation status query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    /// The HTTP-like status code: 200 once assigned, 202 while assigning, 429 when throttled
+    pub status: u16,
+
+    /// The identifier of the request the response answers
+    pub request_id: String,
+
+    /// How long the client should wait before its next request, if specified
+    pub retry_after: Option<Duration>,
+
+    /// The JSON body, or `Null` if the body is not JSON
+    pub body: Value,
+}
+
+impl Response {
+    /// Parses a PUBLISH from the service. Returns None if the topic is not a response topic.
+    pub fn parse(topic: &str, payload: &[u8]) -> Option<Response> {
+        let rest = topic.strip_prefix(RESPONSE_PREFIX)?;
+        let (status, query) = rest.split_once("/?")?;
+        let param = |key: &str| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v)
+        };
+        Some(Response {
+            status: status.parse().ok()?,
+            request_id: param("$rid")?.to_owned(),
+            retry_after: param("retry-after")
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs),
+            body: serde_json::from_slice(payload).unwrap_or(Value::Null),
+        })
+    }
+
+    /// The error message of a failure response, if any
+    pub fn message(&self) -> Option<String> {
+        self.body["message"].as_str().map(str::to_owned)
+    }
+}
+
+/// The state of a registration operation, in the body of a 200 or 202 response
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationOperation {
+    /// The identifier to query the status of the operation with
+    pub operation_id: String,
+
+    /// `assigning`, `assigned`, `failed` or `disabled`
+    pub status: String,
+
+    /// The outcome of the registration, once the operation completed
+    pub registration_state: Option<RegistrationState>,
+}
+
+/// The outcome of a registration
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationState {
+    /// The hub the device was assigned to
+    pub assigned_hub: Option<String>,
+
+    /// The identity of the device in the assigned hub
+    pub device_id: Option<String>,
+
+    /// `assigned`, `failed` or `disabled`
+    pub status: String,
+
+    /// The error code of a failed registration
+    pub error_code: Option<u32>,
+
+    /// The error message of a failed registration
+    pub error_message: Option<String>,
+
+    /// The custom payload returned by the allocation policy, if any
+    pub payload: Option<Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let response = Response::parse(
+            "$dps/registrations/res/202/?$rid=7&retry-after=3",
+            br#"{"operationId":"op1","status":"assigning"}"#,
+        )
+        .unwrap();
+        assert_eq!(response.status, 202);
+        assert_eq!(response.request_id, "7");
+        assert_eq!(response.retry_after, Some(Duration::from_secs(3)));
+        let operation: RegistrationOperation = serde_json::from_value(response.body).unwrap();
+        assert_eq!(operation.operation_id, "op1");
+        assert!(operation.registration_state.is_none());
+
+        let response = Response::parse("$dps/registrations/res/401/?$rid=8", br#"{"message":"Unauthorized"}"#).unwrap();
+        assert_eq!(response.retry_after, None);
+        assert_eq!(response.message().as_deref(), Some("Unauthorized"));
+
+        assert!(Response::parse("$iothub/twin/res/200/?$rid=7", b"{}").is_none());
+        assert!(Response::parse("$dps/registrations/res/abc/?$rid=7", b"{}").is_none());
+    }
+
+    #[test]
+    fn test_request_topics() {
+        assert_eq!(username("0ne0001", "device-1"), "0ne0001/registrations/device-1/api-version=2019-03-31");
+        assert_eq!(register_topic("1"), "$dps/registrations/PUT/iotdps-register/?$rid=1");
+        assert_eq!(
+            status_topic("2", "op1"),
+            "$dps/registrations/GET/iotdps-get-operationstatus/?$rid=2&operationId=op1"
+        );
+        assert_eq!(
+            register_payload("device-1", Some(&json!({ "model": "thermostat" }))),
+            json!({ "registrationId": "device-1", "payload": { "model": "thermostat" } })
+        );
+    }
+}
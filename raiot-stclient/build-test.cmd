@@ -1,3 +1,4 @@
+cargo build --no-default-features --features basic
 cargo build --no-default-features --features twin
 cargo build --no-default-features --features c2d
 cargo build --no-default-features --features direct-methods
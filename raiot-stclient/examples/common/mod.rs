@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+use raiot_client_base::ConnectionSettings;
+use raiot_stclient::{conn::IotConnState, IotClient};
+
+/// Connects to the hub, polling the handshake until it completes
+pub fn connect(settings: ConnectionSettings) -> IotClient {
+    let mut conn = IotClient::connect(&settings).unwrap();
+    loop {
+        match conn.complete() {
+            Ok(IotConnState::Connecting(cip)) => {
+                conn = *cip;
+                // Do some other work!
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            Ok(IotConnState::Connected(client)) => {
+                println!("Connected!");
+                return *client;
+            }
+            Ok(IotConnState::ConnectFailed(rc)) => panic!("oh no! {:?}", rc),
+            Err(e) => panic!("Failed connecting! {:?}", e),
+        }
+    }
+}
@@ -2,16 +2,19 @@ use std::{
     sync::{
         mpsc::{channel, TryRecvError},
     },
-    time::{Duration, Instant},
+    time::Instant,
 };
 
 use raiot_cli::Options;
-use raiot_client_base::{ConnectionSettings, D2CMsg, DMIResult};
+use raiot_client_base::{D2CMsg, DMIResult};
 
 use raiot_protocol::{direct_methods::DirectMethodReq, qos::DeliveryGuarantees};
-use raiot_stclient::{conn::IotConnState, IotClient};
+use raiot_stclient::{MethodsCapable, TwinCapable};
 use serde_json::json;
 
+mod common;
+use common::connect;
+
 fn main() -> ! {
     env_logger::init();
     let options = Options::from_cmd_line();
@@ -21,7 +24,9 @@ fn main() -> ! {
     let c2d_handler = |msg| println!("C2D: {}", msg);
     let c2d_hanler = Box::new(c2d_handler);
     let error_handler = Box::new(|err| println!("C2D Subscription error: {}", err));
-    iot_client.sub_c2d(DeliveryGuarantees::AtMostOnce, c2d_hanler, error_handler);
+    iot_client
+        .sub_c2d_with_error_handler(None, c2d_hanler, error_handler)
+        .unwrap();
 
     let (tx, rx) = channel();
 
@@ -31,25 +36,24 @@ fn main() -> ! {
     };
 
     let dmi_handler = Box::new(dmi_handler);
-    iot_client.sub_dmi(DeliveryGuarantees::AtMostOnce, dmi_handler);
-    iot_client.sub_twin_updates(
-        DeliveryGuarantees::AtMostOnce,
-        Box::new(|msg| println!("Twin: {:?}", msg)),
-    );
+    iot_client.sub_dmi(None, dmi_handler).unwrap();
+    iot_client
+        .sub_twin_updates(
+            Some(DeliveryGuarantees::AtMostOnce),
+            Box::new(|msg| println!("Twin: {:?}", msg)),
+        )
+        .unwrap();
     iot_client.read_twin();
 
     let mut last_telemetry_time = Instant::now();
     loop {
-        // send and receive messages
-        iot_client.process();
-
         if last_telemetry_time.elapsed().as_secs() > 10 {
             let big_value = build_telemetry_msg();
             let msg = D2CMsg {
                 headers: None,
                 content: Some(json!({ "key": big_value })),
             };
-            iot_client.send_d2c(msg, DeliveryGuarantees::AtLeastOnce);
+            iot_client.send_d2c(msg, None);
             last_telemetry_time = Instant::now();
         }
 
@@ -59,32 +63,14 @@ fn main() -> ! {
                     status: 200,
                     payload: Some(json!({ "key": "hellloooo" })),
                 };
-                iot_client.send_dmi_res(&dmi.request_id, res, DeliveryGuarantees::AtLeastOnce);
+                iot_client.send_dmi_res(&dmi.request_id, res, None);
             }
             Err(TryRecvError::Empty) => {}
             Err(TryRecvError::Disconnected) => {}
         }
 
-        std::thread::sleep(Duration::from_millis(5));
-    }
-}
-
-fn connect(settings: ConnectionSettings) -> IotClient {
-    let mut conn = IotClient::connect(&settings).unwrap();
-    loop {
-        match conn.complete() {
-            Ok(IotConnState::Connecting(cip)) => {
-                conn = cip;
-                // Do some other work!
-                std::thread::sleep(Duration::from_millis(5));
-            }
-            Ok(IotConnState::Connected(client)) => {
-                println!("Connected!");
-                return client;
-            }
-            Ok(IotConnState::ConnectFailed(rc)) => panic!("oh no! {:?}", rc),
-            Err(e) => panic!("Failed connecting! {:?}", e),
-        }
+        // send and receive messages, then wait for more
+        iot_client.run_once();
     }
 }
 
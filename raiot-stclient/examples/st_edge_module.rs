@@ -0,0 +1,46 @@
+//! An IoT Edge module forwarding the messages routed to its inputs to its `output1` output.
+//!
+//! cargo run -p raiot-stclient --example st_edge_module --
+//!     -h <hub>.azure-devices.net -d <device> --module <module> -k <key>
+//!
+//! Within the Edge runtime, connect through the edge hub with `--gateway-hostname` and `--trusted-ca-file`.
+
+use std::sync::mpsc::channel;
+
+use raiot_cli::Options;
+use raiot_client_base::D2CMsg;
+use raiot_protocol::c2d::ModuleInputMsg;
+use serde_json::Value;
+
+mod common;
+
+fn main() -> ! {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    if options.module_id.is_none() {
+        panic!("Must provide the module ID (--module)");
+    }
+    let mut iot_client = common::connect(options.get_connection_settings());
+
+    let (tx, rx) = channel();
+    iot_client
+        .sub_inputs_with_error_handler(
+            None,
+            Box::new(move |msg: ModuleInputMsg| tx.send(msg).unwrap()),
+            Box::new(|err| println!("Inputs subscription error: {}", err)),
+        )
+        .unwrap();
+
+    loop {
+        for msg in rx.try_iter() {
+            println!("Input: {}", msg);
+            let content = msg.body.as_deref().map(|body| {
+                serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.to_owned()))
+            });
+            iot_client.send_output("output1", D2CMsg { headers: None, content }, None);
+        }
+
+        // send and receive messages, then wait for more
+        iot_client.run_once();
+    }
+}
@@ -0,0 +1,45 @@
+//! Responds to direct methods: `echo` returns its payload, any other method fails with 404.
+//!
+//! cargo run -p raiot-stclient --example st_methods -- -h <hub>.azure-devices.net -d <device> -k <key>
+
+use std::sync::mpsc::channel;
+
+use raiot_cli::Options;
+use raiot_client_base::DMIResult;
+use raiot_protocol::direct_methods::DirectMethodReq;
+use raiot_stclient::MethodsCapable;
+use serde_json::json;
+
+mod common;
+
+fn main() -> ! {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    let mut iot_client = common::connect(options.get_connection_settings());
+
+    // The handler can't borrow the client, so the invocations are answered from the loop below
+    let (tx, rx) = channel();
+    iot_client
+        .sub_dmi(None, Box::new(move |req: DirectMethodReq| tx.send(req).unwrap()))
+        .unwrap();
+
+    loop {
+        for req in rx.try_iter() {
+            println!("DMI: {}", req);
+            let res = match req.method_name.as_str() {
+                "echo" => DMIResult {
+                    status: 200,
+                    payload: req.body,
+                },
+                _ => DMIResult {
+                    status: 404,
+                    payload: Some(json!({ "error": format!("Unknown method {}", req.method_name) })),
+                },
+            };
+            iot_client.send_dmi_res(&req.request_id, res, None);
+        }
+
+        // send and receive messages, then wait for more
+        iot_client.run_once();
+    }
+}
@@ -0,0 +1,38 @@
+//! Sends a reading every few seconds, and prints the acknowledgement of each one.
+//!
+//! cargo run -p raiot-stclient --example st_telemetry -- -h <hub>.azure-devices.net -d <device> -k <key>
+
+use std::time::{Duration, Instant};
+
+use raiot_cli::Options;
+use raiot_client_base::D2CMsg;
+use raiot_protocol::qos::DeliveryGuarantees;
+use serde_json::json;
+
+mod common;
+
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+fn main() -> ! {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    let mut iot_client = common::connect(options.get_connection_settings());
+    iot_client.on_delivery_receipt(Box::new(|receipt| println!("Acknowledged: {:?}", receipt)));
+
+    let mut reading = 0u64;
+    let mut last_telemetry_time = Instant::now() - TELEMETRY_INTERVAL;
+    loop {
+        if last_telemetry_time.elapsed() >= TELEMETRY_INTERVAL {
+            reading += 1;
+            let msg = D2CMsg {
+                headers: None,
+                content: Some(json!({ "reading": reading, "temperature": 20 + reading % 5 })),
+            };
+            iot_client.send_d2c(msg, Some(DeliveryGuarantees::AtLeastOnce));
+            last_telemetry_time = Instant::now();
+        }
+
+        // send and receive messages, then wait for more
+        iot_client.run_once();
+    }
+}
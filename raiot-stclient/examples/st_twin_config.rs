@@ -0,0 +1,54 @@
+//! Sends telemetry at the interval set by the `telemetryInterval` desired property, in seconds.
+//!
+//! cargo run -p raiot-stclient --example st_twin_config -- -h <hub>.azure-devices.net -d <device> -k <key>
+
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use raiot_cli::Options;
+use raiot_client_base::D2CMsg;
+use raiot_protocol::twin::DesiredPropsUpdated;
+use raiot_stclient::TwinCapable;
+use serde_json::json;
+
+mod common;
+
+fn main() -> ! {
+    env_logger::init();
+    let options = Options::from_cmd_line();
+    let mut iot_client = common::connect(options.get_connection_settings());
+
+    let interval = Rc::new(Cell::new(Duration::from_secs(10)));
+    let handler_interval = interval.clone();
+    iot_client
+        .sub_twin_updates(
+            None,
+            Box::new(move |update: DesiredPropsUpdated| {
+                if let Some(secs) = update.body.get("telemetryInterval").and_then(|secs| secs.as_u64()) {
+                    println!("Telemetry interval set to {}s (version {})", secs, update.desired_properties_version);
+                    handler_interval.set(Duration::from_secs(secs.max(1)));
+                }
+            }),
+        )
+        .unwrap();
+    // The response is logged by the client; the interval follows the updates from here on
+    iot_client.read_twin();
+
+    let mut last_telemetry_time = Instant::now();
+    loop {
+        if last_telemetry_time.elapsed() >= interval.get() {
+            let msg = D2CMsg {
+                headers: None,
+                content: Some(json!({ "interval": interval.get().as_secs() })),
+            };
+            iot_client.send_d2c(msg, None);
+            last_telemetry_time = Instant::now();
+        }
+
+        // send and receive messages, then wait for more
+        iot_client.run_once();
+    }
+}
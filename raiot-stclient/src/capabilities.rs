@@ -0,0 +1,220 @@
+//! The optional IoT Hub features of `IotClient`, each available with its cargo feature,
+//! so that a telemetry-only build carries none of the others.
+//! `IotClient` handles the responses as it processes its connection: its requests return nothing.
+
+#[cfg(feature = "c2d")]
+pub use raiot_client_base::capabilities::C2DCapable;
+pub use raiot_client_base::capabilities::CapabilityError;
+#[cfg(feature = "direct-methods")]
+pub use raiot_client_base::capabilities::MethodsCapable;
+#[cfg(feature = "twin")]
+pub use raiot_client_base::capabilities::TwinCapable;
+
+use crate::sub::SubState;
+use crate::IotClient;
+use raiot_errors::TransportError;
+use raiot_protocol::qos::DeliveryGuarantees;
+use raiot_protocol::SubscriptionKind;
+
+#[cfg(feature = "c2d")]
+use crate::sub::SubErrorHandler;
+#[cfg(feature = "c2d")]
+use crate::{C2DExpiredHandler, C2DHandler, InputHandler};
+#[cfg(feature = "c2d")]
+use raiot_protocol::{ActiveSubscription, ClientIdentity, IotCodec};
+
+#[cfg(feature = "direct-methods")]
+use crate::DMIHandler;
+#[cfg(feature = "direct-methods")]
+use raiot_client_base::DMIResult;
+#[cfg(feature = "direct-methods")]
+use raiot_protocol::direct_methods::DirectMethodsSub;
+#[cfg(feature = "direct-methods")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "twin")]
+use crate::TwinUpdatesHandler;
+#[cfg(feature = "twin")]
+use raiot_protocol::twin::TwinUpdatesSub;
+
+#[cfg(feature = "twin")]
+impl TwinCapable for IotClient {
+    type TwinUpdatesHandler = TwinUpdatesHandler;
+    type TwinRead<'a> = ();
+    type Unsubscribed<'a> = ();
+
+    /// The response is received by `process` once the subscription is acknowledged
+    fn read_twin(&mut self) {
+        match self.twin_read {
+            SubState::Subscribed(_) => self.request_twin(),
+            SubState::Unsubscribed => self.sub_twin_reads(),
+            SubState::Subscribing(_, _, _) => {}
+        }
+    }
+
+    fn sub_twin_updates(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<TwinUpdatesHandler>,
+    ) -> Result<(), CapabilityError> {
+        let packet_id = self.packets_numerator.next_id();
+        let mode = mode.unwrap_or(self.qos.twin);
+        let msg = TwinUpdatesSub { packet_id, mode };
+        let msg = self.encode_subscription(msg.into());
+        self.connection.write(&msg).map_err(TransportError::from)?;
+        self.twin_updates = SubState::Subscribing(
+            handler,
+            Box::new(|e| println!("Twin updates sub error: {}", e)),
+            packet_id,
+        );
+        Ok(())
+    }
+
+    fn unsub_twin_updates(&mut self) {
+        self.twin_updates = SubState::Unsubscribed;
+        self.unsubscribe(SubscriptionKind::TwinUpdates);
+    }
+}
+
+#[cfg(feature = "direct-methods")]
+impl MethodsCapable for IotClient {
+    type DMIHandler = DMIHandler;
+    type Unsubscribed<'a> = ();
+
+    /// Subscribes again with the requested delivery guarantees, which the hub applies to the subscription
+    fn sub_dmi(&mut self, mode: Option<DeliveryGuarantees>, handler: Box<DMIHandler>) -> Result<(), CapabilityError> {
+        let packet_id = self.packets_numerator.next_id();
+        let mode = mode.unwrap_or(self.qos.methods);
+        let msg = DirectMethodsSub { mode, packet_id };
+        let msg = self.encode_subscription(msg.into());
+        self.connection.write(&msg).map_err(TransportError::from)?;
+        self.dmi = SubState::Subscribing(handler, Box::new(|e| println!("DMI Sub Error: {}", e)), packet_id);
+        Ok(())
+    }
+
+    fn unsub_dmi(&mut self) {
+        self.dmi = SubState::Unsubscribed;
+        self.unsubscribe(SubscriptionKind::DirectMethods);
+    }
+
+    fn set_dmi_response_window(&mut self, window: Duration) {
+        self.dmi_response_window = window;
+    }
+}
+
+#[cfg(feature = "direct-methods")]
+impl IotClient {
+    /// Responds to a direct method invocation. A mode of None uses the default methods delivery guarantees.
+    pub fn send_dmi_res(&mut self, request_id: &str, res: DMIResult, mode: Option<DeliveryGuarantees>) {
+        if self.dmi_deadlines.remove(request_id).is_none() {
+            warn!("Not responding to direct method {}: already answered with a timeout", request_id);
+            return;
+        }
+        self.write_dmi_res(request_id, res, mode);
+    }
+
+    /// The time left to respond to the specified direct method invocation, if it is still pending
+    pub fn dmi_remaining_budget(&self, request_id: &str) -> Option<Duration> {
+        self.dmi_deadlines
+            .get(request_id)
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(feature = "c2d")]
+impl IotClient {
+    /// Subscribes to C2D messages like `sub_c2d`, passing a failed subscription to the error handler
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotADevice` on a module,
+    /// with `CapabilityError::Transport` if the subscription could not be written
+    pub fn sub_c2d_with_error_handler(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        msg_handler: Box<C2DHandler>,
+        error_handler: Box<SubErrorHandler>,
+    ) -> Result<(), CapabilityError> {
+        let device_id = match &self.client_id {
+            ClientIdentity::Module(_) => return Err(CapabilityError::NotADevice),
+            ClientIdentity::Device(x) => x,
+        };
+
+        let packet_id = self.packets_numerator.next_id();
+
+        let msg = IotCodec::device_subscriptions(device_id).c2d(packet_id, mode.unwrap_or(self.qos.c2d));
+        let msg = self.encode_subscription(msg.into());
+        self.connection.write(&msg).map_err(TransportError::from)?;
+        self.c2d = SubState::Subscribing(msg_handler, error_handler, packet_id);
+        Ok(())
+    }
+
+    /// Subscribes to the messages routed to the inputs of the module like `sub_inputs`,
+    /// passing a failed subscription to the error handler
+    ///
+    /// # Errors
+    /// Fails with `CapabilityError::NotAModule` on a device,
+    /// with `CapabilityError::Transport` if the subscription could not be written
+    pub fn sub_inputs_with_error_handler(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        msg_handler: Box<InputHandler>,
+        error_handler: Box<SubErrorHandler>,
+    ) -> Result<(), CapabilityError> {
+        let module = match &self.client_id {
+            ClientIdentity::Module(x) => x.clone(),
+            ClientIdentity::Device(_) => return Err(CapabilityError::NotAModule),
+        };
+
+        let packet_id = self.packets_numerator.next_id();
+        let mode = mode.unwrap_or(self.qos.c2d);
+
+        let msg = IotCodec::module_subscriptions(&module).inputs(packet_id, mode);
+        let msg = self.encode_subscription(msg.into());
+        self.connection.write(&msg).map_err(TransportError::from)?;
+        self.pending_subscriptions.insert(packet_id, ActiveSubscription::module_inputs(&module, mode));
+        self.inputs = SubState::Subscribing(msg_handler, error_handler, packet_id);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "c2d")]
+impl C2DCapable for IotClient {
+    type C2DHandler = C2DHandler;
+    type C2DExpiredHandler = C2DExpiredHandler;
+    type InputHandler = InputHandler;
+    type Unsubscribed<'a> = ();
+
+    fn sub_c2d(&mut self, mode: Option<DeliveryGuarantees>, handler: Box<C2DHandler>) -> Result<(), CapabilityError> {
+        self.sub_c2d_with_error_handler(mode, handler, Box::new(|e| warn!("C2D sub error: {}", e)))
+    }
+
+    fn set_c2d_expired_handler(&mut self, handler: Box<C2DExpiredHandler>) {
+        self.c2d_expired_handler = Some(handler);
+    }
+
+    fn unsub_c2d(&mut self) -> Result<(), CapabilityError> {
+        if let ClientIdentity::Module(_) = &self.client_id {
+            return Err(CapabilityError::NotADevice);
+        }
+        self.c2d = SubState::Unsubscribed;
+        self.unsubscribe(SubscriptionKind::CloudToDevice);
+        Ok(())
+    }
+
+    fn sub_inputs(
+        &mut self,
+        mode: Option<DeliveryGuarantees>,
+        handler: Box<InputHandler>,
+    ) -> Result<(), CapabilityError> {
+        self.sub_inputs_with_error_handler(mode, handler, Box::new(|e| warn!("Inputs sub error: {}", e)))
+    }
+
+    fn unsub_inputs(&mut self) -> Result<(), CapabilityError> {
+        if let ClientIdentity::Device(_) = &self.client_id {
+            return Err(CapabilityError::NotAModule);
+        }
+        self.inputs = SubState::Unsubscribed;
+        self.unsubscribe(SubscriptionKind::ModuleInputs);
+        Ok(())
+    }
+}
@@ -1,85 +1,188 @@
-use std::{io::ErrorKind, time::Instant};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
-use mqtt::{control::ConnectReturnCode, packet::VariablePacket};
-use raiot_client_base::{generate_sas_token, ConnectionSettings, PacketsNumerator};
+use mqtt::packet::VariablePacket;
+use raiot_client_base::{
+    generate_sas_token, ClockSync, ConnectionSettings, DiagnosticSampler, PacketsNumerator,
+    RateLimiter, RequestIdSource, TelemetryQuota, ThrottleDetector, ThrottleQueue,
+};
+use raiot_client_base::scheduler::SleepScheduler;
+#[cfg(feature = "direct-methods")]
+use raiot_client_base::DEFAULT_DMI_RESPONSE_WINDOW;
+use raiot_errors::{ClientError, ProtocolError, TransportError};
 use raiot_mqtt::connection::{MqttConnectError, MqttConnectionInProgress, MqttConnector};
-use raiot_protocol::{auth::DeviceCredentials, connect::ConnectMsg, ClientIdentity, IotCodec};
-use raiot_streams::{open_nonblocking_stream, ClientCertificate};
+use raiot_mqtt::session::MqttSession;
+use raiot_protocol::{
+    auth::sas::SasToken, auth::DeviceCredentials, connect::Capabilities, connect::ConnectError, connect::ConnectMsg,
+    connect::ConnectSuccess, qos::ExactlyOnceHandshakes, qos::QosDefaults, twin::TwinCorrelation, ClientIdentity,
+    topics, CodecError, CodecOptions, IotCodec, SubscriptionSnapshot, SubscriptionTracker,
+};
+use raiot_streams::open_nonblocking_stream_with_cancel;
+pub use raiot_streams::CancelToken;
 
-use crate::{sub::SubState, IotClient, MyStream};
+#[cfg(any(feature = "c2d", feature = "direct-methods", feature = "twin"))]
+use crate::sub::SubState;
+use crate::{IotClient, MyStream};
 
+/// The outcome of progressing a connection, see `IotConnectionInProgress::complete`
 pub enum IotConnState {
-    Connected(IotClient),
-    Connecting(IotConnectionInProgress),
-    ConnectFailed(ConnectReturnCode), // TODO encapsulate
+    /// The client is boxed: it is about 1.5KB, which every state would otherwise take up,
+    /// and be moved around for every attempt to complete the connection
+    Connected(Box<IotClient>),
+    /// Boxed for the same reason, if to a lesser extent
+    Connecting(Box<IotConnectionInProgress>),
+    ConnectFailed(ConnectError),
 }
 
 pub struct IotConnectionInProgress {
     connection: MqttConnectionInProgress<MyStream>,
     client_id: ClientIdentity,
+    qos: QosDefaults,
+    token_expiry: Option<SystemTime>,
+    clock: Option<Arc<ClockSync>>,
+    validate_topics: bool,
+    telemetry_quota: Option<TelemetryQuota>,
+    codec: CodecOptions,
+    cancel: CancelToken,
 }
 
 impl IotConnectionInProgress {
-    pub fn complete(self) -> std::io::Result<IotConnState> {
+    /// Progresses the MQTT connection.
+    /// Fails with `TransportError::Cancelled` once the token the connection was opened with is cancelled.
+    pub fn complete(self) -> Result<IotConnState, ClientError> {
+        if self.cancel.is_cancelled() {
+            return Err(TransportError::Cancelled.into());
+        }
         match self.connection.complete() {
-            Ok(connection) => Ok(IotConnState::Connected(IotClient {
-                connection,
+            Ok(connection) => Ok(IotConnState::Connected(Box::new(IotClient {
+                capabilities: Capabilities::negotiated(&ConnectSuccess {
+                    session_present: connection.session_present(),
+                }),
+                connection: MqttSession::new(connection).with_dependencies(topics::response_filter),
                 client_id: self.client_id,
+                qos: self.qos,
                 packets_numerator: PacketsNumerator::new(),
+                diagnostics: DiagnosticSampler::new(0),
+                enrichers: Vec::new(),
+                interceptors: Vec::new(),
+                sequencer: None,
+                sequences_in_flight: HashMap::new(),
+                outbox: None,
+                receipts_handler: None,
+                #[cfg(feature = "c2d")]
+                c2d_expired_handler: None,
+                subscriptions: SubscriptionTracker::new(),
+                pending_subscriptions: HashMap::new(),
+                active_subscriptions: SubscriptionSnapshot::new(),
+                twin_requests: TwinCorrelation::new(),
+                throttle: ThrottleDetector::default(),
+                held: ThrottleQueue::new(),
+                exactly_once: ExactlyOnceHandshakes::default(),
+                dedup: None,
+                #[cfg(feature = "direct-methods")]
+                dmi_response_window: DEFAULT_DMI_RESPONSE_WINDOW,
+                #[cfg(feature = "direct-methods")]
+                dmi_deadlines: HashMap::new(),
+                request_ids: RequestIdSource::default(),
+                serializer: None,
+                audit_sink: None,
+                cipher: None,
+                token_expiry: self.token_expiry,
+                clock: self.clock,
+                validate_topics: self.validate_topics,
+                codec: self.codec,
+                rate_limiter: self.telemetry_quota.map(|quota| RateLimiter::new(quota, Instant::now())),
+                telemetry_delayed: 0,
+                telemetry_rejected: 0,
+                disconnect_reason: None,
+                disconnect_handler: None,
+                scheduler: Box::new(SleepScheduler::default()),
+                #[cfg(feature = "raw-mqtt")]
+                raw_packet_handler: None,
+                #[cfg(feature = "twin")]
                 twin_read: SubState::Unsubscribed,
+                #[cfg(feature = "direct-methods")]
                 dmi: SubState::Unsubscribed,
+                #[cfg(feature = "twin")]
                 twin_updates: SubState::Unsubscribed,
+                #[cfg(feature = "c2d")]
                 c2d: SubState::Unsubscribed,
-            })),
-            Err(MqttConnectError::IOError(kind)) => Err(kind.into()),
-            Err(MqttConnectError::WouldBlock(connection)) => {
-                Ok(IotConnState::Connecting(IotConnectionInProgress {
-                    connection,
+                #[cfg(feature = "c2d")]
+                inputs: SubState::Unsubscribed,
+            }))),
+            Err(MqttConnectError::IOError(kind)) => Err(TransportError::from(kind).into()),
+            Err(MqttConnectError::WouldBlock(connection)) => Ok(IotConnState::Connecting(
+                Box::new(IotConnectionInProgress {
+                    connection: *connection,
                     client_id: self.client_id,
-                }))
-            }
-            Err(MqttConnectError::ConnectFailed(rc)) => Ok(IotConnState::ConnectFailed(rc)),
-            Err(MqttConnectError::ProtocolViolation) => Err(ErrorKind::InvalidData.into()),
+                    qos: self.qos,
+                    token_expiry: self.token_expiry,
+                    clock: self.clock,
+                    validate_topics: self.validate_topics,
+                    telemetry_quota: self.telemetry_quota,
+                    codec: self.codec,
+                    cancel: self.cancel,
+                }),
+            )),
+            Err(MqttConnectError::ConnectFailed(rc)) => Ok(IotConnState::ConnectFailed(
+                IotCodec::decode_connect_return_code(rc, false)
+                    .err()
+                    .unwrap_or(ConnectError::ProtocolViolation),
+            )),
+            Err(MqttConnectError::ProtocolViolation) => Err(ProtocolError::Violation.into()),
         }
     }
 }
 
 impl IotClient {
-    pub fn connect(settings: &ConnectionSettings) -> std::io::Result<IotConnectionInProgress> {
-        let now = Instant::now();
+    pub fn connect(settings: &ConnectionSettings) -> Result<IotConnectionInProgress, ClientError> {
+        IotClient::connect_with_cancel(settings, &CancelToken::new())
+    }
 
-        let client_certificate = match settings.credentials {
-            DeviceCredentials::Certificate(ref cert) => Some(ClientCertificate {
-                bytes: cert.bytes.clone(),
-                password: cert.password.clone(),
-            }),
-            DeviceCredentials::Sas(_) => None,
-        };
+    /// Connects like `connect`, aborting the TCP, TLS or MQTT connection attempt with `TransportError::Cancelled`
+    /// as soon as the token is cancelled (e.g. by an application shutting down)
+    pub fn connect_with_cancel(
+        settings: &ConnectionSettings,
+        cancel: &CancelToken,
+    ) -> Result<IotConnectionInProgress, ClientError> {
+        let now = Instant::now();
 
-        let stream = open_nonblocking_stream(
-            &settings.hostname,
+        let stream = open_nonblocking_stream_with_cancel(
+            settings.transport_hostname(),
             settings.port.into(),
             settings.timeout,
-            client_certificate.as_ref(),
-        )?
+            &settings.tls_settings(),
+            settings.proxy.as_ref(),
+            cancel,
+            settings.handshake_poll,
+        )
+        .map_err(|e| match cancel.is_cancelled() {
+            true => TransportError::Cancelled,
+            false => e.into(),
+        })?
         .inner();
 
         let token = match settings.credentials {
-            DeviceCredentials::Sas(ref key) => Some(generate_sas_token(settings, key).into()),
+            DeviceCredentials::Sas(ref key) => Some(generate_sas_token(settings, key)),
             DeviceCredentials::Certificate(_) => None,
         };
+        let token_expiry = token.as_ref().map(SasToken::expiry);
+        let token = token.map(String::from);
 
         let conn = ConnectMsg {
             client_id: settings.client_id.clone(),
             server_addr: settings.hostname.clone(),
             sas_token: token,
             session_mode: settings.session_mode,
+            client_id_override: settings.client_id_override.clone(),
         };
 
-        let connpack = IotCodec::encode_message(&conn.into()).unwrap();
-        let connpack = match connpack {
+        let connpack = match IotCodec::encode_message(&conn.into())? {
             VariablePacket::ConnectPacket(p) => p,
-            _ => panic!("wat"),
+            _ => return Err(ProtocolError::Codec(CodecError::UnexpectedMqttPacketType).into()),
         };
 
         let connection = MqttConnector::create(stream)
@@ -89,6 +192,13 @@ impl IotClient {
         Ok(IotConnectionInProgress {
             connection,
             client_id: settings.client_id.clone(),
+            qos: settings.qos,
+            token_expiry,
+            clock: settings.clock.clone(),
+            validate_topics: settings.validate_topics,
+            telemetry_quota: settings.telemetry_quota,
+            codec: settings.codec,
+            cancel: cancel.clone(),
         })
     }
 }
@@ -1,38 +1,134 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(any(feature = "c2d", feature = "direct-methods", feature = "twin"))]
+pub mod capabilities;
 pub mod conn;
+#[cfg(any(feature = "c2d", feature = "direct-methods", feature = "twin"))]
 mod sub;
 
-use raiot_client_base::{D2CMsg, DMIResult, PacketsNumerator};
+#[cfg(feature = "c2d")]
+pub use capabilities::C2DCapable;
+#[cfg(feature = "direct-methods")]
+pub use capabilities::MethodsCapable;
+#[cfg(feature = "twin")]
+pub use capabilities::TwinCapable;
+#[cfg(any(feature = "c2d", feature = "direct-methods", feature = "twin"))]
+pub use capabilities::CapabilityError;
+
+use raiot_client_base::audit::{audit_inbound, audit_outbound, AuditOutcome, AuditSink};
+use raiot_client_base::outbox::{Outbox, OutboxDrainer, OutboxError};
+use raiot_client_base::scheduler::{earliest, Scheduler};
+use raiot_errors::ClientError;
+use raiot_client_base::{
+    D2CMsg, DedupConfig, DiagnosticSampler, DisconnectReason, MessageDeduplicator,
+    PacketsNumerator, QuotaPolicy, RateLimiter, RequestIdSource, TelemetrySequencer,
+    ThrottleDetector, ThrottleQueue, ClockSync,
+};
+#[cfg(feature = "direct-methods")]
+use raiot_client_base::{DMIResult, DMI_TIMEOUT_STATUS};
 use raiot_protocol::{
-    c2d::C2DMsg,
-    twin::{DesiredPropsUpdated, ReadTwinRes, TwinUpdatesSub},
+    c2d::{C2DMsg, ModuleInputMsg},
+    telemetry::{EdgeRouteHints, OUTPUT_NAME_PROPERTY},
+    twin::{DesiredPropsUpdated, ReadTwinRes, TwinCorrelation},
+};
+use raiot_protocol::{direct_methods::DirectMethodReq, DeliveryReceipt, MsgFromHub};
+use raiot_protocol::{CodecError, CodecOptions, MsgToHub, SubRes, SubscriptionTracker};
+use raiot_protocol::{ActiveSubscription, SubscriptionSnapshot};
+#[cfg(any(feature = "c2d", feature = "direct-methods", feature = "twin"))]
+use raiot_protocol::{SubscriptionKind, UnsubMsg};
+use raiot_protocol::redact::redact;
+#[cfg(feature = "direct-methods")]
+use raiot_protocol::direct_methods::DirectMethodRes;
+#[cfg(feature = "twin")]
+use raiot_protocol::twin::{ReadTwinReq, TwinReadSub, TwinSection};
+use raiot_protocol::chunking::split_telemetry;
+use raiot_protocol::encryption::{decrypt_publish, encrypt_publish, PayloadCipher};
+use raiot_protocol::serialization::PayloadSerializer;
+use raiot_protocol::connect::{Capabilities, Capability};
+use std::{
+    collections::HashMap,
+    net::TcpStream,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
-use raiot_protocol::{direct_methods::DirectMethodReq, MsgFromHub};
-use raiot_protocol::{direct_methods::DirectMethodRes, SubRes};
-use raiot_protocol::{direct_methods::DirectMethodsSub, twin::TwinReadSub};
-use std::{net::TcpStream, time::Duration};
-use sub::{SubErrorHandler, SubState};
+#[cfg(any(feature = "c2d", feature = "direct-methods", feature = "twin"))]
+use sub::SubState;
 
 use native_tls::TlsStream;
-use raiot_mqtt::connection::MqttConnection;
+use mqtt::packet::VariablePacket;
+use raiot_mqtt::session::MqttSession;
 use raiot_protocol::{
-    c2d::C2DSub, qos::DeliveryGuarantees,
-    telemetry::TelemetryMsg, twin::ReadTwinReq, ClientIdentity, IotCodec,
+    qos::DeliveryGuarantees, qos::ExactlyOnceHandshakes, qos::PacketId, qos::QosDefaults,
+    telemetry::TelemetryMsg, ClientIdentity, IotCodec,
 };
 
 pub type C2DHandler = dyn Fn(C2DMsg);
+pub type C2DExpiredHandler = dyn Fn(C2DMsg);
+pub type InputHandler = dyn Fn(ModuleInputMsg);
 pub type DMIHandler = dyn Fn(DirectMethodReq);
 pub type TwinUpdatesHandler = dyn Fn(DesiredPropsUpdated);
 pub type TwinReadsHandler = dyn Fn(ReadTwinRes);
+pub type TelemetryEnricher = dyn Fn(&mut TelemetryMsg);
+/// Applied to every incoming message before dispatch. Returning FALSE drops the message.
+pub type InboundInterceptor = dyn FnMut(&mut MsgFromHub) -> bool;
+pub type DeliveryReceiptHandler = dyn Fn(DeliveryReceipt);
+pub type DisconnectHandler = dyn Fn(DisconnectReason);
+#[cfg(feature = "raw-mqtt")]
+pub type RawPacketHandler = dyn FnMut(&VariablePacket);
 
 type MyStream = TlsStream<TcpStream>;
 
+/// How long the sequence number of a telemetry message awaits the message's acknowledgement.
+/// Messages acknowledged later, e.g. replayed by `resume_session`, don't advance `last_acknowledged_sequence`.
+const SEQUENCE_ACK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 pub struct IotClient {
-    connection: MqttConnection<MyStream>,
+    connection: MqttSession<MyStream>,
+    capabilities: Capabilities,
     client_id: ClientIdentity,
+    qos: QosDefaults,
     packets_numerator: PacketsNumerator,
+    diagnostics: DiagnosticSampler,
+    enrichers: Vec<Box<TelemetryEnricher>>,
+    interceptors: Vec<Box<InboundInterceptor>>,
+    sequencer: Option<TelemetrySequencer>,
+    // the sequence numbers of the telemetry awaiting an acknowledgement, and when it was sent
+    sequences_in_flight: HashMap<PacketId, (u64, Instant)>,
+    outbox: Option<OutboxDrainer>,
+    receipts_handler: Option<Box<DeliveryReceiptHandler>>,
+    #[cfg(feature = "c2d")]
+    c2d_expired_handler: Option<Box<C2DExpiredHandler>>,
+    subscriptions: SubscriptionTracker,
+    pending_subscriptions: HashMap<PacketId, ActiveSubscription>,
+    active_subscriptions: SubscriptionSnapshot,
+    twin_requests: TwinCorrelation,
+    throttle: ThrottleDetector,
+    // the outgoing messages held back while throttled, sent by `process` once the cool-down ends
+    held: ThrottleQueue<MsgToHub>,
+    exactly_once: ExactlyOnceHandshakes,
+    dedup: Option<MessageDeduplicator>,
+    #[cfg(feature = "direct-methods")]
+    dmi_response_window: Duration,
+    #[cfg(feature = "direct-methods")]
+    dmi_deadlines: HashMap<String, Instant>,
+    request_ids: RequestIdSource,
+    serializer: Option<Arc<dyn PayloadSerializer>>,
+    audit_sink: Option<Box<dyn AuditSink>>,
+    cipher: Option<Arc<dyn PayloadCipher>>,
+    token_expiry: Option<SystemTime>,
+    clock: Option<Arc<ClockSync>>,
+    validate_topics: bool,
+    codec: CodecOptions,
+    rate_limiter: Option<RateLimiter>,
+    telemetry_delayed: u64,
+    telemetry_rejected: u64,
+    disconnect_reason: Option<DisconnectReason>,
+    disconnect_handler: Option<Box<DisconnectHandler>>,
+    scheduler: Box<dyn Scheduler>,
+    #[cfg(feature = "raw-mqtt")]
+    raw_packet_handler: Option<Box<RawPacketHandler>>,
     #[cfg(feature = "twin")]
     twin_read: SubState<ReadTwinRes>,
     #[cfg(feature = "direct-methods")]
@@ -41,105 +137,446 @@ pub struct IotClient {
     twin_updates: SubState<DesiredPropsUpdated>,
     #[cfg(feature = "c2d")]
     c2d: SubState<C2DMsg>,
+    #[cfg(feature = "c2d")]
+    inputs: SubState<ModuleInputMsg>,
 }
 
 impl IotClient {
-    pub fn send_d2c(&mut self, msg: D2CMsg, mode: DeliveryGuarantees) {
-        let msg = TelemetryMsg {
+    /// Sets the percentage of telemetry messages sampled for distributed tracing
+    pub fn set_diagnostic_sampling_percentage(&mut self, percentage: u8) {
+        self.diagnostics = DiagnosticSampler::new(percentage);
+    }
+
+    /// Registers an interceptor applied, in registration order, to every incoming message
+    pub fn add_inbound_interceptor(&mut self, interceptor: Box<InboundInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Sets a handler invoked for every acknowledgement (PUBACK, SUBACK) received from the hub
+    pub fn on_delivery_receipt(&mut self, handler: Box<DeliveryReceiptHandler>) {
+        self.receipts_handler = Some(handler);
+    }
+
+    /// Registers a hook applied, in registration order, to every outgoing telemetry message
+    pub fn add_telemetry_enricher(&mut self, enricher: Box<TelemetryEnricher>) {
+        self.enrichers.push(enricher);
+    }
+
+    /// Stamps a monotonic sequence number into every outgoing telemetry message
+    pub fn enable_sequence_numbers(&mut self) {
+        if self.sequencer.is_none() {
+            self.sequencer = Some(TelemetrySequencer::new());
+        }
+    }
+
+    /// Suppresses C2D messages and direct method invocations delivered again, e.g. across reconnects
+    pub fn enable_deduplication(&mut self, config: DedupConfig) {
+        self.dedup = Some(MessageDeduplicator::new(config));
+    }
+
+    /// Sets the serializer of telemetry and direct method response payloads. Defaults to JSON.
+    pub fn set_payload_serializer(&mut self, serializer: Arc<dyn PayloadSerializer>) {
+        self.serializer = Some(serializer);
+    }
+
+    /// Sets a handler invoked once the connection to the hub is lost, with the inferred reason
+    pub fn on_disconnected(&mut self, handler: Box<DisconnectHandler>) {
+        self.disconnect_handler = Some(handler);
+    }
+
+    /// Sends an MQTT packet as is, for hub features not covered by the typed API.
+    /// QoS 1 publications are replayed when resuming the session, like telemetry.
+    #[cfg(feature = "raw-mqtt")]
+    pub fn send_raw_packet(&mut self, packet: VariablePacket) {
+        self.connection.write(&packet).unwrap();
+    }
+
+    /// Sets a handler invoked with every MQTT packet read from the hub, before it is decoded
+    #[cfg(feature = "raw-mqtt")]
+    pub fn on_raw_packet(&mut self, handler: Box<RawPacketHandler>) {
+        self.raw_packet_handler = Some(handler);
+    }
+
+    /// The reason the connection was lost, if it was. A disconnected client no longer sends or receives.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason
+    }
+
+    /// Ends the connection gracefully: sends everything written so far followed by an MQTT DISCONNECT,
+    /// then closes the stream. The disconnection handler is invoked with `DisconnectReason::Requested`.
+    /// Publications not acknowledged by then are lost.
+    ///
+    /// # Errors
+    /// Fails if the stream failed, or could not take the pending data within the alloted time;
+    /// the stream is closed either way
+    pub fn disconnect(mut self, timeout: Duration) -> Result<(), ClientError> {
+        if self.disconnect_reason.is_some() {
+            return Ok(());
+        }
+        let msg = MsgToHub::Disconnect;
+        self.connection.write(&IotCodec::encode_message(&msg)?)?;
+        let result = self.connection.flush(timeout);
+        let outcome = match &result {
+            Ok(()) => AuditOutcome::Sent,
+            Err(e) => AuditOutcome::Failed(e.to_string()),
+        };
+        audit_outbound(self.audit_sink.as_deref(), &msg, outcome);
+        self.disconnect_reason = Some(DisconnectReason::Requested);
+        if let Some(handler) = &self.disconnect_handler {
+            handler(DisconnectReason::Requested);
+        }
+        Ok(result?)
+    }
+
+    /// Sets a sink recording every telemetry, twin, direct method and C2D message sent or received
+    pub fn set_audit_sink(&mut self, sink: Box<dyn AuditSink>) {
+        self.audit_sink = Some(sink);
+    }
+
+    /// Sets a cipher encrypting outgoing telemetry payloads and decrypting incoming C2D payloads which advertise a key
+    pub fn set_payload_cipher(&mut self, cipher: Arc<dyn PayloadCipher>) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Sets the strategy used to generate twin request identifiers. Defaults to UUIDs.
+    pub fn set_request_id_source(&mut self, source: RequestIdSource) {
+        self.request_ids = source;
+    }
+
+    /// Makes packet and request IDs deterministic, so that tests comparing encoded packets are stable:
+    /// packet IDs follow `packet_id`, and request IDs count up from `request_id`
+    #[cfg(any(test, feature = "test-hooks"))]
+    pub fn seed_ids(&mut self, packet_id: u16, request_id: u64) {
+        self.packets_numerator = PacketsNumerator::starting_after(packet_id);
+        self.request_ids = RequestIdSource::Counter(request_id);
+    }
+
+    /// The time left until the hub's throttling cool-down ends, if throttled.
+    /// Messages other than acknowledgements are held until then, and sent by `process` once it ended.
+    pub fn throttled_for(&self) -> Option<Duration> {
+        self.throttle.remaining_cooldown(Instant::now())
+    }
+
+    /// The highest sequence number acknowledged by the hub (QoS1 messages only), if sequence numbers are enabled
+    pub fn last_acknowledged_sequence(&self) -> Option<u64> {
+        self.sequencer.as_ref().and_then(|s| s.last_acknowledged())
+    }
+
+    /// Number of telemetry messages delayed because the telemetry quota was exhausted
+    pub fn telemetry_delayed(&self) -> u64 {
+        self.telemetry_delayed
+    }
+
+    /// Number of telemetry messages dropped because the telemetry quota was exhausted
+    pub fn telemetry_rejected(&self) -> u64 {
+        self.telemetry_rejected
+    }
+
+    /// Sends a telemetry message. A mode of None uses the default telemetry delivery guarantees.
+    /// Once the telemetry quota is exhausted, blocks until the budget allows the message (`QuotaPolicy::Delay`)
+    /// or drops it (`QuotaPolicy::Reject`).
+    pub fn send_d2c(&mut self, msg: D2CMsg, mode: Option<DeliveryGuarantees>) {
+        if !self.admit_telemetry() {
+            warn!("Telemetry quota exhausted, dropping a message");
+            return;
+        }
+        let (msg, sequence_number) = self.prepare_telemetry(msg);
+        self.write_telemetry(msg, mode, sequence_number);
+    }
+
+    /// Sends a telemetry message, split into correlated chunks of at most `max_chunk_size` payload bytes
+    /// if it is larger (see `split_telemetry`). Every chunk counts against the telemetry quota.
+    pub fn send_d2c_chunked(&mut self, msg: D2CMsg, mode: Option<DeliveryGuarantees>, max_chunk_size: usize) {
+        let (msg, sequence_number) = self.prepare_telemetry(msg);
+        let transfer_id = self.request_ids.next_id();
+        let chunks = split_telemetry(msg, max_chunk_size, &transfer_id);
+        let last = chunks.len() - 1;
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            if !self.admit_telemetry() {
+                warn!("Telemetry quota exhausted, dropping the rest of transfer {}", transfer_id);
+                return;
+            }
+            // the sequence number is acknowledged along with the last chunk
+            let sequence_number = if index == last { sequence_number } else { None };
+            self.write_telemetry(chunk, mode, sequence_number);
+        }
+    }
+
+    /// Sets the outbox drained by `drain_outbox`, replacing (and dropping) the current one
+    pub fn set_outbox(&mut self, outbox: Box<dyn Outbox>) {
+        self.outbox = Some(OutboxDrainer::new(outbox, OutboxDrainer::DEFAULT_BATCH_SIZE));
+    }
+
+    /// Takes the outbox back, e.g. to set it on a new connection.
+    /// Entries sent and not acknowledged yet stay in the outbox, and are sent again.
+    pub fn take_outbox(&mut self) -> Option<Box<dyn Outbox>> {
+        self.outbox.take().map(OutboxDrainer::into_outbox)
+    }
+
+    /// Sends the next entries of the outbox, as telemetry delivered at least once; the hub's acknowledgements
+    /// remove them from the outbox as they arrive. Entries already in flight are not sent again.
+    /// Call whenever the application enqueued entries, and periodically while processing messages.
+    /// Returns the number of entries sent, which stops short of the batch once the telemetry quota is exhausted.
+    ///
+    /// # Errors
+    /// Fails if the outbox failed to list its pending entries
+    pub fn drain_outbox(&mut self) -> Result<usize, OutboxError> {
+        let entries = match self.outbox.as_mut() {
+            Some(drainer) => drainer.next_batch()?,
+            None => return Ok(0),
+        };
+        // the acknowledgements are what removes the entries
+        let mode = match self.qos.telemetry {
+            DeliveryGuarantees::AtMostOnce => DeliveryGuarantees::AtLeastOnce,
+            mode => mode,
+        };
+        let mut sent = 0;
+        for entry in entries {
+            if !self.admit_telemetry() {
+                debug!("Telemetry quota exhausted, holding {} in the outbox", entry.id);
+                break;
+            }
+            let msg = D2CMsg {
+                content: entry.content.clone(),
+                headers: Some(entry.message_headers()),
+            };
+            let (msg, sequence_number) = self.prepare_telemetry(msg);
+            let packet_id = self.write_telemetry(msg, Some(mode), sequence_number);
+            if let (Some(drainer), Some(packet_id)) = (self.outbox.as_mut(), packet_id) {
+                drainer.sent(&entry.id, packet_id);
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Sends a message to an output of the module, for the hub to route it.
+    /// Other than its output name, the message is sent like telemetry (see `send_d2c`).
+    pub fn send_output(&mut self, output_name: &str, mut msg: D2CMsg, mode: Option<DeliveryGuarantees>) {
+        msg.headers
+            .get_or_insert_with(HashMap::new)
+            .insert(OUTPUT_NAME_PROPERTY.to_owned(), output_name.to_owned());
+        self.send_d2c(msg, mode);
+    }
+
+    /// Sends a message to an output of the module like `send_output`, with hints for the routes of edgeHub
+    pub fn send_output_with_hints(
+        &mut self,
+        output_name: &str,
+        mut msg: D2CMsg,
+        hints: &EdgeRouteHints,
+        mode: Option<DeliveryGuarantees>,
+    ) {
+        hints.apply(msg.headers.get_or_insert_with(HashMap::new), SystemTime::now());
+        self.send_output(output_name, msg, mode);
+    }
+
+    // Returns the message without a packet ID, and its sequence number if sequence numbers are enabled
+    fn prepare_telemetry(&mut self, msg: D2CMsg) -> (TelemetryMsg, Option<u64>) {
+        let mut headers = msg.headers;
+        self.diagnostics.stamp(&mut headers);
+        let sequence_number = self.sequencer.as_mut().map(|s| s.stamp(&mut headers));
+        let mut msg = TelemetryMsg {
             client_id: self.client_id.clone(), // TODO
             content: msg.content,
-            headers: msg.headers,
-            packet_id: match mode {
-                DeliveryGuarantees::AtMostOnce => None,
-                DeliveryGuarantees::AtLeastOnce => Some(self.packets_numerator.next()),
-            },
+            headers,
+            packet_id: None,
+            exactly_once: false,
+            serializer: self.serializer.clone(),
         };
-        let msg = IotCodec::encode_message(&msg.into()).unwrap();
-        self.connection.write(&msg).unwrap();
+        for enricher in &self.enrichers {
+            enricher(&mut msg);
+        }
+        (msg, sequence_number)
     }
 
-    pub fn sub_dmi(&mut self, mode: DeliveryGuarantees, handler: Box<DMIHandler>) {
-        let packet_id = self.packets_numerator.next();
-        let msg = DirectMethodsSub { mode, packet_id };
-        let msg = IotCodec::encode_message(&msg.into()).unwrap();
-        self.dmi = SubState::Subscribing(handler, Box::new(|e| println!("DMI Sub Error: {}", e)), packet_id);
-        self.connection.write(&msg).unwrap();
+    // Returns the packet ID of the message, unless sent at most once
+    fn write_telemetry(
+        &mut self,
+        mut msg: TelemetryMsg,
+        mode: Option<DeliveryGuarantees>,
+        sequence_number: Option<u64>,
+    ) -> Option<PacketId> {
+        let mode = mode.unwrap_or(self.qos.telemetry);
+        msg.packet_id = match mode {
+            DeliveryGuarantees::AtMostOnce => None,
+            DeliveryGuarantees::AtLeastOnce | DeliveryGuarantees::ExactlyOnce => {
+                Some(self.packets_numerator.next_id())
+            }
+        };
+        msg.exactly_once = mode == DeliveryGuarantees::ExactlyOnce;
+        if let (Some(packet_id), Some(sequence_number)) = (msg.packet_id, sequence_number) {
+            self.sequences_in_flight.insert(packet_id, (sequence_number, Instant::now()));
+        }
+        let packet_id = msg.packet_id;
+        if !self.write_message(msg.into()) {
+            if let Some(packet_id) = packet_id {
+                self.sequences_in_flight.remove(&packet_id);
+            }
+            return None;
+        }
+        packet_id
+    }
+
+    // Returns FALSE if the message must be dropped
+    fn admit_telemetry(&mut self) -> bool {
+        let limiter = match self.rate_limiter.as_mut() {
+            Some(limiter) => limiter,
+            None => return true,
+        };
+        let mut delayed = false;
+        let admitted = loop {
+            match limiter.try_acquire(Instant::now()) {
+                Ok(()) => break true,
+                Err(_) if limiter.policy() == QuotaPolicy::Reject => break false,
+                Err(wait) => {
+                    delayed = true;
+                    thread::sleep(wait);
+                }
+            }
+        };
+        self.telemetry_delayed += delayed as u64;
+        self.telemetry_rejected += !admitted as u64;
+        admitted
+    }
+
+    /// What the hub agreed to when this connection was established
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Takes over the QoS1 messages sent by a previous connection of the same `SessionMode::Dirty` session
+    /// and never acknowledged, resending them with the DUP flag; their acknowledgements are then handled as usual.
+    /// Unacknowledged subscriptions are resent first, and twin requests are held until the twin responses
+    /// subscription is acknowledged.
+    /// Call right after connecting, before sending anything, since packet IDs continue from the previous connection.
+    /// Messages which don't fit the tx buffer are sent as it drains.
+    ///
+    /// # Errors
+    /// Fails with `ProtocolError::MissingCapability` without replaying anything if the hub did not resume
+    /// the session, in which case the previous connection's packet IDs and subscriptions are gone.
+    /// Fails with `TransportError::TxBuffer` if the subscriptions don't fit the tx buffer; the messages are
+    /// still taken over, and replayed by the next session resumed from this one.
+    pub fn resume_session(&mut self, previous: IotClient) -> Result<(), ClientError> {
+        self.capabilities.require(Capability::SessionResumption)?;
+        let in_flight = previous.connection.into_in_flight();
+        debug!("Replaying {} unacknowledged messages", in_flight.len());
+        self.packets_numerator = previous.packets_numerator;
+        self.sequencer = previous.sequencer;
+        self.sequences_in_flight = previous.sequences_in_flight;
+        self.outbox = previous.outbox;
+        self.scheduler = previous.scheduler;
+        self.exactly_once = previous.exactly_once;
+        self.connection.replay(in_flight)?;
+        Ok(())
     }
 
-    pub fn send_dmi_res(&mut self, request_id: &str, res: DMIResult, mode: DeliveryGuarantees) {
+    #[cfg(feature = "direct-methods")]
+    fn write_dmi_res(&mut self, request_id: &str, res: DMIResult, mode: Option<DeliveryGuarantees>) {
         let msg = DirectMethodRes {
             request_id: request_id.to_owned(),
             status: res.status,
             payload: res.payload,
-            packet_id: match mode {
+            // only telemetry is sent with QoS2
+            packet_id: match mode.unwrap_or(self.qos.methods) {
                 DeliveryGuarantees::AtMostOnce => None,
-                DeliveryGuarantees::AtLeastOnce => Some(self.packets_numerator.next()),
+                DeliveryGuarantees::AtLeastOnce | DeliveryGuarantees::ExactlyOnce => {
+                    Some(self.packets_numerator.next_id())
+                }
             },
+            serializer: self.serializer.clone(),
         };
-
-        let msg = IotCodec::encode_message(&msg.into()).unwrap();
-        self.connection.write(&msg).unwrap();
+        self.write_message(msg.into());
     }
 
-    pub fn sub_c2d(
-        &mut self,
-        mode: DeliveryGuarantees,
-        msg_handler: Box<C2DHandler>,
-        error_handler: Box<SubErrorHandler>,
-    ) {
-        let device_id = match &self.client_id {
-            ClientIdentity::Module(_) => panic!("OMG I'm a MODULE!"),
-            ClientIdentity::Device(x) => x,
-        };
-
-        let packet_id = self.packets_numerator.next();
+    #[cfg(any(feature = "c2d", feature = "direct-methods", feature = "twin"))]
+    fn unsubscribe(&mut self, kind: SubscriptionKind) {
+        self.active_subscriptions.remove(kind);
+        self.pending_subscriptions.retain(|_, subscription| subscription.kind != kind);
+        let packet_id = self.packets_numerator.next_id();
+        if let Some(msg) = UnsubMsg::for_kind(packet_id, kind, &self.client_id) {
+            let msg = IotCodec::encode_message(&msg.into()).unwrap();
+            self.connection.write(&msg).unwrap();
+        }
+    }
 
-        let msg = C2DSub {
-            packet_id,
-            device_id: device_id.clone(),
-            mode,
+    #[cfg(feature = "twin")]
+    fn request_twin(&mut self) {
+        let read_req = ReadTwinReq {
+            request_id: self.request_ids.next_id(),
+            packet_id: match self.qos.twin {
+                DeliveryGuarantees::AtMostOnce => None,
+                DeliveryGuarantees::AtLeastOnce | DeliveryGuarantees::ExactlyOnce => {
+                    Some(self.packets_numerator.next_id())
+                }
+            },
+            section: TwinSection::Full,
         };
-        let msg = IotCodec::encode_message(&msg.into()).unwrap();
-        self.c2d = SubState::Subscribing(msg_handler, error_handler, packet_id);
-        self.connection.write(&msg).unwrap();
+        self.write_message(read_req.into());
     }
 
-    pub fn sub_twin_updates(&mut self, mode: DeliveryGuarantees, handler: Box<TwinUpdatesHandler>) {
-        let packet_id = self.packets_numerator.next();
-        let msg = TwinUpdatesSub { packet_id, mode };
-        let msg = IotCodec::encode_message(&msg.into()).unwrap();
-        self.twin_updates = SubState::Subscribing(
-            handler,
-            Box::new(|e| println!("Twin updates sub error: {}", e)),
-            packet_id,
-        );
-        self.connection.write(&msg).unwrap();
+    // Returns FALSE if strict mode rejected the message, which is then dropped.
+    // Messages held while throttled are rejected, if at all, once `process` sends them.
+    fn write_message(&mut self, msg: MsgToHub) -> bool {
+        // hold back everything but acknowledgements while the hub is throttling us
+        if matches!(msg, MsgToHub::Acknowledge(_) | MsgToHub::ExactlyOnce(_)) {
+            return self.send_message(msg);
+        }
+        match self.held.admit(msg, &self.throttle, Instant::now()) {
+            Some(msg) => self.send_message(msg),
+            None => {
+                debug!(
+                    "Throttled, holding an outgoing message ({} held)",
+                    self.held.len()
+                );
+                true
+            }
+        }
     }
 
-    pub fn read_twin(&mut self) {
-        match self.twin_read {
-            SubState::Subscribed(_) => self.request_twin(),
-            SubState::Unsubscribed => self.sub_twin_reads(),
-            SubState::Subscribing(_, _, _) => {}
+    // Sends the messages held while throttled, once the cool-down ended
+    fn send_held(&mut self) {
+        for msg in self.held.release(&self.throttle, Instant::now()) {
+            self.send_message(msg);
         }
     }
 
-    fn request_twin(&mut self) {
-        let read_req = ReadTwinReq {
-            request_id: format!("{}", uuid::Uuid::new_v4()),
-            packet_id: Some(self.packets_numerator.next()),
+    fn send_message(&mut self, msg: MsgToHub) -> bool {
+        let packet = match IotCodec::encode_message_with(&msg, self.codec) {
+            Err(CodecError::NonConformant(violation)) => {
+                warn!("Dropping a message violating IoT Hub constraints: {}", violation);
+                audit_outbound(self.audit_sink.as_deref(), &msg, AuditOutcome::Failed(violation.to_string()));
+                return false;
+            }
+            other => other.unwrap(),
+        };
+        let packet = match (packet, &msg, &self.cipher) {
+            (VariablePacket::PublishPacket(publish), MsgToHub::Telemetry(_), Some(cipher)) => {
+                encrypt_publish(&publish, cipher.as_ref()).into()
+            }
+            (packet, _, _) => packet,
         };
-        let read_req = IotCodec::encode_message(&read_req.into()).unwrap();
-        self.connection.write(&read_req).unwrap();
+        let result = self.connection.write(&packet);
+        if result.is_ok() {
+            self.twin_requests.track(&msg);
+        }
+        let outcome = match &result {
+            Ok(()) => AuditOutcome::Sent,
+            Err(e) => AuditOutcome::Failed(e.to_string()),
+        };
+        audit_outbound(self.audit_sink.as_deref(), &msg, outcome);
+        result.unwrap();
+        true
     }
 
+    #[cfg(feature = "twin")]
     fn sub_twin_reads(&mut self) {
-        let packet_id = self.packets_numerator.next();
+        let packet_id = self.packets_numerator.next_id();
         let msg = TwinReadSub {
-            mode: DeliveryGuarantees::AtLeastOnce,
+            mode: self.qos.twin,
             packet_id,
         };
-        let msg = IotCodec::encode_message(&msg.into()).unwrap();
+        let msg = self.encode_subscription(msg.into());
         self.connection.write(&msg).unwrap();
         self.twin_read = SubState::Subscribing(
             Box::new(|twin| println!("Got TWIN! {:?}", &twin)),
@@ -148,10 +585,122 @@ impl IotClient {
         );
     }
 
+    fn encode_subscription(&mut self, msg: MsgToHub) -> VariablePacket {
+        self.subscriptions.track(&msg);
+        if let (Some(packet_id), Some(subscription)) = (msg.packet_id(), ActiveSubscription::from_msg(&msg)) {
+            self.pending_subscriptions.insert(packet_id, subscription);
+        }
+        IotCodec::encode_message(&msg).unwrap()
+    }
+
+    /// A snapshot of the subscriptions acknowledged by the hub, which can be persisted and passed to `restore`
+    pub fn subscriptions(&self) -> SubscriptionSnapshot {
+        self.active_subscriptions.clone()
+    }
+
+    /// Re-subscribes to the topics of a snapshot, keeping the handlers already set.
+    /// Messages of restored subscriptions without a handler are dropped until one is set.
+    pub fn restore(&mut self, snapshot: &SubscriptionSnapshot) {
+        for subscription in &snapshot.subscriptions {
+            let packet_id = self.packets_numerator.next_id();
+            let msg = match subscription.to_msg(packet_id, &self.client_id) {
+                Some(msg) => msg,
+                None => {
+                    warn!("Cannot restore subscription to {}", subscription.topic_filter);
+                    continue;
+                }
+            };
+            let restarted = match subscription.kind {
+                #[cfg(feature = "c2d")]
+                SubscriptionKind::CloudToDevice => {
+                    self.c2d.restart(packet_id);
+                    true
+                }
+                #[cfg(feature = "direct-methods")]
+                SubscriptionKind::DirectMethods => {
+                    self.dmi.restart(packet_id);
+                    true
+                }
+                #[cfg(feature = "twin")]
+                SubscriptionKind::TwinResponses => {
+                    self.twin_read.restart(packet_id);
+                    true
+                }
+                #[cfg(feature = "twin")]
+                SubscriptionKind::TwinUpdates => {
+                    self.twin_updates.restart(packet_id);
+                    true
+                }
+                #[cfg(feature = "c2d")]
+                SubscriptionKind::ModuleInputs => {
+                    self.inputs.restart(packet_id);
+                    self.pending_subscriptions.insert(packet_id, subscription.clone());
+                    true
+                }
+                #[allow(unreachable_patterns)]
+                _ => false,
+            };
+            if !restarted {
+                warn!("Cannot restore subscription to {}, its feature is disabled", subscription.topic_filter);
+                continue;
+            }
+            let msg = self.encode_subscription(msg);
+            self.connection.write(&msg).unwrap();
+        }
+    }
+
+    /// Sets how the client is driven: the time budget of `process`, and how `run_once` waits.
+    /// By default, the client sleeps the calling thread with a `SleepScheduler`.
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn Scheduler>) {
+        self.scheduler = scheduler;
+    }
+
+    /// The socket of the connection, to be registered with an event loop which calls `process` once it is readable,
+    /// or writable while `has_pending_writes`
+    pub fn socket(&self) -> &TcpStream {
+        self.connection.connection().stream().get_ref()
+    }
+
+    /// TRUE if data is waiting for the socket to become writable
+    pub fn has_pending_writes(&self) -> bool {
+        self.connection.connection().has_pending_writes()
+    }
+
+    /// The time by which `process` must be called again regardless of the socket: to send a keep-alive,
+    /// to send the messages held while throttled, or to fail a direct method past its response window.
+    /// None if only the socket can give the client work to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.disconnect_reason.is_some() {
+            return None;
+        }
+        #[cfg(feature = "direct-methods")]
+        let dmi_deadline = self.dmi_deadlines.values().min().copied();
+        #[cfg(not(feature = "direct-methods"))]
+        let dmi_deadline = None;
+        earliest(vec![
+            self.connection.connection().keep_alive_deadline(),
+            dmi_deadline,
+            self.held.release_deadline(&self.throttle, Instant::now()),
+        ])
+    }
+
+    /// Processes the connection, then waits with the scheduler until the next deadline
+    pub fn run_once(&mut self) {
+        self.process();
+        let deadline = self.next_deadline();
+        self.scheduler.wait(deadline);
+    }
+
     pub fn process(&mut self) {
-        const MAX_TASK_DURATION: Duration = Duration::from_millis(5);
-        self.connection.send_task(MAX_TASK_DURATION).unwrap();
-        self.connection.recv_task(MAX_TASK_DURATION).unwrap();
+        if self.disconnect_reason.is_some() {
+            return;
+        }
+        self.send_held();
+        let time_slice = self.scheduler.time_slice();
+        let transfer = self
+            .connection
+            .send_task(time_slice)
+            .and_then(|_| self.connection.recv_task(time_slice));
         loop {
             match self.connection.read().unwrap() {
                 None => {
@@ -160,66 +709,226 @@ impl IotClient {
                     break;
                 }
                 Some(packet) => {
-                    debug!("Got packet: {:?}", packet);
-                    let msg = IotCodec::decode_packet(packet).unwrap();
+                    debug!("Got packet: {:?}", redact(&packet));
+                    #[cfg(feature = "raw-mqtt")]
+                    if let Some(handler) = self.raw_packet_handler.as_mut() {
+                        handler(&packet);
+                    }
+                    let (packet, reply) = self.exactly_once.incoming(packet);
+                    if let Some(reply) = reply {
+                        self.write_message(reply);
+                    }
+                    let packet = match packet {
+                        Some(packet) => packet,
+                        None => continue,
+                    };
+                    let packet = match (packet, &self.cipher) {
+                        (VariablePacket::PublishPacket(publish), Some(cipher)) => {
+                            match decrypt_publish(publish, cipher.as_ref()) {
+                                Ok(publish) => publish.into(),
+                                Err(e) => {
+                                    warn!("Ignoring a message which could not be decrypted: {}", e);
+                                    continue;
+                                }
+                            }
+                        }
+                        (packet, _) => packet,
+                    };
+                    let msg = if self.validate_topics {
+                        IotCodec::decode_packet_for(packet, &self.client_id).unwrap()
+                    } else {
+                        IotCodec::decode_packet(packet).unwrap()
+                    };
                     self.process_msg(msg);
                 }
             }
         }
+        if let Err(e) = transfer {
+            // messages received before the failure were processed above
+            let reason = DisconnectReason::infer(e.kind(), self.token_expiry, SystemTime::now());
+            warn!("Connection lost: {}", reason);
+            self.disconnect_reason = Some(reason);
+            if let Some(handler) = &self.disconnect_handler {
+                handler(reason);
+            }
+            return;
+        }
+        #[cfg(feature = "direct-methods")]
+        self.expire_dmi_deadlines();
+        self.expire_sequences();
         trace!("Process function completed");
     }
 
+    // Telemetry unacknowledged for that long is considered lost, and its sequence number forgotten
+    fn expire_sequences(&mut self) {
+        let now = Instant::now();
+        let before = self.sequences_in_flight.len();
+        self.sequences_in_flight
+            .retain(|_, (_, sent_at)| now.saturating_duration_since(*sent_at) < SEQUENCE_ACK_TIMEOUT);
+        if self.sequences_in_flight.len() < before {
+            warn!(
+                "{} telemetry messages were not acknowledged in time, their sequence numbers are forgotten",
+                before - self.sequences_in_flight.len()
+            );
+        }
+    }
+
+    #[cfg(feature = "direct-methods")]
+    fn expire_dmi_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .dmi_deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(request_id, _)| request_id.clone())
+            .collect();
+        for request_id in expired {
+            warn!("Direct method {} exceeded the response window", request_id);
+            self.dmi_deadlines.remove(&request_id);
+            let res = DMIResult {
+                status: DMI_TIMEOUT_STATUS,
+                payload: None,
+            };
+            self.write_dmi_res(&request_id, res, None);
+        }
+    }
+
     fn process_msg(&mut self, msg: MsgFromHub) {
-        debug!("Processing incoming msg: {:?}", msg);
+        let mut msg = self.twin_requests.route(msg);
+        debug!("Processing incoming msg: {:?}", redact(&msg));
+        audit_inbound(self.audit_sink.as_deref(), &msg, AuditOutcome::Received);
+        if let Some(clock) = &self.clock {
+            let _ = clock.observe_msg(&msg, SystemTime::now());
+        }
+        if !self.interceptors.iter_mut().all(|interceptor| interceptor(&mut msg)) {
+            debug!("Message dropped by an interceptor");
+            audit_inbound(self.audit_sink.as_deref(), &msg, AuditOutcome::Dropped);
+            return;
+        }
+        if let Some(dedup) = self.dedup.as_mut() {
+            if dedup.is_duplicate(&msg, Instant::now()) {
+                debug!("Dropping a message delivered again: {:?}", redact(&msg));
+                audit_inbound(self.audit_sink.as_deref(), &msg, AuditOutcome::Dropped);
+                return;
+            }
+        }
+        if self.throttle.observe(&msg, Instant::now()) {
+            warn!("Throttled by the hub, cooling down");
+        }
+        if let MsgFromHub::SubscriptionResponseMessage(res) = &mut msg {
+            // before the receipt is handed out, so that failures carry their topic filter
+            if self.subscriptions.correlate(res) {
+                debug!("Subscription response for {:?}: {:?}", res.topic_filters, res.result);
+            }
+        }
+        if let (Some(handler), Some(receipt)) = (&self.receipts_handler, msg.delivery_receipt()) {
+            handler(receipt);
+        }
         match msg {
             MsgFromHub::SubscriptionResponseMessage(res) => {
                 self.process_sub_res(res);
             }
+            #[cfg(feature = "c2d")]
             MsgFromHub::CloudToDeviceMessage(c2d) => {
+                if let Some(handler) = &self.c2d_expired_handler {
+                    if c2d.is_expired(SystemTime::now()) {
+                        debug!("Dropping expired C2D: {:?}", redact(&c2d));
+                        audit_inbound(self.audit_sink.as_deref(), &c2d.clone().into(), AuditOutcome::Dropped);
+                        handler(c2d);
+                        return;
+                    }
+                }
                 if let SubState::Subscribed(ref mut handler) = self.c2d {
-                    debug!("Processing C2D: {:?}", c2d);
+                    debug!("Processing C2D: {:?}", redact(&c2d));
                     handler(c2d);
                 } else {
                     debug!("Got C2D but no handler was set");
                 }
             }
+            #[cfg(feature = "c2d")]
+            MsgFromHub::ModuleInputMessage(input) => {
+                if let SubState::Subscribed(ref mut handler) = self.inputs {
+                    debug!("Processing module input: {:?}", redact(&input));
+                    handler(input);
+                } else {
+                    debug!("Got module input but no handler was set");
+                }
+            }
+            #[cfg(feature = "direct-methods")]
             MsgFromHub::DirectMethodInvocation(dmi) => {
                 if let SubState::Subscribed(ref mut handler) = self.dmi {
-                    debug!("Processing DMI: {:?}", dmi);
+                    // only invocations passed to the handler are answered, by it or else with a timeout
+                    let deadline = Instant::now() + self.dmi_response_window;
+                    self.dmi_deadlines.insert(dmi.request_id.clone(), deadline);
+                    debug!("Processing DMI: {:?}", redact(&dmi));
                     handler(dmi);
                 } else {
                     debug!("Got DMI but no handler was set");
                 }
             }
+            #[cfg(feature = "twin")]
             MsgFromHub::DesiredPropertiesUpdated(props) => {
                 if let SubState::Subscribed(ref mut handler) = self.twin_updates {
-                    debug!("Processing Desired Props Update: {:?}", props);
+                    debug!("Processing Desired Props Update: {:?}", redact(&props));
                     handler(props);
                 }
             }
+            MsgFromHub::PublicationSucceeded(packet_id) => {
+                if let Some((sequence_number, _)) = self.sequences_in_flight.remove(&packet_id) {
+                    if let Some(sequencer) = self.sequencer.as_mut() {
+                        sequencer.acknowledge(sequence_number);
+                    }
+                }
+                if let Some(drainer) = self.outbox.as_mut() {
+                    if let Err(e) = drainer.acknowledged(packet_id) {
+                        warn!("Failed to remove a delivered entry from the outbox, it will be sent again: {}", e);
+                    }
+                }
+            }
+            MsgFromHub::MisroutedMessage(msg) => {
+                warn!("Ignoring a message addressed to another client: {}", msg.topic);
+            }
             _ => {}
         }
     }
 
     fn process_sub_res(&mut self, res: SubRes) {
+        if let Err(e) = &res.result {
+            warn!("Subscription failed: {}", e);
+        }
+        if let Some(subscription) = self.pending_subscriptions.remove(&res.packet_id) {
+            if res.result.is_ok() {
+                self.active_subscriptions.insert(subscription);
+            }
+        }
+
+        #[cfg(feature = "twin")]
         if self.twin_read.try_complete(&res) {
             debug!("Subscribed to Twin Reads");
             return
         };
 
+        #[cfg(feature = "c2d")]
         if self.c2d.try_complete(&res) {
             debug!("Subscribed to C2D");
             return
         };
 
+        #[cfg(feature = "c2d")]
+        if self.inputs.try_complete(&res) {
+            debug!("Subscribed to module inputs");
+            return
+        };
+
+        #[cfg(feature = "direct-methods")]
         if self.dmi.try_complete(&res) {
             debug!("Subscribed to Direct Methods");
             return
         };
 
+        #[cfg(feature = "twin")]
         if self.twin_updates.try_complete(&res) {
             debug!("Subscribed to Twin Updates");
-            return
-        };
+        }
     }
 }
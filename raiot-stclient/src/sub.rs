@@ -24,12 +24,12 @@ impl<M> SubState<M> {
                                              ref mut error_handler, 
                                              packet_id) = *self {
             if packet_id == res.packet_id {
-                match res.result {
+                match &res.result {
                     Ok(()) => {
                         SubState::Subscribed(mem::replace(msg_handler, Box::new(|_| { })))
                     }
                     Err(e) => { 
-                        error_handler(e);
+                        error_handler(e.clone());
                         SubState::Unsubscribed
                     }
                 }
@@ -40,6 +40,25 @@ impl<M> SubState<M> {
             return false;
         };
 
-        return true;
+        true
+    }
+
+    /// Re-subscribes with the specified packet ID, keeping the handlers if any were set
+    pub fn restart(&mut self, packet_id: PacketId) {
+        *self = match mem::replace(self, SubState::Unsubscribed) {
+            SubState::Subscribing(msg_handler, error_handler, _) => {
+                SubState::Subscribing(msg_handler, error_handler, packet_id)
+            }
+            SubState::Subscribed(msg_handler) => SubState::Subscribing(
+                msg_handler,
+                Box::new(|e| warn!("Restored subscription failed: {}", e)),
+                packet_id,
+            ),
+            SubState::Unsubscribed => SubState::Subscribing(
+                Box::new(|_| debug!("Dropping a message of a restored subscription, no handler was set")),
+                Box::new(|e| warn!("Restored subscription failed: {}", e)),
+                packet_id,
+            ),
+        };
     }
 }
\ No newline at end of file
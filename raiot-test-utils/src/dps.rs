@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+
+use mqtt::control::variable_header::ConnectReturnCode;
+use mqtt::packet::suback::SubscribeReturnCode;
+use mqtt::packet::*;
+use mqtt::TopicName;
+use serde_json::json;
+
+use crate::packets::PacketChannel;
+use crate::MockServerSocket;
+
+const REGISTER_TOPIC: &str = "$dps/registrations/PUT/iotdps-register/";
+const STATUS_TOPIC: &str = "$dps/registrations/GET/iotdps-get-operationstatus/";
+
+/// A scripted response of the mock DPS endpoint
+#[derive(Debug, Clone)]
+pub enum DpsResponse {
+    /// 202 - the assignment is in progress; the client should query its status after `retry_after` seconds
+    Assigning {
+        operation_id: String,
+        retry_after: u32,
+    },
+
+    /// 200 - the device was assigned to a hub
+    Assigned {
+        operation_id: String,
+        assigned_hub: String,
+        device_id: String,
+    },
+
+    /// 429 - the client is throttled and should retry after `retry_after` seconds
+    Throttled { retry_after: u32 },
+
+    /// Any other failure status (e.g. 401, 404, 500)
+    Failed { status: u16 },
+}
+
+/// A request received by the mock DPS endpoint
+#[derive(Debug, Clone, PartialEq)]
+pub enum DpsRequest {
+    /// A registration request, with its JSON payload
+    Register {
+        request_id: String,
+        payload: serde_json::Value,
+    },
+
+    /// An operation status query
+    QueryStatus {
+        request_id: String,
+        operation_id: String,
+    },
+}
+
+/// A mock DPS MQTT endpoint, answering registration requests from a script.
+/// When the script is exhausted, requests are left unanswered (simulating a timeout).
+pub struct MockDps {
+    channel: PacketChannel,
+    script: VecDeque<DpsResponse>,
+    requests: Vec<DpsRequest>,
+}
+
+impl MockDps {
+    pub fn new(server: MockServerSocket) -> MockDps {
+        let mut channel = PacketChannel::new(server);
+        channel.allow_write(64 * 1024);
+        MockDps {
+            channel,
+            script: VecDeque::new(),
+            requests: Vec::new(),
+        }
+    }
+
+    /// Appends a response to the script
+    pub fn push_response(&mut self, response: DpsResponse) {
+        self.script.push_back(response);
+    }
+
+    /// The requests received so far, in order
+    pub fn requests(&self) -> &[DpsRequest] {
+        &self.requests
+    }
+
+    /// Handles every packet written by the client so far
+    ///
+    /// # Panics
+    /// Panics if the client wrote an invalid packet
+    pub fn process(&mut self) {
+        while let Some(packet) = self.channel.recv().expect("The client wrote an invalid packet") {
+            self.channel.allow_write(64 * 1024);
+            match packet {
+                VariablePacket::ConnectPacket(_) => self.channel.send(
+                    ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted).into(),
+                ),
+                VariablePacket::SubscribePacket(sub) => {
+                    let codes = sub
+                        .payload_ref()
+                        .subscribes()
+                        .iter()
+                        .map(|(_, qos)| SubscribeReturnCode::from(*qos))
+                        .collect();
+                    self.channel
+                        .send(SubackPacket::new(sub.packet_identifier(), codes).into());
+                }
+                VariablePacket::PublishPacket(publish) => self.handle_publish(&publish),
+                VariablePacket::PingreqPacket(_) => self.channel.send(PingrespPacket::new().into()),
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_publish(&mut self, publish: &PublishPacket) {
+        if let QoSWithPacketIdentifier::Level1(packet_id) = publish.qos() {
+            self.channel.send(PubackPacket::new(packet_id).into());
+        }
+
+        let topic = publish.topic_name();
+        let (path, query) = match topic.find('?') {
+            Some(index) => (&topic[..index], &topic[index + 1..]),
+            None => (topic, ""),
+        };
+        let param = |key: &str| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_owned())
+                .unwrap_or_default()
+        };
+        let request_id = param("$rid");
+
+        let request = match path {
+            REGISTER_TOPIC => DpsRequest::Register {
+                request_id: request_id.clone(),
+                payload: serde_json::from_slice(publish.payload_ref())
+                    .unwrap_or(serde_json::Value::Null),
+            },
+            STATUS_TOPIC => DpsRequest::QueryStatus {
+                request_id: request_id.clone(),
+                operation_id: param("operationId"),
+            },
+            _ => return,
+        };
+        self.requests.push(request);
+
+        if let Some(response) = self.script.pop_front() {
+            self.respond(&request_id, response);
+        }
+    }
+
+    fn respond(&mut self, request_id: &str, response: DpsResponse) {
+        let (status, retry_after, body) = match response {
+            DpsResponse::Assigning {
+                operation_id,
+                retry_after,
+            } => (
+                202,
+                Some(retry_after),
+                json!({ "operationId": operation_id, "status": "assigning" }),
+            ),
+            DpsResponse::Assigned {
+                operation_id,
+                assigned_hub,
+                device_id,
+            } => (
+                200,
+                None,
+                json!({
+                    "operationId": operation_id,
+                    "status": "assigned",
+                    "registrationState": {
+                        "registrationId": device_id,
+                        "assignedHub": assigned_hub,
+                        "deviceId": device_id,
+                        "status": "assigned",
+                    },
+                }),
+            ),
+            DpsResponse::Throttled { retry_after } => (
+                429,
+                Some(retry_after),
+                json!({ "errorCode": 429001, "message": "Operations are being throttled" }),
+            ),
+            DpsResponse::Failed { status } => (
+                status,
+                None,
+                json!({ "errorCode": u32::from(status) * 1000, "message": "Scripted failure" }),
+            ),
+        };
+
+        let mut topic = format!("$dps/registrations/res/{}/?$rid={}", status, request_id);
+        if let Some(retry_after) = retry_after {
+            topic.push_str(&format!("&retry-after={}", retry_after));
+        }
+        let publish = PublishPacket::new(
+            TopicName::new(topic).unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            body.to_string(),
+        );
+        self.channel.send(publish.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockClientSocket, MockSocket};
+    use mqtt::{Decodable, Encodable};
+    use std::io::{Read, Write};
+
+    fn write_packet(client: &mut MockClientSocket, packet: VariablePacket) {
+        let mut bytes = Vec::new();
+        packet.encode(&mut bytes).unwrap();
+        client.write_all(&bytes).unwrap();
+    }
+
+    fn read_publish(client: &mut MockClientSocket) -> PublishPacket {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let size = client.read(&mut buf).unwrap();
+            if let VariablePacket::PublishPacket(publish) =
+                VariablePacket::decode(&mut &buf[..size]).unwrap()
+            {
+                return publish;
+            }
+        }
+    }
+
+    fn publish(topic: &str, payload: &str) -> VariablePacket {
+        PublishPacket::new(
+            TopicName::new(topic).unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            payload,
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_scripted_registration_flow() {
+        let (mut client, server) = MockSocket::create();
+        let mut sut = MockDps::new(server);
+        sut.push_response(DpsResponse::Throttled { retry_after: 5 });
+        sut.push_response(DpsResponse::Assigning {
+            operation_id: "op1".to_owned(),
+            retry_after: 3,
+        });
+        sut.push_response(DpsResponse::Assigned {
+            operation_id: "op1".to_owned(),
+            assigned_hub: "hub.azure-devices.net".to_owned(),
+            device_id: "device1".to_owned(),
+        });
+
+        write_packet(
+            &mut client,
+            publish(
+                "$dps/registrations/PUT/iotdps-register/?$rid=1",
+                r#"{"registrationId":"device1"}"#,
+            ),
+        );
+        sut.process();
+        let response = read_publish(&mut client);
+        assert_eq!(
+            response.topic_name(),
+            "$dps/registrations/res/429/?$rid=1&retry-after=5"
+        );
+
+        write_packet(
+            &mut client,
+            publish("$dps/registrations/PUT/iotdps-register/?$rid=2", "{}"),
+        );
+        sut.process();
+        let response = read_publish(&mut client);
+        assert_eq!(
+            response.topic_name(),
+            "$dps/registrations/res/202/?$rid=2&retry-after=3"
+        );
+
+        write_packet(
+            &mut client,
+            publish(
+                "$dps/registrations/GET/iotdps-get-operationstatus/?$rid=3&operationId=op1",
+                "",
+            ),
+        );
+        sut.process();
+        let response = read_publish(&mut client);
+        assert_eq!(response.topic_name(), "$dps/registrations/res/200/?$rid=3");
+        let body: serde_json::Value = serde_json::from_slice(response.payload_ref()).unwrap();
+        assert_eq!(body["registrationState"]["assignedHub"], "hub.azure-devices.net");
+
+        assert_eq!(
+            sut.requests()[2],
+            DpsRequest::QueryStatus {
+                request_id: "3".to_owned(),
+                operation_id: "op1".to_owned(),
+            }
+        );
+    }
+}
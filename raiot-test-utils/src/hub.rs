@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
+
+use mqtt::control::variable_header::ConnectReturnCode;
+use mqtt::packet::suback::SubscribeReturnCode;
+use mqtt::packet::*;
+use mqtt::{Encodable, TopicName};
+
+use crate::network::{NetworkLink, NetworkProfile, NetworkStats};
+use crate::packets::PacketChannel;
+use crate::MockServerSocket;
+
+/// How the mock hub treats a QoS1 PUBLISH received from the client
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PubackAction {
+    /// Send a PUBACK
+    Acknowledge,
+
+    /// Never acknowledge this delivery, forcing the client to retransmit
+    Withhold,
+}
+
+/// A mock IoT Hub MQTT broker.
+/// QoS1 publications from the client are acknowledged according to a script (acknowledged by default),
+/// and QoS1 publications to the client are kept until acknowledged, so they can be redelivered with the DUP flag.
+/// QoS2 publications from the client are received (PUBREC) and completed (PUBCOMP) once released.
+/// Packets in both directions go through links delaying them according to a network profile (none by default).
+pub struct MockHub {
+    channel: PacketChannel,
+    uplink: NetworkLink<VariablePacket>,
+    downlink: NetworkLink<VariablePacket>,
+    puback_script: VecDeque<PubackAction>,
+    received: Vec<PublishPacket>,
+    subscriptions: Vec<String>,
+    unacknowledged: BTreeMap<u16, PublishPacket>,
+    next_packet_id: u16,
+}
+
+impl MockHub {
+    pub fn new(server: MockServerSocket) -> MockHub {
+        let mut channel = PacketChannel::new(server);
+        channel.allow_write(64 * 1024);
+        MockHub {
+            channel,
+            uplink: NetworkLink::new(NetworkProfile::IDEAL, 1),
+            downlink: NetworkLink::new(NetworkProfile::IDEAL, 1),
+            puback_script: VecDeque::new(),
+            received: Vec::new(),
+            subscriptions: Vec::new(),
+            unacknowledged: BTreeMap::new(),
+            next_packet_id: 1,
+        }
+    }
+
+    /// Delays the packets exchanged with the client according to the profile.
+    /// The seed makes the random jitter and losses reproducible.
+    pub fn with_network(mut self, profile: NetworkProfile, seed: u64) -> MockHub {
+        self.uplink = NetworkLink::new(profile, seed);
+        self.downlink = NetworkLink::new(profile, seed.wrapping_add(1));
+        self
+    }
+
+    /// What the network did to the packets sent by the client
+    pub fn uplink_stats(&self) -> &NetworkStats {
+        self.uplink.stats()
+    }
+
+    /// What the network did to the packets sent to the client
+    pub fn downlink_stats(&self) -> &NetworkStats {
+        self.downlink.stats()
+    }
+
+    /// When a packet delayed by the network is next due, in either direction, if any
+    pub fn next_delivery(&self) -> Option<Instant> {
+        match (self.uplink.next_delivery(), self.downlink.next_delivery()) {
+            (Some(up), Some(down)) => Some(up.min(down)),
+            (up, down) => up.or(down),
+        }
+    }
+
+    /// Appends an action to the script applied to QoS1 publications received from the client
+    pub fn push_puback_action(&mut self, action: PubackAction) {
+        self.puback_script.push_back(action);
+    }
+
+    /// Every PUBLISH received from the client, including retransmissions, in order
+    pub fn received(&self) -> &[PublishPacket] {
+        &self.received
+    }
+
+    /// The topic filters of every SUBSCRIBE received from the client, including resubscriptions, in order
+    pub fn subscriptions(&self) -> &[String] {
+        &self.subscriptions
+    }
+
+    /// The IDs of the QoS1 publications sent to the client and not yet acknowledged
+    pub fn unacknowledged(&self) -> Vec<u16> {
+        self.unacknowledged.keys().copied().collect()
+    }
+
+    /// Publishes a message to the client with QoS1. Returns the packet ID.
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> u16 {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        let publish = PublishPacket::new(
+            TopicName::new(topic).unwrap(),
+            QoSWithPacketIdentifier::Level1(packet_id),
+            payload,
+        );
+        let _ = self.unacknowledged.insert(packet_id, publish.clone());
+        self.send(publish.into(), Instant::now());
+        packet_id
+    }
+
+    /// Redelivers every unacknowledged publication with the DUP flag set
+    pub fn redeliver_unacknowledged(&mut self) {
+        let now = Instant::now();
+        let publications: Vec<PublishPacket> = self.unacknowledged.values().cloned().collect();
+        for mut publish in publications {
+            publish.set_dup(true);
+            self.send(publish.into(), now);
+        }
+    }
+
+    /// Drops the connection: the next read of the client fails with `ErrorKind::ConnectionReset`
+    pub fn drop_connection(&mut self) {
+        self.channel.reset();
+    }
+
+    /// Handles every packet written by the client so far
+    pub fn process(&mut self) {
+        self.process_at(Instant::now());
+    }
+
+    /// Handles every packet written by the client that the network delivered by the specified time,
+    /// and delivers to the client the packets due by then
+    ///
+    /// # Panics
+    /// Panics if the client wrote an invalid packet
+    pub fn process_at(&mut self, now: Instant) {
+        while let Some(packet) = self.channel.recv().expect("The client wrote an invalid packet") {
+            self.channel.allow_write(64 * 1024);
+            self.uplink.send(packet, now);
+        }
+        while let Some(packet) = self.uplink.deliver(now) {
+            self.handle(packet, now);
+        }
+        self.flush(now);
+    }
+
+    fn send(&mut self, packet: VariablePacket, now: Instant) {
+        self.downlink.send(packet, now);
+        self.flush(now);
+    }
+
+    fn flush(&mut self, now: Instant) {
+        while let Some(packet) = self.downlink.deliver(now) {
+            self.channel.send(packet);
+        }
+    }
+
+    fn handle(&mut self, packet: VariablePacket, now: Instant) {
+        match packet {
+            VariablePacket::ConnectPacket(_) => self.send(
+                ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted).into(),
+                now,
+            ),
+            VariablePacket::SubscribePacket(sub) => {
+                let codes = sub
+                    .payload_ref()
+                    .subscribes()
+                    .iter()
+                    .map(|(_, qos)| SubscribeReturnCode::from(*qos))
+                    .collect();
+                self.subscriptions.extend(
+                    sub.payload_ref()
+                        .subscribes()
+                        .iter()
+                        .map(|(filter, _)| filter.to_string()),
+                );
+                self.send(
+                    SubackPacket::new(sub.packet_identifier(), codes).into(),
+                    now,
+                );
+            }
+            VariablePacket::PublishPacket(publish) => {
+                match publish.qos() {
+                    QoSWithPacketIdentifier::Level1(packet_id) => {
+                        let action = self
+                            .puback_script
+                            .pop_front()
+                            .unwrap_or(PubackAction::Acknowledge);
+                        if action == PubackAction::Acknowledge {
+                            self.send(PubackPacket::new(packet_id).into(), now);
+                        }
+                    }
+                    QoSWithPacketIdentifier::Level2(packet_id) => self.send(PubrecPacket::new(packet_id).into(), now),
+                    QoSWithPacketIdentifier::Level0 => {}
+                }
+                self.received.push(publish);
+            }
+            VariablePacket::PubrelPacket(pubrel) => {
+                self.send(PubcompPacket::new(pubrel.packet_identifier()).into(), now)
+            }
+            VariablePacket::PubackPacket(puback) => {
+                let _ = self.unacknowledged.remove(&puback.packet_identifier());
+            }
+            VariablePacket::PingreqPacket(_) => self.send(PingrespPacket::new().into(), now),
+            _ => {}
+        }
+    }
+}
+
+/// Returns TRUE if the DUP flag of the publication is set.
+/// `PublishPacket::dup` checks the wrong bit of the fixed header, so the flag is read from the encoded packet.
+pub fn is_duplicate(publish: &PublishPacket) -> bool {
+    let mut bytes = Vec::new();
+    publish.encode(&mut bytes).unwrap();
+    bytes[0] & 0x08 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockClientSocket, MockSocket};
+    use mqtt::Decodable;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    fn write_packet(client: &mut MockClientSocket, packet: VariablePacket) {
+        let mut bytes = Vec::new();
+        packet.encode(&mut bytes).unwrap();
+        client.write_all(&bytes).unwrap();
+    }
+
+    fn read_packet(client: &mut MockClientSocket) -> Option<VariablePacket> {
+        let mut buf = vec![0u8; 4096];
+        match client.read(&mut buf) {
+            Ok(size) => Some(VariablePacket::decode(&mut &buf[..size]).unwrap()),
+            Err(_) => None,
+        }
+    }
+
+    fn telemetry(packet_id: u16, dup: bool) -> VariablePacket {
+        let mut publish = PublishPacket::new(
+            TopicName::new("devices/device1/messages/events/").unwrap(),
+            QoSWithPacketIdentifier::Level1(packet_id),
+            "{}",
+        );
+        publish.set_dup(dup);
+        publish.into()
+    }
+
+    #[test]
+    fn test_withheld_puback_is_sent_on_retransmission() {
+        let (mut client, server) = MockSocket::create();
+        let mut sut = MockHub::new(server);
+        sut.push_puback_action(PubackAction::Withhold);
+
+        write_packet(&mut client, telemetry(1, false));
+        sut.process();
+        assert!(read_packet(&mut client).is_none());
+
+        write_packet(&mut client, telemetry(1, true));
+        sut.process();
+        match read_packet(&mut client) {
+            Some(VariablePacket::PubackPacket(puback)) => assert_eq!(puback.packet_identifier(), 1),
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
+        assert_eq!(sut.received().len(), 2);
+        assert!(is_duplicate(&sut.received()[1]));
+    }
+
+    #[test]
+    fn test_network_profile_delays_both_directions() {
+        let (mut client, server) = MockSocket::create();
+        let mut sut = MockHub::new(server).with_network(NetworkProfile::LTE, 42);
+        let start = Instant::now();
+
+        write_packet(&mut client, telemetry(1, false));
+        sut.process_at(start);
+        assert!(sut.received().is_empty());
+
+        let handled_at = start + Duration::from_secs(5);
+        sut.process_at(handled_at);
+        assert_eq!(sut.received().len(), 1);
+        assert!(read_packet(&mut client).is_none());
+        assert!(sut.next_delivery().unwrap() >= handled_at + NetworkProfile::LTE.latency);
+
+        sut.process_at(handled_at + Duration::from_secs(5));
+        match read_packet(&mut client) {
+            Some(VariablePacket::PubackPacket(puback)) => assert_eq!(puback.packet_identifier(), 1),
+            other => panic!("Expected PUBACK, got {:?}", other),
+        }
+        assert_eq!(sut.uplink_stats().delivered, 1);
+        assert!(sut.downlink_stats().max_delay >= NetworkProfile::LTE.latency);
+    }
+
+    #[test]
+    fn test_unacknowledged_publications_are_redelivered_as_dup() {
+        let (mut client, server) = MockSocket::create();
+        let mut sut = MockHub::new(server);
+
+        let packet_id = sut.publish("devices/device1/messages/devicebound/", b"hello");
+        match read_packet(&mut client) {
+            Some(VariablePacket::PublishPacket(publish)) => assert!(!is_duplicate(&publish)),
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+
+        sut.redeliver_unacknowledged();
+        match read_packet(&mut client) {
+            Some(VariablePacket::PublishPacket(publish)) => {
+                assert!(is_duplicate(&publish));
+                assert_eq!(publish.qos(), QoSWithPacketIdentifier::Level1(packet_id));
+            }
+            other => panic!("Expected PUBLISH, got {:?}", other),
+        }
+
+        write_packet(&mut client, PubackPacket::new(packet_id).into());
+        sut.process();
+        assert!(sut.unacknowledged().is_empty());
+    }
+}
@@ -4,6 +4,13 @@ use std::{io::ErrorKind, io::Read, io::Write, sync::mpsc};
 use mpsc::TryRecvError;
 use raiot_buffers::CircularBuffer;
 
+pub mod dps;
+pub mod hub;
+pub mod network;
+mod packets;
+
+pub use crate::packets::PacketChannel;
+
 pub struct MockSocket {}
 
 pub struct MockClientSocket {
@@ -21,12 +28,8 @@ impl MockClientSocket {
                 Ok(next_bytes) => {
                     self.read_data_buf.write_all(&next_bytes).unwrap();
                 }
-                Err(e) => match e {
-                    TryRecvError::Empty => {
-                        break;
-                    }
-                    _other => panic!("ODMFODF"),
-                },
+                // the server end was dropped, nothing more is coming
+                Err(_) => break,
             }
         }
 
@@ -41,12 +44,9 @@ impl Write for MockClientSocket {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let res = self.write_ctl_rx.try_recv();
         match res {
-            Err(TryRecvError::Empty) => {
-                return Err(ErrorKind::WouldBlock.into());
-            }
-            Err(TryRecvError::Disconnected) => {
-                panic!("Discooo");
-            }
+            Err(TryRecvError::Empty) => Err(ErrorKind::WouldBlock.into()),
+            // the server end was dropped
+            Err(TryRecvError::Disconnected) => Err(ErrorKind::BrokenPipe.into()),
             Ok(Ok(usize)) => {
                 let send_size = std::cmp::min(buf.len(), usize);
                 let send_vec = buf[0..send_size].into();
@@ -67,20 +67,18 @@ impl Read for MockClientSocket {
         match self.read_ctl_rx.try_recv() {
             Ok(res) => match res {
                 Ok(size) => {
+                    // a zero-sized read simulates the peer closing the connection
                     let read_size = std::cmp::min(buf.len(), size);
-                    self.read_from_buffer(read_size, buf);
-                    return Ok(read_size);
-                }
-                Err(e) => {
-                    return Err(e);
+                    if read_size > 0 {
+                        self.read_from_buffer(read_size, buf);
+                    }
+                    Ok(read_size)
                 }
+                Err(e) => Err(e),
             },
-            Err(TryRecvError::Empty) => {
-                return Err(ErrorKind::WouldBlock.into());
-            }
-            Err(TryRecvError::Disconnected) => {
-                panic!("Discooo");
-            }
+            Err(TryRecvError::Empty) => Err(ErrorKind::WouldBlock.into()),
+            // the server end was dropped
+            Err(TryRecvError::Disconnected) => Err(ErrorKind::ConnectionReset.into()),
         }
     }
 }
@@ -94,16 +92,18 @@ pub struct MockServerSocket {
 }
 
 impl MockServerSocket {
+    // the sends below fail once the client end was dropped, which has nothing left to read or write
+
     pub fn push_read_ctl(&mut self, ctl: std::io::Result<usize>) {
-        self.read_ctl_tx.send(ctl).unwrap();
+        let _ = self.read_ctl_tx.send(ctl);
     }
 
     pub fn push_write_ctl(&mut self, ctl: std::io::Result<usize>) {
-        self.write_ctl_tx.send(ctl).unwrap();
+        let _ = self.write_ctl_tx.send(ctl);
     }
 
     pub fn push_data(&mut self, buf: &[u8]) {
-        self.write_data_tx.send(buf.into()).unwrap();
+        let _ = self.write_data_tx.send(buf.into());
     }
 
     fn read_from_buffer(&mut self, buf: &mut [u8]) -> usize {
@@ -115,22 +115,26 @@ impl MockServerSocket {
                 Err(TryRecvError::Empty) => {
                     break;
                 }
+                // the client end was dropped, nothing more is coming
                 Err(TryRecvError::Disconnected) => {
-                    panic!("disco");
+                    break;
                 }
             }
         }
 
         let read_size = std::cmp::min(buf.len(), self.read_data_buf.valid_length());
+        if read_size == 0 {
+            return 0;
+        }
         let mut res = self.read_data_buf.read_bytes(read_size);
-        res.read_exact(buf).unwrap();
-        return read_size;
+        res.read_exact(&mut buf[..read_size]).unwrap();
+        read_size
     }
 }
 
 impl Read for MockServerSocket {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        return Ok(self.read_from_buffer(buf));
+        Ok(self.read_from_buffer(buf))
     }
 }
 
@@ -171,6 +175,6 @@ impl MockSocket {
             read_data_buf: server_read_data_buf,
         };
 
-        return (client, server);
+        (client, server)
     }
 }
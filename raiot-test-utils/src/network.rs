@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The conditions of the link between a device and the hub, applied by the mock broker to every packet.
+/// TCP hides packet loss from the application, so a lost segment shows up as a retransmission delay rather
+/// than as a missing packet. Withholding acknowledgements (see `PubackAction`) covers losses above TCP.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NetworkProfile {
+    /// One-way delay of every packet
+    pub latency: Duration,
+
+    /// Maximal random delay added to the latency of a packet
+    pub jitter: Duration,
+
+    /// Probability of a packet being lost and retransmitted, between 0 and 1
+    pub loss: f64,
+
+    /// Delay added by the retransmission of a lost packet
+    pub retransmission_timeout: Duration,
+}
+
+impl NetworkProfile {
+    /// A link with no delays, the behaviour of the mock broker without a profile
+    pub const IDEAL: NetworkProfile = NetworkProfile {
+        latency: Duration::ZERO,
+        jitter: Duration::ZERO,
+        loss: 0.0,
+        retransmission_timeout: Duration::ZERO,
+    };
+
+    /// A typical cellular LTE link
+    pub const LTE: NetworkProfile = NetworkProfile {
+        latency: Duration::from_millis(50),
+        jitter: Duration::from_millis(30),
+        loss: 0.005,
+        retransmission_timeout: Duration::from_millis(300),
+    };
+
+    /// A geostationary satellite link: slow and prone to loss
+    pub const SATELLITE: NetworkProfile = NetworkProfile {
+        latency: Duration::from_millis(600),
+        jitter: Duration::from_millis(150),
+        loss: 0.02,
+        retransmission_timeout: Duration::from_millis(1500),
+    };
+
+    /// A congested Wi-Fi network: low latency, but high jitter and frequent losses
+    pub const LOSSY_WIFI: NetworkProfile = NetworkProfile {
+        latency: Duration::from_millis(5),
+        jitter: Duration::from_millis(80),
+        loss: 0.1,
+        retransmission_timeout: Duration::from_millis(200),
+    };
+}
+
+/// What the network did to the packets that went through it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkStats {
+    /// Packets delivered
+    pub delivered: u64,
+
+    /// Packets that were lost and retransmitted at least once
+    pub retransmitted: u64,
+
+    /// Sum of the delays of the delivered packets
+    pub total_delay: Duration,
+
+    /// Longest delay of a delivered packet
+    pub max_delay: Duration,
+}
+
+impl NetworkStats {
+    /// Average delay of a delivered packet
+    pub fn mean_delay(&self) -> Duration {
+        match self.delivered {
+            0 => Duration::ZERO,
+            n => self.total_delay / n as u32,
+        }
+    }
+}
+
+/// A one-way link delaying packets according to a profile.
+/// Packets are delivered in the order they were sent, as on a TCP connection, so a delayed packet holds back the
+/// ones after it. Randomness comes from a seeded generator, making runs reproducible.
+pub struct NetworkLink<T> {
+    profile: NetworkProfile,
+    rng: XorShift,
+    in_flight: VecDeque<(Instant, Instant, T)>,
+    stats: NetworkStats,
+}
+
+impl<T> NetworkLink<T> {
+    pub fn new(profile: NetworkProfile, seed: u64) -> NetworkLink<T> {
+        NetworkLink {
+            profile,
+            rng: XorShift::new(seed),
+            in_flight: VecDeque::new(),
+            stats: NetworkStats::default(),
+        }
+    }
+
+    /// Sends a packet at the specified time
+    pub fn send(&mut self, packet: T, now: Instant) {
+        let mut due = now + self.profile.latency + self.profile.jitter.mul_f64(self.rng.next_f64());
+        let mut lost = false;
+        while self.profile.loss > 0.0 && self.rng.next_f64() < self.profile.loss {
+            lost = true;
+            due += self.profile.retransmission_timeout;
+        }
+        if lost {
+            self.stats.retransmitted += 1;
+        }
+        if let Some((last_due, _, _)) = self.in_flight.back() {
+            due = due.max(*last_due);
+        }
+        self.in_flight.push_back((due, now, packet));
+    }
+
+    /// Takes the next packet due for delivery at the specified time, if any
+    pub fn deliver(&mut self, now: Instant) -> Option<T> {
+        match self.in_flight.front() {
+            Some((due, _, _)) if *due <= now => {}
+            _ => return None,
+        }
+        let (due, sent_at, packet) = self.in_flight.pop_front()?;
+        let delay = due - sent_at;
+        self.stats.delivered += 1;
+        self.stats.total_delay += delay;
+        self.stats.max_delay = self.stats.max_delay.max(delay);
+        Some(packet)
+    }
+
+    /// When the next packet in flight is due, if any
+    pub fn next_delivery(&self) -> Option<Instant> {
+        self.in_flight.front().map(|(due, _, _)| *due)
+    }
+
+    /// The number of packets in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn profile(&self) -> NetworkProfile {
+        self.profile
+    }
+
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+}
+
+/// A xorshift64* generator: good enough for jitter, and needs no dependency
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // the state must never be zero
+        XorShift(seed.max(1))
+    }
+
+    /// A number uniformly distributed in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let value = self.0.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packets_are_delayed_in_order() {
+        let start = Instant::now();
+        let mut sut = NetworkLink::new(NetworkProfile::SATELLITE, 7);
+        for i in 0..100 {
+            sut.send(i, start);
+        }
+        assert_eq!(sut.deliver(start + Duration::from_millis(599)), None);
+
+        let mut delivered = Vec::new();
+        while let Some(due) = sut.next_delivery() {
+            delivered.push(sut.deliver(due).unwrap());
+        }
+        assert_eq!(delivered, (0..100).collect::<Vec<_>>());
+        let stats = sut.stats();
+        assert_eq!(stats.delivered, 100);
+        assert!(stats.mean_delay() >= NetworkProfile::SATELLITE.latency);
+        assert!(stats.max_delay >= stats.mean_delay());
+    }
+
+    #[test]
+    fn test_ideal_link_delivers_immediately() {
+        let now = Instant::now();
+        let mut sut = NetworkLink::new(NetworkProfile::IDEAL, 1);
+        sut.send("connack", now);
+        assert_eq!(sut.deliver(now), Some("connack"));
+        assert_eq!(sut.stats().retransmitted, 0);
+        assert_eq!(sut.stats().max_delay, Duration::ZERO);
+    }
+}
@@ -0,0 +1,73 @@
+use std::io::{Cursor, ErrorKind, Read};
+
+use mqtt::control::{fixed_header::FixedHeaderError, FixedHeader};
+use mqtt::packet::{VariablePacket, VariablePacketError};
+use mqtt::{Decodable, Encodable};
+
+use crate::MockServerSocket;
+
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Exchanges whole MQTT packets over the server end of a mock socket
+pub struct PacketChannel {
+    server: MockServerSocket,
+    rx_buf: Vec<u8>,
+}
+
+impl PacketChannel {
+    pub fn new(server: MockServerSocket) -> PacketChannel {
+        PacketChannel {
+            server,
+            rx_buf: Vec::new(),
+        }
+    }
+
+    /// Allows the client to write up to `size` bytes in a single write
+    pub fn allow_write(&mut self, size: usize) {
+        self.server.push_write_ctl(Ok(size));
+    }
+
+    /// Sends a packet to the client, making it readable in a single read
+    pub fn send(&mut self, packet: VariablePacket) {
+        let mut bytes = Vec::new();
+        packet.encode(&mut bytes).unwrap();
+        self.server.push_data(&bytes);
+        self.server.push_read_ctl(Ok(bytes.len()));
+    }
+
+    /// Fails the next read of the client with `ErrorKind::ConnectionReset`, as if the network dropped the connection
+    pub fn reset(&mut self) {
+        self.server
+            .push_read_ctl(Err(ErrorKind::ConnectionReset.into()));
+    }
+
+    /// The next complete packet written by the client, if any
+    ///
+    /// # Errors
+    /// Fails if the client wrote bytes which don't decode as an MQTT packet
+    pub fn recv(&mut self) -> Result<Option<VariablePacket>, VariablePacketError> {
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        loop {
+            let size = self.server.read(&mut chunk).unwrap();
+            if size == 0 {
+                break;
+            }
+            self.rx_buf.extend_from_slice(&chunk[..size]);
+        }
+
+        let packet_length = match FixedHeader::decode(&mut Cursor::new(&self.rx_buf[..])) {
+            Ok(header) => (header.encoded_length() + header.remaining_length) as usize,
+            // Incomplete fixed header: wait for the rest of it
+            Err(FixedHeaderError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if self.rx_buf.len() < packet_length {
+            // Incomplete packet: wait for the rest of it
+            return Ok(None);
+        }
+
+        let packet = VariablePacket::decode(&mut Cursor::new(&self.rx_buf[..packet_length]))?;
+        let _ = self.rx_buf.drain(..packet_length);
+        Ok(Some(packet))
+    }
+}
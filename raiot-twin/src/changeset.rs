@@ -0,0 +1,269 @@
+use serde_json::{Map, Value};
+
+use raiot_protocol::twin::DesiredPropsUpdated;
+
+const VERSION_KEY: &str = "$version";
+const METADATA_KEY: &str = "$metadata";
+
+/// A single change to the desired properties.
+/// Paths are dot-separated property names (e.g. `sensors.temp.interval`); IoT Hub forbids dots in property names.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DesiredChange {
+    /// A property that did not exist before
+    Added {
+        /// The property path
+        path: String,
+
+        /// The new value
+        value: Value,
+    },
+
+    /// A property whose value was replaced
+    Changed {
+        /// The property path
+        path: String,
+
+        /// The previous value
+        old: Value,
+
+        /// The new value
+        new: Value,
+    },
+
+    /// A property that no longer exists
+    Removed {
+        /// The property path
+        path: String,
+
+        /// The previous value
+        old: Value,
+    },
+}
+
+impl DesiredChange {
+    /// The path of the changed property
+    pub fn path(&self) -> &str {
+        match self {
+            DesiredChange::Added { path, .. }
+            | DesiredChange::Changed { path, .. }
+            | DesiredChange::Removed { path, .. } => path,
+        }
+    }
+}
+
+/// The changes between two versions of the desired properties
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DesiredChangeset {
+    /// The version of the desired properties after the changes, if known
+    pub version: Option<u64>,
+
+    /// The changes, ordered by path
+    pub changes: Vec<DesiredChange>,
+}
+
+impl DesiredChangeset {
+    /// Compares two full desired sections. The `$version` and `$metadata` keys are ignored.
+    pub fn between(previous: &Map<String, Value>, current: &Map<String, Value>) -> DesiredChangeset {
+        let mut changes = Vec::new();
+        diff_objects("", previous, current, &mut changes);
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        DesiredChangeset {
+            version: current.get(VERSION_KEY).and_then(Value::as_u64),
+            changes,
+        }
+    }
+
+    /// Returns TRUE if nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The change made to the specified path, if any
+    pub fn get(&self, path: &str) -> Option<&DesiredChange> {
+        self.changes.iter().find(|change| change.path() == path)
+    }
+}
+
+/// Tracks the desired properties section, turning hub notifications into changesets
+#[derive(Debug, Clone, Default)]
+pub struct DesiredProperties {
+    current: Map<String, Value>,
+    version: Option<u64>,
+}
+
+impl DesiredProperties {
+    /// Tracks an empty desired section
+    pub fn new() -> DesiredProperties {
+        DesiredProperties::default()
+    }
+
+    /// The current desired section
+    pub fn current(&self) -> &Map<String, Value> {
+        &self.current
+    }
+
+    /// The version of the current desired section, if known
+    pub fn version(&self) -> Option<u64> {
+        self.version
+    }
+
+    /// Replaces the tracked section with a full desired section (e.g. from a twin read)
+    pub fn replace(&mut self, desired: Map<String, Value>) -> DesiredChangeset {
+        let changeset = DesiredChangeset::between(&self.current, &desired);
+        self.version = changeset.version;
+        self.current = desired;
+        changeset
+    }
+
+    /// Applies a desired properties update notification, in which null values remove properties.
+    /// Updates that are not newer than the tracked version produce an empty changeset.
+    pub fn apply(&mut self, update: &DesiredPropsUpdated) -> DesiredChangeset {
+        let version = update.desired_properties_version;
+        if self.version.is_some_and(|current| version <= current) {
+            return DesiredChangeset {
+                version: self.version,
+                changes: Vec::new(),
+            };
+        }
+
+        let mut desired = self.current.clone();
+        merge_patch(&mut desired, &update.body);
+        let _ = desired.insert(VERSION_KEY.to_owned(), Value::from(version));
+        self.replace(desired)
+    }
+}
+
+fn diff_objects(
+    prefix: &str,
+    previous: &Map<String, Value>,
+    current: &Map<String, Value>,
+    changes: &mut Vec<DesiredChange>,
+) {
+    let is_metadata = |key: &str| prefix.is_empty() && (key == VERSION_KEY || key == METADATA_KEY);
+
+    for (key, new) in current.iter().filter(|(key, _)| !is_metadata(key)) {
+        let path = join(prefix, key);
+        match (previous.get(key), new) {
+            (None, _) => changes.push(DesiredChange::Added {
+                path,
+                value: new.clone(),
+            }),
+            (Some(Value::Object(old)), Value::Object(new)) => diff_objects(&path, old, new, changes),
+            (Some(old), new) if old != new => changes.push(DesiredChange::Changed {
+                path,
+                old: old.clone(),
+                new: new.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    for (key, old) in previous.iter().filter(|(key, _)| !is_metadata(key)) {
+        if !current.contains_key(key) {
+            changes.push(DesiredChange::Removed {
+                path: join(prefix, key),
+                old: old.clone(),
+            });
+        }
+    }
+}
+
+fn merge_patch(target: &mut Map<String, Value>, patch: &Map<String, Value>) {
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                let _ = target.remove(key);
+            }
+            Value::Object(patch) => {
+                let entry = target
+                    .entry(key.clone())
+                    .or_insert_with(|| Value::Object(Map::new()));
+                if !entry.is_object() {
+                    *entry = Value::Object(Map::new());
+                }
+                if let Value::Object(target) = entry {
+                    merge_patch(target, patch);
+                }
+            }
+            value => {
+                let _ = target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("Expected an object"),
+        }
+    }
+
+    #[test]
+    fn test_changeset_lists_leaf_changes() {
+        let previous = object(json!({
+            "$version": 1,
+            "interval": 10,
+            "sensors": { "temp": true, "humidity": true },
+        }));
+        let current = object(json!({
+            "$version": 2,
+            "interval": 20,
+            "sensors": { "temp": true },
+            "mode": "eco",
+        }));
+
+        let changeset = DesiredChangeset::between(&previous, &current);
+        assert_eq!(changeset.version, Some(2));
+        assert_eq!(
+            changeset.changes,
+            vec![
+                DesiredChange::Changed {
+                    path: "interval".to_owned(),
+                    old: json!(10),
+                    new: json!(20),
+                },
+                DesiredChange::Added {
+                    path: "mode".to_owned(),
+                    value: json!("eco"),
+                },
+                DesiredChange::Removed {
+                    path: "sensors.humidity".to_owned(),
+                    old: json!(true),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_patches_are_applied_in_version_order() {
+        let mut sut = DesiredProperties::new();
+        let _ = sut.replace(object(json!({ "$version": 3, "interval": 10, "mode": "eco" })));
+
+        let update = DesiredPropsUpdated {
+            packet_id: None,
+            body: object(json!({ "mode": null, "interval": 30 })),
+            desired_properties_version: 4,
+        };
+        let changeset = sut.apply(&update);
+        assert_eq!(changeset.version, Some(4));
+        assert_eq!(changeset.changes.len(), 2);
+        assert!(matches!(changeset.get("mode"), Some(DesiredChange::Removed { .. })));
+        assert_eq!(sut.current()["interval"], json!(30));
+
+        assert!(sut.apply(&update).is_empty());
+    }
+}
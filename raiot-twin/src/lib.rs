@@ -1,166 +1,665 @@
-extern crate mqtt;
-extern crate native_tls;
-extern crate serde;
-extern crate serde_json;
-extern crate uuid;
-extern crate raiot_types;
-extern crate raiot_codec;
-
-use raiot_codec::iot_codec::IotCodec;
-use serde_json::Value;
-use std::collections::HashMap;
-use native_tls::{TlsStream};
+//! Device Twin operations over a nonblocking MQTT connection
+
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
-use std::io::{self, Write};
-use std::net::TcpStream;
-use std::str;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use log::{debug, warn};
+use raiot_client_base::{PacketsNumerator, RequestIdSource};
+use raiot_errors::{ClientError, ProtocolError};
+use raiot_mqtt::connection::MqttConnection;
+use raiot_mqtt::packets::StreamerError;
+use raiot_protocol::qos::{DeliveryGuarantees, PacketId};
+use raiot_protocol::redact::redact;
+use raiot_protocol::compression::CompressionPolicy;
+use raiot_protocol::twin::*;
+use raiot_protocol::{AckMsg, CodecError, IotCodec, MsgFromHub, MsgToHub, SubError};
+use serde_json::{Map, Value};
 
-use uuid::Uuid;
+/// Change-tracked twin state
+pub mod tracked;
 
-use mqtt::packet::{
-    Packet, VariablePacket, PacketError
-};
-use mqtt::{Decodable};
+/// Typed changesets of the desired properties
+pub mod changeset;
 
-use raiot_types::*;
-use raiot_types::twin::*;
-use raiot_codec::*;
-use raiot_types::StatusCode::*;
-use raiot_types::MessageFromServer::*;
+pub use crate::changeset::{DesiredChange, DesiredChangeset, DesiredProperties};
+pub use crate::tracked::TrackedTwin;
 
-/// Errors while parsing topic names
+/// Errors returned by twin operations
 #[derive(Debug)]
-pub enum DeviceTwinError {
-    //FeatureNotSupported(),
-    BadRequest(),
-    Failure(String),
+pub enum TwinError {
+    /// The hub rejected the request as malformed
+    BadRequest,
+
+    /// The request was throttled by the hub
+    TooManyRequests,
+
+    /// The hub failed processing the request
     ServerError(u16),
+
+    /// The hub responded with an unexpected status code
     UnknownError(u16),
-    TooManyRequests(),
-    IoError(io::Error)
+
+    /// The hub rejected the subscription to twin responses or updates
+    SubscriptionFailed(SubError),
+
+    /// The twin returned by the hub could not be parsed
+    InvalidTwin,
+
+    /// A packet from the hub could not be decoded
+    Codec(CodecError),
+
+    /// The underlying connection failed
+    Io(io::Error),
 }
 
-impl fmt::Display for DeviceTwinError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl TwinError {
+    /// Returns TRUE if the request may succeed when retried later: it was throttled,
+    /// or failed with a transient server error (e.g. during hub maintenance)
+    pub fn is_retryable(&self) -> bool {
         match self {
-            //&DeviceTwinError::FeatureNotSupported() => write!(f, "Device Twins are not supported"),
-            &DeviceTwinError::Failure(ref error) => write!(f, "Failure getting device twin ({})", error),
-            &DeviceTwinError::TooManyRequests() => write!(f, "Too many requests (throttled)"),
-            &DeviceTwinError::ServerError(error_code) => write!(f, "Server Error {}", error_code),
-            &DeviceTwinError::IoError(ref io_error) => io_error.fmt(f),
-            &DeviceTwinError::BadRequest() => write!(f, "Bad request"),
-            &DeviceTwinError::UnknownError(error_code) => write!(f, "Unknown error {}", error_code),          
+            TwinError::TooManyRequests => true,
+            TwinError::ServerError(code) => StatusCode::ServerError(*code).is_transient(),
+            _ => false,
         }
     }
 }
 
-impl Error for DeviceTwinError {
-    fn description(&self) -> &str {
+impl From<StreamerError> for TwinError {
+    fn from(e: StreamerError) -> Self {
+        TwinError::Io(e.into())
+    }
+}
+
+impl fmt::Display for TwinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            //&DeviceTwinError::FeatureNotSupported() => "Device Twins are not supported for this IoT Hub",
-            &DeviceTwinError::ServerError(_) => "Server error occured",
-            &DeviceTwinError::TooManyRequests() => "Too many requests (throttled)",
-            &DeviceTwinError::Failure(ref error) => error,
-            &DeviceTwinError::IoError(ref io_error) => io_error.description(),
-            &DeviceTwinError::BadRequest() => "Bad request",
-            &DeviceTwinError::UnknownError(_error_code) => "Unknown error",    
+            TwinError::BadRequest => write!(f, "Bad request"),
+            TwinError::TooManyRequests => write!(f, "Too many requests (throttled)"),
+            TwinError::ServerError(code) => write!(f, "Server error {}", code),
+            TwinError::UnknownError(code) => write!(f, "Unknown error {}", code),
+            TwinError::SubscriptionFailed(e) => write!(f, "Twin subscription failed: {}", e),
+            TwinError::InvalidTwin => write!(f, "Invalid twin document"),
+            TwinError::Codec(e) => write!(f, "Codec error: {}", e),
+            TwinError::Io(e) => write!(f, "I/O error: {}", e),
         }
     }
+}
 
-    fn cause(&self) -> Option<&dyn Error> {
+impl Error for TwinError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            //&DeviceTwinError::FeatureNotSupported() => None,
-            &DeviceTwinError::Failure(ref _error) => None,
+            TwinError::SubscriptionFailed(e) => Some(e),
+            TwinError::Codec(e) => Some(e),
+            TwinError::Io(e) => Some(e),
             _ => None,
         }
     }
 }
 
-impl From<io::Error> for DeviceTwinError {
-    fn from(item: io::Error) -> Self {
-        DeviceTwinError::Failure(format!("I/O Error occured: {}", item.description()))
+impl From<io::Error> for TwinError {
+    fn from(e: io::Error) -> Self {
+        TwinError::Io(e)
     }
 }
 
-impl<T: Packet> From<PacketError<T>> for DeviceTwinError {
-    fn from(item: PacketError<T>) -> Self {
-        return match item {
-            PacketError::IoError(io_err) => DeviceTwinError::IoError(io_err),
-            _ => DeviceTwinError::Failure(format!("Packet Error occured")) // TODO proper error propagation
+impl From<CodecError> for TwinError {
+    fn from(e: CodecError) -> Self {
+        TwinError::Codec(e)
+    }
+}
+
+impl From<TwinError> for ClientError {
+    fn from(e: TwinError) -> Self {
+        match e {
+            TwinError::BadRequest => ClientError::Rejected(400),
+            TwinError::TooManyRequests => ClientError::Rejected(429),
+            TwinError::ServerError(code) | TwinError::UnknownError(code) => ClientError::Rejected(code),
+            TwinError::SubscriptionFailed(e) => e.into(),
+            TwinError::InvalidTwin => ProtocolError::Codec(CodecError::InvalidMessageBody).into(),
+            TwinError::Codec(e) => e.into(),
+            TwinError::Io(e) => e.into(),
         }
     }
 }
 
-pub fn update_reported_properties(mut stream: &mut TlsStream<TcpStream>, reported: HashMap<String, Value>) -> Result<(), DeviceTwinError> {
-    let codec = IotCodec; //TODO
-    let rid = Uuid::new_v4();
-    let buf = codec.encode_twin_update(rid, reported, Option::Some(10)).unwrap();
-    
-    stream.write_all(&buf[..])?;
-
-    let packet2 = match VariablePacket::decode(&mut stream) {
-        Ok(pk) => pk,
-        Err(err) => {
-            debug!("Error in receiving packet {}", err);
-            return Err(DeviceTwinError::Failure(
-                "Unexpected response packet".to_string(),
-            ));
-        }
-    };
-    debug!("PACKET after update {:?}", packet2);
-
-    return match packet2 {
-        VariablePacket::PublishPacket(ref _publ) => Ok(()),
-        _ => Err(DeviceTwinError::Failure("Unexpected response packet".to_string()))
-    };
+/// The outcome of a twin operation, or a notification from the hub
+#[derive(Debug)]
+pub enum TwinEvent {
+    /// The twin requested by `request_twin`
+    TwinReceived {
+        /// The identifier returned by `request_twin`
+        request_id: String,
+
+        /// The twin
+        twin: Twin,
+    },
+
+    /// The reported properties update was accepted
+    ReportedPropertiesUpdated {
+        /// The identifier returned by `update_reported_properties`
+        request_id: String,
+
+        /// The new version of the reported properties
+        version: Option<u64>,
+    },
+
+    /// The desired properties were updated by the backend.
+    /// Changes are relative to the last twin read or update; before the first twin read, to an empty section.
+    DesiredPropertiesUpdated(DesiredChangeset),
+
+    /// The hub rejected a request
+    RequestFailed {
+        /// The identifier of the failed request
+        request_id: String,
+
+        /// The failure
+        error: TwinError,
+    },
 }
 
-pub fn read_twin(mut stream: &mut TlsStream<TcpStream>) -> Result<Twin, DeviceTwinError> {
-    let codec = IotCodec; //TODO
-    subscribe_to_twin_result(&mut stream)?;
-    
-    let packet = codec.decode(stream);
-    match packet {
-        Ok(SubscriptionSucceeded(pkid)) => { },
-        Ok(other) => { panic!("unexpected response") }
-        Err(e) => panic!(e)
-    };
-
-    let rid = Uuid::new_v4();
-    let buf = codec.encode_read_twin(rid, Option::None).unwrap();   
-    stream.write_all(&buf[..])?;
-
-    let packet2 = codec.decode(stream);
-    let packet2 = packet2.unwrap();
-
-    return match packet2 {
-        MessageFromServer::TwinResponseMessage(ref resp) => parse_twin_packet(resp),
-        _ => Err(DeviceTwinError::Failure("Unexpected response packet".to_string()))
-    };
+#[derive(Copy, Clone, Debug)]
+enum RequestKind {
+    Read(TwinSection),
+    UpdateReported,
 }
 
-fn subscribe_to_twin_result(stream: &mut TlsStream<TcpStream>) -> Result<(), DeviceTwinError> {
-    let codec = IotCodec; //TODO
-    let buf = codec.encode_twin_subscription(10).unwrap();
-    stream.write_all(&buf[..])?;
-    Ok(())
+#[derive(Copy, Clone, Debug)]
+enum SubscriptionState {
+    Unsubscribed,
+    Subscribing(PacketId),
+    Subscribed,
 }
 
-fn parse_twin_packet(packet: &TwinResponse) -> Result<Twin, DeviceTwinError> {
-    return match packet.status_code() {
-        StatusCode::OK() => Ok(Twin::new(&packet.body.as_ref().unwrap())),
-        other => Err(error_code_to_twin_error(other))
-    };
+/// Performs twin reads and updates over an established MQTT connection
+///
+/// All operations are nonblocking: requests are buffered and transmitted by `poll`,
+/// which also returns the responses and notifications received from the hub.
+pub struct TwinSession<S: Read + Write> {
+    connection: MqttConnection<S>,
+    packets_numerator: PacketsNumerator,
+    responses: SubscriptionState,
+    desired_updates: SubscriptionState,
+    // the requests awaiting the subscription to twin responses, with their request identifiers
+    queued: VecDeque<(String, MsgToHub)>,
+    requests: HashMap<String, RequestKind>,
+    failed: VecDeque<TwinEvent>,
+    desired: DesiredProperties,
+    request_ids: RequestIdSource,
+    compression: Option<CompressionPolicy>,
 }
 
-fn error_code_to_twin_error(code: &StatusCode) -> DeviceTwinError {
-    return match code {
-        TooManyRequests() => DeviceTwinError::TooManyRequests(),
-        BadRequest() => DeviceTwinError::BadRequest(),
-        ServerError(error_code) => DeviceTwinError::ServerError(*error_code),
-        UnknownStatusCode(error_code) => DeviceTwinError::UnknownError(*error_code),
-        OK() => panic!("Success was passed to error_code_to_twin_error"),
-        NoContent() => panic!("Success was passed to error_code_to_twin_error")
-    };
-}
\ No newline at end of file
+impl<S: Read + Write> TwinSession<S> {
+    /// Creates a twin session over a connected MQTT connection
+    pub fn new(connection: MqttConnection<S>) -> TwinSession<S> {
+        TwinSession {
+            connection,
+            packets_numerator: PacketsNumerator::new(),
+            responses: SubscriptionState::Unsubscribed,
+            desired_updates: SubscriptionState::Unsubscribed,
+            queued: VecDeque::new(),
+            requests: HashMap::new(),
+            failed: VecDeque::new(),
+            desired: DesiredProperties::new(),
+            request_ids: RequestIdSource::default(),
+            compression: None,
+        }
+    }
+
+    /// Sets the strategy used to generate request identifiers. Defaults to UUIDs.
+    pub fn set_request_id_source(&mut self, source: RequestIdSource) {
+        self.request_ids = source;
+    }
+
+    /// Compresses reported properties payloads according to the policy. Defaults to no compression.
+    /// Only for endpoints which decompress reported properties (e.g. an IoT Edge module): IoT Hub itself expects plain JSON.
+    pub fn set_compression(&mut self, policy: CompressionPolicy) {
+        self.compression = Some(policy);
+    }
+
+    /// Requests the full twin. Returns the request identifier, which will appear in the matching `TwinEvent`.
+    pub fn request_twin(&mut self) -> Result<String, TwinError> {
+        self.request_twin_section(TwinSection::Full)
+    }
+
+    /// Requests the twin, keeping only the specified section: the other section of the received twin is empty.
+    /// Returns the request identifier, which will appear in the matching `TwinEvent`.
+    pub fn request_twin_section(&mut self, section: TwinSection) -> Result<String, TwinError> {
+        let request_id = self.request_ids.next_id();
+        let msg = ReadTwinReq {
+            request_id: request_id.clone(),
+            packet_id: None,
+            section,
+        };
+        self.submit(msg.into(), &request_id, RequestKind::Read(section))?;
+        Ok(request_id)
+    }
+
+    /// Requests an update of the reported properties. Returns the request identifier, which will appear in the matching `TwinEvent`.
+    pub fn update_reported_properties(
+        &mut self,
+        reported: Map<String, Value>,
+    ) -> Result<String, TwinError> {
+        let request_id = self.request_ids.next_id();
+        let msg = UpdateReportedPropsReq {
+            request_id: request_id.clone(),
+            reported,
+            packet_id: None,
+            compression: self.compression.clone(),
+        };
+        self.submit(msg.into(), &request_id, RequestKind::UpdateReported)?;
+        Ok(request_id)
+    }
+
+    /// The desired properties, as of the last twin read or update
+    pub fn desired_properties(&self) -> &DesiredProperties {
+        &self.desired
+    }
+
+    /// Subscribes to desired properties update notifications
+    pub fn subscribe_to_desired_properties(&mut self) -> Result<(), TwinError> {
+        if let SubscriptionState::Unsubscribed = self.desired_updates {
+            let packet_id = self.packets_numerator.next_id();
+            self.write(&TwinUpdatesSub {
+                packet_id,
+                mode: DeliveryGuarantees::AtMostOnce,
+            }
+            .into())?;
+            self.desired_updates = SubscriptionState::Subscribing(packet_id);
+        }
+        Ok(())
+    }
+
+    /// Sends pending data, and receives data until a twin event is available or the socket would block.
+    /// Each transfer direction is limited to the specified duration.
+    ///
+    /// If the hub rejects the subscription to twin responses, the error is returned, the requests awaiting it
+    /// are failed by the following polls, and the next request subscribes again.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<TwinEvent>, TwinError> {
+        if let Some(event) = self.failed.pop_front() {
+            return Ok(Some(event));
+        }
+        let _ = self.connection.send_task(timeout)?;
+        let _ = self.connection.recv_task(timeout)?;
+        while let Some(packet) = self.connection.read()? {
+            let msg = IotCodec::decode_packet(packet)?;
+            if let Some(event) = self.process_msg(msg)? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    fn submit(
+        &mut self,
+        msg: MsgToHub,
+        request_id: &str,
+        kind: RequestKind,
+    ) -> Result<(), TwinError> {
+        match self.responses {
+            SubscriptionState::Subscribed => self.write(&msg)?,
+            SubscriptionState::Subscribing(_) => {
+                self.queued.push_back((request_id.to_owned(), msg))
+            }
+            SubscriptionState::Unsubscribed => {
+                let packet_id = self.packets_numerator.next_id();
+                self.write(&TwinReadSub {
+                    packet_id,
+                    mode: DeliveryGuarantees::AtMostOnce,
+                }
+                .into())?;
+                self.responses = SubscriptionState::Subscribing(packet_id);
+                self.queued.push_back((request_id.to_owned(), msg));
+            }
+        }
+        let _ = self.requests.insert(request_id.to_owned(), kind);
+        Ok(())
+    }
+
+    fn write(&mut self, msg: &MsgToHub) -> Result<(), TwinError> {
+        let packet = IotCodec::encode_message(msg)?;
+        self.connection.write(&packet)?;
+        Ok(())
+    }
+
+    fn process_msg(&mut self, msg: MsgFromHub) -> Result<Option<TwinEvent>, TwinError> {
+        match msg {
+            MsgFromHub::SubscriptionResponseMessage(res) => {
+                if let SubscriptionState::Subscribing(packet_id) = self.responses {
+                    if packet_id == res.packet_id {
+                        if let Err(e) = res.result {
+                            self.fail_requests(&e);
+                            return Err(TwinError::SubscriptionFailed(e));
+                        }
+                        self.responses = SubscriptionState::Subscribed;
+                        while let Some((request_id, queued)) = self.queued.pop_front() {
+                            if let Err(e) = self.write(&queued) {
+                                self.queued.push_front((request_id, queued));
+                                self.fail_queued(&e);
+                                return Err(e);
+                            }
+                        }
+                        return Ok(None);
+                    }
+                }
+                if let SubscriptionState::Subscribing(packet_id) = self.desired_updates {
+                    if packet_id == res.packet_id {
+                        if let Err(e) = res.result {
+                            self.desired_updates = SubscriptionState::Unsubscribed;
+                            return Err(TwinError::SubscriptionFailed(e));
+                        }
+                        self.desired_updates = SubscriptionState::Subscribed;
+                    }
+                }
+                Ok(None)
+            }
+            MsgFromHub::TwinResponseMessage(res) => {
+                self.acknowledge(res.packet_id)?;
+                Ok(self.process_twin_response(res))
+            }
+            MsgFromHub::DesiredPropertiesUpdated(update) => {
+                self.acknowledge(update.packet_id)?;
+                let changeset = self.desired.apply(&update);
+                if changeset.is_empty() {
+                    debug!("Ignoring stale desired properties update: {}", update.desired_properties_version);
+                    return Ok(None);
+                }
+                Ok(Some(TwinEvent::DesiredPropertiesUpdated(changeset)))
+            }
+            other => {
+                debug!("Ignoring message: {}", redact(&other));
+                Ok(None)
+            }
+        }
+    }
+
+    // the requests queued until the subscription to twin responses can't be sent anymore
+    fn fail_requests(&mut self, error: &SubError) {
+        self.responses = SubscriptionState::Unsubscribed;
+        self.queued.clear();
+        for (request_id, _) in self.requests.drain() {
+            self.failed.push_back(TwinEvent::RequestFailed {
+                request_id,
+                error: TwinError::SubscriptionFailed(error.clone()),
+            });
+        }
+    }
+
+    // the queued requests from the one which could not be written on, which would otherwise never be sent
+    fn fail_queued(&mut self, error: &TwinError) {
+        let kind = match error {
+            TwinError::Io(e) => e.kind(),
+            _ => io::ErrorKind::InvalidData,
+        };
+        warn!(
+            "Failing {} queued twin requests: {}",
+            self.queued.len(),
+            error
+        );
+        for (request_id, _) in self.queued.drain(..) {
+            let _ = self.requests.remove(&request_id);
+            self.failed.push_back(TwinEvent::RequestFailed {
+                request_id,
+                error: TwinError::Io(kind.into()),
+            });
+        }
+    }
+
+    fn process_twin_response(&mut self, mut res: ReadTwinRes) -> Option<TwinEvent> {
+        let kind = match self.requests.remove(&res.request_id) {
+            Some(kind) => kind,
+            None => {
+                debug!("Got a twin response for an unknown request: {}", res.request_id);
+                return None;
+            }
+        };
+
+        if let RequestKind::Read(section) = kind {
+            res.retain_section(section);
+        }
+        let request_id = res.request_id;
+        let event = match (kind, res.status_code) {
+            (RequestKind::Read(section), StatusCode::OK()) => {
+                match res.body.map(serde_json::from_value::<Twin>) {
+                    Some(Ok(twin)) => {
+                        if section != TwinSection::ReportedOnly {
+                            let _ = self.desired.replace(twin.desired.clone().into_iter().collect());
+                        }
+                        TwinEvent::TwinReceived { request_id, twin }
+                    }
+                    _ => TwinEvent::RequestFailed {
+                        request_id,
+                        error: TwinError::InvalidTwin,
+                    },
+                }
+            }
+            (RequestKind::UpdateReported, StatusCode::OK())
+            | (RequestKind::UpdateReported, StatusCode::NoContent()) => {
+                TwinEvent::ReportedPropertiesUpdated {
+                    request_id,
+                    version: res.version,
+                }
+            }
+            (_, status_code) => TwinEvent::RequestFailed {
+                request_id,
+                error: status_code_to_error(status_code),
+            },
+        };
+        Some(event)
+    }
+
+    fn acknowledge(&mut self, packet_id: Option<PacketId>) -> Result<(), TwinError> {
+        if let Some(packet_id) = packet_id {
+            self.write(&AckMsg { packet_id }.into())?;
+        }
+        Ok(())
+    }
+}
+
+fn status_code_to_error(code: StatusCode) -> TwinError {
+    match code {
+        StatusCode::TooManyRequests() => TwinError::TooManyRequests,
+        StatusCode::BadRequest() => TwinError::BadRequest,
+        StatusCode::ServerError(code) => TwinError::ServerError(code),
+        StatusCode::UnknownStatusCode(code) => TwinError::UnknownError(code),
+        StatusCode::OK() => TwinError::UnknownError(200),
+        StatusCode::NoContent() => TwinError::UnknownError(204),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt::control::variable_header::ConnectReturnCode;
+    use mqtt::packet::suback::SubscribeReturnCode;
+    use mqtt::packet::*;
+    use mqtt::{Encodable, TopicName};
+    use raiot_mqtt::connection::{MqttConnectError, MqttConnector};
+    use raiot_test_utils::{MockClientSocket, MockServerSocket, MockSocket};
+    use raiot_protocol::topics;
+
+    const TIMEOUT: Duration = Duration::from_millis(5);
+
+    fn push_packet(server: &mut MockServerSocket, packet: VariablePacket) {
+        let mut bytes: Vec<u8> = Vec::new();
+        packet.encode(&mut bytes).unwrap();
+        server.push_data(&bytes);
+        server.push_read_ctl(Ok(bytes.len()));
+    }
+
+    fn connected_session() -> (TwinSession<MockClientSocket>, MockServerSocket) {
+        let (client, mut server) = MockSocket::create();
+        push_packet(
+            &mut server,
+            ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted).into(),
+        );
+        server.push_write_ctl(Ok(8 * 1024));
+        let mut in_progress = MqttConnector::create(client)
+            .connect(ConnectPacket::new("device"))
+            .unwrap();
+        let connection = loop {
+            match in_progress.complete() {
+                Ok(connection) => break connection,
+                Err(MqttConnectError::WouldBlock(next)) => in_progress = *next,
+                Err(_) => panic!("Connection failed"),
+            }
+        };
+        (TwinSession::new(connection), server)
+    }
+
+    fn publish(topic: String, payload: &str) -> VariablePacket {
+        PublishPacket::new(
+            TopicName::new(topic).unwrap(),
+            QoSWithPacketIdentifier::Level0,
+            payload,
+        )
+        .into()
+    }
+
+    #[test]
+    fn test_read_twin_waits_for_subscription() {
+        let (mut sut, mut server) = connected_session();
+        server.push_write_ctl(Ok(8 * 1024));
+        let request_id = sut.request_twin().unwrap();
+        assert!(sut.poll(TIMEOUT).unwrap().is_none());
+
+        server.push_write_ctl(Ok(8 * 1024));
+        push_packet(
+            &mut server,
+            SubackPacket::new(1, vec![SubscribeReturnCode::MaximumQoSLevel0]).into(),
+        );
+        assert!(sut.poll(TIMEOUT).unwrap().is_none());
+
+        push_packet(
+            &mut server,
+            publish(
+                topics::twin_response(200, &request_id),
+                r#"{"desired":{"$version":1},"reported":{"$version":2}}"#,
+            ),
+        );
+        match sut.poll(TIMEOUT).unwrap() {
+            Some(TwinEvent::TwinReceived { request_id: id, twin }) => {
+                assert_eq!(id, request_id);
+                assert_eq!(twin.reported["$version"], 2);
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_throttled_update_is_reported_as_failure() {
+        let (mut sut, mut server) = connected_session();
+        server.push_write_ctl(Ok(8 * 1024));
+        let request_id = sut.update_reported_properties(Map::new()).unwrap();
+        push_packet(
+            &mut server,
+            SubackPacket::new(1, vec![SubscribeReturnCode::MaximumQoSLevel0]).into(),
+        );
+        assert!(sut.poll(TIMEOUT).unwrap().is_none());
+
+        push_packet(&mut server, publish(topics::twin_response(429, &request_id), ""));
+        match sut.poll(TIMEOUT).unwrap() {
+            Some(TwinEvent::RequestFailed {
+                request_id: id,
+                error: TwinError::TooManyRequests,
+            }) => assert_eq!(id, request_id),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejected_subscription_returns_error() {
+        let (mut sut, mut server) = connected_session();
+        server.push_write_ctl(Ok(8 * 1024));
+        let _ = sut.request_twin().unwrap();
+        push_packet(
+            &mut server,
+            SubackPacket::new(1, vec![SubscribeReturnCode::Failure]).into(),
+        );
+        match sut.poll(TIMEOUT) {
+            Err(TwinError::SubscriptionFailed(SubError::Failure { return_code: 0x80, .. })) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_requests_are_failed_with_the_subscription() {
+        let (mut sut, mut server) = connected_session();
+        server.push_write_ctl(Ok(8 * 1024));
+        let rejected_id = sut.request_twin().unwrap();
+        push_packet(
+            &mut server,
+            SubackPacket::new(1, vec![SubscribeReturnCode::Failure]).into(),
+        );
+        assert!(sut.poll(TIMEOUT).is_err());
+        match sut.poll(TIMEOUT).unwrap() {
+            Some(TwinEvent::RequestFailed {
+                request_id,
+                error: TwinError::SubscriptionFailed(_),
+            }) => assert_eq!(request_id, rejected_id),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+
+        // subscribes again
+        server.push_write_ctl(Ok(8 * 1024));
+        let request_id = sut.request_twin().unwrap();
+        server.push_write_ctl(Ok(8 * 1024));
+        push_packet(
+            &mut server,
+            SubackPacket::new(2, vec![SubscribeReturnCode::MaximumQoSLevel0]).into(),
+        );
+        assert!(sut.poll(TIMEOUT).unwrap().is_none());
+        push_packet(
+            &mut server,
+            publish(topics::twin_response(200, &request_id), r#"{"desired":{},"reported":{}}"#),
+        );
+        match sut.poll(TIMEOUT).unwrap() {
+            Some(TwinEvent::TwinReceived { request_id: id, .. }) => assert_eq!(id, request_id),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_queued_requests_are_failed_when_they_cannot_be_sent() {
+        let (mut sut, mut server) = connected_session();
+        server.push_write_ctl(Ok(8 * 1024));
+        let first_id = sut.request_twin().unwrap();
+        // larger than the tx buffer of the connection
+        let mut reported = Map::new();
+        let _ = reported.insert("blob".to_owned(), "x".repeat(1024 * 1024).into());
+        let second_id = sut.update_reported_properties(reported).unwrap();
+        push_packet(
+            &mut server,
+            SubackPacket::new(1, vec![SubscribeReturnCode::MaximumQoSLevel0]).into(),
+        );
+        match sut.poll(TIMEOUT) {
+            Err(TwinError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match sut.poll(TIMEOUT).unwrap() {
+            Some(TwinEvent::RequestFailed {
+                request_id,
+                error: TwinError::Io(e),
+            }) => {
+                assert_eq!(request_id, second_id);
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+
+        // the request written before the failure is still answered
+        push_packet(
+            &mut server,
+            publish(
+                topics::twin_response(200, &first_id),
+                r#"{"desired":{},"reported":{}}"#,
+            ),
+        );
+        match sut.poll(TIMEOUT).unwrap() {
+            Some(TwinEvent::TwinReceived { request_id, .. }) => assert_eq!(request_id, first_id),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+}
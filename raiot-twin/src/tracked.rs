@@ -0,0 +1,299 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{TwinError, TwinEvent, TwinSession};
+
+/// A user state struct mirrored into the twin's reported properties
+///
+/// Mutations are made through `update`, which marks the state as changed.
+/// Changes are reported as a patch containing only the modified paths, and rapid changes are coalesced:
+/// a report is produced no sooner than the coalescing interval after the first unreported change.
+///
+/// One report is in flight at a time. It becomes the baseline of the next patches only once the hub accepted it
+/// (see `handle_event`); a failed report is merged into the next one.
+#[derive(Debug)]
+pub struct TrackedTwin<T: Serialize> {
+    state: T,
+    last_reported: Value,
+    changed_at: Option<Instant>,
+    coalescing_interval: Duration,
+    in_flight: Option<InFlightReport>,
+}
+
+/// A report awaiting the hub's response
+#[derive(Debug)]
+struct InFlightReport {
+    // the update request, once sent by `auto_report`
+    request_id: Option<String>,
+    // the reported state, the baseline once accepted
+    snapshot: Value,
+    // the first change of the report, restored if it fails
+    changed_at: Option<Instant>,
+}
+
+impl<T: Serialize> TrackedTwin<T> {
+    /// Tracks the specified state. The first report will contain the entire state.
+    pub fn new(state: T, coalescing_interval: Duration) -> TrackedTwin<T> {
+        TrackedTwin {
+            state,
+            last_reported: Value::Null,
+            changed_at: Some(Instant::now()),
+            coalescing_interval,
+            in_flight: None,
+        }
+    }
+
+    /// Tracks the specified state, which is already reflected in the reported properties
+    pub fn from_reported(state: T, coalescing_interval: Duration) -> TrackedTwin<T> {
+        let last_reported = serde_json::to_value(&state).unwrap_or(Value::Null);
+        TrackedTwin {
+            state,
+            last_reported,
+            changed_at: None,
+            coalescing_interval,
+            in_flight: None,
+        }
+    }
+
+    /// The current state
+    pub fn get(&self) -> &T {
+        &self.state
+    }
+
+    /// Mutates the state, marking it as changed
+    pub fn update<F: FnOnce(&mut T)>(&mut self, mutation: F) {
+        mutation(&mut self.state);
+        if self.changed_at.is_none() {
+            self.changed_at = Some(Instant::now());
+        }
+    }
+
+    /// The patch describing the changes made since the last accepted report, if any
+    pub fn pending_changes(&self) -> Option<Map<String, Value>> {
+        let current = serde_json::to_value(&self.state).ok()?;
+        match diff(&self.last_reported, &current) {
+            Some(Value::Object(patch)) if !patch.is_empty() => Some(patch),
+            _ => None,
+        }
+    }
+
+    /// Takes the pending changes immediately, regardless of the coalescing interval, unless a report is in flight.
+    /// The changes are reported again unless `report_accepted` is called.
+    pub fn take_changes(&mut self) -> Option<Map<String, Value>> {
+        if self.in_flight.is_some() {
+            return None;
+        }
+        let current = serde_json::to_value(&self.state).ok()?;
+        let patch = match diff(&self.last_reported, &current) {
+            Some(Value::Object(patch)) if !patch.is_empty() => patch,
+            _ => {
+                self.changed_at = None;
+                return None;
+            }
+        };
+        self.in_flight = Some(InFlightReport {
+            request_id: None,
+            snapshot: current,
+            changed_at: self.changed_at.take(),
+        });
+        Some(patch)
+    }
+
+    /// Makes the report in flight the baseline of the next patches
+    pub fn report_accepted(&mut self) {
+        if let Some(report) = self.in_flight.take() {
+            self.last_reported = report.snapshot;
+        }
+    }
+
+    /// Drops the report in flight: its changes are reported again, with the next ones
+    pub fn report_failed(&mut self) {
+        if let Some(report) = self.in_flight.take() {
+            self.changed_at = match (report.changed_at, self.changed_at) {
+                (Some(failed), Some(later)) => Some(failed.min(later)),
+                (failed, later) => failed.or(later).or_else(|| Some(Instant::now())),
+            };
+        }
+    }
+
+    /// Completes the report sent by `auto_report` with the session's event answering it.
+    /// Returns TRUE if the event answered the report.
+    pub fn handle_event(&mut self, event: &TwinEvent) -> bool {
+        let (request_id, accepted) = match event {
+            TwinEvent::ReportedPropertiesUpdated { request_id, .. } => (request_id, true),
+            TwinEvent::RequestFailed { request_id, .. } => (request_id, false),
+            _ => return false,
+        };
+        let answered = self
+            .in_flight
+            .as_ref()
+            .is_some_and(|report| report.request_id.as_ref() == Some(request_id));
+        match (answered, accepted) {
+            (true, true) => self.report_accepted(),
+            (true, false) => self.report_failed(),
+            (false, _) => {}
+        }
+        answered
+    }
+
+    /// Takes the pending changes, if the coalescing interval has elapsed since the first unreported change
+    pub fn poll_changes(&mut self, now: Instant) -> Option<Map<String, Value>> {
+        match self.changed_at {
+            Some(changed_at) if now.duration_since(changed_at) >= self.coalescing_interval => {
+                self.take_changes()
+            }
+            _ => None,
+        }
+    }
+
+    /// Reports the pending changes through the session once the coalescing interval has elapsed.
+    /// Returns the identifier of the update request, if one was sent; pass the session's events to `handle_event`
+    /// to complete it.
+    pub fn auto_report<S: Read + Write>(
+        &mut self,
+        session: &mut TwinSession<S>,
+    ) -> Result<Option<String>, TwinError> {
+        let patch = match self.poll_changes(Instant::now()) {
+            Some(patch) => patch,
+            None => return Ok(None),
+        };
+        match session.update_reported_properties(patch) {
+            Ok(request_id) => {
+                if let Some(report) = self.in_flight.as_mut() {
+                    report.request_id = Some(request_id.clone());
+                }
+                Ok(Some(request_id))
+            }
+            Err(e) => {
+                self.report_failed();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Computes the JSON merge patch that turns `old` into `new`.
+/// Returns None if both values are equal.
+fn diff(old: &Value, new: &Value) -> Option<Value> {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut patch = Map::new();
+            for (key, new_value) in new {
+                let changed = match old.get(key) {
+                    Some(old_value) => diff(old_value, new_value),
+                    None => Some(new_value.clone()),
+                };
+                if let Some(changed) = changed {
+                    let _ = patch.insert(key.clone(), changed);
+                }
+            }
+            for key in old.keys() {
+                if !new.contains_key(key) {
+                    let _ = patch.insert(key.clone(), Value::Null);
+                }
+            }
+            if patch.is_empty() {
+                None
+            } else {
+                Some(Value::Object(patch))
+            }
+        }
+        (old, new) if old == new => None,
+        (_, new) => Some(new.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Serialize)]
+    struct State {
+        firmware: String,
+        sensors: Map<String, Value>,
+    }
+
+    fn state() -> State {
+        let mut sensors = Map::new();
+        let _ = sensors.insert("temp".to_owned(), json!({ "interval": 10, "enabled": true }));
+        State {
+            firmware: "1.0".to_owned(),
+            sensors,
+        }
+    }
+
+    #[test]
+    fn test_only_changed_paths_are_reported() {
+        let mut sut = TrackedTwin::from_reported(state(), Duration::from_secs(0));
+        assert!(sut.take_changes().is_none());
+
+        sut.update(|s| {
+            s.sensors["temp"]["interval"] = json!(20);
+            let _ = s.sensors.insert("humidity".to_owned(), json!({ "enabled": false }));
+        });
+
+        let patch = sut.take_changes().unwrap();
+        assert_eq!(
+            Value::Object(patch),
+            json!({ "sensors": { "temp": { "interval": 20 }, "humidity": { "enabled": false } } })
+        );
+        sut.report_accepted();
+        assert!(sut.take_changes().is_none());
+    }
+
+    #[test]
+    fn test_failed_report_is_merged_into_the_next_one() {
+        let mut sut = TrackedTwin::from_reported(state(), Duration::from_secs(0));
+        sut.update(|s| s.firmware = "1.1".to_owned());
+        let _ = sut.take_changes().unwrap();
+
+        // one report at a time
+        sut.update(|s| s.sensors["temp"]["enabled"] = json!(false));
+        assert!(sut.take_changes().is_none());
+
+        let failed = TwinEvent::RequestFailed {
+            request_id: "1".to_owned(),
+            error: TwinError::TooManyRequests,
+        };
+        sut.in_flight.as_mut().unwrap().request_id = Some("1".to_owned());
+        assert!(sut.handle_event(&failed));
+        let patch = sut.poll_changes(Instant::now()).unwrap();
+        assert_eq!(
+            Value::Object(patch),
+            json!({ "firmware": "1.1", "sensors": { "temp": { "enabled": false } } })
+        );
+
+        sut.report_accepted();
+        sut.update(|s| s.firmware = "1.2".to_owned());
+        assert_eq!(Value::Object(sut.take_changes().unwrap()), json!({ "firmware": "1.2" }));
+    }
+
+    #[test]
+    fn test_removed_properties_are_reported_as_null() {
+        let mut sut = TrackedTwin::from_reported(state(), Duration::from_secs(0));
+        sut.update(|s| {
+            let _ = s.sensors.remove("temp");
+        });
+        let patch = sut.take_changes().unwrap();
+        assert_eq!(Value::Object(patch), json!({ "sensors": { "temp": null } }));
+    }
+
+    #[test]
+    fn test_rapid_changes_are_coalesced() {
+        let interval = Duration::from_secs(60);
+        let mut sut = TrackedTwin::from_reported(state(), interval);
+        let start = Instant::now();
+        sut.update(|s| s.firmware = "1.1".to_owned());
+        sut.update(|s| s.firmware = "1.2".to_owned());
+        assert!(sut.poll_changes(start).is_none());
+
+        let patch = sut.poll_changes(start + interval * 2).unwrap();
+        assert_eq!(Value::Object(patch), json!({ "firmware": "1.2" }));
+        sut.report_accepted();
+        assert!(sut.poll_changes(start + interval * 3).is_none());
+    }
+}